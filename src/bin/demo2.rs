@@ -5,9 +5,11 @@ fn test_address(group_index: u32, bitmap_index: u32) {
     //     "initial => group_index: {}, bitmap_index: {}",
     //     group_index, bitmap_index
     // );
-    let block_index = Group::create_public_address(group_index, bitmap_index);
+    let block_index =
+        Group::create_public_address(group_index, bitmap_index, 32_768);
     // println!("computed => block_index: {}", block_index);
-    let (group_index2, bitmap_index2) = Group::translate_public_address(block_index);
+    let (group_index2, bitmap_index2) =
+        Group::translate_public_address(block_index, 32_768);
     // println!(
     //     "translated => group_index: {}, bitmap_index: {}",
     //     group_index2, bitmap_index2
@@ -1,9 +1,10 @@
 use std::{
     fs::File,
-    io::{BufReader, BufWriter, Cursor, Write},
+    io::{BufReader, BufWriter, Write},
+    path::Path,
     time::Instant,
 };
-use walnut::FS;
+use walnut::{EntryKind, FS};
 
 use clap::{Parser, Subcommand};
 
@@ -19,13 +20,25 @@ struct Cli {
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    Init,
+    Init {
+        /// Volume label
+        #[arg(long, default_value = "")]
+        label: String,
+        /// Bytes per block, a power of two from 1024 to 65536
+        #[arg(long, default_value_t = 4096)]
+        block_size: u32,
+    },
     /// Adds files to myapp
     Add {
         from: String,
         path: String,
         filename: String,
     },
+    /// Import a host directory tree, recording the original paths
+    Import {
+        from: String,
+        path: String,
+    },
     Remove {
         path: String,
         filename: String,
@@ -34,23 +47,77 @@ enum Commands {
         path: String,
         filename: String,
     },
+    /// Print the first bytes of a file
+    Head {
+        path: String,
+        filename: String,
+        #[arg(short = 'n', default_value_t = 1024)]
+        bytes: u64,
+    },
     Copy {
         from: String,
         to: String,
     },
-    Fsinfo,
+    /// Copy a file inside the image
+    CopyFile {
+        path: String,
+        filename: String,
+        to_path: String,
+        to_filename: String,
+    },
+    Fsinfo {
+        /// Print space usage by category
+        #[arg(long)]
+        breakdown: bool,
+        /// Print data fragmentation
+        #[arg(long)]
+        frag: bool,
+    },
     Fileinfo {
         path: String,
         filename: String,
     },
     Ls {
         path: String,
+        /// Show kinds, sizes and modification times
+        #[arg(short = 'l')]
+        long: bool,
     },
     Lsdir,
+    /// Print the biggest files
+    Biggest {
+        #[arg(default_value_t = 10)]
+        count: usize,
+    },
+    /// Print the last bytes of a file
+    Tail {
+        path: String,
+        filename: String,
+        #[arg(short = 'n', default_value_t = 1024)]
+        bytes: u64,
+    },
+    /// Check image consistency
+    Fsck {
+        /// Free leaked blocks
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Move file data to the front and shrink the image
+    Compact,
     Export {
         path: String,
         filename: String,
         out: String,
+        /// Write through a memory map of the output file
+        #[arg(long)]
+        mmap: bool,
+    },
+    /// Export every file
+    ExportAll {
+        out: String,
+        /// Restore files to their recorded host paths below out
+        #[arg(long)]
+        original_paths: bool,
     },
 }
 
@@ -58,29 +125,61 @@ fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Init => init(&cli.fs_path, &cli.secret),
-        Commands::Fsinfo => {
+        Commands::Init { label, block_size } => {
+            init(&cli.fs_path, &cli.secret, &label, block_size)
+        }
+        Commands::Fsinfo { breakdown, frag } => {
             let fs = FS::new(&cli.fs_path, &cli.secret).unwrap();
-            println!("{:?}", &fs.superblock)
+            println!("{:?}", &fs.superblock);
+            println!("label: {}", fs.label());
+            println!("uuid: {}", fs.uuid());
+            if breakdown {
+                let b = fs.space_breakdown().unwrap();
+                println!("superblock: {} bytes", b.superblock);
+                println!("bitmaps:    {} bytes", b.bitmaps);
+                println!("inodes:     {} bytes", b.inodes);
+                println!("file data:  {} bytes", b.file_data);
+                println!("free:       {} bytes", b.free);
+            }
+            if frag {
+                println!("fragmentation: {:.3}", fs.fragmentation().unwrap());
+            }
         }
         Commands::Fileinfo { path, filename } => {
             let mut fs = FS::new(&cli.fs_path, &cli.secret).unwrap();
             let inode = fs.get_file_info(&path, &filename).unwrap();
             println!("{:?}", &inode);
         }
-        Commands::Ls { path } => {
+        Commands::Ls { path, long: true } => {
+            let mut fs = FS::new(&cli.fs_path, &cli.secret).unwrap();
+            fs.list_detailed(&path).unwrap().iter().for_each(|e| {
+                let kind = match e.kind {
+                    EntryKind::File => "-",
+                    EntryKind::Directory => "d",
+                };
+                println!(
+                    "{0} {1: <20} | inode: {2: <8} | size: {3: <10} | modified: {4}",
+                    kind, e.name, e.inode, e.size, e.modified
+                )
+            })
+        }
+        Commands::Ls { path, long: false } => {
             let fs = FS::new(&cli.fs_path, &cli.secret).unwrap();
-            let (dir, _) = fs.find_directory(&path).unwrap();
-            dir.files
-                .iter()
-                .for_each(|f| println!("{0: <20} | inode: {1}", f.0, f.1))
+            let stdout = std::io::stdout();
+            fs.ls_stream(&path, &mut stdout.lock()).unwrap();
         }
         Commands::Lsdir => {
             let fs = FS::new(&cli.fs_path, &cli.secret).unwrap();
-            let dirindex = fs.get_directory_index().unwrap();
-            dirindex.directories().iter().for_each(|(dir, _index)| {
-                println!("{}", dir.to_string_lossy());
-            });
+            print_tree(&fs, Path::new(""), 0);
+        }
+        Commands::Biggest { count } => {
+            let fs = FS::new(&cli.fs_path, &cli.secret).unwrap();
+            fs.largest_files(count).unwrap().iter().for_each(
+                |(dir, name, size)| {
+                    let path = Path::new(dir).join(name);
+                    println!("{0: <10} {1}", size, path.display())
+                },
+            );
         }
         Commands::Add {
             from,
@@ -102,17 +201,79 @@ fn main() {
             let duration = start.elapsed();
             println!("Time alapsed: {} millisec", duration.as_millis());
         }
+        Commands::CopyFile {
+            path,
+            filename,
+            to_path,
+            to_filename,
+        } => {
+            let mut fs = FS::new(&cli.fs_path, &cli.secret).unwrap();
+            fs.copy_file(&path, &filename, &to_path, &to_filename)
+                .unwrap();
+        }
         Commands::Remove { path, filename } => {
             remove_file(&cli.fs_path, &cli.secret, &path, &filename);
         }
         Commands::Get { path, filename } => {
             print_file(&cli.fs_path, &cli.secret, &path, &filename);
         }
+        Commands::Head {
+            path,
+            filename,
+            bytes,
+        } => {
+            let fs = FS::new(&cli.fs_path, &cli.secret).unwrap();
+            let data = fs.head(&path, &filename, bytes).unwrap();
+            std::io::stdout().write_all(&data).unwrap();
+        }
+        Commands::Tail {
+            path,
+            filename,
+            bytes,
+        } => {
+            let fs = FS::new(&cli.fs_path, &cli.secret).unwrap();
+            let data = fs.tail(&path, &filename, bytes).unwrap();
+            std::io::stdout().write_all(&data).unwrap();
+        }
+        Commands::Compact => {
+            let mut fs = FS::new(&cli.fs_path, &cli.secret).unwrap();
+            let report = fs.compact().unwrap();
+            println!("moved {} bytes", report.bytes_moved);
+            println!("regions eliminated: {}", report.regions_eliminated);
+            println!("groups removed: {}", report.groups_removed);
+        }
+        Commands::Fsck { repair } => fsck(&cli.fs_path, &cli.secret, repair),
         Commands::Export {
             path,
             filename,
             out,
-        } => export(&cli.fs_path, &cli.secret, &path, &filename, &out),
+            mmap,
+        } => export(&cli.fs_path, &cli.secret, &path, &filename, &out, mmap),
+        Commands::Import { from, path } => {
+            let mut fs = FS::new(&cli.fs_path, &cli.secret).unwrap();
+            let stats = fs.import_tree(&from, &path).unwrap();
+            stats.skipped.iter().for_each(|(path, e)| {
+                println!("skipped {}: {}", path.display(), e)
+            });
+            println!("imported {} files, {} bytes", stats.files, stats.bytes);
+        }
+        Commands::ExportAll {
+            out,
+            original_paths,
+        } => {
+            let fs = FS::new(&cli.fs_path, &cli.secret).unwrap();
+            let count = fs.export_all(&out, original_paths).unwrap();
+            println!("exported {} files", count);
+        }
+    }
+}
+
+fn print_tree(fs: &FS, dir: &Path, depth: usize) {
+    for child in fs.list_subdirectories(dir).unwrap() {
+        let child = Path::new(&child);
+        let name = child.file_name().unwrap_or_default();
+        println!("{}{}", "  ".repeat(depth), name.to_string_lossy());
+        print_tree(fs, child, depth + 1);
     }
 }
 
@@ -146,12 +307,40 @@ fn remove_file(fs_path: &str, secret: &str, path: &str, file_name: &str) {
 
 fn print_file(fs_path: &str, secret: &str, path: &str, file_name: &str) {
     let mut fs = FS::new(fs_path, secret).unwrap();
-    let mut d = vec![];
-    let mut buf = Cursor::new(&mut d);
 
-    fs.get_file_data(path, file_name, &mut buf).unwrap();
+    // Stream file content directly to stdout
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
 
-    println!("{}", String::from_utf8_lossy(&d));
+    fs.get_file_data(path, file_name, &mut out).unwrap();
+    out.flush().unwrap();
+}
+
+fn fsck(fs_path: &str, secret: &str, repair: bool) {
+    let mut fs = FS::new(fs_path, secret).unwrap();
+    let report = if repair {
+        fs.fsck_repair().unwrap()
+    } else {
+        fs.fsck().unwrap()
+    };
+
+    println!("files checked: {}", report.files);
+    report
+        .bad_checksums
+        .iter()
+        .for_each(|path| println!("bad checksum: {}", path.display()));
+    println!("leaked blocks: {:?}", report.leaked);
+    println!("double allocated blocks: {:?}", report.double_allocated);
+    println!("unallocated blocks: {:?}", report.unallocated);
+    report.directory_collisions.iter().for_each(|paths| {
+        println!("directories sharing an inode: {:?}", paths)
+    });
+    if repair && !report.leaked.is_empty() {
+        println!("freed {} leaked blocks", report.leaked.len());
+    }
+    if report.is_clean() {
+        println!("clean");
+    }
 }
 
 fn export(
@@ -160,23 +349,29 @@ fn export(
     path: &str,
     file_name: &str,
     output: &str,
+    mmap: bool,
 ) {
     let mut fs = FS::new(fs_path, secret).unwrap();
 
     let start = Instant::now();
 
-    let mut file = File::create(output).unwrap();
-    let finfo = fs.get_file_info(path, file_name).unwrap();
+    if mmap {
+        fs.export_mmap(path, file_name, output).unwrap();
+    } else {
+        let mut file = File::create(output).unwrap();
+        let finfo = fs.get_file_info(path, file_name).unwrap();
 
-    file.set_len(finfo.size).unwrap();
+        file.set_len(finfo.size).unwrap();
 
-    fs.get_file_data(path, file_name, &mut file).unwrap();
-    file.flush().unwrap();
+        fs.get_file_data(path, file_name, &mut file).unwrap();
+        file.flush().unwrap();
+    }
 
     let duration = start.elapsed();
     println!("Time alapsed: {} millisec", duration.as_millis());
 }
 
-fn init(path: &str, secret: &str) {
-    FS::init(path, secret).unwrap();
+fn init(path: &str, secret: &str, label: &str, block_size: u32) {
+    let mut fs = FS::init_with_options(path, secret, block_size).unwrap();
+    fs.set_label(label).unwrap();
 }
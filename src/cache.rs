@@ -0,0 +1,137 @@
+//! Block cache for data reads
+//! Keeps on disk (encrypted) block bytes, least recently used
+//! blocks are evicted first, pinned blocks are never evicted
+
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+struct Entry {
+    data: Vec<u8>,
+    last_used: u64,
+    pinned: bool,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct BlockCache {
+    capacity: usize, // Unpinned blocks to keep, 0 disables caching
+    entries: HashMap<u32, Entry>,
+    unpinned: usize, // Unpinned entry count
+    tick: u64,
+}
+
+impl BlockCache {
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Set unpinned capacity in blocks
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.unpinned > self.capacity {
+            self.evict();
+        }
+    }
+
+    pub(crate) fn contains(&self, block_index: u32) -> bool {
+        self.entries.contains_key(&block_index)
+    }
+
+    /// Copy cached block into buffer
+    /// Returns false if block is not cached
+    #[inline]
+    pub(crate) fn get(&mut self, block_index: u32, buf: &mut [u8]) -> bool {
+        self.tick += 1;
+        match self.entries.get_mut(&block_index) {
+            Some(entry) if entry.data.len() >= buf.len() => {
+                entry.last_used = self.tick;
+                buf.copy_from_slice(&entry.data[..buf.len()]);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Cache block read from disk
+    #[inline]
+    pub(crate) fn insert(&mut self, block_index: u32, data: &[u8]) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(&block_index) {
+            entry.data = data.to_vec();
+            entry.last_used = self.tick;
+            return;
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        if self.unpinned >= self.capacity {
+            self.evict();
+        }
+        self.entries.insert(
+            block_index,
+            Entry {
+                data: data.to_vec(),
+                last_used: self.tick,
+                pinned: false,
+            },
+        );
+        self.unpinned += 1;
+    }
+
+    /// Update block written to disk if it is cached
+    #[inline]
+    pub(crate) fn update(&mut self, block_index: u32, data: &[u8]) {
+        if let Some(entry) = self.entries.get_mut(&block_index) {
+            entry.data = data.to_vec();
+        }
+    }
+
+    /// Keep block in cache until it is unpinned
+    pub(crate) fn pin(&mut self, block_index: u32, data: &[u8]) {
+        match self.entries.get_mut(&block_index) {
+            Some(entry) => {
+                if !entry.pinned {
+                    entry.pinned = true;
+                    self.unpinned -= 1;
+                }
+                entry.data = data.to_vec();
+            }
+            None => {
+                self.entries.insert(
+                    block_index,
+                    Entry {
+                        data: data.to_vec(),
+                        last_used: self.tick,
+                        pinned: true,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Pinned block becomes a normal cache entry
+    pub(crate) fn unpin(&mut self, block_index: u32) {
+        if let Some(entry) = self.entries.get_mut(&block_index) {
+            if entry.pinned {
+                entry.pinned = false;
+                self.unpinned += 1;
+            }
+        }
+        while self.unpinned > self.capacity {
+            self.evict();
+        }
+    }
+
+    /// Remove least recently used unpinned block
+    fn evict(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.pinned)
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(block_index, _)| *block_index);
+        if let Some(block_index) = oldest {
+            self.entries.remove(&block_index);
+            self.unpinned -= 1;
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Errors of the public API
+//! Internal code passes anyhow errors around, common failures are
+//! created as WalnutError and recovered from them at the API boundary
+
+use std::fmt;
+
+/// Result of the public API
+pub type Result<T, E = WalnutError> = std::result::Result<T, E>;
+
+/// Error returned by the public API
+/// Common failures have their own variant, the rest is kept as Other
+#[derive(Debug)]
+pub enum WalnutError {
+    /// Directory is not in the directory index
+    DirNotFound,
+    /// File is not in its directory
+    FileNotFound,
+    /// File with the given name is already in the directory
+    FileExists(String),
+    /// Stored data does not match its checksum
+    ChecksumMismatch,
+    /// Inode or data blocks cannot be allocated
+    OutOfSpace,
+    /// Image was encrypted with another secret
+    WrongSecret,
+    /// File size differs from its directory entry, e.g. after a torn write
+    IncompleteFile(String),
+    Io(std::io::Error),
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for WalnutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DirNotFound => write!(f, "Directory not found"),
+            Self::FileNotFound => write!(f, "File not found"),
+            Self::FileExists(name) => write!(f, "File {} already exist", name),
+            Self::ChecksumMismatch => {
+                write!(f, "Data checksum verification failed")
+            }
+            Self::OutOfSpace => write!(f, "Out of space"),
+            Self::WrongSecret => write!(f, "wrong secret"),
+            Self::IncompleteFile(name) => {
+                write!(f, "File {} is incomplete", name)
+            }
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for WalnutError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for WalnutError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<WalnutError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        match e.downcast::<std::io::Error>() {
+            Ok(e) => e.into(),
+            Err(e) => Self::Other(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for WalnutError {
+    fn from(e: std::io::Error) -> Self {
+        // Readers pass WalnutError through io::Error
+        match e.downcast::<WalnutError>() {
+            Ok(e) => e,
+            Err(e) => Self::Io(e),
+        }
+    }
+}
+
+impl From<bincode::Error> for WalnutError {
+    fn from(e: bincode::Error) -> Self {
+        Self::Other(e.into())
+    }
+}
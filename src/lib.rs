@@ -1,39 +1,142 @@
 use anyhow::anyhow;
 use bitvec::{order::Lsb0, vec::BitVec};
 use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
 use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader, BufWriter, Cursor, Seek, SeekFrom};
+use std::rc::Rc;
 use std::{
     collections::BTreeMap,
     ffi::OsString,
     io::{Read, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
+use uuid::Uuid;
 
+use cache::BlockCache;
+use cipher::*;
+use render::BitmapImage;
 use util::*;
 
+pub use error::{Result, WalnutError};
+pub use storage::Storage;
+
 const MAGIC: [u8; 7] = *b"*bitfs*";
+/// Encrypted to tell whether a secret is right
+const KEY_CHECK_BYTES: [u8; 32] = *b"walnut secret verification bytes";
 // const TEST_BYTES: [u8; 20] = *b"canureadthistextbro?";
-const FS_VERSION: u32 = 1;
+const FS_VERSION: u32 = 18;
 const ROOT_INODE_INDEX: u32 = 2;
+/// Block size of images created without options
 const BLOCK_SIZE: u32 = 4096;
-const BLOCKS_PER_GROUP: u32 = BLOCK_SIZE * 8;
-const INODE_CAPACITY: usize = 4047;
-const INODE_MAX_REGION: usize = 500;
-
+const MIN_BLOCK_SIZE: u32 = 1024;
+const MAX_BLOCK_SIZE: u32 = 64 * 1024;
+const BLOCKS_PER_GROUP: u32 = max_blocks_per_group(BLOCK_SIZE);
+const MIN_BLOCKS_PER_GROUP: u32 = 64;
+const BLOCKS_PER_INODE: u32 = 4;
+/// Region start of a hole, block 0 is the superblock
+/// so it never holds file data
+const HOLE: u32 = 0;
+const LABEL_SIZE: usize = 32;
+const DEFAULT_RESERVED_PERCENTAGE: u8 = 5;
+const MAX_RESERVED_PERCENTAGE: u8 = 50;
+const FLAG_COMPRESS_INDEX: u32 = 1;
+const FLAG_PORTABLE_NAMES: u32 = 2;
+/// Files may hold holes, older tools read them from the superblock
+const FLAG_SPARSE: u32 = 4;
+const KNOWN_FLAGS: u32 =
+    FLAG_COMPRESS_INDEX | FLAG_PORTABLE_NAMES | FLAG_SPARSE;
+const TEMP_DIRECTORY: &str = ".walnut-tmp";
+const CORRUPT_DIRECTORY: &str = ".corrupt";
+const CONFIG_DIRECTORY: &str = ".walnut"; // Config file before version 18
+const CONFIG_FILE: &str = "config";
+const STREAM_CHUNK_BLOCKS: u64 = 256;
+
+mod cache;
+pub mod cipher;
+mod error;
+mod migrate;
+pub mod render;
+mod storage;
 pub mod util;
 
+/// How the image was opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Opened with its secret, full access
+    ReadWrite,
+    /// Opened without secret, only structural metadata is readable
+    MetadataOnly,
+    /// Opened with its secret, files can be added and appended to,
+    /// but not removed, renamed or rewritten
+    AppendOnly,
+}
+
 #[derive(Debug)]
 pub struct FS {
     pub superblock: Superblock,
-    pub file: File,
+    pub file: Storage,
     pub groups: Vec<Group>,
-    pub lookup_table: Vec<u8>,
+    cipher: Box<dyn Cipher>,
+    mode: Mode,
+    throttle: Option<Throttle>,
+    allocation: AllocationMode,
+    alloc_strategy: AllocStrategy,
+    max_regions: usize, // Region limit of file data
+    clock: Box<dyn Clock>,
+    retry: RetryPolicy,
+    cache: RefCell<BlockCache>,
+    pins: BTreeMap<PathBuf, Vec<u32>>, // Pinned blocks by file path
+    pin_budget: u64,                   // Maximum pinned bytes
+    access_log: RefCell<AccessLog>,    // Last file reads of this handle
+    bytes_read: Cell<u64>,             // Data block bytes read since open
+    bytes_written: Cell<u64>,          // Data block bytes written since open
+    path: Option<PathBuf>,             // Image file, None if opened by storage
+    writing: Cell<bool>,               // Stored generation is marked odd
 }
 
 impl FS {
     /// Init FS to a given path
-    pub fn init<P>(path: P, secret: &str) -> anyhow::Result<Self>
+    pub fn init<P>(path: P, secret: &str) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::init_with_label(path, secret, "")
+    }
+
+    /// Init FS to a given path with a volume label
+    pub fn init_with_label<P>(
+        path: P,
+        secret: &str,
+        label: &str,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::init_with_cipher(path, label, Box::new(XorCipher::new(secret)))
+    }
+
+    /// Init FS to a given path using a custom cipher
+    /// Cipher id is stored, the image must be opened with the same cipher
+    pub fn init_with_cipher<P>(
+        path: P,
+        label: &str,
+        cipher: Box<dyn Cipher>,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::init_with_clock(path, label, cipher, Box::new(SystemClock))
+    }
+
+    /// Init FS taking every timestamp from the given clock
+    /// e.g. a FixedClock for reproducible images
+    pub fn init_with_clock<P>(
+        path: P,
+        label: &str,
+        cipher: Box<dyn Cipher>,
+        clock: Box<dyn Clock>,
+    ) -> Result<Self>
     where
         P: AsRef<Path>,
     {
@@ -48,41 +151,391 @@ impl FS {
         // Create mmap from file
         // let mmap = unsafe { MmapMut::map_mut(&file)? };
 
-        let superblock = Superblock::new();
+        let mut fs = Self::init_storage(
+            Storage::File(file),
+            label,
+            cipher,
+            clock,
+            BLOCK_SIZE,
+        )?;
+        fs.path = Some(path.as_ref().to_path_buf());
+        Ok(fs)
+    }
+
+    /// Init FS with the given block size, a power of two
+    /// from 1KiB to 64KiB. Small blocks waste less space on tiny files,
+    /// large blocks need fewer regions for large files
+    pub fn init_with_options<P>(
+        path: P,
+        secret: &str,
+        block_size: u32,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        // Fail before creating the image
+        check_block_size(block_size)?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path.as_ref())?;
+
+        let mut fs = Self::init_storage(
+            Storage::File(file),
+            "",
+            Box::new(XorCipher::new(secret)),
+            Box::new(SystemClock),
+            block_size,
+        )?;
+        fs.path = Some(path.as_ref().to_path_buf());
+        Ok(fs)
+    }
+
+    /// Init FS kept in memory, e.g. for tests
+    /// The image is lost with its last handle, see from_storage
+    pub fn init_in_memory(secret: &str) -> Result<Self> {
+        Ok(Self::init_storage(
+            Storage::memory(),
+            "",
+            Box::new(XorCipher::new(secret)),
+            Box::new(SystemClock),
+            BLOCK_SIZE,
+        )?)
+    }
+
+    /// Open FS from a storage, e.g. a clone of an in memory image
+    pub fn from_storage(file: Storage, secret: &str) -> Result<Self> {
+        let mut fs = Self::open(file, Mode::ReadWrite)?;
+        fs.cipher = cipher_by_id(fs.superblock.cipher_id, secret)?;
+
+        Ok(fs.finish_open()?)
+    }
+
+    fn init_storage(
+        file: Storage,
+        label: &str,
+        cipher: Box<dyn Cipher>,
+        clock: Box<dyn Clock>,
+        block_size: u32,
+    ) -> anyhow::Result<Self> {
+        check_block_size(block_size)?;
+
+        let mut superblock = Superblock::new(clock.now());
+        superblock.set_label(label)?;
+        superblock.cipher_id = cipher.id();
+        superblock.key_check = key_check(cipher.as_ref());
+        superblock.block_size = block_size;
+        superblock.blocks_per_group = max_blocks_per_group(block_size);
+
+        Self::format_storage(file, superblock, cipher, clock)
+    }
 
+    /// Format the storage as a new image of the given superblock
+    fn format_storage(
+        file: Storage,
+        superblock: Superblock,
+        cipher: Box<dyn Cipher>,
+        clock: Box<dyn Clock>,
+    ) -> anyhow::Result<Self> {
         let mut fs = Self {
+            max_regions: inode_max_region(superblock.block_size),
             superblock,
             file,
             groups: vec![],
-            lookup_table: create_lookup_table(secret.as_bytes(), BLOCK_SIZE),
+            cipher,
+            mode: Mode::ReadWrite,
+            throttle: None,
+            allocation: AllocationMode::default(),
+            alloc_strategy: AllocStrategy::default(),
+            clock,
+            retry: RetryPolicy::default(),
+            cache: RefCell::new(BlockCache::default()),
+            pins: BTreeMap::new(),
+            pin_budget: 0,
+            access_log: RefCell::new(AccessLog::default()),
+            bytes_read: Cell::new(0),
+            bytes_written: Cell::new(0),
+            writing: Cell::new(false),
+            path: None,
         };
 
+        fs.format()?;
+
+        Ok(fs)
+    }
+
+    /// Create the first group and the directory index
+    /// Superblock has to be set already
+    fn format(&mut self) -> anyhow::Result<()> {
         // Create group
-        let mut group = Group::init();
+        let mut group = Group::init(self.blocks_per_group());
 
         // Set root inode index as allocated
         group.force_allocate_at(0);
 
         // Add to superblock
-        fs.add_group(group)?;
+        self.add_group(group)?;
+        self.superblock.inode_counts[0] = 1;
 
         // Create directory_index
-        fs.init_directory_index()?;
+        self.init_directory_index()?;
 
-        Ok(fs)
+        // Readers wait for this save, see begin_write
+        self.save_superblock()
     }
 
     /// Open FS from a given path
-    pub fn new<P>(path: P, secret: &str) -> anyhow::Result<Self>
+    /// Cipher is selected by the cipher id stored in the superblock
+    pub fn new<P>(path: P, secret: &str) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut fs = Self::open_read_write(path)?;
+
+        // Select built-in cipher
+        fs.cipher = cipher_by_id(fs.superblock.cipher_id, secret)?;
+
+        Ok(fs.finish_open()?)
+    }
+
+    /// Check a secret without opening the image
+    /// Only the superblock is read, images before version 11
+    /// have no key check, their directory index is read instead
+    pub fn check_secret<P>(path: P, secret: &str) -> bool
+    where
+        P: AsRef<Path>,
+    {
+        Self::check_secret_inner(path.as_ref(), secret).unwrap_or(false)
+    }
+
+    fn check_secret_inner(path: &Path, secret: &str) -> anyhow::Result<bool> {
+        let file = File::open(path)?;
+        let superblock = Superblock::deserialize_from(BufReader::new(&file))?;
+        let cipher = cipher_by_id(superblock.cipher_id, secret)?;
+
+        if superblock.fs_version >= 11 {
+            return Ok(superblock.key_check == key_check(cipher.as_ref()));
+        }
+
+        let mut fs = Self::open(Storage::File(file), Mode::ReadWrite)?;
+        fs.cipher = cipher;
+        Ok(fs.index_readable())
+    }
+
+    /// Open FS from a given path using a custom cipher
+    pub fn new_with_cipher<P>(path: P, cipher: Box<dyn Cipher>) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut fs = Self::open_read_write(path)?;
+
+        if fs.superblock.cipher_id != cipher.id() {
+            return Err(anyhow!(
+                "Image uses cipher id {}, got {}",
+                fs.superblock.cipher_id,
+                cipher.id()
+            )
+            .into());
+        }
+        fs.cipher = cipher;
+
+        Ok(fs.finish_open()?)
+    }
+
+    /// Open FS for adding and appending files only
+    /// e.g. for audit logs, existing data cannot be changed by this handle
+    pub fn open_append_only<P>(path: P, secret: &str) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut fs = Self::new(path, secret)?;
+        fs.mode = Mode::AppendOnly;
+        Ok(fs)
+    }
+
+    /// Open FS without its secret
+    /// Superblock, groups and inodes are readable,
+    /// any data read or write is refused
+    pub fn open_metadata_only<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        // Open image path as read only
+        let file = OpenOptions::new().read(true).open(path.as_ref())?;
+
+        Ok(Self::open(Storage::File(file), Mode::MetadataOnly)?)
+    }
+
+    /// Open FS for reading from several threads
+    /// Returned handle reads with positioned reads, see ReadOnlyFs
+    pub fn open_readonly<P>(path: P, secret: &str) -> Result<ReadOnlyFs>
+    where
+        P: AsRef<Path>,
+    {
+        // Open image path as read only
+        let file = OpenOptions::new().read(true).open(path.as_ref())?;
+        let fs =
+            Self::open(Storage::File(file.try_clone()?), Mode::MetadataOnly)?;
+
+        // Upgrades write the image
+        if fs.superblock.fs_version < FS_VERSION {
+            return Err(anyhow!(
+                "Image version {} needs an upgrade, open it with FS::new first",
+                fs.superblock.fs_version
+            )
+            .into());
+        }
+
+        let cipher = cipher_by_id(fs.superblock.cipher_id, secret)?;
+        if fs.superblock.key_check != key_check(cipher.as_ref()) {
+            return Err(WalnutError::WrongSecret);
+        }
+
+        Ok(ReadOnlyFs {
+            group_count: fs.groups.len() as u32,
+            superblock: fs.superblock,
+            file,
+            cipher,
+        })
+    }
+
+    /// Open image with a corrupted superblock
+    /// Last resort recovery: superblock is rebuilt from the given
+    /// parameters and the group bitmaps, then written to disk.
+    /// Label, UUID and settings are reset to their defaults
+    pub fn open_with_params<P>(
+        path: P,
+        secret: &str,
+        block_size: u32,
+        group_count: u32,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open_with_params_and_clock(
+            path,
+            secret,
+            block_size,
+            group_count,
+            Box::new(SystemClock),
+        )
+    }
+
+    /// Open image with a corrupted superblock, see open_with_params
+    /// The rebuilt superblock takes its timestamps from the given clock
+    pub fn open_with_params_and_clock<P>(
+        path: P,
+        secret: &str,
+        block_size: u32,
+        group_count: u32,
+        clock: Box<dyn Clock>,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        check_block_size(block_size)?;
+        if group_count == 0 {
+            return Err(anyhow!("Image has at least one group").into());
+        }
+
+        // Open image path as read & write
+        let mut file = Storage::File(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path.as_ref())?,
+        );
+
+        // Read group bitmaps, skipping the superblock
+        let mut groups = vec![];
+        {
+            let mut r = BufReader::new(&mut file);
+            for group_index in 0..group_count {
+                // Checksums are in the lost superblock
+                groups.push(Group::deserialize_from(
+                    &mut r,
+                    group_index,
+                    max_blocks_per_group(block_size),
+                    block_size,
+                    None,
+                )?);
+            }
+        }
+
+        let mut superblock = Superblock::new(clock.now());
+        superblock.group_count = group_count;
+        superblock.block_size = block_size;
+        superblock.blocks_per_group = max_blocks_per_group(block_size);
+
+        let mut fs = Self {
+            superblock,
+            groups,
+            file,
+            cipher: Box::new(XorCipher::new(secret)),
+            mode: Mode::ReadWrite,
+            throttle: None,
+            allocation: AllocationMode::default(),
+            alloc_strategy: AllocStrategy::default(),
+            max_regions: inode_max_region(block_size),
+            clock,
+            retry: RetryPolicy::default(),
+            cache: RefCell::new(BlockCache::default()),
+            pins: BTreeMap::new(),
+            pin_budget: 0,
+            access_log: RefCell::new(AccessLog::default()),
+            bytes_read: Cell::new(0),
+            bytes_written: Cell::new(0),
+            writing: Cell::new(false),
+            path: Some(path.as_ref().to_path_buf()),
+        };
+
+        // Flags are lost, detect index compression
+        if fs.get_directory_index().is_err() {
+            fs.superblock.flags |= FLAG_COMPRESS_INDEX;
+            if fs.get_directory_index().is_err() {
+                return Err(anyhow!(
+                    "Cannot read directory index with the given parameters"
+                )
+                .into());
+            }
+        }
+        fs.superblock.key_check = key_check(fs.cipher.as_ref());
+
+        // Rebuild counters from the directories
+        fs.recount_inodes()?;
+        let mut file_count = 0;
+        for dir_inode_index in fs.get_directory_index()?.directories().values()
+        {
+            file_count += fs.read_directory(*dir_inode_index)?.files.len();
+        }
+        fs.superblock.file_count = file_count as u32;
+
+        // Write fresh superblock
+        fs.save_superblock()?;
+
+        Ok(fs)
+    }
+
+    fn open_read_write<P>(path: P) -> anyhow::Result<Self>
     where
         P: AsRef<Path>,
     {
         // Open image path as read & write
-        let mut file = OpenOptions::new()
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
             .open(path.as_ref())?;
 
+        let mut fs = Self::open(Storage::File(file), Mode::ReadWrite)?;
+        fs.path = Some(path.as_ref().to_path_buf());
+        Ok(fs)
+    }
+
+    /// Read superblock and groups
+    /// Data cannot be read until a cipher is set
+    fn open(mut file: Storage, mode: Mode) -> anyhow::Result<Self> {
         let mut r = BufReader::new(&mut file);
 
         r.seek(SeekFrom::Start(0))?;
@@ -90,1256 +543,11621 @@ impl FS {
         // Deserialize superblock from cursor
         let superblock: Superblock = Superblock::deserialize_from(&mut r)?;
 
+        // Every seek position depends on the block geometry
+        check_block_size(superblock.block_size)?;
+        let max = max_blocks_per_group(superblock.block_size);
+        if !(MIN_BLOCKS_PER_GROUP..=max).contains(&superblock.blocks_per_group)
+        {
+            return Err(anyhow!(
+                "Invalid blocks per group {}",
+                superblock.blocks_per_group
+            ));
+        }
+
+        // Images before version 15 have no group checksums yet
+        let verify_groups = superblock.fs_version >= 15;
+        if verify_groups
+            && superblock.group_checksums.len()
+                != superblock.group_count as usize
+        {
+            return Err(anyhow!(
+                "Superblock has {} group checksums for {} groups",
+                superblock.group_checksums.len(),
+                superblock.group_count
+            ));
+        }
+
         let mut groups = vec![];
 
         // Deserialize groups based on superblock group count
         for group_index in 0..superblock.group_count {
-            let group = Group::deserialize_from(&mut r, group_index)?;
+            let group = Group::deserialize_from(
+                &mut r,
+                group_index,
+                superblock.blocks_per_group,
+                superblock.block_size,
+                verify_groups
+                    .then(|| superblock.group_checksums[group_index as usize]),
+            )?;
             groups.push(group);
         }
 
-        let fs = Self {
+        Ok(Self {
+            max_regions: inode_max_region(superblock.block_size),
             superblock,
             groups,
             file,
-            lookup_table: create_lookup_table(secret.as_bytes(), BLOCK_SIZE),
-        };
-
-        // Return FS
-        Ok(fs)
+            cipher: Box::new(NoCipher),
+            mode,
+            throttle: None,
+            allocation: AllocationMode::default(),
+            alloc_strategy: AllocStrategy::default(),
+            clock: Box::new(SystemClock),
+            retry: RetryPolicy::default(),
+            cache: RefCell::new(BlockCache::default()),
+            pins: BTreeMap::new(),
+            pin_budget: 0,
+            access_log: RefCell::new(AccessLog::default()),
+            bytes_read: Cell::new(0),
+            bytes_written: Cell::new(0),
+            writing: Cell::new(false),
+            path: None,
+        })
     }
 
-    #[inline]
-    pub fn get_directory_index(&self) -> anyhow::Result<DirectoryIndex> {
-        // Get inode
-        let mut inode = self.get_inode(ROOT_INODE_INDEX)?;
+    fn finish_open(mut self) -> anyhow::Result<Self> {
+        // Images before version 11 are checked by their upgrade
+        if self.superblock.fs_version >= 11
+            && self.superblock.key_check != key_check(self.cipher.as_ref())
+        {
+            return Err(WalnutError::WrongSecret.into());
+        }
 
-        // Read inode data
-        let mut data = vec![];
+        // Upgrade image created by an older FS version
+        if self.superblock.fs_version < FS_VERSION {
+            self.upgrade()?;
+        }
 
+        // Writer stopped before its superblock save, see FsReader
+        if self.superblock.generation % 2 == 1
+            && self.mode != Mode::MetadataOnly
         {
-            let mut w = BufWriter::new(&mut data);
-            self.read_inode_data(&mut inode, &mut w)?;
+            self.save_superblock()?;
         }
 
-        // Deserialize
-        let mut directory_index: DirectoryIndex = bincode::deserialize(&data)?;
-
-        if !directory_index.verify_checksum() {
-            return Err(anyhow!("Directory index checksum error"));
+        // Stored options override the defaults,
+        // unreadable ones do not keep the image from opening
+        for (key, value) in self.read_config().unwrap_or_default() {
+            self.apply_config(&key, &value)?;
         }
 
-        Ok(directory_index)
+        // Return FS
+        Ok(self)
     }
 
-    fn save_directory_index(
-        &mut self,
-        mut directory_index: DirectoryIndex,
-    ) -> anyhow::Result<()> {
-        let mut inode = self.get_inode(ROOT_INODE_INDEX)?;
-
-        // Set checksum
-        directory_index.checksum();
+    /// Mode the image was opened with
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
 
-        let data = bincode::serialize(&directory_index)?;
-        let mut w = Cursor::new(&data);
+    /// Limit block reads and writes to the given bytes per second
+    /// e.g. for background maintenance, None removes the limit
+    pub fn set_throttle(&mut self, bytes_per_sec: Option<u64>) {
+        self.throttle = bytes_per_sec.map(Throttle::new);
+    }
 
-        // Save directory
-        self.write_inode_data(&mut inode, &mut w, data.len() as u64)?;
+    /// Current I/O rate limit in bytes per second
+    pub fn throttle(&self) -> Option<u64> {
+        self.throttle.as_ref().map(|t| t.bytes_per_sec())
+    }
 
-        Ok(())
+    /// Data block bytes read by this handle since open
+    /// Cached blocks are counted too, raw data inside inodes is not
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.get()
     }
 
-    fn init_directory_index(&mut self) -> anyhow::Result<()> {
-        let di = DirectoryIndex::init();
+    /// Data block bytes written by this handle since open
+    /// Raw data inside inodes is not counted
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.get()
+    }
 
-        let di_data = bincode::serialize(&di)?;
-        let mut r = Cursor::new(&di_data);
+    /// Block allocation strategy of file inodes and data
+    pub fn set_allocation_mode(&mut self, mode: AllocationMode) {
+        self.allocation = mode;
+    }
 
-        let mut directory_index_inode = Inode::new(ROOT_INODE_INDEX);
+    pub fn allocation_mode(&self) -> AllocationMode {
+        self.allocation
+    }
 
-        self.save_inode(&mut directory_index_inode)?;
+    /// Selection of the free runs data is written to
+    pub fn set_alloc_strategy(&mut self, strategy: AllocStrategy) {
+        self.alloc_strategy = strategy;
+    }
 
-        self.write_inode_data(
-            &mut directory_index_inode,
-            &mut r,
-            di_data.len() as u64,
-        )?;
+    pub fn alloc_strategy(&self) -> AllocStrategy {
+        self.alloc_strategy
+    }
 
+    /// Limit data regions of a file, writes that would need more
+    /// allocate in new groups or fail
+    pub fn set_max_regions_per_file(&mut self, count: usize) -> Result<()> {
+        let max = inode_max_region(self.block_size());
+        if count == 0 || count > max {
+            return Err(
+                anyhow!("Region limit must be between 1 and {}", max).into()
+            );
+        }
+        self.max_regions = count;
         Ok(())
     }
 
-    /// Find directory
-    /// returns directory and its inode index
-    #[inline]
-    pub fn find_directory<P>(&self, dir: P) -> anyhow::Result<(Directory, u32)>
-    where
-        P: AsRef<Path>,
-    {
-        // First get directory index
-        let directory_index = self.get_directory_index()?;
-
-        // Find directory in dir.index
-        if let Some(directory_inode_index) = directory_index.find_dir(dir) {
-            // Get directory inode
-            let mut directory_inode = self.get_inode(*directory_inode_index)?;
+    pub fn max_regions_per_file(&self) -> usize {
+        self.max_regions
+    }
 
-            let mut data = Vec::new();
+    /// Retry transient errors of data block reads and writes
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
 
-            // Read inode data
-            {
-                let mut w = BufWriter::new(&mut data);
-                self.read_inode_data(&mut directory_inode, &mut w)?;
-            }
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
 
-            // Deserialize directory
-            let directory: Directory = bincode::deserialize(&data)?;
+    /// Take timestamps from the given clock from now on
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
 
-            // Return it
-            return Ok((directory, *directory_inode_index));
-        } else {
-            return Err(anyhow!("Directory not found"));
-        }
+    /// Cache up to the given blocks of file data, 0 disables it
+    pub fn set_cache_capacity(&mut self, blocks: usize) {
+        self.cache.borrow_mut().set_capacity(blocks);
     }
 
-    #[inline]
-    fn save_directory(
-        &mut self,
-        directory: Directory,
-        directory_inode_index: u32,
-    ) -> anyhow::Result<Directory> {
-        // Get directory inode
-        let mut directory_inode = self.get_inode(directory_inode_index)?;
+    /// Block cache capacity in blocks, pinned blocks not included
+    pub fn cache_capacity(&self) -> usize {
+        self.cache.borrow().capacity()
+    }
 
-        // Serialize directory
-        let data = bincode::serialize(&directory)?;
-        let mut reader = Cursor::new(&data);
+    /// Block is kept in the cache
+    pub fn is_block_cached(&self, block_index: u32) -> bool {
+        self.cache.borrow().contains(block_index)
+    }
 
-        self.write_inode_data(
-            &mut directory_inode,
-            &mut reader,
-            data.len() as u64,
-        )?;
+    /// Maximum bytes pinned files can hold in the cache
+    pub fn set_pin_budget(&mut self, bytes: u64) {
+        self.pin_budget = bytes;
+    }
 
-        Ok(directory)
+    /// Bytes held by pinned files
+    pub fn pinned_bytes(&self) -> u64 {
+        self.pins
+            .values()
+            .map(|blocks| blocks.len() as u64 * self.block_size() as u64)
+            .sum()
     }
 
-    /// Create directory
-    /// returns created directory
-    #[inline]
-    pub fn create_directory<P>(&mut self, dir: P) -> anyhow::Result<Directory>
+    /// Keep file blocks in the cache, they are never evicted
+    /// Pin again after the file is rewritten
+    pub fn pin_file<P>(&mut self, dir: P, name: &str) -> Result<()>
     where
         P: AsRef<Path>,
     {
-        // First get directory index
-        let mut directory_index = self.get_directory_index()?;
+        let path = dir.as_ref().join(name);
+        if self.pins.contains_key(&path) {
+            return Ok(());
+        }
 
-        // Then allocate dir inode index
-        let directory_inode = if let Some(i) = self.allocate_inode() {
-            i
-        } else {
-            return Err(anyhow!("Could not allocate inode block"));
-        };
+        let blocks = self.get_file_info(&dir, name)?.data_blocks();
 
-        // Then try to add directory to dir index
-        // If it fails, then free up allocated block
-        if let None =
-            directory_index.create_dir(dir, directory_inode.block_index)
+        if self.pinned_bytes() + blocks.len() as u64 * self.block_size() as u64
+            > self.pin_budget
         {
-            self.release_inode(directory_inode.block_index)?;
+            return Err(anyhow!("Pin budget exceeded").into());
         }
 
-        // Save directory index
-        self.save_directory_index(directory_index)?;
+        // Load blocks into the cache
+        let mut r = BufReader::new(&self.file);
+        let mut buffer = vec![0; self.block_size() as usize];
+        for block_index in &blocks {
+            let pos = block_seek_position(*block_index, self.block_size());
+            r.seek(SeekFrom::Start(pos))?;
+            read_exact_at(&mut r, pos, &mut buffer, &self.retry)?;
+            self.cache.borrow_mut().pin(*block_index, &buffer);
+        }
 
-        // Create empty directory
-        let directory = Directory::init();
+        self.pins.insert(path, blocks);
 
-        // Try to save directory
-        self.save_directory(directory, directory_inode.block_index)
+        Ok(())
     }
 
-    /// Get file by dir and filename
-    /// returns found file inode
-    #[inline]
-    pub fn get_file_info<P>(
-        &mut self,
-        dir: P,
-        file_name: &str,
-    ) -> anyhow::Result<Inode>
+    /// Let file blocks be evicted again
+    pub fn unpin_file<P>(&mut self, dir: P, name: &str) -> Result<()>
     where
         P: AsRef<Path>,
     {
-        // Check if dir exist
-        let (dir, _dir_inode_index) = self.find_directory(dir)?;
-
-        // Find file
-        if let Some(inode_block_index) = dir.get_file(file_name) {
-            return self.get_inode(inode_block_index);
-        } else {
-            return Err(anyhow!("File not found"));
+        match self.pins.remove(&dir.as_ref().join(name)) {
+            Some(blocks) => {
+                let mut cache = self.cache.borrow_mut();
+                blocks.iter().for_each(|b| cache.unpin(*b));
+                Ok(())
+            }
+            None => Err(anyhow!("File is not pinned").into()),
         }
     }
 
-    /// Create a file at a given dir
-    /// with a given name
-    /// Copy data to the given file
-    /// data_len (bytes) must be correct
-    #[inline]
-    pub fn add_file<P, R>(
-        &mut self,
+    /// Read inode structure: size, timestamps and region layout
+    /// Available in metadata only mode as well
+    pub fn inode_info(&self, inode_index: u32) -> Result<Inode> {
+        Ok(self.get_inode(inode_index)?)
+    }
+
+    /// Data blocks of the inode in block index order
+    /// e.g. to render which blocks belong to which file
+    pub fn inode_block_map(&self, inode_index: u32) -> Result<Vec<u32>> {
+        let mut blocks = self.get_inode(inode_index)?.data_blocks();
+        blocks.sort_unstable();
+        Ok(blocks)
+    }
+
+    /// Byte offsets and lengths of the file data in the image file
+    /// e.g. for readers bypassing walnut. Blocks are encrypted by
+    /// their block index, raw data by its inode block index.
+    /// Holes have no data on disk, they are not listed
+    pub fn data_offsets<P>(
+        &self,
         dir: P,
         file_name: &str,
-        data: &mut R,
-        data_len: u64,
-    ) -> anyhow::Result<()>
+    ) -> Result<Vec<(u64, u64)>>
     where
         P: AsRef<Path>,
-        R: BufRead,
     {
-        // Check if dir exist
-        let (mut dir, dir_inode_index) = self.find_directory(dir)?;
+        let (directory, _) = self.find_directory(&dir)?;
+        let inode = match directory.get_file(file_name) {
+            Some(inode_index) => self.get_inode(inode_index)?,
+            None => return Err(WalnutError::FileNotFound),
+        };
 
-        // Find file
-        let mut file_inode =
-            if let Some(inode_block_index) = dir.get_file(file_name) {
-                self.get_inode(inode_block_index)?
-            } else {
-                let file_inode = self.allocate_inode().unwrap();
-                dir.add_file(file_name, file_inode.block_index)?;
-                self.save_directory(dir, dir_inode_index)?;
+        match &inode.data {
+            Data::Raw(raw) if raw.is_empty() => Ok(vec![]),
+            // Raw data is the end of the serialized inode
+            Data::Raw(raw) => {
+                let header =
+                    bincode::serialized_size(&inode)? - raw.len() as u64;
+                let offset =
+                    block_seek_position(inode.block_index, self.block_size())
+                        + header;
+                Ok(vec![(offset, raw.len() as u64)])
+            }
+            Data::DirectPointers(pointers) => {
+                let mut offsets = vec![];
+                let mut data_left = inode.size;
+                for (start, len) in pointers.iter().copied() {
+                    let bytes =
+                        (len as u64 * self.block_size() as u64).min(data_left);
+                    data_left -= bytes;
+                    if start != HOLE && bytes > 0 {
+                        offsets.push((
+                            block_seek_position(start, self.block_size()),
+                            bytes,
+                        ));
+                    }
+                }
+                Ok(offsets)
+            }
+        }
+    }
 
-                // Inc. file count
-                self.superblock_mut().file_count += 1;
+    #[inline]
+    fn check_data_access(&self) -> anyhow::Result<()> {
+        match self.mode {
+            Mode::ReadWrite | Mode::AppendOnly => Ok(()),
+            Mode::MetadataOnly => {
+                Err(anyhow!("Image is opened for metadata only"))
+            }
+        }
+    }
 
-                file_inode
-            };
+    /// Existing file data and entries can be changed
+    #[inline]
+    fn check_modify_access(&self) -> anyhow::Result<()> {
+        self.check_data_access()?;
+        match self.mode {
+            Mode::AppendOnly => Err(anyhow!("Image is opened append only")),
+            _ => Ok(()),
+        }
+    }
 
-        self.write_inode_data(&mut file_inode, data, data_len)?;
+    /// Image UUID, generated at init
+    pub fn uuid(&self) -> Uuid {
+        Uuid::from_bytes(self.superblock.uuid)
+    }
 
-        // Save superblock
+    /// Generate a new UUID for the image
+    /// e.g. to tell a cloned image from its original
+    pub fn regenerate_uuid(&mut self) -> Result<Uuid> {
+        self.superblock.uuid = Uuid::new_v4().into_bytes();
         self.save_superblock()?;
+        Ok(self.uuid())
+    }
 
-        Ok(())
+    /// Volume label
+    pub fn label(&self) -> String {
+        self.superblock.label()
     }
 
-    #[inline]
-    pub fn remove_file(
-        &mut self,
-        dir: &str,
-        file_name: &str,
-    ) -> anyhow::Result<()> {
-        // Check if dir exist
-        let (mut dir, dir_inode_index) = self.find_directory(dir)?;
+    /// Id of the cipher encrypting inode data
+    pub fn cipher_id(&self) -> u8 {
+        self.superblock.cipher_id
+    }
 
-        // Find file
-        let file_inode =
-            if let Some(inode_block_index) = dir.get_file(file_name) {
-                if let Ok(inode) = self.get_inode(inode_block_index) {
-                    inode
-                } else {
-                    return Err(anyhow!("No file found in dir!"));
-                }
-            } else {
-                return Err(anyhow!("Unknown directory!"));
-            };
+    /// Counter bumped by every change of the image
+    pub fn generation(&self) -> u64 {
+        self.superblock.generation
+    }
 
-        // Release inode
-        self.release_inode(file_inode.block_index)?;
+    /// Set volume label, maximum 32 bytes
+    pub fn set_label(&mut self, label: &str) -> Result<()> {
+        self.superblock.set_label(label)?;
+        Ok(self.save_superblock()?)
+    }
 
-        // Remove file from directory
-        dir.remove_file(file_name)?;
+    /// Directory index is stored compressed
+    pub fn index_compression(&self) -> bool {
+        self.superblock.flags & FLAG_COMPRESS_INDEX != 0
+    }
 
-        // Save directory
-        self.save_directory(dir, dir_inode_index)?;
+    /// Turn directory index compression on or off
+    /// and rewrite the index in the new format
+    pub fn set_index_compression(&mut self, enabled: bool) -> Result<()> {
+        let directory_index = self.get_directory_index()?;
 
-        // Save superblock
-        self.save_superblock()?;
+        if enabled {
+            self.superblock.flags |= FLAG_COMPRESS_INDEX;
+        } else {
+            self.superblock.flags &= !FLAG_COMPRESS_INDEX;
+        }
 
-        Ok(())
+        self.save_directory_index(directory_index)?;
+        Ok(self.save_superblock()?)
     }
 
-    /// Read file data
-    /// Finds file by dir and filename
-    /// And writes its content to the given writer
-    #[inline]
-    pub fn get_file_data<P, W>(
-        &mut self,
-        dir: P,
-        file_name: &str,
-        w: &mut W,
-    ) -> anyhow::Result<u32>
-    where
-        P: AsRef<Path>,
-        W: Write,
-    {
-        // First find directory
-        let (directory, _) = self.find_directory(dir)?;
-
-        // Then find file
-        let mut file_inode =
-            if let Some(file_inode_index) = directory.get_file(file_name) {
-                self.get_inode(file_inode_index)?
-            } else {
-                // Else return error
-                return Err(anyhow!("File not found"));
-            };
+    /// File names accepted for new files of this image
+    pub fn name_policy(&self) -> NamePolicy {
+        if self.superblock.flags & FLAG_PORTABLE_NAMES != 0 {
+            NamePolicy::Portable
+        } else {
+            NamePolicy::Permissive
+        }
+    }
 
-        self.read_inode_data(&mut file_inode, w)
+    /// Set the file name policy, existing files are kept
+    pub fn set_name_policy(&mut self, policy: NamePolicy) -> Result<()> {
+        match policy {
+            NamePolicy::Permissive => {
+                self.superblock.flags &= !FLAG_PORTABLE_NAMES
+            }
+            NamePolicy::Portable => {
+                self.superblock.flags |= FLAG_PORTABLE_NAMES
+            }
+        }
+        Ok(self.save_superblock()?)
     }
 
-    #[inline]
-    fn superblock_check(&mut self) {
-        // Set group count
-        self.superblock.group_count = self.groups.len() as u32;
-        // Set free blocks
-        self.superblock.free_blocks = self
-            .groups
-            .iter()
-            .map(|g| g.block_bitmap.count_zeros() as u32)
-            .sum();
-        // Set block count
-        self.superblock.block_count = self
-            .groups
-            .iter()
-            .map(|g| g.total_data_blocks() as u32)
-            .sum();
-        // Set last modified time
-        self.superblock.modified = now();
-        // Set checksum
-        self.superblock.checksum();
+    /// Options stored in the image, see set_config
+    pub fn config(&self) -> Result<BTreeMap<String, String>> {
+        Ok(self.read_config()?)
     }
 
-    #[inline]
-    fn save_superblock(&mut self) -> anyhow::Result<()> {
-        // Create superblock checks
-        self.superblock_check();
+    /// Store an option applied whenever the image is opened
+    /// Known keys are applied and checked right away:
+    /// alloc_strategy (greedy, best_fit), allocation_mode
+    /// (first_fit, colocate), max_regions and cache_capacity.
+    /// Other keys are kept for newer versions
+    pub fn set_config(&mut self, key: &str, value: &str) -> Result<()> {
+        self.apply_config(key, value)?;
+        let mut config = self.read_config()?;
+        config.insert(key.to_string(), value.to_string());
+        Ok(self.save_config(config)?)
+    }
 
-        let mut w = BufWriter::new(&self.file);
-        let mut data = bincode::serialize(&self.superblock)?;
-        w.seek(SeekFrom::Start(0))?;
-        w.write_all(&mut data)?;
+    /// Remove a stored option, the current setting is kept
+    /// until the image is opened again
+    pub fn remove_config(&mut self, key: &str) -> Result<()> {
+        let mut config = self.read_config()?;
+        if config.remove(key).is_some() {
+            self.save_config(config)?;
+        }
         Ok(())
     }
 
-    #[inline]
-    fn get_inode(&self, inode_block_index: u32) -> anyhow::Result<Inode> {
-        let mut r = BufReader::new(&self.file);
-
-        r.seek(SeekFrom::Start(
-            block_seek_position(inode_block_index) as u64
-        ))?;
+    /// Options stored in their metadata inode, empty if none
+    /// Options that cannot be decoded are dropped, set_config
+    /// writes them again
+    fn read_config(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        match self.superblock.config {
+            0 => Ok(BTreeMap::new()),
+            inode_index => {
+                Ok(bincode::deserialize(&self.read_inode_bytes(inode_index)?)
+                    .unwrap_or_default())
+            }
+        }
+    }
 
-        // Deserialize by bincode
-        let inode: Inode = Inode::deserialize_from(r)?;
+    /// Save options, the inode is released once empty
+    fn save_config(
+        &mut self,
+        config: BTreeMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let inode_index = self.superblock.config;
+        if config.is_empty() {
+            if inode_index != 0 {
+                self.superblock_mut().config = 0;
+                self.release_inode(inode_index)?;
+            }
+            return Ok(());
+        }
 
-        // Return inode
-        Ok(inode)
+        let mut inode = match inode_index {
+            0 => match self.allocate_inode(true)? {
+                Some(inode) => inode,
+                None => return Err(WalnutError::OutOfSpace.into()),
+            },
+            inode_index => self.get_inode(inode_index)?,
+        };
+        let data = bincode::serialize(&config)?;
+        self.write_inode_data(
+            &mut inode,
+            &mut Cursor::new(&data),
+            data.len() as u64,
+            true,
+        )?;
+        self.superblock_mut().config = inode.block_index;
+        self.save_superblock()
     }
 
-    #[inline]
-    fn save_inode(&mut self, inode: &mut Inode) -> anyhow::Result<()> {
-        let mut w = BufWriter::new(&self.file);
+    /// Apply one stored option, unknown keys are ignored
+    fn apply_config(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        let invalid = || anyhow!("Invalid value {} of config {}", value, key);
+        match key {
+            "alloc_strategy" => {
+                self.alloc_strategy = match value {
+                    "greedy" => AllocStrategy::Greedy,
+                    "best_fit" => AllocStrategy::BestFit,
+                    _ => return Err(invalid()),
+                }
+            }
+            "allocation_mode" => {
+                self.allocation = match value {
+                    "first_fit" => AllocationMode::FirstFit,
+                    "colocate" => AllocationMode::Colocate,
+                    _ => return Err(invalid()),
+                }
+            }
+            "max_regions" => {
+                let count = value.parse().map_err(|_| invalid())?;
+                self.set_max_regions_per_file(count)?;
+            }
+            "cache_capacity" => {
+                let blocks = value.parse().map_err(|_| invalid())?;
+                self.set_cache_capacity(blocks);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
 
-        w.seek(SeekFrom::Start(
-            block_seek_position(inode.block_index) as u64
-        ))?;
-        inode.set_last_modified();
-        inode.serialize_into(w)?;
+    /// Check a new file name against the name policy
+    fn check_file_name(&self, file_name: &str) -> anyhow::Result<()> {
+        if self.name_policy() == NamePolicy::Portable
+            && !portable_name(file_name)
+        {
+            return Err(anyhow!("File name {} is not portable", file_name));
+        }
         Ok(())
     }
 
     #[inline]
-    fn save_group(
+    pub fn get_directory_index(&self) -> Result<DirectoryIndex> {
+        // Read inode data
+        let data = self.read_inode_bytes(ROOT_INODE_INDEX)?;
+
+        Ok(decode_directory_index(data, self.index_compression())?)
+    }
+
+    fn save_directory_index(
         &mut self,
-        group: Group,
-        group_index: u32,
+        mut directory_index: DirectoryIndex,
     ) -> anyhow::Result<()> {
-        // Update group at FS
-        self.groups[group_index as usize] = group.clone();
+        let mut inode = self.get_inode(ROOT_INODE_INDEX)?;
 
-        // Write group to disk
-        let mut w = BufWriter::new(&self.file);
+        // Set checksum
+        directory_index.checksum();
 
-        w.seek(SeekFrom::Start(Group::seek_position(group_index) as u64))?;
-        group.serialize_into(w)?;
-        Ok(())
-    }
+        let mut data = bincode::serialize(&directory_index)?;
 
-    #[inline]
-    fn read_inode_data<W>(
-        &self,
-        inode: &mut Inode,
-        w: &mut W,
-    ) -> anyhow::Result<u32>
-    where
-        W: Write,
-    {
-        let mut checksum = Checksum::new();
-        let mut r = BufReader::new(&self.file);
+        // Compress before it gets encrypted
+        if self.index_compression() {
+            data = compress(&data)?;
+        }
 
-        match &mut inode.data {
-            Data::Raw(data) => {
-                // Decrypt raw data
-                encrypt(data, &self.lookup_table);
+        let mut w = Cursor::new(&data);
 
-                // Update checksum
-                checksum.update(data);
+        // Save directory
+        self.write_inode_data(&mut inode, &mut w, data.len() as u64, true)?;
 
-                // Write data into writer
-                w.write_all(data)?;
-            }
-            Data::DirectPointers(pointers) => {
-                // Counting data left to read
-                let mut data_left = inode.size;
+        Ok(())
+    }
 
-                let mut block_buffer: Vec<u8> =
-                    Vec::with_capacity(BLOCK_SIZE as usize);
-                unsafe { block_buffer.set_len(BLOCK_SIZE as usize) };
+    fn init_directory_index(&mut self) -> anyhow::Result<()> {
+        let mut directory_index_inode =
+            Inode::with_time(ROOT_INODE_INDEX, self.clock.now());
 
-                for (block_index, range) in pointers {
-                    // Seek start position
-                    r.seek(SeekFrom::Start(
-                        block_seek_position(*block_index) as u64
-                    ))?;
+        self.save_inode(&mut directory_index_inode)?;
 
-                    for _ in *block_index..(*block_index + *range) {
-                        // Determine if last block
-                        if data_left < BLOCK_SIZE as u64 {
-                            block_buffer =
-                                Vec::with_capacity(data_left as usize);
-                            unsafe { block_buffer.set_len(data_left as usize) };
-                        };
+        // Saved in the format set by the superblock flags
+        self.save_directory_index(DirectoryIndex::init())
+    }
+
+    /// Find directory
+    /// returns directory and its inode index
+    #[inline]
+    pub fn find_directory<P>(&self, dir: P) -> Result<(Directory, u32)>
+    where
+        P: AsRef<Path>,
+    {
+        // First get directory index
+        let directory_index = self.get_directory_index()?;
 
-                        // Read range bytes
-                        r.read_exact(&mut block_buffer)?;
+        // Find directory in dir.index
+        if let Some(directory_inode_index) = directory_index.find_dir(dir) {
+            // Read directory and return it
+            let directory = self.read_directory(*directory_inode_index)?;
+            Ok((directory, *directory_inode_index))
+        } else {
+            Err(WalnutError::DirNotFound)
+        }
+    }
 
-                        // Decrypt chunk
-                        encrypt(&mut block_buffer, &self.lookup_table);
+    /// Read directory stored at the given inode index
+    #[inline]
+    fn read_directory(
+        &self,
+        directory_inode_index: u32,
+    ) -> anyhow::Result<Directory> {
+        // Read inode data
+        let data = self.read_inode_bytes(directory_inode_index)?;
 
-                        // Update checksum
-                        checksum.update(&block_buffer);
+        // Deserialize directory
+        let directory: Directory = bincode::deserialize(&data)?;
 
-                        // Write buffer to writer
-                        w.write_all(&mut block_buffer)?;
-                        // std::io::copy(&mut BufReader::new(Cursor::new(&block_buffer)), &mut w)?;
+        Ok(directory)
+    }
 
-                        // Decrease data_left
-                        data_left -= block_buffer.capacity() as u64;
-                    }
-                }
-            }
+    /// Read the whole inode data into memory
+    /// Use it for metadata only, file data should be streamed
+    #[inline]
+    fn read_inode_bytes(&self, inode_index: u32) -> anyhow::Result<Vec<u8>> {
+        let mut inode = self.get_inode(inode_index)?;
+
+        let mut data = Vec::new();
+        {
+            let mut w = BufWriter::new(&mut data);
+            self.read_inode_data(&mut inode, &mut w)?;
         }
 
-        Ok(checksum.finalize())
+        Ok(data)
     }
 
+    /// Save directory and update its hash in the directory index
     #[inline]
-    fn write_inode_data<R>(
+    fn commit_directory<P>(
         &mut self,
-        inode: &mut Inode,
-        data: &mut R,
-        data_len: u64,
+        dir: P,
+        directory: Directory,
+        directory_inode_index: u32,
+        old_hash: u32,
     ) -> anyhow::Result<()>
     where
-        R: BufRead,
+        P: AsRef<Path>,
     {
-        // Release inode data
-        match &inode.data {
-            Data::Raw(_) => (),
-            Data::DirectPointers(pointers) => {
-                self.release_inode_data(pointers.clone())?
-            }
+        let new_hash = directory.hash();
+        self.save_directory(directory, directory_inode_index)?;
+
+        if old_hash != new_hash {
+            let mut directory_index = self.get_directory_index()?;
+            directory_index.update_dir_hash(dir, old_hash, new_hash);
+            self.save_directory_index(directory_index)?;
         }
 
-        // If data length fits inside inode
-        if data_len as usize <= INODE_CAPACITY {
-            // Create buffer
-            let mut buffer = vec![];
+        Ok(())
+    }
 
-            // and read data into it
-            data.read_to_end(&mut buffer)?;
+    /// Hash of the directory content
+    /// changes whenever a file is added, removed or rewritten
+    pub fn directory_hash<P>(&self, dir: P) -> Result<u32>
+    where
+        P: AsRef<Path>,
+    {
+        let (directory, _) = self.find_directory(dir)?;
+        Ok(directory.hash())
+    }
 
-            // Encrypt buffer
-            encrypt(&mut buffer, &self.lookup_table);
+    /// Entries of a directory not pointing to a file inode
+    /// Inodes have no kind tag, the directory index and the superblock
+    /// tell which inodes are the root, the directories and the metadata
+    pub fn check_directory_entries<P>(&self, dir: P) -> Result<Vec<String>>
+    where
+        P: AsRef<Path>,
+    {
+        let directory_index = self.get_directory_index()?;
+        let not_files: std::collections::BTreeSet<u32> =
+            std::iter::once(ROOT_INODE_INDEX)
+                .chain(self.metadata_inodes())
+                .chain(directory_index.inode_indices())
+                .collect();
 
-            // Create reader from buffer
-            let mut data = Cursor::new(&buffer);
+        let (directory, _) = self.find_directory(dir)?;
+        Ok(directory
+            .files
+            .iter()
+            .filter(|(_, inode_index)| not_files.contains(inode_index))
+            .map(|(file_name, _)| file_name.clone())
+            .collect())
+    }
 
-            // Set data inside inode
-            inode.set_raw_data(&mut data, data_len)?;
+    /// Inodes of the shared block references, the original paths
+    /// and the stored options, the superblock points to them
+    fn metadata_inodes(&self) -> impl Iterator<Item = u32> {
+        [
+            self.superblock.shared_blocks,
+            self.superblock.original_paths,
+            self.superblock.config,
+        ]
+        .into_iter()
+        .filter(|inode_index| *inode_index != 0)
+    }
 
-            // Save inode
-            self.save_inode(inode)?;
+    /// In-memory copy of the current metadata
+    /// Superblock, groups, directory index and directories are copied,
+    /// so the view is not affected by later changes of the image
+    pub fn view(&self) -> Result<FsView> {
+        let directory_index = self.get_directory_index()?;
 
-            // Return ok
-            return Ok(());
+        let mut directories = BTreeMap::new();
+        for (dir_path, dir_inode_index) in directory_index.directories() {
+            directories.insert(
+                dir_path.clone(),
+                self.read_directory(*dir_inode_index)?,
+            );
         }
 
-        // If data does not fit inside Inode as raw data
+        Ok(FsView {
+            superblock: self.superblock.clone(),
+            groups: self.groups.clone(),
+            directory_index,
+            directories,
+        })
+    }
 
-        // Set inode data size
-        inode.size = data_len;
-        // And save it
-        self.save_inode(inode)?;
+    /// Content hash of every directory, e.g. for a sync manifest
+    /// Hashes cover file names, sizes and data checksums, not the
+    /// block layout, so equal directories of two images match
+    pub fn manifest(&self) -> Result<BTreeMap<OsString, u32>> {
+        let directory_index = self.get_directory_index()?;
 
-        // Define empty ranges
-        let mut ranges: Vec<(u32, u32)> = vec![];
+        let mut manifest = BTreeMap::new();
+        for (dir_path, dir_inode_index) in directory_index.directories() {
+            let directory = self.read_directory(*dir_inode_index)?;
+            let mut files = vec![];
+            for (file_name, file_inode_index) in &directory.files {
+                let inode = self.get_inode(*file_inode_index)?;
+                files.push((file_name, inode.size, inode.data_checksum));
+            }
+            manifest.insert(dir_path.clone(), calculate_checksum(&files));
+        }
 
-        // Define block_to_allocate
-        let blocks_to_allocate = |data_size| {
-            data_size / BLOCK_SIZE as u64
-                + u64::from(data_size % BLOCK_SIZE as u64 != 0)
-        };
+        Ok(manifest)
+    }
 
-        // Determine how many block we need
-        let mut block_to_allocate = blocks_to_allocate(data_len);
+    /// Combined hash of all directories
+    /// changes whenever anything changes in any directory
+    pub fn index_hash(&self) -> Result<u32> {
+        Ok(self.get_directory_index()?.hash())
+    }
 
-        // Check if we have enough space for file
-        while self.superblock().free_blocks < block_to_allocate as u32 {
-            // Add new group
-            self.add_group(Group::init())?;
-        }
+    #[inline]
+    fn save_directory(
+        &mut self,
+        directory: Directory,
+        directory_inode_index: u32,
+    ) -> anyhow::Result<Directory> {
+        // Get directory inode
+        let mut directory_inode = self.get_inode(directory_inode_index)?;
 
-        let groups = self.groups.clone();
+        // Serialize directory
+        let data = bincode::serialize(&directory)?;
+        let mut reader = Cursor::new(&data);
 
-        for (group_index, mut group) in groups.into_iter().enumerate() {
-            // Check if we need any blocks?
-            if block_to_allocate > 0 {
-                // Allocate regions from group
-                let (mut range, left) = group.allocate_region(
-                    group_index as u32,
-                    block_to_allocate as usize,
-                    INODE_MAX_REGION,
-                );
+        self.write_inode_data(
+            &mut directory_inode,
+            &mut reader,
+            data.len() as u64,
+            true,
+        )?;
 
-                // Save group
-                self.save_group(group, group_index as u32)?;
+        Ok(directory)
+    }
 
-                ranges.append(&mut range);
+    /// Create directory
+    /// Missing parent directories are created as well, like mkdir -p
+    /// returns created directory
+    #[inline]
+    pub fn create_directory<P>(&mut self, dir: P) -> Result<Directory>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
 
-                // Decrease block wanted
-                block_to_allocate = left as u64;
-            }
+        let depth = normal_components(dir).components().count() as u32;
+        let max_depth = self.superblock.max_dir_depth;
+        if max_depth != 0 && depth > max_depth {
+            return Err(anyhow!(
+                "Directory depth {} exceeds the limit of {}",
+                depth,
+                max_depth
+            )
+            .into());
         }
 
-        // Save ranges
-        inode.set_direct_pointers(ranges.clone(), data_len);
-        self.save_inode(inode)?;
-
-        // Write data into ranges
-        let mut data_left = data_len;
-
-        let mut w = BufWriter::new(&self.file);
+        // Parents from the top, "" and "/" are not directories
+        let directory_index = self.get_directory_index()?;
+        let mut parents: Vec<&Path> = dir
+            .ancestors()
+            .skip(1)
+            .filter(|p| p.parent().is_some())
+            .filter(|p| directory_index.find_dir(p).is_none())
+            .collect();
+        parents.reverse();
+        for parent in parents {
+            self.create_single_directory(parent)?;
+        }
 
-        let mut block_buffer: Vec<u8> = Vec::with_capacity(BLOCK_SIZE as usize);
-        unsafe { block_buffer.set_len(BLOCK_SIZE as usize) };
+        Ok(self.create_single_directory(dir)?)
+    }
 
-        for (block_index, range) in ranges {
-            // Seek position
-            w.seek(SeekFrom::Start(block_seek_position(block_index) as u64))?;
+    /// Remove an empty directory and release its inode
+    /// Directories with files or subdirectories are kept
+    pub fn remove_directory<P>(&mut self, dir: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        self.check_modify_access()?;
 
-            // Iter over rage
-            for _ in block_index..(block_index + range) {
-                // Determine if last block
-                if data_left < BLOCK_SIZE as u64 {
-                    block_buffer = Vec::with_capacity(data_left as usize);
-                    unsafe { block_buffer.set_len(data_left as usize) };
-                };
+        let (directory, dir_inode_index) = self.find_directory(&dir)?;
+        if !directory.files.is_empty() {
+            let names: Vec<&str> =
+                directory.files.keys().map(|n| n.as_str()).collect();
+            return Err(anyhow!(
+                "Directory is not empty, it has {}",
+                names.join(", ")
+            )
+            .into());
+        }
+        if !self.list_subdirectories(&dir)?.is_empty() {
+            return Err(anyhow!("Directory has subdirectories").into());
+        }
 
-                // Read data into chunk buffer
-                data.read_exact(&mut block_buffer)?;
+        let mut directory_index = self.get_directory_index()?;
+        directory_index.remove_dir(&dir, directory.hash());
+        self.save_directory_index(directory_index)?;
 
-                // Encrypt chunk
-                encrypt(&mut block_buffer, &self.lookup_table);
+        // Inode is released once the index does not point to it
+        Ok(self.release_inode(dir_inode_index)?)
+    }
 
-                // Write chunk buffer to disk
-                w.write_all(&mut block_buffer)?;
+    /// Remove a directory with its files and subdirectories
+    pub fn remove_directory_recursive<P>(&mut self, dir: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        self.check_modify_access()?;
 
-                // Decrease data left
-                data_left -= block_buffer.capacity() as u64;
+        let dir = dir.as_ref();
+        if dir.parent().is_none() {
+            return Err(anyhow!("Root directory cannot be removed").into());
+        }
+        self.find_directory(dir)?;
+
+        // Deepest directories first
+        let mut dirs: Vec<OsString> = self
+            .get_directory_index()?
+            .directories()
+            .keys()
+            .filter(|d| Path::new(d).starts_with(dir))
+            .cloned()
+            .collect();
+        dirs.sort_by_key(|d| {
+            std::cmp::Reverse(Path::new(d).components().count())
+        });
+
+        for d in dirs {
+            let (directory, _) = self.find_directory(&d)?;
+            for file_name in directory.files.keys() {
+                self.remove_file(&d, file_name)?;
             }
+            self.remove_directory(&d)?;
         }
 
-        // Check all data has written
-        assert!(data_left == 0);
-
-        // Flush disk
-        w.flush()?;
-
         Ok(())
     }
 
-    #[inline]
-    fn truncate(&mut self) -> anyhow::Result<()> {
-        // Superblock + GroupCount * (Group bitmap + group data inodes)
-        let size = BLOCK_SIZE
-            + (self.groups.len() as u32)
-                * (BLOCK_SIZE + BLOCKS_PER_GROUP * BLOCK_SIZE);
-        // Set file size
-        self.file.set_len(size as u64)?;
-        // Return ok
-        Ok(())
+    /// Direct subdirectories of a directory, as full paths
+    /// "" and "/" list the top level directories
+    pub fn list_subdirectories<P>(&self, dir: P) -> Result<Vec<OsString>>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        let is_root = |p: &Path| p.parent().is_none();
+
+        let directory_index = self.get_directory_index()?;
+        Ok(directory_index
+            .directories()
+            .keys()
+            .filter(|dir_path| match Path::new(dir_path).parent() {
+                Some(parent) => {
+                    parent == dir || (is_root(parent) && is_root(dir))
+                }
+                None => false,
+            })
+            .cloned()
+            .collect())
     }
 
-    #[inline]
-    fn allocate_inode(&mut self) -> Option<Inode> {
-        // Check if we need more space
-        // while self.superblock().free_blocks < 3 {
-        //     self.add_group(Group::init()).unwrap();
-        // }
+    fn create_single_directory<P>(
+        &mut self,
+        dir: P,
+    ) -> anyhow::Result<Directory>
+    where
+        P: AsRef<Path>,
+    {
+        // First get directory index
+        let mut directory_index = self.get_directory_index()?;
 
-        let mut res = None;
-        for (group_index, group) in self.groups_mut().iter_mut().enumerate() {
-            if let Some(inode_block_index) =
-                group.allocate_one(group_index as u32)
-            {
-                let inode = Inode::new(inode_block_index);
-                res = Some(inode);
-                break;
-            }
-        }
-        if let Some(inode) = &mut res {
-            self.save_inode(inode).unwrap();
+        // Saved with the superblock by the inode allocation
+        let sequence = self.next_sequence();
+
+        // Then allocate dir inode index
+        let directory_inode = if let Some(i) = self.allocate_inode(false)? {
+            i
+        } else {
+            return Err(anyhow!("Could not allocate inode block"));
+        };
+
+        // Then try to add directory to dir index
+        // If it fails, then free up allocated block
+        if directory_index
+            .create_dir(dir, directory_inode.block_index)
+            .is_none()
+        {
+            self.release_inode(directory_inode.block_index)?;
         }
-        // TODO! Shoud handle the case when inode fails to save
-        res
-    }
 
-    #[inline]
-    fn add_group(&mut self, group: Group) -> anyhow::Result<()> {
-        // Insert new group to FS groups
-        self.groups.push(group.clone());
-        // Save group to disk
-        self.save_group(group, self.groups.len() as u32 - 1)?;
-        // Increment group count
-        self.superblock.group_count += 1;
-        // Truncate itself
-        self.truncate()?;
-        // Save superblock
+        // Save directory index
+        self.save_directory_index(directory_index)?;
+
+        // Create empty directory
+        let mut directory = Directory::init();
+        directory.sequence = sequence;
+
+        // Try to save directory
+        let directory =
+            self.save_directory(directory, directory_inode.block_index)?;
+
+        // Readers wait for this save, see begin_write
         self.save_superblock()?;
-        // Return ok
-        Ok(())
+        Ok(directory)
     }
 
-    #[inline]
-    fn groups_mut(&mut self) -> &mut [Group] {
-        &mut self.groups
+    /// Metadata of the inode at the given block index
+    /// Only the inode block is read and nothing is decrypted,
+    /// so it works on images opened for metadata only.
+    /// Finding a file by name decrypts its directory, see get_file_info
+    pub fn stat(&self, inode_index: u32) -> Result<Stat> {
+        if self.is_block_free(inode_index) {
+            return Err(anyhow!("No inode at block {}", inode_index).into());
+        }
+        let inode = self.get_inode(inode_index)?;
+
+        Ok(Stat {
+            inode: inode.block_index,
+            size: inode.size,
+            created: inode.created,
+            modified: inode.last_modified,
+            data_checksum: inode.data_checksum,
+            blocks: inode.data_blocks().len() as u32,
+        })
     }
 
-    #[inline]
-    fn superblock(&self) -> &Superblock {
-        &self.superblock
+    /// File size in bytes, only the directory and the inode are read
+    pub fn file_size<P>(&self, dir: P, file_name: &str) -> Result<u64>
+    where
+        P: AsRef<Path>,
+    {
+        let (directory, _) = self.find_directory(dir)?;
+        match directory.get_file(file_name) {
+            Some(inode_index) => Ok(self.get_inode(inode_index)?.size),
+            None => Err(WalnutError::FileNotFound),
+        }
     }
 
-    #[inline]
-    fn superblock_mut(&mut self) -> &mut Superblock {
-        &mut self.superblock
+    /// File count and summed file sizes of one directory
+    /// Subdirectories are not included, only inodes are read
+    pub fn directory_stats<P>(&self, dir: P) -> Result<DirStats>
+    where
+        P: AsRef<Path>,
+    {
+        let (directory, _) = self.find_directory(dir)?;
+        let mut stats = DirStats::default();
+        for inode_index in directory.files.values() {
+            stats.files += 1;
+            stats.bytes += self.get_inode(*inode_index)?.size;
+        }
+        Ok(stats)
     }
 
-    #[inline]
-    fn release_inode_data(
-        &mut self,
-        data_pointers: Vec<(u32, u32)>,
-    ) -> anyhow::Result<()> {
-        let mut groups = self.groups_mut().as_mut().to_owned();
-
-        // Check each data region
-        for (block_index, range) in data_pointers {
-            // Translate public address
-            let (group_index, bitmap_index) =
-                Group::translate_public_address(block_index);
-            // Release data region
-            groups[group_index as usize]
-                .release_data_region(bitmap_index, range);
-        }
-        // Iter groups
-        for (group_index, group) in groups.into_iter().enumerate() {
-            {
-                // And save each group to disk
-                self.save_group(group, group_index as u32)?;
-            }
-        }
-        Ok(())
+    /// Whether the directory has the file
+    /// Fails only if the directory cannot be read
+    pub fn file_exists<P>(&self, dir: P, file_name: &str) -> Result<bool>
+    where
+        P: AsRef<Path>,
+    {
+        let (directory, _) = self.find_directory(dir)?;
+        Ok(directory.get_file(file_name).is_some())
     }
 
-    #[inline]
-    fn release_inode(&mut self, inode_block_index: u32) -> anyhow::Result<()> {
-        // Check if inode exist
-        let inode = self.get_inode(inode_block_index)?;
+    /// Whether the directory index has the directory
+    pub fn dir_exists<P>(&self, dir: P) -> bool
+    where
+        P: AsRef<Path>,
+    {
+        self.get_directory_index()
+            .map(|index| index.find_dir(dir).is_some())
+            .unwrap_or(false)
+    }
 
-        // Translate block index
-        let (group_index, bitmap_index) =
-            Group::translate_public_address(inode_block_index);
+    /// Read every file and verify its data checksum
+    /// Corrupt and unreadable files are moved below the quarantine
+    /// directory keeping their directory path, their inode and data
+    /// blocks are kept as they are, see get_file_data_lenient
+    pub fn scrub(&mut self) -> Result<ScrubReport> {
+        self.check_modify_access()?;
+
+        let mut report = ScrubReport::default();
+        let mut quarantine = self.quarantine_dir()?;
+        for (path, inode) in self.list_files_recursive("")? {
+            // Already quarantined
+            if quarantine.as_ref().is_some_and(|q| path.starts_with(q)) {
+                continue;
+            }
+            report.checked += 1;
+
+            // Unreadable data is as damaged as a wrong checksum
+            let checksum = self.read_inode_data_unverified(
+                &mut inode.clone(),
+                &mut std::io::sink(),
+            );
+            if checksum.is_ok_and(|c| c == inode.data_checksum) {
+                continue;
+            }
+            let quarantine = match &quarantine {
+                Some(quarantine) => quarantine.clone(),
+                None => quarantine.insert(self.create_quarantine()?).clone(),
+            };
 
-        // Release data
-        match inode.data {
-            // Dont do anything when it has raw data
-            Data::Raw(_) => (),
-            // Release all direct pointers
-            Data::DirectPointers(direct_pointers) => {
-                self.release_inode_data(direct_pointers)?
+            let dir = path.parent().unwrap_or(Path::new(""));
+            let file_name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            let target_dir = quarantine.join(dir);
+            if !self.dir_exists(&target_dir) {
+                self.create_directory(&target_dir)?;
             }
+            let mut target_name = file_name.clone();
+            let mut n = 1;
+            while self.file_exists(&target_dir, &target_name)? {
+                target_name = format!("{}.{}", file_name, n);
+                n += 1;
+            }
+
+            self.move_file_entry(dir, &file_name, &target_dir, &target_name)?;
+            report
+                .quarantined
+                .push((path, target_dir.join(target_name)));
         }
 
-        let mut group = self.groups[group_index as usize].to_owned();
+        Ok(report)
+    }
 
-        {
-            // Release index bitmap
-            group.release_one(bitmap_index);
+    /// Path of the scrub quarantine directory, None if not created yet
+    fn quarantine_dir(&self) -> anyhow::Result<Option<PathBuf>> {
+        let quarantine = self.superblock.quarantine;
+        if quarantine == 0 {
+            return Ok(None);
         }
+        Ok(self
+            .get_directory_index()?
+            .directories()
+            .iter()
+            .find(|(_, inode_index)| **inode_index == quarantine)
+            .map(|(dir_path, _)| PathBuf::from(dir_path)))
+    }
+
+    /// Create the quarantine directory, an existing .corrupt
+    /// directory of the user gets a numbered sibling
+    fn create_quarantine(&mut self) -> anyhow::Result<PathBuf> {
+        let mut quarantine = PathBuf::from(CORRUPT_DIRECTORY);
+        let mut n = 1;
+        while self.dir_exists(&quarantine) {
+            quarantine = PathBuf::from(format!("{}.{}", CORRUPT_DIRECTORY, n));
+            n += 1;
+        }
+        self.create_directory(&quarantine)?;
+        let (_, inode_index) = self.find_directory(&quarantine)?;
+        self.superblock_mut().quarantine = inode_index;
+        self.save_superblock()?;
+        Ok(quarantine)
+    }
+
+    /// Check the image consistency without changing it
+    /// Verifies file data checksums and compares the blocks
+    /// referenced by inodes to the group bitmaps
+    pub fn fsck(&self) -> Result<FsckReport> {
+        self.check_data_access()?;
+
+        let mut report = FsckReport::default();
+
+        // Reference count of every inode and data block
+        let mut references: BTreeMap<u32, u32> = BTreeMap::new();
+        let mut reference = |inode: &Inode| {
+            std::iter::once(inode.block_index)
+                .chain(inode.data_blocks())
+                .for_each(|block| *references.entry(block).or_default() += 1);
+        };
+
+        // Directory index, directories and metadata inodes
+        let directory_index = self.get_directory_index()?;
+        report.directory_collisions = directory_index.inode_collisions();
+        reference(&self.get_inode(ROOT_INODE_INDEX)?);
+        for directory_inode_index in directory_index.inode_indices() {
+            reference(&self.get_inode(directory_inode_index)?);
+        }
+        for table in self.metadata_inodes() {
+            reference(&self.get_inode(table)?);
+        }
+
+        // Files
+        for (path, inode) in self.list_files_recursive("")? {
+            reference(&inode);
+            report.files += 1;
+            report.inodes.push(inode.block_index);
+            let checksum = self.read_inode_data_unverified(
+                &mut inode.clone(),
+                &mut std::io::sink(),
+            )?;
+            if checksum != inode.data_checksum {
+                report.bad_checksums.push(path);
+            }
+        }
+
+        let blocks_per_group = self.blocks_per_group();
+        for (group_index, group) in self.groups.iter().enumerate() {
+            for bitmap_index in group.block_bitmap.iter_ones() {
+                let block = Group::create_public_address(
+                    group_index as u32,
+                    bitmap_index as u32,
+                    blocks_per_group,
+                );
+                if !references.contains_key(&block) {
+                    report.leaked.push(block);
+                }
+            }
+        }
+        let shared = self.shared_blocks()?;
+        for (block, count) in references {
+            if count > shared.get(&block).copied().unwrap_or(1) {
+                report.double_allocated.push(block);
+            }
+            if self.is_block_free(block) {
+                report.unallocated.push(block);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Check the image and free its leaked blocks
+    /// Inode counts of the groups are recomputed as well
+    pub fn fsck_repair(&mut self) -> Result<FsckReport> {
+        self.check_modify_access()?;
+
+        let report = self.fsck()?;
+        let blocks_per_group = self.blocks_per_group();
+
+        // Release leaked blocks
+        let mut groups = self.groups.clone();
+        for block in &report.leaked {
+            let (group_index, bitmap_index) =
+                Group::translate_public_address(*block, blocks_per_group);
+            groups[group_index as usize].release_one(bitmap_index);
+        }
+        for (group_index, group) in groups.into_iter().enumerate() {
+            self.save_group(group, group_index as u32)?;
+        }
+
+        // Recount inodes, the root, the directories
+        // and the metadata inodes are inodes too
+        let directory_index = self.get_directory_index()?;
+        let metadata_inodes: Vec<u32> = self.metadata_inodes().collect();
+        let inode_counts = &mut self.superblock_mut().inode_counts;
+        inode_counts.iter_mut().for_each(|count| *count = 0);
+        std::iter::once(ROOT_INODE_INDEX)
+            .chain(metadata_inodes)
+            .chain(directory_index.inode_indices())
+            .chain(report.inodes.iter().copied())
+            .for_each(|inode_index| {
+                let group_index =
+                    Group::group_of_block(inode_index, blocks_per_group);
+                inode_counts[group_index as usize] += 1;
+            });
+
+        self.save_superblock()?;
+        Ok(report)
+    }
+
+    /// Read file data without verifying its checksum
+    /// e.g. to salvage a corrupt file.
+    /// Returns the checksum of the read data
+    pub fn get_file_data_lenient<P, W>(
+        &self,
+        dir: P,
+        file_name: &str,
+        w: &mut W,
+    ) -> Result<u32>
+    where
+        P: AsRef<Path>,
+        W: Write,
+    {
+        let (directory, _) = self.find_directory(&dir)?;
+        let mut inode = match directory.get_file(file_name) {
+            Some(inode_index) => self.get_inode(inode_index)?,
+            None => return Err(WalnutError::FileNotFound),
+        };
+        Ok(self.read_inode_data_unverified(&mut inode, w)?)
+    }
+
+    /// Move a file entry to another directory, data is not touched
+    fn move_file_entry(
+        &mut self,
+        from_dir: &Path,
+        from: &str,
+        to_dir: &Path,
+        to: &str,
+    ) -> anyhow::Result<()> {
+        let (mut source, source_inode_index) = self.find_directory(from_dir)?;
+        let source_hash = source.hash();
+        let inode = match source.get_file(from) {
+            Some(inode_index) => self.get_inode(inode_index)?,
+            None => return Err(WalnutError::FileNotFound.into()),
+        };
+
+        let (mut target, target_inode_index) = self.find_directory(to_dir)?;
+        let target_hash = target.hash();
+        if target.get_file(to).is_some() {
+            return Err(WalnutError::FileExists(to.to_string()).into());
+        }
+        self.check_file_name(to)?;
+
+        let sequence = source.sequence(from);
+        source.remove_file(from, &inode)?;
+        target.add_file(to, &inode)?;
+        target.set_sequence(to, sequence);
+        self.access_log
+            .borrow_mut()
+            .remove(from_dir.as_os_str(), from);
+
+        // Save directories
+        self.commit_directory(
+            from_dir,
+            source,
+            source_inode_index,
+            source_hash,
+        )?;
+        self.commit_directory(to_dir, target, target_inode_index, target_hash)?;
+
+        // Save superblock
+        self.save_superblock()
+    }
+
+    /// Get file by dir and filename
+    /// returns found file inode
+    #[inline]
+    pub fn get_file_info<P>(&mut self, dir: P, file_name: &str) -> Result<Inode>
+    where
+        P: AsRef<Path>,
+    {
+        // Check if dir exist
+        let (dir, _dir_inode_index) = self.find_directory(dir)?;
+
+        // Find file
+        if let Some(inode_block_index) = dir.get_file(file_name) {
+            Ok(self.get_inode(inode_block_index)?)
+        } else {
+            Err(WalnutError::FileNotFound)
+        }
+    }
+
+    /// Create a file at a given dir
+    /// with a given name
+    /// Copy data to the given file
+    /// data_len (bytes) must be correct
+    #[inline]
+    pub fn add_file<P, R>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+        data: &mut R,
+        data_len: u64,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+        R: BufRead,
+    {
+        Ok(self.add_file_inner(dir, file_name, data, data_len, false)?)
+    }
+
+    /// Same as add_file, but allowed to allocate
+    /// from the reserved space
+    #[inline]
+    pub fn add_file_force<P, R>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+        data: &mut R,
+        data_len: u64,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+        R: BufRead,
+    {
+        Ok(self.add_file_inner(dir, file_name, data, data_len, true)?)
+    }
+
+    /// Add file from a reader of unknown length
+    /// e.g. a pipe or socket, data is read once in bounded chunks.
+    /// Data ending with UnexpectedEof fails with IncompleteFile
+    /// and nothing is written.
+    /// Returns (bytes written, crc32 of the data)
+    pub fn add_file_streaming<P, R>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+        data: &mut R,
+    ) -> Result<(u64, u32)>
+    where
+        P: AsRef<Path>,
+        R: Read,
+    {
+        let mut res = (0, 0);
+        self.add_file_with(dir, file_name, None, false, |fs, inode| {
+            res = fs.write_inode_data_streaming(inode, data, false)?;
+            Ok(())
+        })
+        .map_err(|e| match e.downcast_ref::<std::io::Error>() {
+            Some(io) if io.kind() == std::io::ErrorKind::UnexpectedEof => {
+                WalnutError::IncompleteFile(file_name.to_string()).into()
+            }
+            _ => e,
+        })?;
+        Ok(res)
+    }
+
+    #[inline]
+    fn add_file_inner<P, R>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+        data: &mut R,
+        data_len: u64,
+        force: bool,
+    ) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+        R: BufRead,
+    {
+        self.add_file_with(
+            dir,
+            file_name,
+            Some(data_len),
+            force,
+            |fs, inode| fs.write_inode_data(inode, data, data_len, force),
+        )
+    }
+
+    /// Create or overwrite file, data is written by the given function
+    /// data_len is the data size if known in advance
+    #[inline]
+    fn add_file_with<P, F>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+        data_len: Option<u64>,
+        force: bool,
+        write: F,
+    ) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut Self, &mut Inode) -> anyhow::Result<()>,
+    {
+        // Check if dir exist
+        let (mut directory, dir_inode_index) = self.find_directory(&dir)?;
+        let old_hash = directory.hash();
+
+        // Find file
+        if let Some(inode_block_index) = directory.get_file(file_name) {
+            self.check_modify_access()?;
+            let mut file_inode = self.get_inode(inode_block_index)?;
+            let old_inode = file_inode.clone();
+
+            write(self, &mut file_inode)?;
+
+            directory.update_file(file_name, &old_inode, &file_inode);
+        } else {
+            self.check_file_name(file_name)?;
+            let mut file_inode =
+                match self.allocate_file_inode(data_len, force)? {
+                    Some(inode) => inode,
+                    None => return Err(WalnutError::OutOfSpace.into()),
+                };
+
+            // Release inode if data cannot be written
+            if let Err(e) = write(self, &mut file_inode) {
+                self.release_inode(file_inode.block_index)?;
+                return Err(e);
+            }
+
+            directory.add_file(file_name, &file_inode)?;
+            let sequence = self.next_sequence();
+            directory.set_sequence(file_name, sequence);
+
+            // Inc. file count
+            self.superblock_mut().file_count += 1;
+        }
+
+        // Save directory
+        self.commit_directory(dir, directory, dir_inode_index, old_hash)?;
+
+        // Save superblock
+        self.save_superblock()?;
 
-        // Save group
-        self.save_group(group, group_index)?;
-
         Ok(())
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Superblock {
-    magic: [u8; 7],  // Magic number to check
-    fs_version: u32, // FS Version
-    // test_bytes: [u8; 20], // Secret test bytes
-    block_size: u32,  // Block size in bytes
-    group_count: u32, // Total groups count
-    block_count: u32, // Total blocks count
-    free_blocks: u32, // Available blocks
-    file_count: u32,  // File count in fs
-    created: u64,     // FS creation time
-    modified: u64,    // FS last modification time
-    checksum: u32,    // Superblock checksum
-}
+    #[inline]
+    pub fn remove_file<P>(&mut self, dir: P, file_name: &str) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        self.check_modify_access()?;
 
-impl Superblock {
-    fn new() -> Self {
-        Self {
-            magic: MAGIC,
-            fs_version: FS_VERSION,
-            block_size: BLOCK_SIZE,
-            group_count: 0,
-            block_count: 1,
-            free_blocks: 0,
-            file_count: 0,
-            created: now(),
-            modified: now(),
-            checksum: 0,
+        // Check if dir exist
+        let (mut directory, dir_inode_index) = self.find_directory(&dir)?;
+        let old_hash = directory.hash();
+
+        // Find file
+        let file_inode =
+            if let Some(inode_block_index) = directory.get_file(file_name) {
+                if let Ok(inode) = self.get_inode(inode_block_index) {
+                    inode
+                } else {
+                    return Err(anyhow!("No file found in dir!").into());
+                }
+            } else {
+                return Err(WalnutError::FileNotFound);
+            };
+
+        // Release inode
+        self.release_inode(file_inode.block_index)?;
+
+        // Remove file from directory
+        directory.remove_file(file_name, &file_inode)?;
+        self.access_log
+            .borrow_mut()
+            .remove(dir.as_ref().as_os_str(), file_name);
+
+        // Save directory
+        self.commit_directory(dir, directory, dir_inode_index, old_hash)?;
+
+        // Save superblock
+        let count = &mut self.superblock_mut().file_count;
+        *count = count.saturating_sub(1);
+        self.save_superblock()?;
+
+        Ok(())
+    }
+
+    /// Rename a file inside its directory
+    pub fn rename_file<P>(&mut self, dir: P, from: &str, to: &str) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        self.rename_many(dir, vec![(from.to_string(), to.to_string())])?;
+        Ok(())
+    }
+
+    /// Rename every file matching the glob pattern
+    /// e.g. `*.tmp` -> `*.bak`, wildcards of the template
+    /// are filled with the text matched by the pattern.
+    /// Nothing is renamed if any new name collides.
+    /// Returns renamed file count
+    pub fn rename_matching<P>(
+        &mut self,
+        dir: P,
+        from_pat: &str,
+        to_template: &str,
+    ) -> Result<usize>
+    where
+        P: AsRef<Path>,
+    {
+        let (directory, _) = self.find_directory(&dir)?;
+
+        let mut renames = vec![];
+        for file_name in directory.files.keys() {
+            if let Some(captures) = glob_match(from_pat, file_name) {
+                let new_name = fill_template(to_template, &captures)?;
+                if &new_name != file_name {
+                    renames.push((file_name.clone(), new_name));
+                }
+            }
         }
+
+        Ok(self.rename_many(dir, renames)?)
     }
 
-    pub fn update_modified(&mut self) {
-        self.modified = now();
+    /// Apply renames at once, after checking collisions
+    fn rename_many<P>(
+        &mut self,
+        dir: P,
+        renames: Vec<(String, String)>,
+    ) -> anyhow::Result<usize>
+    where
+        P: AsRef<Path>,
+    {
+        self.check_modify_access()?;
+
+        let (mut directory, dir_inode_index) = self.find_directory(&dir)?;
+        let old_hash = directory.hash();
+
+        // Check every rename before applying any
+        let mut targets = std::collections::BTreeSet::new();
+        for (from, to) in &renames {
+            if directory.get_file(from).is_none() {
+                return Err(WalnutError::FileNotFound.into());
+            }
+            self.check_file_name(to)?;
+            // Target can only exist if it gets renamed too
+            let renamed_away = renames.iter().any(|(f, _)| f == to);
+            if (directory.get_file(to).is_some() && !renamed_away)
+                || !targets.insert(to)
+            {
+                return Err(WalnutError::FileExists(to.to_string()).into());
+            }
+        }
+
+        // Remove all sources first, so targets can reuse their names
+        let mut inodes = vec![];
+        for (from, _) in &renames {
+            let inode = self.get_inode(directory.get_file(from).unwrap())?;
+            let sequence = directory.sequence(from);
+            directory.remove_file(from, &inode)?;
+            inodes.push((inode, sequence));
+        }
+        for ((_, to), (inode, sequence)) in renames.iter().zip(inodes) {
+            directory.add_file(to, &inode)?;
+            directory.set_sequence(to, sequence);
+        }
+        self.access_log
+            .borrow_mut()
+            .rename(dir.as_ref().as_os_str(), &renames);
+
+        // Save directory
+        self.commit_directory(dir, directory, dir_inode_index, old_hash)?;
+
+        // Save superblock
+        self.save_superblock()?;
+
+        Ok(renames.len())
     }
 
-    #[allow(dead_code)]
-    pub fn serialize(&mut self) -> anyhow::Result<Vec<u8>> {
-        self.checksum();
-        bincode::serialize(self).map_err(|e| e.into())
+    /// Write directory entries as they are read, one line each
+    /// Entries are decoded block by block, the directory is never
+    /// held in memory. Its checksum is verified after the last entry.
+    /// Returns the entry count
+    pub fn ls_stream<P, W>(&self, dir: P, w: &mut W) -> Result<u32>
+    where
+        P: AsRef<Path>,
+        W: Write,
+    {
+        self.check_data_access()?;
+
+        let directory_index = self.get_directory_index()?;
+        let inode = match directory_index.find_dir(dir) {
+            Some(inode_index) => self.get_inode(*inode_index)?,
+            None => return Err(WalnutError::DirNotFound),
+        };
+
+        if let Some(throttle) = &self.throttle {
+            throttle.begin();
+        }
+
+        let mut r = BufReader::new(FileReader::new(self, inode));
+
+        // Files map is stored as its length and the entries in order
+        let count: u64 = bincode::deserialize_from(&mut r)?;
+        for _ in 0..count {
+            let (name, inode_index): (String, u32) =
+                bincode::deserialize_from(&mut r)?;
+            writeln!(w, "{0: <20} | inode: {1}", name, inode_index)?;
+        }
+
+        // Rest of the directory is read, the reader verifies its checksum
+        std::io::copy(&mut r, &mut std::io::sink())?;
+
+        Ok(count as u32)
+    }
+
+    /// List directory with entry kinds and sizes
+    /// Files and direct subdirectories, ordered by name.
+    /// Each entry inode is read once
+    pub fn list_detailed<P>(&mut self, dir: P) -> Result<Vec<DirEntry>>
+    where
+        P: AsRef<Path>,
+    {
+        self.list_ordered(dir, ListOrder::Lexical)
+    }
+
+    /// Same as list_detailed, in the given order
+    /// Order is computed at list time, storage is not affected
+    pub fn list_ordered<P>(
+        &mut self,
+        dir: P,
+        order: ListOrder,
+    ) -> Result<Vec<DirEntry>>
+    where
+        P: AsRef<Path>,
+    {
+        let directory_index = self.get_directory_index()?;
+        let (directory, _) = self.find_directory(&dir)?;
+
+        // Entries with their creation sequence
+        let mut entries = vec![];
+        for (name, inode_index) in &directory.files {
+            let inode = self.get_inode(*inode_index)?;
+            let sequence = directory.sequence(name);
+            entries.push((
+                DirEntry {
+                    name: name.clone(),
+                    inode: *inode_index,
+                    size: inode.size,
+                    created: inode.created,
+                    modified: inode.last_modified,
+                    kind: EntryKind::File,
+                },
+                sequence,
+            ));
+        }
+
+        // Subdirectories are stored in the directory index
+        for (dir_path, inode_index) in directory_index.directories() {
+            let dir_path = Path::new(dir_path);
+            if dir_path.parent() != Some(dir.as_ref()) {
+                continue;
+            }
+            let inode = self.get_inode(*inode_index)?;
+            let sequence = self.read_directory(*inode_index)?.sequence;
+            entries.push((
+                DirEntry {
+                    name: dir_path
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned(),
+                    inode: *inode_index,
+                    size: inode.size,
+                    created: inode.created,
+                    modified: inode.last_modified,
+                    kind: EntryKind::Directory,
+                },
+                sequence,
+            ));
+        }
+        match order {
+            ListOrder::Lexical => {
+                entries.sort_by(|a, b| a.0.name.cmp(&b.0.name))
+            }
+            ListOrder::Natural => {
+                entries.sort_by(|a, b| natural_cmp(&a.0.name, &b.0.name))
+            }
+            // Entries before version 17 have sequence 0,
+            // their creation time and inode index decide
+            ListOrder::Creation => entries
+                .sort_by_key(|(e, sequence)| (*sequence, e.created, e.inode)),
+        }
+
+        Ok(entries.into_iter().map(|(e, _)| e).collect())
+    }
+
+    /// List files of a directory and all directories below it
+    /// Returns file paths with their inodes, ordered by path
+    pub fn list_files_recursive<P>(
+        &self,
+        dir: P,
+    ) -> Result<Vec<(PathBuf, Inode)>>
+    where
+        P: AsRef<Path>,
+    {
+        let directory_index = self.get_directory_index()?;
+
+        let mut files = vec![];
+        for (dir_path, dir_inode_index) in directory_index.directories() {
+            let dir_path = Path::new(dir_path);
+            if !dir_path.starts_with(&dir) {
+                continue;
+            }
+            let directory = self.read_directory(*dir_inode_index)?;
+            for (file_name, file_inode_index) in directory.files {
+                let inode = self.get_inode(file_inode_index)?;
+                files.push((dir_path.join(file_name), inode));
+            }
+        }
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(files)
+    }
+
+    /// Iterate every file as (directory, file name, inode index)
+    /// Directories are read once, when the walk reaches them
+    pub fn walk(&self) -> Result<FsWalker<'_>> {
+        self.check_data_access()?;
+
+        Ok(FsWalker {
+            fs: self,
+            directories: self.get_directory_index()?.directories.into_iter(),
+            current: None,
+        })
+    }
+
+    /// Files changed since the base image
+    /// Files are compared by their size and data checksum
+    pub fn diff(&mut self, base: &mut FS) -> Result<Diff> {
+        let current: BTreeMap<PathBuf, Inode> =
+            self.list_files_recursive("")?.into_iter().collect();
+        let base: BTreeMap<PathBuf, Inode> =
+            base.list_files_recursive("")?.into_iter().collect();
+
+        let mut diff = Diff::default();
+        for (path, inode) in &current {
+            match base.get(path) {
+                None => diff.added.push(path.clone()),
+                Some(base_inode) => {
+                    if base_inode.size != inode.size
+                        || base_inode.data_checksum != inode.data_checksum
+                    {
+                        diff.modified.push(path.clone());
+                    }
+                }
+            }
+        }
+        diff.removed = base
+            .into_keys()
+            .filter(|path| !current.contains_key(path))
+            .collect();
+
+        Ok(diff)
+    }
+
+    /// The n biggest files as (directory, file name, size)
+    /// ordered by size, largest first
+    pub fn largest_files(
+        &self,
+        n: usize,
+    ) -> Result<Vec<(OsString, String, u64)>> {
+        let directory_index = self.get_directory_index()?;
+
+        let mut files = vec![];
+        for (dir_path, dir_inode_index) in directory_index.directories() {
+            let directory = self.read_directory(*dir_inode_index)?;
+            for (file_name, file_inode_index) in directory.files {
+                let size = self.get_inode(file_inode_index)?.size;
+                files.push((dir_path.clone(), file_name, size));
+            }
+        }
+        // Ties are ordered by path
+        files.sort_by(|a, b| {
+            b.2.cmp(&a.2).then_with(|| (&a.0, &a.1).cmp(&(&b.0, &b.1)))
+        });
+        files.truncate(n);
+
+        Ok(files)
+    }
+
+    /// Capture the file set of a directory
+    /// Data is written copy-on-write, so the captured inodes stay
+    /// restorable until their blocks are allocated again
+    pub fn snapshot_directory<P>(&self, dir: P) -> Result<DirectorySnapshot>
+    where
+        P: AsRef<Path>,
+    {
+        let (directory, _) = self.find_directory(&dir)?;
+        let mut files = BTreeMap::new();
+        for (file_name, inode_index) in directory.files {
+            files.insert(file_name, self.get_inode(inode_index)?);
+        }
+
+        Ok(DirectorySnapshot {
+            dir: dir.as_ref().to_path_buf(),
+            files,
+        })
+    }
+
+    /// Revert a directory to its snapshot
+    /// Fails without changes if blocks of a changed or removed
+    /// file were allocated again since the snapshot
+    pub fn restore_directory(
+        &mut self,
+        snapshot: &DirectorySnapshot,
+    ) -> Result<()> {
+        self.check_modify_access()?;
+
+        let (mut directory, dir_inode_index) =
+            self.find_directory(&snapshot.dir)?;
+        let old_hash = directory.hash();
+
+        // Step 1: current files not matching the snapshot
+        let mut stale = vec![];
+        for (file_name, inode_index) in &directory.files {
+            let inode = self.get_inode(*inode_index)?;
+            let unchanged = snapshot.files.get(file_name).is_some_and(|s| {
+                s.block_index == inode.block_index
+                    && s.size == inode.size
+                    && s.data_checksum == inode.data_checksum
+            });
+            if !unchanged {
+                stale.push((file_name.clone(), inode));
+            }
+        }
+        let missing: Vec<(&String, &Inode)> = snapshot
+            .files
+            .iter()
+            .filter(|(file_name, _)| {
+                directory.get_file(file_name).is_none()
+                    || stale.iter().any(|(name, _)| name == *file_name)
+            })
+            .collect();
+
+        // Step 2: check snapshot blocks are still available
+        // Inodes are rewritten, so they can take any stale file block.
+        // Data blocks have to be free or kept by the same file
+        let stale_blocks = |name: Option<&String>| {
+            stale
+                .iter()
+                .filter(|(n, _)| name.is_none_or(|name| n == name))
+                .flat_map(|(_, inode)| {
+                    let mut blocks = inode.data_blocks();
+                    if name.is_none() {
+                        blocks.push(inode.block_index);
+                    }
+                    blocks
+                })
+                .collect::<std::collections::BTreeSet<u32>>()
+        };
+        let any_stale = stale_blocks(None);
+        for (file_name, inode) in &missing {
+            let same_file = stale_blocks(Some(file_name));
+            let inode_free = self.is_block_free(inode.block_index)
+                || any_stale.contains(&inode.block_index);
+            let data_free = inode
+                .data_blocks()
+                .iter()
+                .all(|b| self.is_block_free(*b) || same_file.contains(b));
+
+            // Free blocks could have been written and released since
+            let intact = inode_free
+                && data_free
+                && self
+                    .read_inode_data(
+                        &mut (*inode).clone(),
+                        &mut std::io::sink(),
+                    )
+                    .is_ok();
+            if !intact {
+                return Err(anyhow!(
+                    "Blocks of {} were allocated again",
+                    file_name
+                )
+                .into());
+            }
+        }
+
+        // Step 3: release stale files
+        for (file_name, inode) in &stale {
+            self.release_inode(inode.block_index)?;
+            directory.remove_file(file_name, inode)?;
+        }
+
+        // Step 4: allocate snapshot blocks again and relink the files
+        let blocks_per_group = self.blocks_per_group();
+        let mut touched = std::collections::BTreeSet::new();
+        for (file_name, inode) in &missing {
+            for block in inode.data_blocks() {
+                let (group_index, bitmap_index) =
+                    Group::translate_public_address(block, blocks_per_group);
+                self.groups[group_index as usize]
+                    .force_allocate_at(bitmap_index);
+                touched.insert(group_index);
+            }
+
+            let (group_index, bitmap_index) = Group::translate_public_address(
+                inode.block_index,
+                blocks_per_group,
+            );
+            self.groups[group_index as usize].force_allocate_at(bitmap_index);
+            touched.insert(group_index);
+            self.superblock.inode_counts[group_index as usize] += 1;
+
+            let mut inode = (*inode).clone();
+            self.save_inode(&mut inode)?;
+            directory.add_file(file_name, &inode)?;
+        }
+        for group_index in touched {
+            let group = self.groups[group_index as usize].clone();
+            self.save_group(group, group_index)?;
+        }
+
+        // Stale files were removed, missing ones relinked
+        let file_count = &mut self.superblock.file_count;
+        *file_count = file_count.saturating_sub(stale.len() as u32)
+            + missing.len() as u32;
+
+        // Save directory
+        self.commit_directory(
+            &snapshot.dir,
+            directory,
+            dir_inode_index,
+            old_hash,
+        )?;
+
+        // Save superblock
+        Ok(self.save_superblock()?)
+    }
+
+    /// Create an empty scratch file in the hidden temp directory
+    /// The file is removed when the returned handle is dropped
+    pub fn create_temp(&mut self) -> Result<TempHandle<'_>> {
+        // Create temp directory on first use
+        if self
+            .get_directory_index()?
+            .find_dir(TEMP_DIRECTORY)
+            .is_none()
+        {
+            self.create_directory(TEMP_DIRECTORY)?;
+        }
+
+        let name = Uuid::new_v4().simple().to_string();
+        self.add_file(TEMP_DIRECTORY, &name, &mut Cursor::new(&[]), 0)?;
+
+        Ok(TempHandle {
+            fs: self,
+            name,
+            discarded: false,
+        })
+    }
+
+    /// List files of a directory
+    /// ordered by their inode block index (allocation order)
+    pub fn list_by_inode<P>(&self, dir: P) -> Result<Vec<(String, u32)>>
+    where
+        P: AsRef<Path>,
+    {
+        let (directory, _) = self.find_directory(dir)?;
+
+        let mut files: Vec<(String, u32)> =
+            directory.files.into_iter().collect();
+        files.sort_by_key(|(_, inode_index)| *inode_index);
+
+        Ok(files)
+    }
+
+    /// Read file data
+    /// Finds file by dir and filename
+    /// And writes its content to the given writer
+    /// Data is streamed block by block, so memory usage
+    /// is O(BLOCK_SIZE) regardless of the file size
+    #[inline]
+    pub fn get_file_data<P, W>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+        w: &mut W,
+    ) -> Result<u32>
+    where
+        P: AsRef<Path>,
+        W: Write,
+    {
+        // First find directory
+        let (directory, _) = self.find_directory(&dir)?;
+
+        // Then find file
+        let mut file_inode =
+            if let Some(file_inode_index) = directory.get_file(file_name) {
+                self.get_inode(file_inode_index)?
+            } else {
+                // Else return error
+                return Err(WalnutError::FileNotFound);
+            };
+        directory.check_size(file_name, &file_inode)?;
+
+        self.record_access(dir, file_name);
+        Ok(self.read_inode_data(&mut file_inode, w)?)
+    }
+
+    /// Open a stored file as a reader
+    /// Data is read block by block while reading
+    pub fn open_file<P>(
+        &self,
+        dir: P,
+        file_name: &str,
+    ) -> Result<FileReader<'_>>
+    where
+        P: AsRef<Path>,
+    {
+        self.check_data_access()?;
+
+        let (directory, _) = self.find_directory(&dir)?;
+        let inode = match directory.get_file(file_name) {
+            Some(inode_index) => self.get_inode(inode_index)?,
+            None => return Err(WalnutError::FileNotFound),
+        };
+        directory.check_size(file_name, &inode)?;
+        self.record_access(dir, file_name);
+
+        if let Some(throttle) = &self.throttle {
+            throttle.begin();
+        }
+
+        Ok(FileReader::new(self, inode))
+    }
+
+    /// Create or replace a file written through the returned writer
+    /// e.g. data of unknown length from a socket. Blocks are allocated
+    /// as data arrives, the image grows when needed. The file is added
+    /// once the writer is finished, if the process stops before,
+    /// the file is not added and its written blocks stay allocated
+    /// without any inode pointing to them
+    pub fn create_writer<P>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+    ) -> Result<FileWriter<'_>>
+    where
+        P: AsRef<Path>,
+    {
+        self.check_data_access()?;
+
+        // Fail early, directory is read again when the file is added
+        let (directory, _) = self.find_directory(&dir)?;
+        if directory.get_file(file_name).is_some() {
+            self.check_modify_access()?;
+        }
+
+        Ok(FileWriter {
+            fs: self,
+            dir: dir.as_ref().to_path_buf(),
+            file_name: file_name.to_string(),
+            buffer: vec![],
+            ranges: vec![],
+            checksum: Checksum::new(),
+            data_len: 0,
+            failed: false,
+            finished: false,
+        })
+    }
+
+    /// First n bytes of a file, e.g. for previews
+    /// Only the leading blocks are read and decrypted
+    pub fn head<P>(&self, dir: P, file_name: &str, n: u64) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+    {
+        let mut data = vec![];
+        self.open_file(dir, file_name)?
+            .take(n)
+            .read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Last n bytes of a file
+    /// Only the trailing blocks are read and decrypted
+    pub fn tail<P>(&self, dir: P, file_name: &str, n: u64) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+    {
+        let mut reader = self.open_file(dir, file_name)?;
+        let size = reader.inode().size;
+        reader.seek(SeekFrom::Start(size.saturating_sub(n)))?;
+
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Call f with every decrypted block of a file and its offset
+    /// The last block is passed with its data length only,
+    /// raw data stored in the inode is passed as a single block
+    pub fn for_each_block<P, F>(
+        &self,
+        dir: P,
+        file_name: &str,
+        mut f: F,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnMut(u64, &[u8]),
+    {
+        let mut reader = self.open_file(dir, file_name)?;
+        while reader.loaded < reader.inode.size {
+            let offset = reader.loaded;
+            reader.load_next()?;
+            f(offset, &reader.buffer);
+        }
+
+        Ok(())
+    }
+
+    /// Read files of a directory back-to-back as one reader
+    /// e.g. for a combined download. Every file is opened upfront,
+    /// so a missing one fails before anything is read
+    pub fn open_concat<P>(
+        &self,
+        dir: P,
+        names: &[&str],
+    ) -> Result<impl Read + '_>
+    where
+        P: AsRef<Path>,
+    {
+        let mut readers = vec![];
+        for name in names {
+            readers.push(self.open_file(&dir, name)?);
+        }
+
+        Ok(ConcatReader {
+            readers: readers.into_iter(),
+            current: None,
+        })
+    }
+
+    /// Export file to a host file through a memory map
+    /// Blocks are decrypted right inside the mapped output,
+    /// holes are left as zeros. Returns the data checksum
+    pub fn export_mmap<P, Q>(
+        &self,
+        dir: P,
+        file_name: &str,
+        host_path: Q,
+    ) -> Result<u32>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.check_data_access()?;
+
+        let (directory, _) = self.find_directory(&dir)?;
+        let inode = match directory.get_file(file_name) {
+            Some(inode_index) => self.get_inode(inode_index)?,
+            None => return Err(WalnutError::FileNotFound),
+        };
+        self.record_access(dir, file_name);
+
+        let out = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(host_path)?;
+        out.set_len(inode.size)?;
+
+        // Empty files cannot be mapped
+        if inode.size == 0 {
+            return Ok(inode.data_checksum);
+        }
+        let mut mmap = unsafe { memmap2::MmapMut::map_mut(&out)? };
+
+        if let Some(throttle) = &self.throttle {
+            throttle.begin();
+        }
+
+        match &inode.data {
+            Data::Raw(data) => {
+                mmap.copy_from_slice(data);
+                self.cipher
+                    .decrypt_block(inode.block_index as u64, &mut mmap);
+            }
+            Data::DirectPointers(pointers) => {
+                let mut offset = 0;
+                for (block_index, range) in pointers {
+                    for block in *block_index..(*block_index + *range) {
+                        let len =
+                            (inode.size - offset).min(self.block_size() as u64);
+                        let end = (offset + len) as usize;
+                        if *block_index != HOLE {
+                            let buf = &mut mmap[offset as usize..end];
+                            self.read_data_block(block, buf)?;
+                        }
+                        offset += len;
+                    }
+                }
+            }
+        }
+        mmap.flush()?;
+
+        let mut checksum = Checksum::new();
+        checksum.update(&mmap);
+
+        Ok(checksum.finalize())
+    }
+
+    /// Record the host path a file was imported from
+    pub fn set_original_path<P, Q>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+        host_path: Q,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.check_data_access()?;
+
+        let (directory, _) = self.find_directory(dir)?;
+        let inode_index = match directory.get_file(file_name) {
+            Some(inode_index) => inode_index,
+            None => return Err(WalnutError::FileNotFound),
+        };
+
+        let mut paths = self.original_paths()?;
+        paths.insert(inode_index, host_path.as_ref().to_path_buf());
+        self.save_original_paths(paths)?;
+        Ok(self.save_superblock()?)
+    }
+
+    /// Host path a file was imported from, if it was recorded
+    pub fn original_path<P>(
+        &self,
+        dir: P,
+        file_name: &str,
+    ) -> Result<Option<PathBuf>>
+    where
+        P: AsRef<Path>,
+    {
+        let (directory, _) = self.find_directory(dir)?;
+        match directory.get_file(file_name) {
+            Some(inode_index) => {
+                Ok(self.original_paths()?.remove(&inode_index))
+            }
+            None => Err(WalnutError::FileNotFound),
+        }
+    }
+
+    /// Import a host directory tree into dir
+    /// Fails on the first entry import_tree would skip.
+    /// Returns the imported file count
+    pub fn import_dir<P, Q>(&mut self, host_dir: P, dir: Q) -> Result<u32>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let stats = self.import_tree(host_dir, dir)?;
+        match stats.skipped.first() {
+            Some((path, e)) => {
+                Err(anyhow!("Cannot import {}: {}", path.display(), e).into())
+            }
+            None => Ok(stats.files),
+        }
+    }
+
+    /// Import a host directory tree below fs_prefix
+    /// Every file records the host path it was read from, symlinks
+    /// are not followed. Entries that cannot be read are skipped
+    /// and collected in the result, image errors stop the import
+    pub fn import_tree<P, Q>(
+        &mut self,
+        host_root: P,
+        fs_prefix: Q,
+    ) -> Result<ImportStats>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let mut stats = ImportStats::default();
+        let entries = std::fs::read_dir(&host_root)?;
+        self.import_entries(entries, fs_prefix.as_ref(), &mut stats)?;
+        Ok(stats)
+    }
+
+    fn import_entries(
+        &mut self,
+        entries: std::fs::ReadDir,
+        dir: &Path,
+        stats: &mut ImportStats,
+    ) -> anyhow::Result<()> {
+        if !self.dir_exists(dir) {
+            self.create_directory(dir)?;
+        }
+
+        let mut entries: Vec<_> = entries
+            .filter_map(|entry| {
+                entry.map_err(|e| stats.skip(dir.into(), e.into())).ok()
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let host_path = entry.path();
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => {
+                    stats.skip(host_path, anyhow!("Invalid file name"));
+                    continue;
+                }
+            };
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    stats.skip(host_path, e.into());
+                    continue;
+                }
+            };
+
+            if file_type.is_dir() {
+                match std::fs::read_dir(&host_path) {
+                    Ok(entries) => {
+                        self.import_entries(entries, &dir.join(name), stats)?
+                    }
+                    Err(e) => stats.skip(host_path, e.into()),
+                }
+            } else if file_type.is_file() {
+                let opened = File::open(&host_path)
+                    .and_then(|file| Ok((file.metadata()?.len(), file)));
+                let (len, file) = match opened {
+                    Ok(opened) => opened,
+                    Err(e) => {
+                        stats.skip(host_path, e.into());
+                        continue;
+                    }
+                };
+                self.add_file(dir, &name, &mut BufReader::new(file), len)?;
+                self.set_original_path(dir, &name, host_path)?;
+                stats.files += 1;
+                stats.bytes += len;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Export every file under host_dir, returns the exported file count
+    /// Files go to their image path, with original_paths files having
+    /// a recorded host path go there instead. Only the normal components
+    /// of a recorded path are kept, so files stay under host_dir
+    pub fn export_all<P>(
+        &self,
+        host_dir: P,
+        original_paths: bool,
+    ) -> Result<u32>
+    where
+        P: AsRef<Path>,
+    {
+        self.check_data_access()?;
+
+        let paths = if original_paths {
+            self.original_paths()?
+        } else {
+            BTreeMap::new()
+        };
+
+        let mut exported = 0;
+        for (path, mut inode) in self.list_files_recursive("")? {
+            let path = paths.get(&inode.block_index).unwrap_or(&path);
+            let target = host_dir.as_ref().join(normal_components(path));
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            self.export_inode(&mut inode, &target)?;
+            exported += 1;
+        }
+
+        Ok(exported)
+    }
+
+    /// Export the directories below fs_prefix under host_dest
+    /// Directories are created even when empty, files keep their
+    /// names and bytes only
+    pub fn export_tree<P, Q>(&self, fs_prefix: P, host_dest: Q) -> Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.check_data_access()?;
+
+        let prefix = fs_prefix.as_ref();
+        let directory_index = self.get_directory_index()?;
+        let mut found = prefix.as_os_str().is_empty();
+        for (dir, dir_inode_index) in directory_index.directories() {
+            let relative = match Path::new(dir).strip_prefix(prefix) {
+                Ok(relative) => relative,
+                Err(_) => continue,
+            };
+            found = true;
+
+            let host_dir = host_dest.as_ref().join(normal_components(relative));
+            std::fs::create_dir_all(&host_dir)?;
+            let directory = self.read_directory(*dir_inode_index)?;
+            for (file_name, file_inode_index) in directory.files {
+                let mut inode = self.get_inode(file_inode_index)?;
+                self.export_inode(&mut inode, &host_dir.join(file_name))?;
+            }
+        }
+
+        if !found {
+            return Err(WalnutError::DirNotFound);
+        }
+        Ok(())
+    }
+
+    /// Stream inode data to a new host file
+    /// Its length is set up front
+    fn export_inode(
+        &self,
+        inode: &mut Inode,
+        host_path: &Path,
+    ) -> anyhow::Result<()> {
+        let file = File::create(host_path)?;
+        file.set_len(inode.size)?;
+
+        let mut w = BufWriter::new(file);
+        self.read_inode_data(inode, &mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// The n most recently read files as (directory, file name, read time)
+    /// most recent first. Reads are tracked in memory by this handle,
+    /// access times are not stored in the image
+    pub fn recently_accessed(&self, n: usize) -> Vec<(OsString, String, u64)> {
+        self.access_log.borrow().recent(n)
+    }
+
+    #[inline]
+    fn record_access<P>(&self, dir: P, file_name: &str)
+    where
+        P: AsRef<Path>,
+    {
+        let now = self.clock.now();
+        self.access_log.borrow_mut().record(
+            dir.as_ref().as_os_str(),
+            file_name,
+            now,
+        );
+    }
+
+    /// Compare a stored file with a host file, e.g. after export
+    /// Both are streamed, returns the first mismatching offset
+    pub fn first_mismatch<P, Q>(
+        &mut self,
+        dir: P,
+        name: &str,
+        host_path: Q,
+    ) -> Result<Option<u64>>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let host = BufReader::new(File::open(host_path)?);
+        let mut w = CompareWriter {
+            host,
+            buffer: vec![],
+            offset: 0,
+            mismatch: None,
+        };
+
+        let res = self.get_file_data(dir, name, &mut w);
+        if w.mismatch.is_some() {
+            return Ok(w.mismatch);
+        }
+        res?;
+
+        // Host file is longer
+        if w.host.read(&mut [0])? > 0 {
+            return Ok(Some(w.offset));
+        }
+
+        Ok(None)
+    }
+
+    /// Host file has the same content as the stored one
+    pub fn verify_against<P, Q>(
+        &mut self,
+        dir: P,
+        name: &str,
+        host_path: Q,
+    ) -> Result<bool>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        Ok(self.first_mismatch(dir, name, host_path)?.is_none())
+    }
+
+    /// Release file blocks holding only zeros
+    /// Released blocks become holes, reading them back gives zeros.
+    /// Returns the number of released blocks
+    pub fn sparsify<P>(&mut self, dir: P, name: &str) -> Result<u32>
+    where
+        P: AsRef<Path>,
+    {
+        self.check_modify_access()?;
+
+        let mut inode = self.get_file_info(&dir, name)?;
+        let pointers = match &inode.data {
+            // Raw data has no blocks to release
+            Data::Raw(_) => return Ok(0),
+            Data::DirectPointers(pointers) => pointers.clone(),
+        };
+
+        // Step 1: find zero blocks, one block at a time
+        let mut new_pointers: Vec<(u32, u32)> = vec![];
+        let mut released: Vec<(u32, u32)> = vec![];
+        let mut data_left = inode.size;
+        let mut r = BufReader::new(&self.file);
+        let mut buffer = vec![0; self.block_size() as usize];
+        for (block_index, range) in pointers {
+            if block_index == HOLE {
+                push_region(&mut new_pointers, HOLE, range);
+                data_left = data_left
+                    .saturating_sub(range as u64 * self.block_size() as u64);
+                continue;
+            }
+
+            r.seek(SeekFrom::Start(block_seek_position(
+                block_index,
+                self.block_size(),
+            )))?;
+            for block in block_index..(block_index + range) {
+                let len = data_left.min(self.block_size() as u64) as usize;
+                let pos = block_seek_position(block, self.block_size());
+                read_exact_at(&mut r, pos, &mut buffer[..len], &self.retry)?;
+                self.cipher.decrypt_block(block as u64, &mut buffer[..len]);
+                data_left -= len as u64;
+
+                if buffer[..len].iter().all(|b| *b == 0) {
+                    push_region(&mut new_pointers, HOLE, 1);
+                    merge_ranges(&mut released, &[(block, 1)]);
+                } else {
+                    push_region(&mut new_pointers, block, 1);
+                }
+            }
+        }
+        drop(r);
+
+        if released.is_empty() {
+            return Ok(0);
+        }
+        if new_pointers.len() > self.max_regions {
+            return Err(anyhow!("Too many regions to store holes").into());
+        }
+
+        // Step 2: save the new layout, then free the blocks
+        let released_count = released.iter().map(|(_, len)| *len).sum();
+        self.mark_sparse()?;
+        inode.data = Data::DirectPointers(new_pointers);
+        self.save_inode(&mut inode)?;
+
+        // Released blocks cannot stay pinned
+        if let Some(blocks) = self.pins.get_mut(&dir.as_ref().join(name)) {
+            let mut cache = self.cache.borrow_mut();
+            blocks.retain(|b| {
+                let keep = !released
+                    .iter()
+                    .any(|(start, len)| (*start..*start + *len).contains(b));
+                if !keep {
+                    cache.unpin(*b);
+                }
+                keep
+            });
+        }
+
+        self.release_inode_data(released)?;
+        self.save_superblock()?;
+
+        Ok(released_count)
+    }
+
+    /// Grow a file by additional zero bytes
+    /// The added range is stored as holes, no blocks are allocated.
+    /// Raw data not fitting into the inode anymore moves to a block
+    pub fn extend_with_zeros<P>(
+        &mut self,
+        dir: P,
+        name: &str,
+        additional: u64,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        self.check_data_access()?;
+
+        let (mut directory, dir_inode_index) = self.find_directory(&dir)?;
+        let old_hash = directory.hash();
+        let mut inode = match directory.get_file(name) {
+            Some(inode_index) => self.get_inode(inode_index)?,
+            None => return Err(WalnutError::FileNotFound),
+        };
+        if additional == 0 {
+            return Ok(());
+        }
+        let old_inode = inode.clone();
+
+        self.extend_inode(&mut inode, additional)?;
+
+        // Save directory
+        directory.update_file(name, &old_inode, &inode);
+        self.commit_directory(dir, directory, dir_inode_index, old_hash)?;
+
+        // Save superblock
+        Ok(self.save_superblock()?)
+    }
+
+    fn extend_inode(
+        &mut self,
+        inode: &mut Inode,
+        additional: u64,
+    ) -> anyhow::Result<()> {
+        let old_size = inode.size;
+        let new_size = old_size + additional;
+        let blocks = |size: u64| size.div_ceil(self.block_size() as u64);
+        let block_len =
+            |start: u64| (new_size - start).min(self.block_size() as u64);
+
+        let holes = blocks(new_size) - blocks(old_size);
+        if holes > u32::MAX as u64 {
+            return Err(anyhow!("Too many blocks to extend at once"));
+        }
+
+        // Checksum continues from the current data
+        let mut checksum = Checksum::with_initial(inode.data_checksum);
+        let zeros = vec![0; self.block_size() as usize];
+        let mut zeros_left = additional;
+        while zeros_left > 0 {
+            let len = zeros_left.min(self.block_size() as u64);
+            checksum.update(&zeros[..len as usize]);
+            zeros_left -= len;
+        }
+
+        let mut unshared = vec![];
+        let mut pointers = match &inode.data {
+            Data::Raw(raw) => {
+                let mut data = raw.clone();
+                self.cipher
+                    .decrypt_block(inode.block_index as u64, &mut data);
+
+                // Small files stay raw
+                if new_size as usize <= self.inode_capacity() {
+                    data.resize(new_size as usize, 0);
+                    let mut data = Cursor::new(&data);
+                    return self
+                        .write_inode_data(inode, &mut data, new_size, false);
+                }
+                if data.is_empty() {
+                    vec![]
+                } else {
+                    // Raw data moves into its first block, padded with zeros
+                    data.resize(block_len(0) as usize, 0);
+                    let ranges = self.allocate_blocks(
+                        1,
+                        1,
+                        self.first_data_group(inode),
+                        false,
+                    )?;
+                    let mut r = Cursor::new(&data);
+                    if let Err(e) = self.write_blocks(
+                        &ranges,
+                        &mut r,
+                        data.len() as u64,
+                        &mut Checksum::new(),
+                    ) {
+                        self.release_inode_data(ranges)?;
+                        return Err(e);
+                    }
+                    ranges
+                }
+            }
+            Data::DirectPointers(pointers) => {
+                let new_region =
+                    pointers.last().is_none_or(|(start, _)| *start != HOLE);
+                if holes > 0 && new_region && pointers.len() >= self.max_regions
+                {
+                    return Err(anyhow!("Too many regions to store holes"));
+                }
+                let mut pointers = pointers.clone();
+
+                // Bytes after the end of a partial last block are not
+                // zeroed on write, clear them before they become readable
+                let used = (old_size % self.block_size() as u64) as usize;
+                match pointers.last().copied() {
+                    Some((start, len)) if start != HOLE && used > 0 => {
+                        let last = start + len - 1;
+                        let mut buffer = vec![0; used];
+                        self.read_data_block(last, &mut buffer)?;
+                        buffer.resize(
+                            block_len(old_size - used as u64) as usize,
+                            0,
+                        );
+                        let n = old_size / self.block_size() as u64;
+                        let target =
+                            self.unshare_block(inode, &mut pointers, n, last)?;
+                        if target != last {
+                            unshared.push((last, 1));
+                        }
+                        self.write_blocks(
+                            &[(target, 1)],
+                            &mut Cursor::new(&buffer),
+                            buffer.len() as u64,
+                            &mut Checksum::new(),
+                        )?;
+                    }
+                    _ => (),
+                }
+                pointers
+            }
+        };
+
+        if holes > 0 {
+            self.mark_sparse()?;
+            push_region(&mut pointers, HOLE, holes as u32);
+        }
+
+        inode.set_direct_pointers(pointers, new_size);
+        inode.data_checksum = checksum.finalize();
+        self.save_inode(inode)?;
+        self.release_inode_data(unshared)
+    }
+
+    /// Flag the image before its first hole is written,
+    /// so tools that cannot read holes refuse it
+    fn mark_sparse(&mut self) -> anyhow::Result<()> {
+        if self.superblock.flags & FLAG_SPARSE == 0 {
+            self.superblock.flags |= FLAG_SPARSE;
+            self.save_superblock()?;
+        }
+        Ok(())
+    }
+
+    /// Make every preceding write durable
+    /// Data and inode blocks are synced before the superblock,
+    /// so the image is readable in this state after a crash
+    pub fn checkpoint(&mut self) -> Result<()> {
+        // Data blocks, inodes, directories and bitmaps first
+        self.file.sync_data()?;
+
+        // Then the superblock referencing them
+        self.save_superblock()?;
+        self.file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Append data to the end of a file
+    /// data_len (bytes) must be correct
+    pub fn append_file<P, R>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+        data: &mut R,
+        data_len: u64,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+        R: BufRead,
+    {
+        self.check_data_access()?;
+
+        let (mut directory, dir_inode_index) = self.find_directory(&dir)?;
+        let old_hash = directory.hash();
+        let mut inode = match directory.get_file(file_name) {
+            Some(inode_index) => self.get_inode(inode_index)?,
+            None => return Err(WalnutError::FileNotFound),
+        };
+        if data_len == 0 {
+            return Ok(());
+        }
+        let old_inode = inode.clone();
+
+        self.append_inode(&mut inode, data, data_len)?;
+
+        // Save directory
+        directory.update_file(file_name, &old_inode, &inode);
+        self.commit_directory(dir, directory, dir_inode_index, old_hash)?;
+
+        // Save superblock
+        Ok(self.save_superblock()?)
+    }
+
+    /// Copy a file to a new file, data is decrypted and written again
+    /// into newly allocated blocks, the source data is read into memory
+    pub fn copy_file<P, Q>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+        target_dir: Q,
+        target_name: &str,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.check_data_access()?;
+
+        let mut source = self.get_file_info(&dir, file_name)?;
+        if self.file_exists(&target_dir, target_name)? {
+            return Err(WalnutError::FileExists(target_name.to_string()));
+        }
+
+        // Source checksum is verified while reading
+        let mut data = Vec::with_capacity(source.size as usize);
+        self.read_inode_data(&mut source, &mut data)?;
+
+        self.add_file_inner(
+            target_dir,
+            target_name,
+            &mut Cursor::new(&data),
+            source.size,
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// Copy a file sharing its data blocks, e.g. a cheap duplicate
+    /// Shared blocks are copied on the first write of either file
+    pub fn clone_file<P, Q>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+        target_dir: Q,
+        target_name: &str,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.check_data_access()?;
+
+        let source = self.get_file_info(&dir, file_name)?;
+        if self.file_exists(&target_dir, target_name)? {
+            return Err(WalnutError::FileExists(target_name.to_string()));
+        }
+
+        self.add_file_with(
+            target_dir,
+            target_name,
+            None,
+            false,
+            |fs, inode| {
+                inode.size = source.size;
+                inode.data_checksum = source.data_checksum;
+                match &source.data {
+                    // Raw data is encrypted by its inode block index
+                    Data::Raw(raw) => {
+                        let mut raw = raw.clone();
+                        fs.cipher
+                            .decrypt_block(source.block_index as u64, &mut raw);
+                        fs.cipher
+                            .encrypt_block(inode.block_index as u64, &mut raw);
+                        inode.data = Data::Raw(raw);
+                    }
+                    Data::DirectPointers(pointers) => {
+                        let mut shared = fs.shared_blocks()?;
+                        for block in source.data_blocks() {
+                            *shared.entry(block).or_insert(1) += 1;
+                        }
+                        fs.save_shared_blocks(shared)?;
+                        inode.data = Data::DirectPointers(pointers.clone());
+                    }
+                }
+                fs.save_inode(inode)
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Overwrite file data at offset, the file size is kept
+    /// Shared blocks and holes get new blocks, the data checksum
+    /// is computed again by reading the file
+    pub fn write_file_range<P>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        self.check_modify_access()?;
+
+        let (mut directory, dir_inode_index) = self.find_directory(&dir)?;
+        let old_hash = directory.hash();
+        let mut inode = match directory.get_file(file_name) {
+            Some(inode_index) => self.get_inode(inode_index)?,
+            None => return Err(WalnutError::FileNotFound),
+        };
+        let end = offset.checked_add(data.len() as u64);
+        if end.is_none_or(|end| end > inode.size) {
+            return Err(anyhow!("Range is out of the file").into());
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        let old_inode = inode.clone();
+
+        self.write_inode_range(&mut inode, offset, data)?;
+
+        // Save directory
+        directory.update_file(file_name, &old_inode, &inode);
+        self.commit_directory(dir, directory, dir_inode_index, old_hash)?;
+
+        // Save superblock
+        Ok(self.save_superblock()?)
+    }
+
+    fn write_inode_range(
+        &mut self,
+        inode: &mut Inode,
+        offset: u64,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut pointers = match &inode.data {
+            // Raw data is written again as a whole
+            Data::Raw(raw) => {
+                let mut raw = raw.clone();
+                self.cipher
+                    .decrypt_block(inode.block_index as u64, &mut raw);
+                raw[offset as usize..offset as usize + data.len()]
+                    .copy_from_slice(data);
+                let size = raw.len() as u64;
+                return self.write_inode_data(
+                    inode,
+                    &mut Cursor::new(&raw),
+                    size,
+                    false,
+                );
+            }
+            Data::DirectPointers(pointers) => pointers.clone(),
+        };
+
+        let block_size = self.block_size() as u64;
+        let end = offset + data.len() as u64;
+        let mut unshared = vec![];
+        for n in offset / block_size..end.div_ceil(block_size) {
+            let block_start = n * block_size;
+            let block_len = (inode.size - block_start).min(block_size);
+            let mut buffer = vec![0; block_len as usize];
+
+            let block = block_at(&pointers, n);
+            let target = if block == HOLE {
+                // Holes read as zeros
+                let ranges = self.allocate_blocks(
+                    1,
+                    1,
+                    self.first_data_group(inode),
+                    false,
+                )?;
+                let replaced = replace_block(&pointers, n, ranges[0].0);
+                if replaced.len() > self.max_regions {
+                    self.release_inode_data(ranges)?;
+                    return Err(anyhow!("Too many regions to fill a hole"));
+                }
+                pointers = replaced;
+                ranges[0].0
+            } else {
+                self.read_data_block(block, &mut buffer)?;
+                let target =
+                    self.unshare_block(inode, &mut pointers, n, block)?;
+                if target != block {
+                    unshared.push((block, 1));
+                }
+                target
+            };
+
+            let from = offset.max(block_start);
+            let to = end.min(block_start + block_len);
+            buffer[(from - block_start) as usize..(to - block_start) as usize]
+                .copy_from_slice(
+                    &data[(from - offset) as usize..(to - offset) as usize],
+                );
+            self.write_blocks(
+                &[(target, 1)],
+                &mut Cursor::new(&buffer),
+                block_len,
+                &mut Checksum::new(),
+            )?;
+        }
+
+        inode.data = Data::DirectPointers(pointers);
+        inode.data_checksum = self.read_inode_data_unverified(
+            &mut inode.clone(),
+            &mut std::io::sink(),
+        )?;
+        self.save_inode(inode)?;
+        self.release_inode_data(unshared)
+    }
+
+    fn append_inode<R>(
+        &mut self,
+        inode: &mut Inode,
+        data: &mut R,
+        data_len: u64,
+    ) -> anyhow::Result<()>
+    where
+        R: BufRead,
+    {
+        let old_size = inode.size;
+        let new_size = old_size + data_len;
+
+        let mut pointers = match &inode.data {
+            // Raw data is written again with the appended data
+            Data::Raw(raw) => {
+                let mut raw = raw.clone();
+                self.cipher
+                    .decrypt_block(inode.block_index as u64, &mut raw);
+                let mut data = Cursor::new(raw).chain(data);
+                return self
+                    .write_inode_data(inode, &mut data, new_size, false);
+            }
+            Data::DirectPointers(pointers) => pointers.clone(),
+        };
+
+        // Checksum continues from the current data
+        let mut checksum = Checksum::with_initial(inode.data_checksum);
+        let mut data_left = data_len;
+        let mut unshared = vec![];
+
+        // Fill up a partial last block first
+        let used = (old_size % self.block_size() as u64) as usize;
+        if used > 0 {
+            let fill =
+                data_left.min(self.block_size() as u64 - used as u64) as usize;
+            let mut buffer = vec![0; used + fill];
+            data.read_exact(&mut buffer[used..])?;
+            checksum.update(&buffer[used..]);
+            data_left -= fill as u64;
+
+            let (start, len) = *pointers.last().unwrap();
+            if start == HOLE {
+                // Last block of a hole gets a block of its own
+                if len > 1 && pointers.len() >= self.max_regions {
+                    return Err(anyhow!("Too many regions to append data"));
+                }
+                let ranges = self.allocate_blocks(
+                    1,
+                    1,
+                    self.first_data_group(inode),
+                    false,
+                )?;
+                pointers.pop();
+                if len > 1 {
+                    pointers.push((HOLE, len - 1));
+                }
+                let mut r = Cursor::new(&buffer);
+                let res = self.write_blocks(
+                    &ranges,
+                    &mut r,
+                    buffer.len() as u64,
+                    &mut Checksum::new(),
+                );
+                if let Err(e) = res {
+                    self.release_inode_data(ranges)?;
+                    return Err(e);
+                }
+                push_region(&mut pointers, ranges[0].0, 1);
+            } else {
+                let last = start + len - 1;
+                self.read_data_block(last, &mut buffer[..used])?;
+                let n = old_size / self.block_size() as u64;
+                let target =
+                    self.unshare_block(inode, &mut pointers, n, last)?;
+                if target != last {
+                    unshared.push((last, 1));
+                }
+                self.write_blocks(
+                    &[(target, 1)],
+                    &mut Cursor::new(&buffer),
+                    buffer.len() as u64,
+                    &mut Checksum::new(),
+                )?;
+            }
+        }
+
+        // Rest of the data goes to new blocks
+        if data_left > 0 {
+            if pointers.len() >= self.max_regions {
+                return Err(anyhow!("Too many regions to append data"));
+            }
+            let ranges = self.allocate_blocks(
+                data_left.div_ceil(self.block_size() as u64),
+                self.max_regions.saturating_sub(pointers.len()),
+                self.first_data_group(inode),
+                false,
+            )?;
+            if let Err(e) =
+                self.write_blocks(&ranges, data, data_left, &mut checksum)
+            {
+                self.release_inode_data(ranges)?;
+                return Err(e);
+            }
+            for (start, len) in ranges {
+                push_region(&mut pointers, start, len);
+            }
+        }
+
+        inode.set_direct_pointers(pointers, new_size);
+        inode.data_checksum = checksum.finalize();
+        self.save_inode(inode)?;
+        self.release_inode_data(unshared)
+    }
+
+    /// Rebuild the image with a new group size
+    /// Offline operation: every file is copied into a new image next to
+    /// the old one, which replaces the old image once it is complete.
+    /// On error the old image is left as it was.
+    /// Superblock settings and original paths are kept
+    pub fn reformat_groups(&mut self, blocks_per_group: u32) -> Result<()> {
+        let max = max_blocks_per_group(self.block_size());
+        if !(MIN_BLOCKS_PER_GROUP..=max).contains(&blocks_per_group)
+            || !blocks_per_group.is_multiple_of(8)
+        {
+            return Err(anyhow!(
+                "Blocks per group must be a multiple of 8 between {} and {}",
+                MIN_BLOCKS_PER_GROUP,
+                max
+            )
+            .into());
+        }
+        self.check_modify_access()?;
+
+        // New image is a sibling, so it can be renamed over the old one
+        let sibling = match (&self.file, &self.path) {
+            (Storage::Memory(_), _) => None,
+            (Storage::File(_), Some(path)) => {
+                let file_name = path
+                    .file_name()
+                    .ok_or_else(|| anyhow!("Invalid image path"))?
+                    .to_string_lossy();
+                Some(path.with_file_name(format!(
+                    ".{}.{}.reformat",
+                    file_name,
+                    Uuid::new_v4()
+                )))
+            }
+            (Storage::File(_), None) => {
+                return Err(anyhow!(
+                    "Image path is unknown, open the image by its path"
+                )
+                .into())
+            }
+        };
+        let storage = match &sibling {
+            Some(sibling) => Storage::File(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(sibling)?,
+            ),
+            None => Storage::memory(),
+        };
+
+        // Both images are encrypted by the same cipher
+        let cipher =
+            Rc::new(std::mem::replace(&mut self.cipher, Box::new(NoCipher)));
+        self.cipher = Box::new(SharedCipher(cipher.clone()));
+        let res = self.reformat_into(
+            storage,
+            blocks_per_group,
+            Box::new(SharedCipher(cipher.clone())),
+        );
+        let res = res.and_then(|target| self.replace_image(target, &sibling));
+        self.cipher = Box::new(NoCipher);
+        self.cipher = Rc::try_unwrap(cipher)
+            .expect("reformat handles are dropped by now");
+
+        if res.is_err() {
+            if let Some(sibling) = &sibling {
+                let _ = std::fs::remove_file(sibling);
+            }
+        }
+        Ok(res?)
+    }
+
+    /// Copy every directory and file into a new image
+    /// of blocks_per_group groups
+    fn reformat_into(
+        &self,
+        storage: Storage,
+        blocks_per_group: u32,
+        cipher: Box<dyn Cipher>,
+    ) -> anyhow::Result<Self> {
+        // Step 1: new superblock keeping the image settings
+        let old = &self.superblock;
+        let mut superblock = Superblock::new(self.clock.now());
+        superblock.created = old.created;
+        superblock.label = old.label;
+        superblock.uuid = old.uuid;
+        superblock.reserved_percentage = old.reserved_percentage;
+        superblock.flags = old.flags;
+        superblock.min_free_inodes = old.min_free_inodes;
+        superblock.cipher_id = old.cipher_id;
+        superblock.key_check = old.key_check;
+        superblock.block_size = old.block_size;
+        superblock.blocks_per_group = blocks_per_group;
+        superblock.max_dir_depth = old.max_dir_depth;
+
+        // Timestamps of the copies are the reformat time
+        let clock = Box::new(FixedClock(self.clock.now()));
+        let mut target =
+            Self::format_storage(storage, superblock, cipher, clock)?;
+        // Marking writes needs a saved superblock, so only set it now
+        target.superblock.generation = old.generation;
+        target.allocation = self.allocation;
+        target.alloc_strategy = self.alloc_strategy;
+        target.max_regions = self.max_regions;
+
+        // Step 2: recreate directories, empty ones included
+        let directory_index = self.get_directory_index()?;
+        for dir_path in directory_index.directories().keys() {
+            if !target
+                .get_directory_index()?
+                .directories()
+                .contains_key(dir_path)
+            {
+                target.create_directory(dir_path)?;
+            }
+        }
+        if let Some(quarantine) = self.quarantine_dir()? {
+            target.superblock.quarantine = target.find_directory(quarantine)?.1;
+        }
+
+        // Step 3: copy files, data never leaves the images
+        let mut copied = BTreeMap::new();
+        for (dir_path, dir_inode_index) in directory_index.directories() {
+            let directory = self.read_directory(*dir_inode_index)?;
+            for (file_name, inode_index) in &directory.files {
+                let size = self.get_inode(*inode_index)?.size;
+                let mut r =
+                    BufReader::new(self.open_file(dir_path, file_name)?);
+                target.add_file_force(dir_path, file_name, &mut r, size)?;
+                // Data checksum is verified at the end of the file
+                std::io::copy(&mut r, &mut std::io::sink())?;
+                let (copy, _) = target.find_directory(dir_path)?;
+                if let Some(copy_index) = copy.get_file(file_name) {
+                    copied.insert(*inode_index, copy_index);
+                }
+            }
+
+            // Listings keep their creation order
+            let (mut copy, copy_inode_index) =
+                target.find_directory(dir_path)?;
+            copy.sequence = directory.sequence;
+            copy.sequences = directory.sequences;
+            target.save_directory(copy, copy_inode_index)?;
+        }
+        target.superblock.sequence = self.superblock.sequence;
+
+        // Step 4: original paths follow the new inode indexes
+        let paths: BTreeMap<u32, PathBuf> = self
+            .original_paths()?
+            .into_iter()
+            .filter_map(|(index, path)| Some((*copied.get(&index)?, path)))
+            .collect();
+        target.save_original_paths(paths)?;
+        target.save_config(self.read_config()?)?;
+        target.checkpoint()?;
+
+        Ok(target)
+    }
+
+    /// Take the image of a reformat target
+    /// Sibling image file is renamed over the image path
+    fn replace_image(
+        &mut self,
+        target: Self,
+        sibling: &Option<PathBuf>,
+    ) -> anyhow::Result<()> {
+        match (sibling, &self.path) {
+            (Some(sibling), Some(path)) => {
+                std::fs::rename(sibling, path)?;
+                self.file = target.file;
+            }
+            // Clones of an in memory image share its bytes
+            _ => {
+                self.begin_write()?;
+                let mut r = BufReader::new(&target.file);
+                r.seek(SeekFrom::Start(0))?;
+                self.file.set_len(0)?;
+                let mut w = BufWriter::new(&self.file);
+                w.seek(SeekFrom::Start(0))?;
+                std::io::copy(&mut r, &mut w)?;
+                w.flush()?;
+            }
+        }
+        self.superblock = target.superblock;
+        self.groups = target.groups;
+        self.max_regions = target.max_regions;
+        self.writing.set(false);
+
+        // Cached and pinned blocks belong to the old layout
+        self.pins.clear();
+        let cache_capacity = self.cache_capacity();
+        self.cache = RefCell::new(BlockCache::default());
+        self.set_cache_capacity(cache_capacity);
+        Ok(())
+    }
+
+    /// Space usage of the image in bytes, split by purpose
+    /// Every allocated block that does not hold file data
+    /// is accounted as inode (inode blocks and directory metadata)
+    pub fn space_breakdown(&self) -> Result<SpaceBreakdown> {
+        let directory_index = self.get_directory_index()?;
+
+        // Count blocks referenced by file data pointers
+        let mut file_data_blocks: u64 = 0;
+        for directory_inode_index in directory_index.directories().values() {
+            let directory = self.read_directory(*directory_inode_index)?;
+            for file_inode_index in directory.files.values() {
+                let inode = self.get_inode(*file_inode_index)?;
+                if let Data::DirectPointers(pointers) = &inode.data {
+                    file_data_blocks += pointers
+                        .iter()
+                        .filter(|(start, _)| *start != HOLE)
+                        .map(|(_, range)| *range as u64)
+                        .sum::<u64>();
+                }
+            }
+        }
+
+        let total_blocks: u64 = self
+            .groups
+            .iter()
+            .map(|g| g.total_data_blocks() as u64)
+            .sum();
+        let free_blocks: u64 = self
+            .groups
+            .iter()
+            .map(|g| g.free_data_blocks() as u64)
+            .sum();
+        let allocated_blocks = total_blocks - free_blocks;
+
+        Ok(SpaceBreakdown {
+            superblock: self.block_size() as u64,
+            bitmaps: self.groups.len() as u64 * self.block_size() as u64,
+            inodes: (allocated_blocks - file_data_blocks)
+                * self.block_size() as u64,
+            file_data: file_data_blocks * self.block_size() as u64,
+            free: free_blocks * self.block_size() as u64,
+        })
+    }
+
+    /// Data regions per allocated data block over all files
+    /// 1.0 means every block is its own region, holes are not counted.
+    /// Returns 0.0 if no file has data blocks
+    pub fn fragmentation(&self) -> Result<f32> {
+        let mut regions: u64 = 0;
+        let mut blocks: u64 = 0;
+        for (_, inode) in self.list_files_recursive("")? {
+            if let Data::DirectPointers(pointers) = &inode.data {
+                for (_, range) in pointers.iter().filter(|(s, _)| *s != HOLE) {
+                    regions += 1;
+                    blocks += *range as u64;
+                }
+            }
+        }
+
+        if blocks == 0 {
+            return Ok(0.0);
+        }
+        Ok(regions as f32 / blocks as f32)
+    }
+
+    /// Share of inode blocks placed above a data block of their group
+    /// 0.0 means inodes are at the start of every group
+    pub fn inode_data_interleave(&self) -> Result<f32> {
+        let inode_blocks: std::collections::BTreeSet<u32> = self
+            .inode_owners()?
+            .iter()
+            .map(|(_, inode)| inode.block_index)
+            .collect();
+
+        let blocks_per_group = self.blocks_per_group();
+        let mut above: u32 = 0;
+        for (group_index, group) in self.groups.iter().enumerate() {
+            let first =
+                Group::first_data_block(group_index as u32, blocks_per_group);
+            let mut data_seen = false;
+            for bitmap_index in group.block_bitmap.iter_ones() {
+                if !inode_blocks.contains(&(first + bitmap_index as u32)) {
+                    data_seen = true;
+                } else if data_seen {
+                    above += 1;
+                }
+            }
+        }
+
+        Ok(above as f32 / inode_blocks.len() as f32)
+    }
+
+    /// Move inodes to the start and data to the end of every group
+    /// so free blocks form one run per group for large allocations.
+    /// Offline operation, data keeps its order and file timestamps
+    /// are kept. Returns the number of moved inodes
+    pub fn segregate_inodes(&mut self) -> Result<u32> {
+        self.check_modify_access()?;
+        if !self.pins.is_empty() {
+            return Err(
+                anyhow!("Pinned files have to be unpinned first").into()
+            );
+        }
+
+        let mut inodes = self.inode_owners()?;
+        let original: Vec<u32> =
+            inodes.iter().map(|(_, inode)| inode.block_index).collect();
+        let mut inode_slots: BTreeMap<u32, usize> = original
+            .iter()
+            .enumerate()
+            .map(|(slot, block)| (*block, slot))
+            .collect();
+
+        // Data block moves, every data block moves at most once
+        let mut moves: BTreeMap<u32, u32> = BTreeMap::new();
+        let mut buf = vec![0u8; self.block_size() as usize];
+        let blocks_per_group = self.blocks_per_group();
+        for group_index in 0..self.groups.len() as u32 {
+            let mut group = self.groups[group_index as usize].clone();
+            let first = Group::first_data_block(group_index, blocks_per_group);
+            let end = first + group.total_data_blocks() as u32;
+
+            // Not referenced blocks are moved as data
+            let data: Vec<u32> = group
+                .block_bitmap
+                .iter_ones()
+                .map(|bitmap_index| first + bitmap_index as u32)
+                .filter(|block| !inode_slots.contains_key(block))
+                .collect();
+
+            // Data to the end, highest first, so a target is
+            // either free or an inode swapped down to the source
+            for (source, target) in
+                data.into_iter().rev().zip((first..end).rev())
+            {
+                if source == target {
+                    continue;
+                }
+                self.read_data_block(source, &mut buf)?;
+                self.write_blocks(
+                    &[(target, 1)],
+                    &mut Cursor::new(&buf),
+                    self.block_size() as u64,
+                    &mut Checksum::new(),
+                )?;
+                moves.insert(source, target);
+
+                match inode_slots.remove(&target) {
+                    Some(slot) => {
+                        inodes[slot].1.block_index = source;
+                        inode_slots.insert(source, slot);
+                    }
+                    None => {
+                        group.force_allocate_at(target - first);
+                        group.release_one(source - first);
+                    }
+                }
+            }
+
+            // Inodes to the start, targets below the data are free
+            let group_inodes: Vec<u32> = inode_slots
+                .range(first..end)
+                .map(|(block, _)| *block)
+                .collect();
+            for (source, target) in group_inodes.into_iter().zip(first..) {
+                if source == target {
+                    continue;
+                }
+                if let Some(slot) = inode_slots.remove(&source) {
+                    inodes[slot].1.block_index = target;
+                    inode_slots.insert(target, slot);
+                    group.force_allocate_at(target - first);
+                    group.release_one(source - first);
+                }
+            }
+
+            self.save_group(group, group_index)?;
+        }
+
+        // Save inodes as they are, modification time is kept
+        let mut moved = 0;
+        for (slot, (_, inode)) in inodes.iter_mut().enumerate() {
+            let relocated = inode.block_index != original[slot];
+            let mut changed = relocated;
+            match &mut inode.data {
+                // Raw data is encrypted by its inode block index
+                Data::Raw(raw) if relocated => {
+                    self.cipher.decrypt_block(original[slot] as u64, raw);
+                    self.cipher.encrypt_block(inode.block_index as u64, raw);
+                }
+                Data::Raw(_) => (),
+                Data::DirectPointers(pointers) => {
+                    let pointers_moved = relocate_regions(pointers, &moves);
+                    if pointers_moved != *pointers {
+                        *pointers = pointers_moved;
+                        changed = true;
+                    }
+                }
+            }
+            if changed {
+                self.begin_write()?;
+                let mut w = BufWriter::new(&self.file);
+                w.seek(SeekFrom::Start(block_seek_position(
+                    inode.block_index,
+                    self.block_size(),
+                )))?;
+                inode.serialize_into(w, self.block_size())?;
+            }
+            if relocated {
+                moved += 1;
+            }
+        }
+
+        // Point the index and the directories to the moved inodes
+        let mut directory_index = self.get_directory_index()?;
+        let mut relinked = false;
+        let mut directories: BTreeMap<OsString, Vec<(String, Inode, Inode)>> =
+            BTreeMap::new();
+        let mut inode_moves = BTreeMap::new();
+        for (slot, (owners, inode)) in inodes.into_iter().enumerate() {
+            if inode.block_index == original[slot] {
+                continue;
+            }
+            for owner in owners {
+                match owner {
+                    InodeOwner::Root => {}
+                    InodeOwner::SharedBlocks => {
+                        self.superblock_mut().shared_blocks = inode.block_index
+                    }
+                    InodeOwner::OriginalPaths => {
+                        self.superblock_mut().original_paths = inode.block_index
+                    }
+                    InodeOwner::Config => {
+                        self.superblock_mut().config = inode.block_index
+                    }
+                    InodeOwner::Directory(dir) => {
+                        directory_index.relink_dir(&dir, inode.block_index);
+                        relinked = true;
+                        if self.superblock.quarantine == original[slot] {
+                            self.superblock_mut().quarantine =
+                                inode.block_index;
+                        }
+                    }
+                    InodeOwner::File(dir, file_name) => {
+                        let mut old = inode.clone();
+                        old.block_index = original[slot];
+                        inode_moves.insert(old.block_index, inode.block_index);
+                        directories.entry(dir).or_default().push((
+                            file_name,
+                            old,
+                            inode.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+        if relinked {
+            self.save_directory_index(directory_index)?;
+        }
+
+        // Shared blocks are counted at their new place
+        if !moves.is_empty() && self.superblock.shared_blocks != 0 {
+            let shared = self
+                .shared_blocks()?
+                .into_iter()
+                .map(|(block, count)| {
+                    (moves.get(&block).copied().unwrap_or(block), count)
+                })
+                .collect();
+            self.save_shared_blocks(shared)?;
+        }
+
+        // Original paths follow their file inodes
+        if !inode_moves.is_empty() && self.superblock.original_paths != 0 {
+            let paths = self
+                .original_paths()?
+                .into_iter()
+                .map(|(inode_index, path)| {
+                    let inode_index = inode_moves
+                        .get(&inode_index)
+                        .copied()
+                        .unwrap_or(inode_index);
+                    (inode_index, path)
+                })
+                .collect();
+            self.save_original_paths(paths)?;
+        }
+
+        for (dir, files) in directories {
+            let (mut directory, dir_inode_index) = self.find_directory(&dir)?;
+            let old_hash = directory.hash();
+            for (file_name, old, new) in files {
+                let sequence = directory.sequence(&file_name);
+                directory.remove_file(&file_name, &old)?;
+                directory.add_file(&file_name, &new)?;
+                directory.set_sequence(&file_name, sequence);
+            }
+            self.commit_directory(&dir, directory, dir_inode_index, old_hash)?;
+        }
+
+        self.save_superblock()?;
+        Ok(moved)
+    }
+
+    /// Move data to the front of the image and shrink it
+    /// Data of every inode is copied to the first free run holding
+    /// all its blocks, if that is lower or merges its regions.
+    /// The inode switches to the copy in one block write, then the old
+    /// blocks are freed, an interrupted move leaves blocks fsck reports
+    /// as leaked. Trailing groups without allocated blocks are removed.
+    /// Offline operation, inodes and shared blocks are not moved
+    pub fn compact(&mut self) -> Result<CompactReport> {
+        let opts = MaintenanceOpts {
+            defragment: true,
+            rekey: None,
+            compact: true,
+        };
+        Ok(self.maintenance(opts)?.compact)
+    }
+
+    /// Defragment, rekey and compact in one pass over the data
+    /// Every data block is read once and written once, moved blocks
+    /// are written encrypted by the new secret directly. See compact.
+    /// Rekeyed blocks are written in place or to their moved copy,
+    /// an interrupted rekey leaves an image neither secret can fully
+    /// read, so keep a copy of the image. Built-in ciphers only
+    pub fn maintenance(
+        &mut self,
+        opts: MaintenanceOpts,
+    ) -> Result<MaintenanceReport> {
+        self.check_modify_access()?;
+        if !self.pins.is_empty() {
+            return Err(
+                anyhow!("Pinned files have to be unpinned first").into()
+            );
+        }
+
+        // Metadata is read before any block is rekeyed
+        let shared = self.shared_blocks()?;
+        let mut inodes: Vec<Inode> = self
+            .inode_owners()?
+            .into_iter()
+            .map(|(_, inode)| inode)
+            .collect();
+
+        // Lowest data first, so freed runs are filled from the front
+        inodes.sort_by_key(|inode| inode.data_blocks().first().copied());
+
+        let old_cipher = match &opts.rekey {
+            Some(secret) => {
+                let cipher = cipher_by_id(self.cipher_id(), secret)?;
+                Some(std::mem::replace(&mut self.cipher, cipher))
+            }
+            None => None,
+        };
+
+        let mut report = MaintenanceReport::default();
+        let mut rekeyed_shared = std::collections::BTreeSet::new();
+        for inode in inodes {
+            self.maintain_inode(
+                inode,
+                opts.defragment,
+                old_cipher.as_deref(),
+                &shared,
+                &mut rekeyed_shared,
+                &mut report,
+            )?;
+        }
+        if old_cipher.is_some() {
+            self.superblock.key_check = key_check(self.cipher.as_ref());
+        }
+
+        if opts.compact {
+            report.compact.groups_removed = self.drop_empty_groups()?;
+        }
+        self.save_superblock()?;
+
+        Ok(report)
+    }
+
+    /// Give the empty groups at the end of the image back to the host
+    /// Returns the reclaimed bytes, compact moves data out of them first
+    pub fn shrink(&mut self) -> Result<u64> {
+        self.check_modify_access()?;
+
+        let len = self.file.size()?;
+        self.drop_empty_groups()?;
+        Ok(len.saturating_sub(self.file.size()?))
+    }
+
+    /// Remove trailing groups without allocated blocks, one group stays
+    /// Fails if an inode still references a block of them
+    fn drop_empty_groups(&mut self) -> anyhow::Result<u32> {
+        let keep = self
+            .groups
+            .iter()
+            .rposition(|g| g.block_bitmap.any())
+            .map_or(1, |last| last + 1);
+        let dropped = self.groups.len().saturating_sub(keep) as u32;
+        if dropped == 0 {
+            return Ok(0);
+        }
+
+        let first_dropped =
+            Group::bitmap_block(keep as u32, self.blocks_per_group());
+        for (_, inode) in self.inode_owners()? {
+            let referenced = std::iter::once(inode.block_index)
+                .chain(inode.data_blocks())
+                .find(|block| *block >= first_dropped);
+            if let Some(block) = referenced {
+                return Err(anyhow!(
+                    "Block {} of an empty group is still referenced",
+                    block
+                ));
+            }
+        }
+
+        self.remove_groups_from(keep as u32)?;
+
+        Ok(dropped)
+    }
+
+    /// Move the data of one inode into a single free run and
+    /// encrypt it again, old_cipher is set when rekeying.
+    /// Shared blocks are rekeyed once, they are never moved
+    fn maintain_inode(
+        &mut self,
+        mut inode: Inode,
+        defragment: bool,
+        old_cipher: Option<&dyn Cipher>,
+        shared: &BTreeMap<u32, u32>,
+        rekeyed_shared: &mut std::collections::BTreeSet<u32>,
+        report: &mut MaintenanceReport,
+    ) -> anyhow::Result<()> {
+        let pointers = match &mut inode.data {
+            // Raw data is encrypted by its inode block index
+            Data::Raw(raw) => {
+                if let Some(old_cipher) = old_cipher {
+                    old_cipher.decrypt_block(inode.block_index as u64, raw);
+                    self.cipher.encrypt_block(inode.block_index as u64, raw);
+                    report.bytes_rekeyed += raw.len() as u64;
+                    self.write_inode_as_is(&inode)?;
+                }
+                return Ok(());
+            }
+            Data::DirectPointers(pointers) => pointers.clone(),
+        };
+        let blocks = inode.data_blocks();
+        let moveable = defragment
+            && !blocks.is_empty()
+            && !blocks.iter().any(|b| shared.contains_key(b));
+        let regions = |pointers: &[(u32, u32)]| {
+            pointers.iter().filter(|(start, _)| *start != HOLE).count() as u32
+        };
+        let old_regions = regions(&pointers);
+
+        // Same first fit run allocate_run takes
+        let count = blocks.len() as u32;
+        let start = if moveable {
+            self.free_regions()
+                .into_iter()
+                .find(|(_, len)| *len >= count)
+                .map(|(start, _)| start)
+                .filter(|start| *start < blocks[0] || old_regions > 1)
+        } else {
+            None
+        };
+        if start.is_none() && old_cipher.is_none() {
+            return Ok(());
+        }
+        if let Some(start) = start {
+            if self.allocate_run(count, true)? != Some((start, count)) {
+                return Err(anyhow!(
+                    "Cannot allocate {} blocks at {}",
+                    count,
+                    start
+                ));
+            }
+        }
+
+        // Step 1: copy or rewrite the blocks, holes stay holes
+        let mut new_pointers = vec![];
+        let mut buf = vec![0u8; self.block_size() as usize];
+        let mut offset = 0;
+        let mut target = start;
+        for (block_index, range) in pointers.iter().copied() {
+            if block_index == HOLE {
+                push_region(&mut new_pointers, HOLE, range);
+                offset += range as u64 * self.block_size() as u64;
+                continue;
+            }
+            for block in block_index..block_index + range {
+                let len = inode
+                    .size
+                    .saturating_sub(offset)
+                    .min(self.block_size() as u64);
+                offset += self.block_size() as u64;
+                let buf = &mut buf[..len as usize];
+                if let Some(old_cipher) = old_cipher {
+                    if shared.contains_key(&block)
+                        && !rekeyed_shared.insert(block)
+                    {
+                        continue;
+                    }
+                    self.read_encrypted_block(block, buf)?;
+                    old_cipher.decrypt_block(block as u64, buf);
+                    self.count_read(buf.len());
+                    report.bytes_rekeyed += len;
+                } else {
+                    self.read_data_block(block, buf)?;
+                }
+
+                let written = target.unwrap_or(block);
+                self.write_blocks(
+                    &[(written, 1)],
+                    &mut Cursor::new(&buf),
+                    len,
+                    &mut Checksum::new(),
+                )?;
+                if let Some(t) = target.as_mut() {
+                    push_region(&mut new_pointers, *t, 1);
+                    report.compact.bytes_moved += len;
+                    *t += 1;
+                }
+            }
+        }
+        if start.is_none() {
+            return Ok(());
+        }
+        report.compact.regions_eliminated +=
+            old_regions - regions(&new_pointers);
+
+        // Step 2: switch the inode, timestamps are kept
+        inode.data = Data::DirectPointers(new_pointers);
+        self.write_inode_as_is(&inode)?;
+
+        // Step 3: free the old blocks, none of them is shared
+        self.release_blocks(pointers)
+    }
+
+    /// Write inode without touching its modification time
+    fn write_inode_as_is(&self, inode: &Inode) -> anyhow::Result<()> {
+        self.begin_write()?;
+        let mut w = BufWriter::new(&self.file);
+        w.seek(SeekFrom::Start(block_seek_position(
+            inode.block_index,
+            self.block_size(),
+        )))?;
+        inode.serialize_into(w, self.block_size())?;
+        Ok(())
+    }
+
+    /// Every inode with the entries referencing it
+    /// The root, the directories, the shared block
+    /// references and the original paths are inodes too
+    fn inode_owners(&self) -> anyhow::Result<Vec<(Vec<InodeOwner>, Inode)>> {
+        let directory_index = self.get_directory_index()?;
+
+        let mut owners: BTreeMap<u32, Vec<InodeOwner>> = BTreeMap::new();
+        owners.insert(ROOT_INODE_INDEX, vec![InodeOwner::Root]);
+        if self.superblock.shared_blocks != 0 {
+            owners
+                .entry(self.superblock.shared_blocks)
+                .or_default()
+                .push(InodeOwner::SharedBlocks);
+        }
+        if self.superblock.original_paths != 0 {
+            owners
+                .entry(self.superblock.original_paths)
+                .or_default()
+                .push(InodeOwner::OriginalPaths);
+        }
+        if self.superblock.config != 0 {
+            owners
+                .entry(self.superblock.config)
+                .or_default()
+                .push(InodeOwner::Config);
+        }
+        for (dir, dir_inode_index) in directory_index.directories() {
+            owners
+                .entry(*dir_inode_index)
+                .or_default()
+                .push(InodeOwner::Directory(dir.clone()));
+            let directory = self.read_directory(*dir_inode_index)?;
+            for (file_name, file_inode_index) in directory.files {
+                owners
+                    .entry(file_inode_index)
+                    .or_default()
+                    .push(InodeOwner::File(dir.clone(), file_name));
+            }
+        }
+
+        owners
+            .into_iter()
+            .map(|(inode_index, owners)| {
+                Ok((owners, self.get_inode(inode_index)?))
+            })
+            .collect()
+    }
+
+    /// Free block count stored in the superblock
+    /// and the one computed from the group bitmaps
+    /// Returns (stored, computed), they differ on drift
+    pub fn audit_free_space(&self) -> (u32, u32) {
+        (self.superblock.free_blocks, self.free_blocks())
+    }
+
+    /// Block is inside a group and not allocated
+    fn is_block_free(&self, block_index: u32) -> bool {
+        let blocks_per_group = self.blocks_per_group();
+        if block_index == 0 {
+            return false;
+        }
+        // Superblock and group bitmaps are never free
+        let group_index = Group::group_of_block(block_index, blocks_per_group);
+        let first_data_block =
+            Group::first_data_block(group_index, blocks_per_group);
+        if block_index < first_data_block {
+            return false;
+        }
+        let bitmap_index = (block_index - first_data_block) as usize;
+        self.groups.get(group_index as usize).is_some_and(|g| {
+            g.block_bitmap.get(bitmap_index).is_some_and(|b| !*b)
+        })
+    }
+
+    /// Free blocks computed from the group bitmaps
+    #[inline]
+    fn free_blocks(&self) -> u32 {
+        self.groups
+            .iter()
+            .map(|g| g.free_data_blocks() as u32)
+            .sum()
+    }
+
+    /// Percentage of blocks reserved for privileged writes
+    pub fn reserved_percentage(&self) -> u8 {
+        self.superblock.reserved_percentage
+    }
+
+    /// Set reserved space percentage, maximum 50
+    pub fn set_reserved_percentage(&mut self, percentage: u8) -> Result<()> {
+        if percentage > MAX_RESERVED_PERCENTAGE {
+            return Err(anyhow!(
+                "Reserved percentage must be at most {}",
+                MAX_RESERVED_PERCENTAGE
+            )
+            .into());
+        }
+        self.superblock.reserved_percentage = percentage;
+        Ok(self.save_superblock()?)
+    }
+
+    /// Blocks kept free for privileged writes
+    pub fn reserved_blocks(&self) -> u32 {
+        let total_blocks: u64 = self
+            .groups
+            .iter()
+            .map(|g| g.total_data_blocks() as u64)
+            .sum();
+        (total_blocks * self.superblock.reserved_percentage as u64 / 100) as u32
+    }
+
+    /// Inodes allocated in the image
+    pub fn inode_count(&self) -> u32 {
+        self.superblock.inode_counts.iter().sum()
+    }
+
+    /// Data blocks per group
+    pub fn blocks_per_group(&self) -> u32 {
+        self.superblock.blocks_per_group
+    }
+
+    /// Bytes per block, chosen at init
+    pub fn block_size(&self) -> u32 {
+        self.superblock.block_size
+    }
+
+    /// Raw data bytes stored in the inode block itself
+    fn inode_capacity(&self) -> usize {
+        inode_capacity(self.block_size())
+    }
+
+    /// Inode slots per group
+    pub fn inodes_per_group(&self) -> u32 {
+        self.blocks_per_group() / BLOCKS_PER_INODE
+    }
+
+    /// Inodes the group can still hold
+    /// Limited by its inode slots and its free blocks
+    pub fn group_free_inodes(&self, group_index: u32) -> u32 {
+        let used = self.superblock.inode_counts[group_index as usize];
+        let free_blocks = self.groups[group_index as usize].free_data_blocks();
+        self.inodes_per_group()
+            .saturating_sub(used)
+            .min(free_blocks as u32)
+    }
+
+    /// Inodes the image can still hold
+    pub fn free_inodes(&self) -> u32 {
+        (0..self.groups.len() as u32)
+            .map(|g| self.group_free_inodes(g))
+            .sum()
+    }
+
+    /// Upper bound of files the image can hold without growing
+    /// Assumes every remaining block usable by normal writes
+    /// becomes a file inode, limited by the free inode slots
+    pub fn max_files(&self) -> u32 {
+        let inodes = self
+            .free_inodes()
+            .saturating_sub(self.superblock.min_free_inodes);
+        self.superblock.file_count + inodes.min(self.available_blocks(false))
+    }
+
+    /// Hand out the next creation sequence number
+    /// Saved with the superblock by the calling mutation
+    fn next_sequence(&mut self) -> u64 {
+        self.superblock.sequence += 1;
+        self.superblock.sequence
+    }
+
+    /// Most groups the image can grow to
+    /// Limited by the per group fields of the superblock
+    pub fn max_groups(&self) -> u32 {
+        max_groups(self.block_size())
+    }
+
+    /// Free inodes reserved for privileged allocations
+    pub fn min_free_inodes(&self) -> u32 {
+        self.superblock.min_free_inodes
+    }
+
+    /// Set free inodes reserved for privileged allocations
+    pub fn set_min_free_inodes(&mut self, count: u32) -> Result<()> {
+        self.superblock.min_free_inodes = count;
+        Ok(self.save_superblock()?)
+    }
+
+    pub fn max_directory_depth(&self) -> u32 {
+        self.superblock.max_dir_depth
+    }
+
+    /// Limit the path components of new directories, 0 is unlimited
+    /// Existing deeper directories are kept
+    pub fn set_max_directory_depth(&mut self, depth: u32) -> Result<()> {
+        self.superblock.max_dir_depth = depth;
+        Ok(self.save_superblock()?)
+    }
+
+    /// Free blocks a write can allocate
+    /// Normal writes cannot use the reserved blocks
+    #[inline]
+    fn available_blocks(&self, force: bool) -> u32 {
+        if force {
+            self.free_blocks()
+        } else {
+            self.free_blocks().saturating_sub(self.reserved_blocks())
+        }
+    }
+
+    #[inline]
+    fn superblock_check(&mut self) {
+        // Set group count
+        self.superblock.group_count = self.groups.len() as u32;
+        // Set free blocks
+        self.superblock.free_blocks = self
+            .groups
+            .iter()
+            .map(|g| g.block_bitmap.count_zeros() as u32)
+            .sum();
+        // Set block count
+        self.superblock.block_count = self
+            .groups
+            .iter()
+            .map(|g| g.total_data_blocks() as u32)
+            .sum();
+        // Set group bitmap checksums
+        self.superblock.group_checksums =
+            self.groups.iter().map(Group::checksum).collect();
+        // Set last modified time
+        self.superblock.modified = self.clock.now();
+        // Set checksum
+        self.superblock.checksum();
+    }
+
+    #[inline]
+    fn save_superblock(&mut self) -> anyhow::Result<()> {
+        // Mutations save the superblock as their last step,
+        // the next even generation ends the write, see begin_write
+        self.superblock.generation = (self.superblock.generation | 1) + 1;
+
+        // Create superblock checks
+        self.superblock_check();
+
+        // Per group fields grow with the image, small blocks fill first
+        let data = self.superblock.encode();
+        if data.len() > self.block_size() as usize {
+            return Err(anyhow!(
+                "Superblock of {} groups does not fit in a {} bytes block",
+                self.groups.len(),
+                self.block_size()
+            ));
+        }
+
+        let mut w = BufWriter::new(&self.file);
+        w.seek(SeekFrom::Start(0))?;
+        w.write_all(&data)?;
+        w.flush()?;
+        self.writing.set(false);
+        Ok(())
+    }
+
+    /// Mark the stored superblock as being written until the next save:
+    /// its generation is odd meanwhile, so readers wait, see FsReader
+    fn begin_write(&self) -> anyhow::Result<()> {
+        // Nothing to mark before the first save or while marked
+        if self.writing.get() || self.superblock.generation == 0 {
+            return Ok(());
+        }
+        let mut r = BufReader::new(&self.file);
+        r.seek(SeekFrom::Start(0))?;
+        let mut superblock = Superblock::deserialize_from(r)?;
+        superblock.generation |= 1;
+        superblock.checksum();
+
+        let mut w = BufWriter::new(&self.file);
+        w.seek(SeekFrom::Start(0))?;
+        w.write_all(&superblock.encode())?;
+        w.flush()?;
+        self.writing.set(true);
+        Ok(())
+    }
+
+    /// Check inode index points to a data block inside the image
+    #[inline]
+    fn validate_inode_index(
+        &self,
+        inode_block_index: u32,
+    ) -> anyhow::Result<()> {
+        check_inode_index(
+            inode_block_index,
+            self.blocks_per_group(),
+            self.groups.len() as u32,
+        )
+    }
+
+    #[inline]
+    fn get_inode(&self, inode_block_index: u32) -> anyhow::Result<Inode> {
+        self.validate_inode_index(inode_block_index)?;
+
+        let mut r = BufReader::new(&self.file);
+
+        r.seek(SeekFrom::Start(block_seek_position(
+            inode_block_index,
+            self.block_size(),
+        )))?;
+
+        // Deserialize by bincode
+        let inode: Inode = Inode::deserialize_from(r)?;
+
+        // Return inode
+        Ok(inode)
+    }
+
+    #[inline]
+    fn save_inode(&mut self, inode: &mut Inode) -> anyhow::Result<()> {
+        self.validate_inode_index(inode.block_index)?;
+        self.begin_write()?;
+
+        let mut w = BufWriter::new(&self.file);
+
+        w.seek(SeekFrom::Start(block_seek_position(
+            inode.block_index,
+            self.block_size(),
+        )))?;
+        inode.set_last_modified(self.clock.now());
+        inode.serialize_into(w, self.block_size())?;
+        Ok(())
+    }
+
+    #[inline]
+    fn save_group(
+        &mut self,
+        group: Group,
+        group_index: u32,
+    ) -> anyhow::Result<()> {
+        // Update group at FS
+        self.groups[group_index as usize] = group.clone();
+
+        // Write group to disk
+        self.begin_write()?;
+        let mut w = BufWriter::new(&self.file);
+
+        w.seek(SeekFrom::Start(Group::seek_position(
+            group_index,
+            self.blocks_per_group(),
+            self.block_size(),
+        )))?;
+        group.serialize_into(w)?;
+        Ok(())
+    }
+
+    /// Read inode data and verify its checksum
+    #[inline]
+    fn read_inode_data<W>(
+        &self,
+        inode: &mut Inode,
+        w: &mut W,
+    ) -> anyhow::Result<u32>
+    where
+        W: Write,
+    {
+        // Detect corrupted blocks
+        let checksum = self.read_inode_data_unverified(inode, w)?;
+        if checksum != inode.data_checksum {
+            return Err(WalnutError::ChecksumMismatch.into());
+        }
+
+        Ok(checksum)
+    }
+
+    /// Read inode data, returns its computed checksum
+    #[inline]
+    fn read_inode_data_unverified<W>(
+        &self,
+        inode: &mut Inode,
+        w: &mut W,
+    ) -> anyhow::Result<u32>
+    where
+        W: Write,
+    {
+        self.check_data_access()?;
+
+        let mut checksum = Checksum::new();
+        let mut r = BufReader::new(&self.file);
+
+        match &mut inode.data {
+            Data::Raw(data) => {
+                // Decrypt raw data
+                self.cipher.decrypt_block(inode.block_index as u64, data);
+
+                // Update checksum
+                checksum.update(data);
+
+                // Write data into writer
+                w.write_all(data)?;
+            }
+            Data::DirectPointers(pointers) => {
+                // Counting data left to read
+                let mut data_left = inode.size;
+
+                if let Some(throttle) = &self.throttle {
+                    throttle.begin();
+                }
+
+                let mut block_buffer = vec![0u8; self.block_size() as usize];
+
+                let mut cache = self.cache.borrow_mut();
+
+                for (block_index, range) in pointers {
+                    // Holes read as zeros
+                    if *block_index == HOLE {
+                        for _ in 0..*range {
+                            let len = data_left.min(self.block_size() as u64);
+                            let zeros = vec![0; len as usize];
+                            checksum.update(&zeros);
+                            w.write_all(&zeros)?;
+                            data_left -= len;
+                        }
+                        continue;
+                    }
+
+                    // Seek start position
+                    r.seek(SeekFrom::Start(block_seek_position(
+                        *block_index,
+                        self.block_size(),
+                    )))?;
+                    let mut seek_needed = false;
+
+                    for block in *block_index..(*block_index + *range) {
+                        // Determine if last block
+                        if data_left < self.block_size() as u64 {
+                            block_buffer.truncate(data_left as usize);
+                        };
+
+                        if cache.get(block, &mut block_buffer) {
+                            // Disk position is behind the cached block
+                            seek_needed = true;
+                        } else {
+                            if seek_needed {
+                                r.seek(SeekFrom::Start(block_seek_position(
+                                    block,
+                                    self.block_size(),
+                                )))?;
+                                seek_needed = false;
+                            }
+
+                            // Read range bytes
+                            read_exact_at(
+                                &mut r,
+                                block_seek_position(block, self.block_size()),
+                                &mut block_buffer,
+                                &self.retry,
+                            )?;
+                            cache.insert(block, &block_buffer);
+
+                            if let Some(throttle) = &self.throttle {
+                                throttle.consume(block_buffer.len() as u64);
+                            }
+                        }
+
+                        // Decrypt chunk
+                        self.cipher
+                            .decrypt_block(block as u64, &mut block_buffer);
+                        self.count_read(block_buffer.len());
+
+                        // Update checksum
+                        checksum.update(&block_buffer);
+
+                        // Write buffer to writer
+                        w.write_all(&block_buffer)?;
+                        // std::io::copy(&mut BufReader::new(Cursor::new(&block_buffer)), &mut w)?;
+
+                        // Decrease data_left
+                        data_left -= block_buffer.len() as u64;
+                    }
+                }
+            }
+        }
+
+        Ok(checksum.finalize())
+    }
+
+    /// Account data block bytes read by this handle
+    #[inline]
+    fn count_read(&self, bytes: usize) {
+        self.bytes_read.set(self.bytes_read.get() + bytes as u64);
+    }
+
+    /// Read one data block into buf and decrypt it
+    /// buf is at most BLOCK_SIZE long, the last block can be shorter
+    fn read_data_block(
+        &self,
+        block: u32,
+        buf: &mut [u8],
+    ) -> anyhow::Result<()> {
+        self.read_encrypted_block(block, buf)?;
+        self.cipher.decrypt_block(block as u64, buf);
+        self.count_read(buf.len());
+
+        Ok(())
+    }
+
+    /// Read one data block into buf as it is stored
+    fn read_encrypted_block(
+        &self,
+        block: u32,
+        buf: &mut [u8],
+    ) -> anyhow::Result<()> {
+        if !self.cache.borrow_mut().get(block, buf) {
+            let pos = block_seek_position(block, self.block_size());
+            let mut r = &self.file;
+            r.seek(SeekFrom::Start(pos))?;
+            read_exact_at(&mut r, pos, buf, &self.retry)?;
+            self.cache.borrow_mut().insert(block, buf);
+
+            if let Some(throttle) = &self.throttle {
+                throttle.consume(buf.len() as u64);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn write_inode_data<R>(
+        &mut self,
+        inode: &mut Inode,
+        data: &mut R,
+        data_len: u64,
+        force: bool,
+    ) -> anyhow::Result<()>
+    where
+        R: BufRead,
+    {
+        self.check_data_access()?;
+
+        // Old data is released only after the inode points to the new one
+        // so an interrupted write leaves the previous content intact
+        let old_pointers = match &inode.data {
+            Data::Raw(_) => None,
+            Data::DirectPointers(pointers) => Some(pointers.clone()),
+        };
+
+        // If data length fits inside inode
+        if data_len as usize <= self.inode_capacity() {
+            // Create buffer
+            let mut buffer = vec![];
+
+            // and read data into it
+            data.read_to_end(&mut buffer)?;
+
+            // Checksum of plain data
+            let mut checksum = Checksum::new();
+            checksum.update(&buffer);
+            inode.data_checksum = checksum.finalize();
+
+            // Encrypt buffer
+            self.cipher
+                .encrypt_block(inode.block_index as u64, &mut buffer);
+
+            // Create reader from buffer
+            let mut data = Cursor::new(&buffer);
+
+            // Set data inside inode
+            inode.set_raw_data(&mut data, data_len, self.inode_capacity())?;
+
+            // Save inode
+            self.save_inode(inode)?;
+
+            // Release old data
+            if let Some(pointers) = old_pointers {
+                self.release_inode_data(pointers)?;
+            }
+
+            // Return ok
+            return Ok(());
+        }
+
+        // If data does not fit inside Inode as raw data
+
+        // Define block_to_allocate
+        let blocks_to_allocate = |data_size| {
+            data_size / self.block_size() as u64
+                + u64::from(data_size % self.block_size() as u64 != 0)
+        };
+
+        // Allocate blocks for the whole file
+        let ranges = self.allocate_blocks(
+            blocks_to_allocate(data_len),
+            self.max_regions,
+            self.first_data_group(inode),
+            force,
+        )?;
+
+        // Write data into ranges
+        // release them if data cannot be written
+        let mut checksum = Checksum::new();
+        if let Err(e) =
+            self.write_blocks(&ranges, data, data_len, &mut checksum)
+        {
+            self.release_inode_data(ranges)?;
+            return Err(e);
+        }
+
+        // Save ranges and checksum once data is on disk
+        inode.set_direct_pointers(ranges, data_len);
+        inode.data_checksum = checksum.finalize();
+        self.save_inode(inode)?;
+
+        // Release old data
+        if let Some(pointers) = old_pointers {
+            self.release_inode_data(pointers)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write inode data of unknown length
+    /// Data is read in chunks of STREAM_CHUNK_BLOCKS blocks,
+    /// checksum is computed in the same pass.
+    /// Returns (bytes written, checksum)
+    fn write_inode_data_streaming<R>(
+        &mut self,
+        inode: &mut Inode,
+        data: &mut R,
+        force: bool,
+    ) -> anyhow::Result<(u64, u32)>
+    where
+        R: Read,
+    {
+        self.check_data_access()?;
+
+        // Read one byte more than fits inside the inode
+        let mut buffer = vec![];
+        data.take(self.inode_capacity() as u64 + 1)
+            .read_to_end(&mut buffer)?;
+
+        // Small data is stored as raw data
+        if buffer.len() <= self.inode_capacity() {
+            let len = buffer.len() as u64;
+            self.write_inode_data(
+                inode,
+                &mut Cursor::new(&buffer),
+                len,
+                force,
+            )?;
+            return Ok((len, inode.data_checksum));
+        }
+
+        let old_pointers = match &inode.data {
+            Data::Raw(_) => None,
+            Data::DirectPointers(pointers) => Some(pointers.clone()),
+        };
+
+        let chunk_size = STREAM_CHUNK_BLOCKS * self.block_size() as u64;
+        let mut ranges: Vec<(u32, u32)> = vec![];
+        let mut checksum = Checksum::new();
+        let mut data_len = 0;
+
+        let res = (|| -> anyhow::Result<()> {
+            loop {
+                // Fill chunk buffer
+                let missing = chunk_size - buffer.len() as u64;
+                data.take(missing).read_to_end(&mut buffer)?;
+                if buffer.is_empty() {
+                    return Ok(());
+                }
+
+                let blocks =
+                    (buffer.len() as u64).div_ceil(self.block_size() as u64);
+                let new_ranges = self.allocate_blocks(
+                    blocks,
+                    self.max_regions.saturating_sub(ranges.len()),
+                    self.first_data_group(inode),
+                    force,
+                )?;
+
+                // Keep allocated ranges to release them on error
+                merge_ranges(&mut ranges, &new_ranges);
+
+                self.write_blocks(
+                    &new_ranges,
+                    &mut Cursor::new(&buffer),
+                    buffer.len() as u64,
+                    &mut checksum,
+                )?;
+                data_len += buffer.len() as u64;
+
+                buffer.clear();
+            }
+        })();
+
+        if let Err(e) = res {
+            self.release_inode_data(ranges)?;
+            return Err(e);
+        }
+
+        // Save ranges and checksum once data is on disk
+        let checksum = checksum.finalize();
+        inode.set_direct_pointers(ranges, data_len);
+        inode.data_checksum = checksum;
+        self.save_inode(inode)?;
+
+        // Release old data
+        if let Some(pointers) = old_pointers {
+            self.release_inode_data(pointers)?;
+        }
+
+        Ok((data_len, checksum))
+    }
+
+    /// Group the data allocation of the inode starts in
+    /// Colocate mode keeps data next to its inode
+    #[inline]
+    fn first_data_group(&self, inode: &Inode) -> u32 {
+        match self.allocation {
+            AllocationMode::FirstFit => 0,
+            AllocationMode::Colocate => Group::group_of_block(
+                inode.block_index,
+                self.blocks_per_group(),
+            ),
+        }
+    }
+
+    /// Allocate data blocks in at most max_regions regions
+    /// Groups are visited from first_group, wrapping around.
+    /// Grows the image when needed, also when free space
+    /// is too fragmented for max_regions regions.
+    /// Groups added for a failed allocation are dropped again
+    fn allocate_blocks(
+        &mut self,
+        count: u64,
+        max_regions: usize,
+        first_group: u32,
+        force: bool,
+    ) -> anyhow::Result<Vec<(u32, u32)>> {
+        let group_count = self.groups.len() as u32;
+        let res = self.allocate_blocks_growing(
+            count,
+            max_regions,
+            first_group,
+            force,
+        );
+        if res.is_err() && self.groups.len() as u32 > group_count {
+            let _ = self.remove_groups_from(group_count);
+        }
+        res
+    }
+
+    fn allocate_blocks_growing(
+        &mut self,
+        count: u64,
+        max_regions: usize,
+        first_group: u32,
+        force: bool,
+    ) -> anyhow::Result<Vec<(u32, u32)>> {
+        // Check if we have enough space for file
+        // normal writes leave the reserved blocks untouched
+        while (self.available_blocks(force) as u64) < count {
+            // Add new group
+            self.add_group(Group::init(self.blocks_per_group()))?;
+        }
+
+        let group_count = self.groups.len();
+        let first_group = (first_group as usize).min(group_count - 1);
+
+        let (ranges, block_to_allocate) = match self.alloc_strategy {
+            AllocStrategy::Greedy => {
+                self.allocate_greedy(count, max_regions, first_group)?
+            }
+            AllocStrategy::BestFit => {
+                self.allocate_best_fit(count, max_regions, first_group)?
+            }
+        };
+
+        if block_to_allocate > 0 {
+            self.release_inode_data(ranges)?;
+            return self.allocate_in_new_groups(count, max_regions);
+        }
+
+        Ok(ranges)
+    }
+
+    /// Take the first free blocks of the groups
+    /// Returns the regions and the blocks left to allocate
+    fn allocate_greedy(
+        &mut self,
+        count: u64,
+        max_regions: usize,
+        first_group: usize,
+    ) -> anyhow::Result<(Vec<(u32, u32)>, u64)> {
+        let mut block_to_allocate = count;
+
+        // Define empty ranges
+        let mut ranges: Vec<(u32, u32)> = vec![];
+
+        let group_count = self.groups.len();
+        for group_index in (first_group..group_count).chain(0..first_group) {
+            // Check if we need any blocks?
+            if block_to_allocate > 0 && ranges.len() < max_regions {
+                let mut group = self.groups[group_index].clone();
+
+                // Allocate regions from group
+                let (mut range, left) = group.allocate_region(
+                    group_index as u32,
+                    block_to_allocate as usize,
+                    max_regions - ranges.len(),
+                );
+
+                // Save group
+                self.save_group(group, group_index as u32)?;
+
+                ranges.append(&mut range);
+
+                // Decrease block wanted
+                block_to_allocate = left as u64;
+            }
+        }
+
+        Ok((ranges, block_to_allocate))
+    }
+
+    /// Take the smallest free run holding all blocks left,
+    /// or the largest run when none of them does
+    /// Returns the regions and the blocks left to allocate
+    fn allocate_best_fit(
+        &mut self,
+        count: u64,
+        max_regions: usize,
+        first_group: usize,
+    ) -> anyhow::Result<(Vec<(u32, u32)>, u64)> {
+        let blocks_per_group = self.blocks_per_group();
+        let group_count = self.groups.len();
+
+        // Free runs as (group index, bitmap index, length)
+        let mut runs: Vec<(usize, u32, u32)> = (first_group..group_count)
+            .chain(0..first_group)
+            .flat_map(|group_index| {
+                self.groups[group_index]
+                    .free_runs()
+                    .into_iter()
+                    .map(move |(start, len)| (group_index, start, len))
+            })
+            .collect();
+
+        let mut groups: BTreeMap<usize, Group> = BTreeMap::new();
+        let mut ranges: Vec<(u32, u32)> = vec![];
+        let mut block_to_allocate = count;
+        while block_to_allocate > 0 && ranges.len() < max_regions {
+            let fitting = runs
+                .iter()
+                .enumerate()
+                .filter(|(_, run)| run.2 as u64 >= block_to_allocate)
+                .min_by_key(|(_, run)| run.2);
+            let largest = || {
+                runs.iter()
+                    .enumerate()
+                    .min_by_key(|(_, run)| std::cmp::Reverse(run.2))
+            };
+            let Some((run_index, _)) = fitting.or_else(largest) else {
+                break;
+            };
+
+            let (group_index, start, len) = &mut runs[run_index];
+            let take = (*len as u64).min(block_to_allocate) as u32;
+            groups
+                .entry(*group_index)
+                .or_insert_with(|| self.groups[*group_index].clone())
+                .block_bitmap[*start as usize..(*start + take) as usize]
+                .fill(true);
+            merge_ranges(
+                &mut ranges,
+                &[(
+                    Group::create_public_address(
+                        *group_index as u32,
+                        *start,
+                        blocks_per_group,
+                    ),
+                    take,
+                )],
+            );
+            *start += take;
+            *len -= take;
+            if *len == 0 {
+                runs.remove(run_index);
+            }
+            block_to_allocate -= take as u64;
+        }
+
+        for (group_index, group) in groups {
+            self.save_group(group, group_index as u32)?;
+        }
+
+        Ok((ranges, block_to_allocate))
+    }
+
+    /// Allocate data blocks in groups added for them
+    /// Every new group holds one region
+    fn allocate_in_new_groups(
+        &mut self,
+        count: u64,
+        max_regions: usize,
+    ) -> anyhow::Result<Vec<(u32, u32)>> {
+        let blocks_per_group = self.blocks_per_group();
+        let group_count = count.div_ceil(blocks_per_group as u64);
+        if group_count > max_regions as u64 {
+            return Err(anyhow!("Free space is too fragmented"));
+        }
+        // Superblock has to hold every new group
+        if self.groups.len() as u64 + group_count > self.max_groups() as u64 {
+            return Err(WalnutError::OutOfSpace.into());
+        }
+
+        let first_group = self.groups.len() as u32;
+        for _ in 0..group_count {
+            self.add_group(Group::init(blocks_per_group))?;
+        }
+
+        let mut ranges = vec![];
+        let mut block_to_allocate = count as usize;
+        for group_index in first_group..self.groups.len() as u32 {
+            let mut group = self.groups[group_index as usize].clone();
+            let (mut range, left) =
+                group.allocate_region(group_index, block_to_allocate, 1);
+            self.save_group(group, group_index)?;
+            ranges.append(&mut range);
+            block_to_allocate = left;
+        }
+
+        Ok(ranges)
+    }
+
+    /// Write data into allocated ranges
+    /// Checksum is updated with the plain data
+    #[inline]
+    fn write_blocks<R>(
+        &self,
+        ranges: &[(u32, u32)],
+        data: &mut R,
+        data_len: u64,
+        checksum: &mut Checksum,
+    ) -> anyhow::Result<()>
+    where
+        R: BufRead,
+    {
+        let mut data_left = data_len;
+
+        self.begin_write()?;
+        let mut w = BufWriter::new(&self.file);
+
+        if let Some(throttle) = &self.throttle {
+            throttle.begin();
+        }
+
+        let mut block_buffer = vec![0u8; self.block_size() as usize];
+
+        for (block_index, range) in ranges.iter().copied() {
+            // Seek position
+            w.seek(SeekFrom::Start(block_seek_position(
+                block_index,
+                self.block_size(),
+            )))?;
+
+            // Iter over rage
+            for block in block_index..(block_index + range) {
+                // Determine if last block
+                if data_left < self.block_size() as u64 {
+                    block_buffer.truncate(data_left as usize);
+                };
+
+                // Read data into chunk buffer
+                data.read_exact(&mut block_buffer)?;
+
+                // Update checksum of plain data
+                checksum.update(&block_buffer);
+
+                // Encrypt chunk
+                self.cipher.encrypt_block(block as u64, &mut block_buffer);
+
+                // Write chunk buffer to disk
+                write_all_at(
+                    &mut w,
+                    block_seek_position(block, self.block_size()),
+                    &block_buffer,
+                    &self.retry,
+                )?;
+                self.cache.borrow_mut().update(block, &block_buffer);
+                self.bytes_written
+                    .set(self.bytes_written.get() + block_buffer.len() as u64);
+
+                if let Some(throttle) = &self.throttle {
+                    throttle.consume(block_buffer.len() as u64);
+                }
+
+                // Decrease data left
+                data_left -= block_buffer.len() as u64;
+            }
+        }
+
+        // Check all data has written
+        if data_left != 0 {
+            return Err(anyhow!("Allocated blocks are too short for the data"));
+        }
+
+        // Flush disk
+        self.retry.run(|_| w.flush())?;
+
+        Ok(())
+    }
+
+    #[inline]
+    fn truncate(&mut self) -> anyhow::Result<()> {
+        // Superblock + GroupCount * (Group bitmap + group data inodes)
+        // ends where the bitmap of the next group would start
+        let size = block_seek_position(
+            Group::bitmap_block(
+                self.groups.len() as u32,
+                self.blocks_per_group(),
+            ),
+            self.block_size(),
+        );
+        // Set file size, the saved superblock uses no block past it
+        self.file.set_len(size)?;
+        // Return ok
+        Ok(())
+    }
+
+    /// Allocate the inode of a new file
+    /// Colocate mode puts it in the first group with room
+    /// for the inode and its data_len bytes of data
+    fn allocate_file_inode(
+        &mut self,
+        data_len: Option<u64>,
+        force: bool,
+    ) -> anyhow::Result<Option<Inode>> {
+        let blocks = match (self.allocation, data_len) {
+            (AllocationMode::Colocate, Some(len))
+                if len as usize > self.inode_capacity() =>
+            {
+                len.div_ceil(self.block_size() as u64)
+            }
+            _ => return self.allocate_inode(force),
+        };
+
+        let group_index = (0..self.groups.len() as u32).find(|g| {
+            self.group_free_inodes(*g) > 0
+                && self.groups[*g as usize].free_data_blocks() as u64 > blocks
+        });
+        match group_index {
+            Some(group_index) => self.allocate_inode_in(group_index, force),
+            // Data does not fit into any single group
+            None => self.allocate_inode(force),
+        }
+    }
+
+    #[inline]
+    fn allocate_inode(&mut self, force: bool) -> anyhow::Result<Option<Inode>> {
+        self.allocate_inode_in(0, force)
+    }
+
+    /// Allocate inode, trying first_group before the others
+    fn allocate_inode_in(
+        &mut self,
+        first_group: u32,
+        force: bool,
+    ) -> anyhow::Result<Option<Inode>> {
+        // Normal allocations cannot use the reserved blocks
+        if self.available_blocks(force) == 0 {
+            return Ok(None);
+        }
+
+        // Every group ran out of inode slots
+        if self.free_inodes() == 0 {
+            self.add_group(Group::init(self.blocks_per_group()))?;
+        }
+
+        // Normal allocations cannot use the reserved inodes
+        if !force && self.free_inodes() <= self.superblock.min_free_inodes {
+            return Ok(None);
+        }
+
+        let first_group = first_group as usize;
+        let mut res = None;
+        let others = (0..self.groups.len()).filter(|g| *g != first_group);
+        for group_index in std::iter::once(first_group).chain(others) {
+            if self.group_free_inodes(group_index as u32) == 0 {
+                continue;
+            }
+            let old = self.groups[group_index].clone();
+            if let Some(inode_block_index) =
+                self.groups[group_index].allocate_one(group_index as u32)
+            {
+                let inode =
+                    Inode::with_time(inode_block_index, self.clock.now());
+                res = Some((group_index as u32, old, inode));
+                break;
+            }
+        }
+        let (group_index, old, mut inode) = match res {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+
+        // Persist allocation, otherwise it is lost on reopen
+        let group = self.groups[group_index as usize].clone();
+        self.superblock.inode_counts[group_index as usize] += 1;
+        let res = self
+            .save_group(group, group_index)
+            .and_then(|_| self.save_inode(&mut inode))
+            .and_then(|_| self.save_superblock());
+        if let Err(e) = res {
+            // Undo the allocation so the bitmap matches the inode count
+            self.superblock.inode_counts[group_index as usize] -= 1;
+            self.groups[group_index as usize] = old.clone();
+            let _ = self.save_group(old, group_index);
+            return Err(e);
+        }
+        Ok(Some(inode))
+    }
+
+    /// Reserve a single run of free blocks, e.g. as scratch space
+    /// Runs cannot span groups and the image is not grown.
+    /// Returns (start block, length) or None if no free run is long enough
+    pub fn allocate_contiguous(
+        &mut self,
+        blocks: u32,
+    ) -> Result<Option<(u32, u32)>> {
+        Ok(self.allocate_run(blocks, false)?)
+    }
+
+    /// Allocate a single run of free blocks in the first group
+    /// holding one. Bitmap is left as it was if it cannot be saved
+    fn allocate_run(
+        &mut self,
+        blocks: u32,
+        force: bool,
+    ) -> anyhow::Result<Option<(u32, u32)>> {
+        // Normal runs cannot reach into the reserved blocks
+        if blocks == 0 || blocks > self.available_blocks(force) {
+            return Ok(None);
+        }
+
+        for group_index in 0..self.groups.len() as u32 {
+            let old = self.groups[group_index as usize].clone();
+            let mut group = old.clone();
+            if let Some(start) = group.allocate_run(group_index, blocks) {
+                if let Err(e) = self
+                    .save_group(group, group_index)
+                    .and_then(|_| self.save_superblock())
+                {
+                    self.groups[group_index as usize] = old.clone();
+                    let _ = self.save_group(old, group_index);
+                    return Err(e);
+                }
+                return Ok(Some((start, blocks)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Free block runs of every group as (start block, length)
+    pub fn free_regions(&self) -> Vec<(u32, u32)> {
+        let blocks_per_group = self.blocks_per_group();
+        let mut regions = vec![];
+        for (group_index, group) in self.groups.iter().enumerate() {
+            regions.extend(group.free_runs().into_iter().map(
+                |(start, len)| {
+                    let start = Group::create_public_address(
+                        group_index as u32,
+                        start,
+                        blocks_per_group,
+                    );
+                    (start, len)
+                },
+            ));
+        }
+        regions
+    }
+
+    /// Render block allocation, one row per group
+    pub fn bitmap_image(&self) -> BitmapImage {
+        BitmapImage::from_groups(&self.groups)
+    }
+
+    /// Save block allocation as a PNG image
+    #[cfg(feature = "png")]
+    pub fn bitmap_png<P>(&self, path: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::create(path)?;
+        let mut w = BufWriter::new(file);
+        self.bitmap_image().write_png(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Allocate an empty inode at the given block index
+    /// e.g. to rebuild an image with known inode numbers.
+    /// The inode is not linked to any directory
+    pub fn allocate_inode_at(&mut self, inode_index: u32) -> Result<Inode> {
+        self.validate_inode_index(inode_index)?;
+        if !self.is_block_free(inode_index) {
+            return Err(
+                anyhow!("Block {} is already taken", inode_index).into()
+            );
+        }
+
+        let (group_index, bitmap_index) = Group::translate_public_address(
+            inode_index,
+            self.blocks_per_group(),
+        );
+        if self.group_free_inodes(group_index) == 0 {
+            return Err(
+                anyhow!("Group {} has no free inode", group_index).into()
+            );
+        }
+
+        let mut group = self.groups[group_index as usize].clone();
+        group.force_allocate_at(bitmap_index);
+        self.save_group(group, group_index)?;
+
+        let mut inode = Inode::with_time(inode_index, self.clock.now());
+        self.save_inode(&mut inode)?;
+        self.superblock.inode_counts[group_index as usize] += 1;
+        self.save_superblock()?;
+
+        Ok(inode)
+    }
+
+    #[inline]
+    fn add_group(&mut self, group: Group) -> anyhow::Result<()> {
+        // Per group fields of the superblock must fit into block 0
+        if self.groups.len() as u32 >= self.max_groups() {
+            return Err(WalnutError::OutOfSpace.into());
+        }
+        // Insert new group to FS groups
+        let group_index = self.groups.len() as u32;
+        self.groups.push(group.clone());
+        self.superblock.inode_counts.push(0);
+        // Save group, grow the image and save superblock,
+        // a failed step drops the group again
+        let res = self
+            .save_group(group, group_index)
+            .and_then(|_| self.truncate())
+            .and_then(|_| self.save_superblock());
+        if res.is_err() {
+            let _ = self.remove_groups_from(group_index);
+        }
+        res
+    }
+
+    /// Drop the groups from first_group on and shrink the image
+    /// The groups must hold no data, e.g. added by a failed operation
+    fn remove_groups_from(&mut self, first_group: u32) -> anyhow::Result<()> {
+        // Superblock drops the groups before the image shrinks
+        self.groups.truncate(first_group as usize);
+        self.superblock.inode_counts.truncate(first_group as usize);
+        self.save_superblock()?;
+        self.truncate()
+    }
+
+    #[inline]
+    fn groups_mut(&mut self) -> &mut [Group] {
+        &mut self.groups
+    }
+
+    #[inline]
+    fn superblock_mut(&mut self) -> &mut Superblock {
+        &mut self.superblock
+    }
+
+    #[inline]
+    fn release_inode_data(
+        &mut self,
+        data_pointers: Vec<(u32, u32)>,
+    ) -> anyhow::Result<()> {
+        // Shared blocks lose a reference instead
+        let data_pointers = self.release_shared(data_pointers)?;
+        self.release_blocks(data_pointers)
+    }
+
+    /// Free data regions in the group bitmaps
+    fn release_blocks(
+        &mut self,
+        data_pointers: Vec<(u32, u32)>,
+    ) -> anyhow::Result<()> {
+        let blocks_per_group = self.blocks_per_group();
+        let mut groups = self.groups_mut().as_mut().to_owned();
+
+        // Check each data region
+        for (block_index, range) in data_pointers {
+            // Holes have no blocks
+            if block_index == HOLE {
+                continue;
+            }
+            // Translate public address
+            let (group_index, bitmap_index) =
+                Group::translate_public_address(block_index, blocks_per_group);
+            // Release data region
+            groups[group_index as usize]
+                .release_data_region(bitmap_index, range);
+        }
+        // Iter groups
+        for (group_index, group) in groups.into_iter().enumerate() {
+            {
+                // And save each group to disk
+                self.save_group(group, group_index as u32)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reference counts of blocks shared by more than one inode
+    fn shared_blocks(&self) -> anyhow::Result<BTreeMap<u32, u32>> {
+        match self.superblock.shared_blocks {
+            0 => Ok(BTreeMap::new()),
+            inode_index => {
+                Ok(bincode::deserialize(&self.read_inode_bytes(inode_index)?)?)
+            }
+        }
+    }
+
+    /// Save reference counts, the inode is released once empty
+    fn save_shared_blocks(
+        &mut self,
+        shared: BTreeMap<u32, u32>,
+    ) -> anyhow::Result<()> {
+        let inode_index = self.superblock.shared_blocks;
+        if shared.is_empty() {
+            if inode_index != 0 {
+                self.superblock_mut().shared_blocks = 0;
+                self.release_inode(inode_index)?;
+            }
+            return Ok(());
+        }
+
+        let mut inode = match inode_index {
+            0 => match self.allocate_inode(true)? {
+                Some(inode) => inode,
+                None => return Err(WalnutError::OutOfSpace.into()),
+            },
+            inode_index => self.get_inode(inode_index)?,
+        };
+        let data = bincode::serialize(&shared)?;
+        self.write_inode_data(
+            &mut inode,
+            &mut Cursor::new(&data),
+            data.len() as u64,
+            true,
+        )?;
+        self.superblock_mut().shared_blocks = inode.block_index;
+        Ok(())
+    }
+
+    /// Host paths of imported files by inode index
+    fn original_paths(&self) -> anyhow::Result<BTreeMap<u32, PathBuf>> {
+        match self.superblock.original_paths {
+            0 => Ok(BTreeMap::new()),
+            inode_index => {
+                Ok(bincode::deserialize(&self.read_inode_bytes(inode_index)?)?)
+            }
+        }
+    }
+
+    /// Save original paths, the inode is released once empty
+    fn save_original_paths(
+        &mut self,
+        paths: BTreeMap<u32, PathBuf>,
+    ) -> anyhow::Result<()> {
+        let inode_index = self.superblock.original_paths;
+        if paths.is_empty() {
+            if inode_index != 0 {
+                self.superblock_mut().original_paths = 0;
+                self.release_inode(inode_index)?;
+            }
+            return Ok(());
+        }
+
+        let mut inode = match inode_index {
+            0 => match self.allocate_inode(true)? {
+                Some(inode) => inode,
+                None => return Err(WalnutError::OutOfSpace.into()),
+            },
+            inode_index => self.get_inode(inode_index)?,
+        };
+        let data = bincode::serialize(&paths)?;
+        self.write_inode_data(
+            &mut inode,
+            &mut Cursor::new(&data),
+            data.len() as u64,
+            true,
+        )?;
+        self.superblock_mut().original_paths = inode.block_index;
+        Ok(())
+    }
+
+    /// Drop one reference of the shared blocks in the regions
+    /// Returns the regions of the blocks not shared anymore
+    fn release_shared(
+        &mut self,
+        regions: Vec<(u32, u32)>,
+    ) -> anyhow::Result<Vec<(u32, u32)>> {
+        if self.superblock.shared_blocks == 0 {
+            return Ok(regions);
+        }
+
+        let mut shared = self.shared_blocks()?;
+        let mut released = vec![];
+        let mut changed = false;
+        for (start, len) in regions {
+            if start == HOLE
+                || shared.range(start..start + len).next().is_none()
+            {
+                released.push((start, len));
+                continue;
+            }
+            for block in start..start + len {
+                match shared.get_mut(&block) {
+                    // Last owner keeps the block
+                    Some(2) => {
+                        shared.remove(&block);
+                    }
+                    Some(count) => *count -= 1,
+                    None => {
+                        push_region(&mut released, block, 1);
+                        continue;
+                    }
+                }
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.save_shared_blocks(shared)?;
+        }
+        Ok(released)
+    }
+
+    /// Block of the data that can be written in place
+    /// A shared block is moved to a new block in pointers,
+    /// the caller copies its content and releases the old one
+    /// once the inode is saved. n is the block number in the data
+    fn unshare_block(
+        &mut self,
+        inode: &Inode,
+        pointers: &mut Vec<(u32, u32)>,
+        n: u64,
+        block: u32,
+    ) -> anyhow::Result<u32> {
+        if !self.shared_blocks()?.contains_key(&block) {
+            return Ok(block);
+        }
+
+        let ranges =
+            self.allocate_blocks(1, 1, self.first_data_group(inode), false)?;
+        let replaced = replace_block(pointers, n, ranges[0].0);
+        if replaced.len() > self.max_regions {
+            self.release_inode_data(ranges)?;
+            return Err(anyhow!("Too many regions to copy a shared block"));
+        }
+        *pointers = replaced;
+        Ok(ranges[0].0)
+    }
+
+    #[inline]
+    fn release_inode(&mut self, inode_block_index: u32) -> anyhow::Result<()> {
+        // Check if inode exist
+        let inode = self.get_inode(inode_block_index)?;
+
+        // Removed quarantine is created again by the next scrub
+        if self.superblock.quarantine == inode_block_index {
+            self.superblock_mut().quarantine = 0;
+        }
+
+        // Forget the original path of a released file
+        let original_paths = self.superblock.original_paths;
+        if original_paths != 0 && original_paths != inode_block_index {
+            let mut paths = self.original_paths()?;
+            if paths.remove(&inode_block_index).is_some() {
+                self.save_original_paths(paths)?;
+            }
+        }
+
+        // Translate block index
+        let (group_index, bitmap_index) = Group::translate_public_address(
+            inode_block_index,
+            self.blocks_per_group(),
+        );
+
+        // Release data
+        match inode.data {
+            // Dont do anything when it has raw data
+            Data::Raw(_) => (),
+            // Release all direct pointers
+            Data::DirectPointers(direct_pointers) => {
+                self.release_inode_data(direct_pointers)?
+            }
+        }
+
+        let mut group = self.groups[group_index as usize].to_owned();
+
+        {
+            // Release index bitmap
+            group.release_one(bitmap_index);
+        }
+
+        // Save group
+        self.save_group(group, group_index)?;
+
+        // Update inode accounting
+        let count = &mut self.superblock.inode_counts[group_index as usize];
+        *count = count.saturating_sub(1);
+        self.save_superblock()?;
+
+        Ok(())
+    }
+}
+
+/// Image space usage in bytes
+/// Categories sum up to the image file size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceBreakdown {
+    pub superblock: u64, // Superblock block
+    pub bitmaps: u64,    // Group bitmap blocks
+    pub inodes: u64,     // Inode and directory metadata blocks
+    pub file_data: u64,  // Blocks holding file data
+    pub free: u64,       // Free blocks
+}
+
+impl SpaceBreakdown {
+    pub fn total(&self) -> u64 {
+        self.superblock
+            + self.bitmaps
+            + self.inodes
+            + self.file_data
+            + self.free
+    }
+}
+
+/// Entry referencing an inode
+enum InodeOwner {
+    Root,
+    Directory(OsString),
+    File(OsString, String),
+    SharedBlocks,
+    OriginalPaths,
+    Config,
+}
+
+/// Writer comparing written bytes with a reader
+/// Stops at the first mismatch
+struct CompareWriter<R> {
+    host: R,
+    buffer: Vec<u8>,
+    offset: u64,
+    mismatch: Option<u64>,
+}
+
+impl<R> Write for CompareWriter<R>
+where
+    R: Read,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.resize(buf.len(), 0);
+
+        // Read the same amount, host can be shorter
+        let mut read = 0;
+        while read < buf.len() {
+            match self.host.read(&mut self.buffer[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+
+        let position = buf
+            .iter()
+            .zip(&self.buffer[..read])
+            .position(|(a, b)| a != b)
+            .or(if read < buf.len() { Some(read) } else { None });
+
+        if let Some(position) = position {
+            self.mismatch = Some(self.offset + position as u64);
+            return Err(std::io::Error::other("Content mismatch"));
+        }
+
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reader of a stored file, returned by FS::open_file
+/// Holds one decrypted block at a time. Data read from the start
+/// to the end is verified, a checksum mismatch is returned by
+/// the read after the last byte instead of the end of file
+pub struct FileReader<'a> {
+    fs: &'a FS,
+    inode: Inode,
+    loaded: u64,                // Bytes loaded into the buffer so far
+    region: usize,              // Current region of the direct pointers
+    block: u32,                 // Next block inside the current region
+    buffer: Vec<u8>,            // Current block
+    position: usize,            // Read position inside the buffer
+    checksum: Option<Checksum>, // Checksum of the loaded data, None if seeked
+}
+
+impl<'a> FileReader<'a> {
+    fn new(fs: &'a FS, inode: Inode) -> Self {
+        Self {
+            fs,
+            inode,
+            loaded: 0,
+            region: 0,
+            block: 0,
+            buffer: vec![],
+            position: 0,
+            checksum: Some(Checksum::new()),
+        }
+    }
+
+    /// File inode
+    pub fn inode(&self) -> &Inode {
+        &self.inode
+    }
+
+    /// Load the next block into the buffer
+    fn load_next(&mut self) -> anyhow::Result<()> {
+        match &self.inode.data {
+            Data::Raw(data) => {
+                self.buffer = data.clone();
+                self.fs.cipher.decrypt_block(
+                    self.inode.block_index as u64,
+                    &mut self.buffer,
+                );
+            }
+            Data::DirectPointers(pointers) => {
+                let (start, range) =
+                    *pointers.get(self.region).ok_or_else(|| {
+                        anyhow!("File data is shorter than its size")
+                    })?;
+                let len = (self.inode.size - self.loaded)
+                    .min(self.fs.block_size() as u64);
+                self.buffer.resize(len as usize, 0);
+
+                // Holes read as zeros
+                if start == HOLE {
+                    self.buffer.fill(0);
+                } else {
+                    self.fs.read_data_block(
+                        start + self.block,
+                        &mut self.buffer,
+                    )?;
+                }
+
+                self.block += 1;
+                if self.block == range {
+                    self.region += 1;
+                    self.block = 0;
+                }
+            }
+        }
+
+        self.loaded += self.buffer.len() as u64;
+        self.position = 0;
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update(&self.buffer);
+        }
+
+        Ok(())
+    }
+
+    /// Move to a data offset, only the block holding it is read
+    fn seek_to(&mut self, offset: u64) -> anyhow::Result<()> {
+        self.region = 0;
+        self.block = 0;
+        self.loaded = 0;
+        self.buffer.clear();
+        self.position = 0;
+
+        // Only data read from the start can be verified
+        self.checksum = (offset == 0).then(Checksum::new);
+
+        // Position past the end is kept, reads there return nothing
+        if offset >= self.inode.size {
+            self.loaded = offset;
+            return Ok(());
+        }
+
+        // Find the region holding the block, regions differ in length
+        if let Data::DirectPointers(pointers) = &self.inode.data {
+            let mut block = offset / self.fs.block_size() as u64;
+            for (_, range) in pointers {
+                if block < *range as u64 {
+                    break;
+                }
+                block -= *range as u64;
+                self.region += 1;
+            }
+            self.block = block as u32;
+            self.loaded = offset - offset % self.fs.block_size() as u64;
+        }
+
+        let block_start = self.loaded;
+        self.load_next()?;
+        self.position = (offset - block_start) as usize;
+
+        Ok(())
+    }
+}
+
+impl Seek for FileReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let current = self.loaded - (self.buffer.len() - self.position) as u64;
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.inode.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => current.checked_add_signed(delta),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seek before the start of the file",
+            )
+        })?;
+
+        self.seek_to(offset).map_err(std::io::Error::other)?;
+        Ok(offset)
+    }
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.buffer.len() {
+            if self.loaded >= self.inode.size {
+                // Verified once, later reads are at the end of file
+                let checksum = self.checksum.take().map(Checksum::finalize);
+                if checksum.is_some_and(|c| c != self.inode.data_checksum) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        WalnutError::ChecksumMismatch,
+                    ));
+                }
+                return Ok(0);
+            }
+            self.load_next().map_err(std::io::Error::other)?;
+        }
+
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n]
+            .copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+
+        Ok(n)
+    }
+}
+
+/// Readers read one after the other
+struct ConcatReader<'a> {
+    readers: std::vec::IntoIter<FileReader<'a>>,
+    current: Option<FileReader<'a>>,
+}
+
+impl Read for ConcatReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.current.is_none() {
+                self.current = self.readers.next();
+            }
+            match &mut self.current {
+                // Every reader is exhausted
+                None => return Ok(0),
+                Some(reader) => match reader.read(buf)? {
+                    0 => self.current = None,
+                    n => return Ok(n),
+                },
+            }
+        }
+    }
+}
+
+/// Iterator over every file of the image, returned by FS::walk
+/// A directory that cannot be read is yielded as an error,
+/// the walk goes on with the next directory
+pub struct FsWalker<'a> {
+    fs: &'a FS,
+    directories: std::collections::btree_map::IntoIter<OsString, u32>,
+    current:
+        Option<(OsString, std::collections::btree_map::IntoIter<String, u32>)>,
+}
+
+impl Iterator for FsWalker<'_> {
+    type Item = anyhow::Result<(OsString, String, u32)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((dir, files)) = &mut self.current {
+                if let Some((file_name, inode_index)) = files.next() {
+                    return Some(Ok((dir.clone(), file_name, inode_index)));
+                }
+            }
+
+            let (dir, dir_inode_index) = self.directories.next()?;
+            match self.fs.read_directory(dir_inode_index) {
+                Ok(directory) => {
+                    self.current = Some((dir, directory.files.into_iter()))
+                }
+                Err(e) => {
+                    self.current = None;
+                    return Some(Err(anyhow!(
+                        "Cannot read directory {}: {}",
+                        Path::new(&dir).display(),
+                        e
+                    )));
+                }
+            }
+        }
+    }
+}
+
+/// Writer of a file content, returned by FS::create_writer
+/// Finish adds the file, dropping the writer finishes it too
+/// but its errors are lost
+pub struct FileWriter<'a> {
+    fs: &'a mut FS,
+    dir: PathBuf,
+    file_name: String,
+    buffer: Vec<u8>,         // Data not written to blocks yet
+    ranges: Vec<(u32, u32)>, // Blocks written so far
+    checksum: Checksum,
+    data_len: u64, // Bytes written to blocks
+    failed: bool,
+    finished: bool,
+}
+
+impl FileWriter<'_> {
+    /// Write the remaining data and add the file
+    /// Returns (file size, crc32 of the data)
+    pub fn finish(mut self) -> Result<(u64, u32)> {
+        Ok(self.finish_inner()?)
+    }
+
+    fn finish_inner(&mut self) -> anyhow::Result<(u64, u32)> {
+        self.finished = true;
+
+        let res = if self.failed {
+            Err(anyhow!("File data could not be written"))
+        } else {
+            self.add_file()
+        };
+
+        // Blocks of a file not added are released
+        if res.is_err() {
+            let ranges = std::mem::take(&mut self.ranges);
+            self.fs.release_inode_data(ranges)?;
+        }
+
+        res
+    }
+
+    fn add_file(&mut self) -> anyhow::Result<(u64, u32)> {
+        // Small data is stored as raw data
+        if self.ranges.is_empty()
+            && self.buffer.len() <= self.fs.inode_capacity()
+        {
+            let data = std::mem::take(&mut self.buffer);
+            let len = data.len() as u64;
+            self.fs.add_file(
+                &self.dir,
+                &self.file_name,
+                &mut Cursor::new(&data),
+                len,
+            )?;
+            let mut checksum = Checksum::new();
+            checksum.update(&data);
+            return Ok((len, checksum.finalize()));
+        }
+
+        self.write_blocks(true)?;
+
+        let ranges = self.ranges.clone();
+        let data_len = self.data_len;
+        let checksum = std::mem::take(&mut self.checksum).finalize();
+        let mut linked = false;
+        let res = self.fs.add_file_with(
+            &self.dir,
+            &self.file_name,
+            Some(data_len),
+            false,
+            |fs, inode| {
+                let old_pointers = match &inode.data {
+                    Data::Raw(_) => None,
+                    Data::DirectPointers(pointers) => Some(pointers.clone()),
+                };
+
+                inode.set_direct_pointers(ranges, data_len);
+                inode.data_checksum = checksum;
+                fs.save_inode(inode)?;
+                linked = true;
+
+                // Release data of the replaced file
+                if let Some(pointers) = old_pointers {
+                    fs.release_inode_data(pointers)?;
+                }
+                Ok(())
+            },
+        );
+
+        // Blocks belong to the inode from now on
+        if linked {
+            self.ranges.clear();
+        }
+        res?;
+
+        Ok((data_len, checksum))
+    }
+
+    /// Write buffered data to newly allocated blocks
+    /// Only whole blocks are written unless all is set
+    fn write_blocks(&mut self, all: bool) -> anyhow::Result<()> {
+        let len = if all {
+            self.buffer.len()
+        } else {
+            self.buffer.len()
+                - self.buffer.len() % self.fs.block_size() as usize
+        };
+        if len == 0 {
+            return Ok(());
+        }
+
+        let blocks = (len as u64).div_ceil(self.fs.block_size() as u64);
+        let new_ranges = self.fs.allocate_blocks(
+            blocks,
+            self.fs.max_regions.saturating_sub(self.ranges.len()),
+            0,
+            false,
+        )?;
+        if let Err(e) = self.fs.write_blocks(
+            &new_ranges,
+            &mut Cursor::new(&self.buffer[..len]),
+            len as u64,
+            &mut self.checksum,
+        ) {
+            self.fs.release_inode_data(new_ranges)?;
+            return Err(e);
+        }
+        merge_ranges(&mut self.ranges, &new_ranges);
+
+        self.data_len += len as u64;
+        self.buffer.drain(..len);
+
+        Ok(())
+    }
+}
+
+impl Write for FileWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.failed {
+            return Err(std::io::Error::other(
+                "File data could not be written",
+            ));
+        }
+
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() as u64
+            >= STREAM_CHUNK_BLOCKS * self.fs.block_size() as u64
+        {
+            if let Err(e) = self.write_blocks(false) {
+                self.failed = true;
+                return Err(std::io::Error::other(e));
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Data is stored once the writer is finished
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for FileWriter<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.finish_inner();
+        }
+    }
+}
+
+/// Last read time of files by (directory, file name)
+/// Reads within the same second are ordered by their sequence
+#[derive(Debug, Default)]
+struct AccessLog {
+    files: BTreeMap<(OsString, String), (u64, u64)>, // (time, sequence)
+    sequence: u64,
+}
+
+impl AccessLog {
+    fn record(&mut self, dir: &std::ffi::OsStr, file_name: &str, time: u64) {
+        self.sequence += 1;
+        self.files.insert(
+            (dir.to_os_string(), file_name.to_string()),
+            (time, self.sequence),
+        );
+    }
+
+    fn remove(&mut self, dir: &std::ffi::OsStr, file_name: &str) {
+        self.files
+            .remove(&(dir.to_os_string(), file_name.to_string()));
+    }
+
+    /// Keep read times of renamed files
+    fn rename(&mut self, dir: &std::ffi::OsStr, renames: &[(String, String)]) {
+        let moved: Vec<_> = renames
+            .iter()
+            .map(|(from, to)| {
+                let key = (dir.to_os_string(), from.clone());
+                (to, self.files.remove(&key))
+            })
+            .collect();
+        for (to, access) in moved {
+            if let Some(access) = access {
+                self.files.insert((dir.to_os_string(), to.clone()), access);
+            }
+        }
+    }
+
+    fn recent(&self, n: usize) -> Vec<(OsString, String, u64)> {
+        let mut files: Vec<_> = self.files.iter().collect();
+        files.sort_by(|a, b| b.1.cmp(a.1));
+        files
+            .into_iter()
+            .take(n)
+            .map(|((dir, file_name), (time, _))| {
+                (dir.clone(), file_name.clone(), *time)
+            })
+            .collect()
+    }
+}
+
+/// Kind of a directory entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// Block allocation strategy of new files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocationMode {
+    /// Inode and data take the first free blocks
+    #[default]
+    FirstFit,
+    /// Inode goes to the first group with room for its data,
+    /// data allocation starts in the group of the inode
+    Colocate,
+}
+
+/// Free run selection of data allocations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocStrategy {
+    /// First free blocks of the groups, in as many runs as they are
+    #[default]
+    Greedy,
+    /// Smallest free run holding the whole data, else the largest
+    /// runs first, so data is split into the fewest regions
+    BestFit,
+}
+
+/// File names accepted for new files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NamePolicy {
+    /// Any name
+    #[default]
+    Permissive,
+    /// Names every host can store, e.g. no characters
+    /// or device names reserved on Windows
+    Portable,
+}
+
+/// Order of directory listings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListOrder {
+    Lexical,  // Byte order of names
+    Natural,  // Digit runs compared by value, file2 before file10
+    Creation, // Creation order of the entries
+}
+
+/// Directory entry with its inode details
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,    // Entry name inside the directory
+    pub inode: u32,      // Inode block index
+    pub size: u64,       // Data size in bytes
+    pub created: u64,    // Creation time
+    pub modified: u64,   // Last modification time
+    pub kind: EntryKind, // File or directory
+}
+
+/// File metadata read from its inode block only
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stat {
+    pub inode: u32,         // Inode block index
+    pub size: u64,          // Data size in bytes
+    pub created: u64,       // Creation time
+    pub modified: u64,      // Last modification time
+    pub data_checksum: u32, // Checksum of the plain data
+    pub blocks: u32,        // Data blocks, raw data and holes take none
+}
+
+/// Result of FS::directory_stats
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DirStats {
+    pub files: u32, // Files of the directory
+    pub bytes: u64, // Summed file sizes
+}
+
+/// Result of FS::compact
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompactReport {
+    pub bytes_moved: u64,        // Copied file data
+    pub regions_eliminated: u32, // Data regions merged by the moves
+    pub groups_removed: u32,     // Empty groups cut from the image end
+}
+
+/// Operations of FS::maintenance
+#[derive(Debug, Clone, Default)]
+pub struct MaintenanceOpts {
+    pub defragment: bool, // Move data into single runs at the front
+    pub rekey: Option<String>, // Encrypt every block by this new secret
+    pub compact: bool,    // Remove empty groups from the image end
+}
+
+/// Result of FS::maintenance
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    pub compact: CompactReport, // Moved data and removed groups
+    pub bytes_rekeyed: u64,     // Data encrypted by the new secret
+}
+
+/// Result of FS::scrub
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ScrubReport {
+    pub checked: usize,                       // Files read
+    pub quarantined: Vec<(PathBuf, PathBuf)>, // Corrupt files (from, to)
+}
+
+/// Result of FS::import_tree
+#[derive(Debug, Default)]
+pub struct ImportStats {
+    pub files: u32,                             // Imported files
+    pub bytes: u64,                             // Imported file data
+    pub skipped: Vec<(PathBuf, anyhow::Error)>, // Unreadable host entries
+}
+
+impl ImportStats {
+    fn skip(&mut self, path: PathBuf, e: anyhow::Error) {
+        self.skipped.push((path, e));
+    }
+}
+
+/// Result of FS::fsck, blocks are in block index order
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FsckReport {
+    pub files: usize,                            // Files checked
+    pub inodes: Vec<u32>,                        // File inode indices
+    pub bad_checksums: Vec<PathBuf>, // Files failing data verification
+    pub leaked: Vec<u32>,            // Allocated but not referenced blocks
+    pub double_allocated: Vec<u32>,  // Blocks referenced too many times
+    pub unallocated: Vec<u32>,       // Referenced but free blocks
+    pub directory_collisions: Vec<Vec<PathBuf>>, // Directories sharing an inode
+}
+
+impl FsckReport {
+    /// No problem was found
+    pub fn is_clean(&self) -> bool {
+        self.bad_checksums.is_empty()
+            && self.leaked.is_empty()
+            && self.double_allocated.is_empty()
+            && self.unallocated.is_empty()
+            && self.directory_collisions.is_empty()
+    }
+}
+
+/// File changes between two images
+/// Paths are ordered
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Diff {
+    pub added: Vec<PathBuf>, // Files only in the current image
+    pub removed: Vec<PathBuf>, // Files only in the base image
+    pub modified: Vec<PathBuf>, // Files with different content
+}
+
+/// File set of a directory at snapshot time
+/// Restored by FS::restore_directory
+#[derive(Debug, Clone)]
+pub struct DirectorySnapshot {
+    dir: PathBuf,
+    files: BTreeMap<String, Inode>,
+}
+
+impl DirectorySnapshot {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Captured file names with their inodes
+    pub fn files(&self) -> &BTreeMap<String, Inode> {
+        &self.files
+    }
+}
+
+/// Read-only handle of an image written by other handles
+/// Reads are repeated until the stored generation is the same
+/// before and after them, so they never mix two image states.
+/// Writers keep the generation odd while they change the image
+/// and readers wait for it to be even again. The image is opened
+/// read only, config and upgrades are left to the writers
+#[derive(Debug)]
+pub struct FsReader {
+    fs: FS,
+    max_attempts: u32,
+}
+
+impl FsReader {
+    pub fn open<P>(path: P, secret: &str) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        // Readers never write, open image path as read only
+        let file = OpenOptions::new().read(true).open(path.as_ref())?;
+        let mut fs = FS::open(Storage::File(file), Mode::ReadWrite)?;
+
+        // Upgrades write the image
+        if fs.superblock.fs_version < FS_VERSION {
+            return Err(anyhow!(
+                "Image version {} needs an upgrade, open it with FS::new first",
+                fs.superblock.fs_version
+            )
+            .into());
+        }
+
+        fs.cipher = cipher_by_id(fs.superblock.cipher_id, secret)?;
+        if fs.superblock.key_check != key_check(fs.cipher.as_ref()) {
+            return Err(WalnutError::WrongSecret);
+        }
+
+        Ok(Self {
+            fs,
+            max_attempts: 16,
+        })
+    }
+
+    /// Give up reading after the given attempts
+    pub fn set_max_attempts(&mut self, attempts: u32) {
+        assert!(attempts > 0);
+        self.max_attempts = attempts;
+    }
+
+    /// Generation stored in the image
+    pub fn generation(&self) -> Result<u64> {
+        let mut r = BufReader::new(&self.fs.file);
+        r.seek(SeekFrom::Start(0))?;
+        Ok(Superblock::deserialize_from(r)?.generation)
+    }
+
+    /// Run read until no write happens during it
+    /// Read gets the attempt number from 1
+    pub fn read<T, F>(&mut self, mut read: F) -> Result<T>
+    where
+        F: FnMut(&FS, u32) -> anyhow::Result<T>,
+    {
+        for attempt in 1..=self.max_attempts {
+            // Superblock can be read while it is written,
+            // odd generation marks a write in progress
+            let before = match self.generation() {
+                Ok(generation) if generation % 2 == 0 => generation,
+                _ => {
+                    std::thread::yield_now();
+                    continue;
+                }
+            };
+            if before != self.fs.superblock.generation
+                && self.refresh().is_err()
+            {
+                continue;
+            }
+
+            let res = read(&self.fs, attempt);
+
+            if self.generation().ok() == Some(before) {
+                return Ok(res?);
+            }
+        }
+
+        Err(anyhow!("Image kept changing during read").into())
+    }
+
+    /// Read whole file data
+    pub fn get_file_data<P>(
+        &mut self,
+        dir: P,
+        file_name: &str,
+    ) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+    {
+        self.read(|fs, _| {
+            let mut data = vec![];
+            fs.open_file(&dir, file_name)?.read_to_end(&mut data)?;
+            Ok(data)
+        })
+    }
+
+    /// Load superblock and groups written by others
+    /// Config and upgrades are left to writers
+    fn refresh(&mut self) -> anyhow::Result<()> {
+        let file = self.fs.file.try_clone()?;
+        let mut fs = FS::open(file, Mode::ReadWrite)?;
+        if fs.superblock.fs_version != FS_VERSION {
+            return Err(anyhow!(
+                "Image version changed to {}",
+                fs.superblock.fs_version
+            ));
+        }
+        fs.cipher = std::mem::replace(&mut self.fs.cipher, Box::new(NoCipher));
+        fs.clock = std::mem::replace(&mut self.fs.clock, Box::new(SystemClock));
+        fs.retry = self.fs.retry;
+        fs.set_cache_capacity(self.fs.cache_capacity());
+        self.fs = fs;
+        Ok(())
+    }
+}
+
+/// Read-only handle shared between threads, see FS::open_readonly
+/// Every read is positioned (pread on unix, offset ReadFile on windows),
+/// so no seek position is shared and no lock is taken. Other platforms
+/// are not supported. Writes of other handles are not picked up
+#[derive(Debug)]
+pub struct ReadOnlyFs {
+    superblock: Superblock,
+    group_count: u32,
+    file: File,
+    cipher: Box<dyn Cipher + Send + Sync>,
+}
+
+impl ReadOnlyFs {
+    pub fn superblock(&self) -> &Superblock {
+        &self.superblock
+    }
+
+    fn block_size(&self) -> u32 {
+        self.superblock.block_size
+    }
+
+    pub fn get_directory_index(&self) -> Result<DirectoryIndex> {
+        let mut data = vec![];
+        self.read_inode_data(
+            &mut self.get_inode(ROOT_INODE_INDEX)?,
+            &mut data,
+        )?;
+        Ok(decode_directory_index(
+            data,
+            self.superblock.flags & FLAG_COMPRESS_INDEX != 0,
+        )?)
+    }
+
+    /// Find directory
+    /// returns directory and its inode index
+    pub fn find_directory<P>(&self, dir: P) -> Result<(Directory, u32)>
+    where
+        P: AsRef<Path>,
+    {
+        let directory_index = self.get_directory_index()?;
+        let directory_inode_index = directory_index
+            .find_dir(dir)
+            .copied()
+            .ok_or(WalnutError::DirNotFound)?;
+
+        let mut data = vec![];
+        self.read_inode_data(
+            &mut self.get_inode(directory_inode_index)?,
+            &mut data,
+        )?;
+        Ok((bincode::deserialize(&data)?, directory_inode_index))
+    }
+
+    /// Get file info
+    pub fn get_file_info<P>(&self, dir: P, file_name: &str) -> Result<Inode>
+    where
+        P: AsRef<Path>,
+    {
+        let (directory, _) = self.find_directory(dir)?;
+        match directory.get_file(file_name) {
+            Some(file_inode_index) => Ok(self.get_inode(file_inode_index)?),
+            None => Err(WalnutError::FileNotFound),
+        }
+    }
+
+    /// Write file data to the given writer, returns its checksum
+    pub fn get_file_data<P, W>(
+        &self,
+        dir: P,
+        file_name: &str,
+        w: &mut W,
+    ) -> Result<u32>
+    where
+        P: AsRef<Path>,
+        W: Write,
+    {
+        let (directory, _) = self.find_directory(dir)?;
+        let mut inode = match directory.get_file(file_name) {
+            Some(file_inode_index) => self.get_inode(file_inode_index)?,
+            None => return Err(WalnutError::FileNotFound),
+        };
+        directory.check_size(file_name, &inode)?;
+        Ok(self.read_inode_data(&mut inode, w)?)
+    }
+
+    fn get_inode(&self, inode_block_index: u32) -> anyhow::Result<Inode> {
+        check_inode_index(
+            inode_block_index,
+            self.superblock.blocks_per_group,
+            self.group_count,
+        )?;
+
+        let mut buf = vec![0u8; self.block_size() as usize];
+        read_exact_pos(
+            &self.file,
+            block_seek_position(inode_block_index, self.block_size()),
+            &mut buf,
+        )?;
+        Ok(Inode::deserialize_from(Cursor::new(buf))?)
+    }
+
+    /// Read inode data and verify its checksum
+    fn read_inode_data<W>(
+        &self,
+        inode: &mut Inode,
+        w: &mut W,
+    ) -> anyhow::Result<u32>
+    where
+        W: Write,
+    {
+        let mut checksum = Checksum::new();
+
+        match &mut inode.data {
+            Data::Raw(data) => {
+                self.cipher.decrypt_block(inode.block_index as u64, data);
+                checksum.update(data);
+                w.write_all(data)?;
+            }
+            Data::DirectPointers(pointers) => {
+                let mut data_left = inode.size;
+                let mut buf = vec![0u8; self.block_size() as usize];
+
+                for (start, len) in pointers {
+                    for block in *start..*start + *len {
+                        buf.truncate(
+                            data_left.min(self.block_size() as u64) as usize
+                        );
+
+                        // Holes read as zeros
+                        if *start == HOLE {
+                            buf.fill(0);
+                        } else {
+                            read_exact_pos(
+                                &self.file,
+                                block_seek_position(block, self.block_size()),
+                                &mut buf,
+                            )?;
+                            self.cipher.decrypt_block(block as u64, &mut buf);
+                        }
+
+                        checksum.update(&buf);
+                        w.write_all(&buf)?;
+                        data_left -= buf.len() as u64;
+                    }
+                }
+            }
+        }
+
+        let checksum = checksum.finalize();
+        if checksum != inode.data_checksum {
+            return Err(WalnutError::ChecksumMismatch.into());
+        }
+
+        Ok(checksum)
+    }
+}
+
+/// Read-only metadata of an image at the time of FS::view
+#[derive(Debug)]
+pub struct FsView {
+    superblock: Superblock,
+    groups: Vec<Group>,
+    directory_index: DirectoryIndex,
+    directories: BTreeMap<OsString, Directory>,
+}
+
+impl FsView {
+    pub fn superblock(&self) -> &Superblock {
+        &self.superblock
+    }
+
+    pub fn groups(&self) -> &[Group] {
+        &self.groups
+    }
+
+    pub fn label(&self) -> String {
+        self.superblock.label()
+    }
+
+    pub fn directory_index(&self) -> &DirectoryIndex {
+        &self.directory_index
+    }
+
+    pub fn find_directory<P>(&self, dir: P) -> Option<&Directory>
+    where
+        P: AsRef<Path>,
+    {
+        self.directories.get(dir.as_ref().as_os_str())
+    }
+
+    /// Files in all directories
+    pub fn file_count(&self) -> usize {
+        self.directories.values().map(|d| d.files.len()).sum()
+    }
+
+    /// Free blocks computed from the group bitmaps
+    pub fn free_blocks(&self) -> u32 {
+        self.groups
+            .iter()
+            .map(|g| g.free_data_blocks() as u32)
+            .sum()
+    }
+}
+
+/// Scratch file inside the image
+/// Its inode and blocks are released on drop
+pub struct TempHandle<'a> {
+    fs: &'a mut FS,
+    name: String,
+    discarded: bool,
+}
+
+impl TempHandle<'_> {
+    /// File name inside the temp directory
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Replace temp file content
+    pub fn write<R>(&mut self, data: &mut R, data_len: u64) -> Result<()>
+    where
+        R: BufRead,
+    {
+        self.fs.add_file(TEMP_DIRECTORY, &self.name, data, data_len)
+    }
+
+    /// Write temp file content to the given writer
+    pub fn read<W>(&mut self, w: &mut W) -> Result<u32>
+    where
+        W: Write,
+    {
+        self.fs.get_file_data(TEMP_DIRECTORY, &self.name, w)
+    }
+
+    /// Remove temp file now, reporting errors
+    pub fn discard(mut self) -> Result<()> {
+        self.discarded = true;
+        self.fs.remove_file(TEMP_DIRECTORY, &self.name)
+    }
+}
+
+impl Drop for TempHandle<'_> {
+    fn drop(&mut self) {
+        if !self.discarded {
+            // Cannot report errors from drop
+            let _ = self.fs.remove_file(TEMP_DIRECTORY, &self.name);
+        }
+    }
+}
+
+/// Superblock on disk layout
+/// Version 1 fields come first, fields added by later
+/// versions are appended after them in version order.
+/// Older layouts are decoded with defaults for the missing fields
+#[derive(Debug, Clone)]
+pub struct Superblock {
+    magic: [u8; 7],  // Magic number to check
+    fs_version: u32, // FS Version
+    // test_bytes: [u8; 20], // Secret test bytes
+    block_size: u32,  // Block size in bytes
+    group_count: u32, // Total groups count
+    block_count: u32, // Total blocks count
+    free_blocks: u32, // Available blocks
+    file_count: u32,  // File count in fs
+    created: u64,     // FS creation time
+    modified: u64,    // FS last modification time
+    checksum: u32,    // Superblock checksum
+    // Since version 2
+    label: [u8; LABEL_SIZE], // Volume label, zero padded
+    // Since version 3
+    uuid: [u8; 16], // Image identifier
+    // Since version 4
+    reserved_percentage: u8, // Blocks reserved for privileged writes
+    // Since version 6
+    flags: u32, // Feature flags
+    // Since version 7
+    inode_counts: Vec<u32>, // Allocated inodes per group
+    min_free_inodes: u32,   // Inodes reserved for privileged allocations
+    // Since version 8
+    cipher_id: u8, // Cipher used for inode data
+    // Since version 9
+    blocks_per_group: u32, // Data blocks per group
+    // Since version 10
+    generation: u64, // Bumped on every superblock save
+    // Since version 11
+    key_check: u32, // Checksum of KEY_CHECK_BYTES encrypted by the cipher
+    // Since version 12
+    shared_blocks: u32, // Inode of the shared block references, 0 if none
+    // Since version 13
+    original_paths: u32, // Inode of the file host paths, 0 if none
+    // Since version 14
+    max_dir_depth: u32, // Deepest directory allowed, 0 if unlimited
+    // Since version 15
+    group_checksums: Vec<u32>, // Bitmap checksum per group
+    // Since version 17
+    sequence: u64, // Last creation sequence number handed out
+    // Since version 18
+    quarantine: u32, // Inode of the scrub quarantine directory, 0 if none
+    config: u32,     // Inode of the stored options, 0 if none
+}
+
+impl Superblock {
+    fn new(created: u64) -> Self {
+        Self {
+            magic: MAGIC,
+            fs_version: FS_VERSION,
+            block_size: BLOCK_SIZE,
+            group_count: 0,
+            block_count: 1,
+            free_blocks: 0,
+            file_count: 0,
+            created,
+            modified: created,
+            checksum: 0,
+            label: [0; LABEL_SIZE],
+            uuid: Uuid::new_v4().into_bytes(),
+            reserved_percentage: DEFAULT_RESERVED_PERCENTAGE,
+            flags: 0,
+            inode_counts: vec![],
+            min_free_inodes: 0,
+            cipher_id: XOR_CIPHER_ID,
+            blocks_per_group: BLOCKS_PER_GROUP,
+            generation: 0,
+            key_check: 0,
+            shared_blocks: 0,
+            original_paths: 0,
+            max_dir_depth: 0,
+            group_checksums: vec![],
+            sequence: 0,
+            quarantine: 0,
+            config: 0,
+        }
+    }
+
+    pub fn update_modified(&mut self) {
+        self.modified = now();
+    }
+
+    /// Volume label
+    pub fn label(&self) -> String {
+        let len = self
+            .label
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(LABEL_SIZE);
+        String::from_utf8_lossy(&self.label[..len]).into_owned()
+    }
+
+    fn set_label(&mut self, label: &str) -> anyhow::Result<()> {
+        if label.len() > LABEL_SIZE {
+            return Err(anyhow!(
+                "Label is too long. Maximum {} bytes",
+                LABEL_SIZE
+            ));
+        }
+        self.label = [0; LABEL_SIZE];
+        self.label[..label.len()].copy_from_slice(label.as_bytes());
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn serialize(&mut self) -> Result<Vec<u8>> {
+        self.checksum();
+        Ok(self.encode())
+    }
+
+    #[inline]
+    pub fn serialize_into<W>(&mut self, mut w: W) -> Result<()>
+    where
+        W: Write,
+    {
+        self.checksum();
+        w.write_all(&self.encode())?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn deserialize_from<R>(r: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut sb = Self::decode(r)?;
+        if !sb.verify_checksum() {
+            return Err(
+                anyhow!("Superblock checksum verification failed").into()
+            );
+        }
+
+        Ok(sb)
+    }
+
+    /// Encode superblock in the layout of its own fs_version
+    fn encode(&self) -> Vec<u8> {
+        let mut data = bincode::serialize(&(
+            self.magic,
+            self.fs_version,
+            self.block_size,
+            self.group_count,
+            self.block_count,
+            self.free_blocks,
+            self.file_count,
+            self.created,
+            self.modified,
+            self.checksum,
+        ))
+        .unwrap();
+
+        if self.fs_version >= 2 {
+            data.extend(bincode::serialize(&self.label).unwrap());
+        }
+        if self.fs_version >= 3 {
+            data.extend(bincode::serialize(&self.uuid).unwrap());
+        }
+        if self.fs_version >= 4 {
+            data.extend(bincode::serialize(&self.reserved_percentage).unwrap());
+        }
+        if self.fs_version >= 6 {
+            data.extend(bincode::serialize(&self.flags).unwrap());
+        }
+        if self.fs_version >= 7 {
+            data.extend(bincode::serialize(&self.inode_counts).unwrap());
+            data.extend(bincode::serialize(&self.min_free_inodes).unwrap());
+        }
+        if self.fs_version >= 8 {
+            data.extend(bincode::serialize(&self.cipher_id).unwrap());
+        }
+        if self.fs_version >= 9 {
+            data.extend(bincode::serialize(&self.blocks_per_group).unwrap());
+        }
+        if self.fs_version >= 10 {
+            data.extend(bincode::serialize(&self.generation).unwrap());
+        }
+        if self.fs_version >= 11 {
+            data.extend(bincode::serialize(&self.key_check).unwrap());
+        }
+        if self.fs_version >= 12 {
+            data.extend(bincode::serialize(&self.shared_blocks).unwrap());
+        }
+        if self.fs_version >= 13 {
+            data.extend(bincode::serialize(&self.original_paths).unwrap());
+        }
+        if self.fs_version >= 14 {
+            data.extend(bincode::serialize(&self.max_dir_depth).unwrap());
+        }
+        if self.fs_version >= 15 {
+            data.extend(bincode::serialize(&self.group_checksums).unwrap());
+        }
+        if self.fs_version >= 17 {
+            data.extend(bincode::serialize(&self.sequence).unwrap());
+        }
+        if self.fs_version >= 18 {
+            data.extend(bincode::serialize(&self.quarantine).unwrap());
+            data.extend(bincode::serialize(&self.config).unwrap());
+        }
+
+        data
+    }
+
+    /// Decode superblock of any known layout version
+    fn decode<R>(mut r: R) -> anyhow::Result<Self>
+    where
+        R: Read,
+    {
+        let (
+            magic,
+            fs_version,
+            block_size,
+            group_count,
+            block_count,
+            free_blocks,
+            file_count,
+            created,
+            modified,
+            checksum,
+        ) = bincode::deserialize_from(&mut r)?;
+
+        if magic != MAGIC {
+            return Err(anyhow!("Not a walnut image"));
+        }
+
+        if fs_version > FS_VERSION {
+            return Err(anyhow!("Unsupported FS version {}", fs_version));
+        }
+
+        // Other block sizes came with version 18
+        if fs_version < 18 && block_size != BLOCK_SIZE {
+            return Err(anyhow!(
+                "Block size {} is not supported by version {}",
+                block_size,
+                fs_version
+            ));
+        }
+
+        let mut sb = Self {
+            magic,
+            fs_version,
+            block_size,
+            group_count,
+            block_count,
+            free_blocks,
+            file_count,
+            created,
+            modified,
+            checksum,
+            label: [0; LABEL_SIZE],
+            uuid: *Uuid::nil().as_bytes(),
+            reserved_percentage: DEFAULT_RESERVED_PERCENTAGE,
+            flags: 0,
+            inode_counts: vec![0; group_count as usize],
+            min_free_inodes: 0,
+            cipher_id: XOR_CIPHER_ID,
+            blocks_per_group: BLOCKS_PER_GROUP,
+            generation: 0,
+            key_check: 0,
+            shared_blocks: 0,
+            original_paths: 0,
+            max_dir_depth: 0,
+            group_checksums: vec![],
+            sequence: 0,
+            quarantine: 0,
+            config: 0,
+        };
+
+        if fs_version >= 2 {
+            sb.label = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 3 {
+            sb.uuid = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 4 {
+            sb.reserved_percentage = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 6 {
+            sb.flags = bincode::deserialize_from(&mut r)?;
+            if sb.flags & !KNOWN_FLAGS != 0 {
+                return Err(anyhow!(
+                    "Unsupported feature flags {:#x}",
+                    sb.flags & !KNOWN_FLAGS
+                ));
+            }
+        }
+        if fs_version >= 7 {
+            sb.inode_counts = bincode::deserialize_from(&mut r)?;
+            sb.min_free_inodes = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 8 {
+            sb.cipher_id = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 9 {
+            sb.blocks_per_group = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 10 {
+            sb.generation = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 11 {
+            sb.key_check = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 12 {
+            sb.shared_blocks = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 13 {
+            sb.original_paths = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 14 {
+            sb.max_dir_depth = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 15 {
+            sb.group_checksums = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 17 {
+            sb.sequence = bincode::deserialize_from(&mut r)?;
+        }
+        if fs_version >= 18 {
+            sb.quarantine = bincode::deserialize_from(&mut r)?;
+            sb.config = bincode::deserialize_from(&mut r)?;
+        }
+
+        Ok(sb)
+    }
+
+    #[inline]
+    fn checksum(&mut self) {
+        self.checksum = 0;
+        let mut hasher = Checksum::new();
+        hasher.update(&self.encode());
+        self.checksum = hasher.finalize();
+    }
+
+    #[inline]
+    fn verify_checksum(&mut self) -> bool {
+        let checksum = self.checksum;
+        self.checksum();
+        let ok = checksum == self.checksum;
+        self.checksum = checksum;
+
+        ok
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Group {
+    pub block_bitmap: BitVec<u8, Lsb0>,
+}
+
+impl Group {
+    fn new(block_bitmap: BitVec<u8, Lsb0>) -> Self {
+        Self { block_bitmap }
+    }
+
+    pub fn init(blocks_per_group: u32) -> Self {
+        let mut block_bitmap =
+            BitVec::<u8, Lsb0>::with_capacity(blocks_per_group as usize);
+        block_bitmap.resize(blocks_per_group as usize, false);
+        Self { block_bitmap }
+    }
+
+    /// Data blocks managed by the group
+    #[inline]
+    pub fn blocks_per_group(&self) -> u32 {
+        self.block_bitmap.len() as u32
+    }
+
+    /// Block index of the group bitmap
+    /// Superblock is block 0, then every group is its bitmap block
+    /// followed by blocks_per_group data blocks
+    #[inline]
+    pub fn bitmap_block(group_index: u32, blocks_per_group: u32) -> u32 {
+        1 + group_index * (blocks_per_group + 1)
+    }
+
+    /// Block index of the first data block of the group
+    /// The only place defining where group data starts
+    #[inline]
+    pub fn first_data_block(group_index: u32, blocks_per_group: u32) -> u32 {
+        Self::bitmap_block(group_index, blocks_per_group) + 1
+    }
+
+    /// Byte position of the group bitmap
+    #[inline]
+    fn seek_position(
+        group_index: u32,
+        blocks_per_group: u32,
+        block_size: u32,
+    ) -> u64 {
+        block_seek_position(
+            Self::bitmap_block(group_index, blocks_per_group),
+            block_size,
+        )
+    }
+
+    #[inline]
+    pub fn create_public_address(
+        group_index: u32,
+        bitmap_index: u32,
+        blocks_per_group: u32,
+    ) -> u32 {
+        Self::first_data_block(group_index, blocks_per_group) + bitmap_index
+    }
+
+    /// Group containing the block, block 0 is the superblock
+    #[inline]
+    pub fn group_of_block(block_index: u32, blocks_per_group: u32) -> u32 {
+        (block_index - 1) / (blocks_per_group + 1)
+    }
+
+    /// Returns (group_index, bitmap_index)
+    #[inline]
+    pub fn translate_public_address(
+        block_index: u32,
+        blocks_per_group: u32,
+    ) -> (u32, u32) {
+        let group_index = Self::group_of_block(block_index, blocks_per_group);
+        let first_data_block =
+            Self::first_data_block(group_index, blocks_per_group);
+        debug_assert!(block_index >= first_data_block);
+        (group_index, block_index - first_data_block)
+    }
+
+    #[inline]
+    pub fn serialize_into<W>(&self, mut w: W) -> Result<()>
+    where
+        W: Write + Seek,
+    {
+        w.write_all(self.block_bitmap.as_raw_slice())?;
+
+        // Buffered writers would drop a write error
+        w.flush()?;
+
+        Ok(())
+    }
+
+    /// Read group bitmap, verified against its checksum if given
+    #[inline]
+    pub fn deserialize_from<R>(
+        mut r: R,
+        group_index: u32,
+        blocks_per_group: u32,
+        block_size: u32,
+        checksum: Option<u32>,
+    ) -> Result<Group>
+    where
+        R: Read + Seek,
+    {
+        let mut buf = vec![0u8; block_size as usize];
+
+        let offset =
+            Self::seek_position(group_index, blocks_per_group, block_size);
+        r.seek(SeekFrom::Start(offset))?;
+        r.read_exact(&mut buf)?;
+        let mut data_bitmap = BitVec::<u8, Lsb0>::from_slice(&buf);
+        data_bitmap.truncate(blocks_per_group as usize);
+
+        let group = Group::new(data_bitmap);
+        if checksum.is_some_and(|checksum| checksum != group.checksum()) {
+            return Err(anyhow!(
+                "Group {} bitmap checksum verification failed",
+                group_index
+            )
+            .into());
+        }
+
+        Ok(group)
+    }
+
+    /// Checksum of the bitmap bytes written to disk
+    /// Kept in the superblock since version 15, the bitmap block
+    /// has no spare bytes when it manages BLOCKS_PER_GROUP blocks
+    #[inline]
+    pub fn checksum(&self) -> u32 {
+        let mut hasher = Checksum::new();
+        hasher.update(self.block_bitmap.as_raw_slice());
+        hasher.finalize()
+    }
+
+    // #[inline]
+    // pub fn has_data_block(&self, i: usize) -> bool {
+    //     self.block_bitmap.get(i - 1).as_deref().unwrap_or(&false) == &true
+    // }
+
+    #[inline]
+    pub fn free_data_blocks(&self) -> usize {
+        self.block_bitmap.count_zeros()
+    }
+
+    #[inline]
+    pub fn total_data_blocks(&self) -> usize {
+        self.block_bitmap.len()
+    }
+
+    #[inline]
+    fn release_one(&mut self, bitmap_index: u32) {
+        self.block_bitmap.set(bitmap_index as usize, false);
+    }
+
+    #[inline]
+    pub fn release_data_region(&mut self, bitmap_index: u32, length: u32) {
+        for i in bitmap_index..(bitmap_index + length) {
+            self.block_bitmap.set(i as usize, false);
+        }
+    }
+
+    /// Set bitmap index by force
+    #[inline]
+    fn force_allocate_at(&mut self, bitmap_index: u32) {
+        // Set it to be taken
+        self.block_bitmap.set(bitmap_index as usize, true);
+    }
+
+    /// Allocate one block
+    #[inline]
+    fn allocate_one(&mut self, group_index: u32) -> Option<u32> {
+        // If we have at least one free block index
+        if let Some(bitmap_index) = self.block_bitmap.iter_zeros().next() {
+            // Set it to be taken
+            self.block_bitmap.set(bitmap_index, true);
+            // Return index as public address
+            return Some(Self::create_public_address(
+                group_index,
+                bitmap_index as u32,
+                self.blocks_per_group(),
+            ));
+        }
+        None
+    }
+
+    /// Maximal runs of free blocks as (bitmap index, length)
+    /// Adjacent free blocks always belong to the same run
+    pub fn free_runs(&self) -> Vec<(u32, u32)> {
+        let mut runs: Vec<(u32, u32)> = vec![];
+        for bitmap_index in self.block_bitmap.iter_zeros() {
+            merge_ranges(&mut runs, &[(bitmap_index as u32, 1)]);
+        }
+        runs
+    }
+
+    /// Allocate the first run of length free blocks
+    /// Returns the public address of its first block
+    fn allocate_run(&mut self, group_index: u32, length: u32) -> Option<u32> {
+        let (start, _) = self
+            .free_runs()
+            .into_iter()
+            .find(|(_, run_length)| *run_length >= length)?;
+        self.block_bitmap[start as usize..(start + length) as usize].fill(true);
+        Some(Self::create_public_address(
+            group_index,
+            start,
+            self.blocks_per_group(),
+        ))
+    }
+
+    /// Allocate data region
+    #[inline]
+    fn allocate_region(
+        &mut self,
+        // to translate internal ID into public address
+        group_index: u32,
+        // Blocks to allocate
+        mut blocks_to_allocate: usize,
+        // Maximum number of region to allocate
+        max_regions: usize,
+    ) -> (Vec<(u32, u32)>, usize) {
+        let mut regions = Vec::new();
+        let mut region: Option<(u32, u32)> = None;
+
+        let blocks_per_group = self.blocks_per_group();
+        let mut iter = self.block_bitmap.iter_mut().enumerate().peekable();
+
+        while let Some((bitmap_index, mut i)) = iter.next() {
+            // Break loop if we dont need more blocks
+            // to allocate
+            if blocks_to_allocate == 0 {
+                // Add opened region to regions if we have one opened
+                if let Some(r) = region.take() {
+                    regions.push(r);
+                }
+                break;
+            }
+
+            // If current block index is free
+            if !*i {
+                // If we have opened region
+                if let Some((_block_index, region_length)) = region.as_mut() {
+                    // Then increment region_length
+                    *region_length += 1;
+                } else {
+                    // Else we need to create a new opened region
+                    region = Some((
+                        Self::create_public_address(
+                            group_index,
+                            bitmap_index as u32,
+                            blocks_per_group,
+                        ),
+                        1,
+                    ));
+                }
+
+                // Decrease blocks number to allocate by one
+                // As we allocate on in this if block
+                blocks_to_allocate -= 1;
+
+                // Set block index as taken
+                i.set(true);
+
+                // If i is taken
+            } else {
+                // Check if we have opened region
+                // and close it
+                if let Some(r) = region.take() {
+                    regions.push(r);
+
+                    // Break loop if we reached the maximum region number
+                    // we dont have room to allocate more regions
+                    if regions.len() == max_regions {
+                        break;
+                    }
+                }
+            }
+
+            // If last item, then clean up
+            if iter.peek().is_none() {
+                // If we have opened region
+                // then close it
+                if let Some(r) = region.take() {
+                    regions.push(r);
+                }
+            }
+        }
+
+        // allocated regions
+        //  |                  remaining blocks to allocate
+        //  |                     |
+        (regions, blocks_to_allocate)
+    }
+
+    // #[inline]
+    // fn next_free_data_region(&self, size: u32) -> Option<(usize, usize)> {
+    //     self.block_bitmap
+    //         .windows(size as usize)
+    //         .position(|p| p.not_any())
+    //         .map(|p| (p + 1, p + size as usize + 1))
+    // }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Inode {
+    pub block_index: u32,
+    pub created: u64,
+    pub last_modified: u64,
+    pub size: u64,
+    pub data_checksum: u32,
+    pub data: Data,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Data {
+    Raw(Vec<u8>),
+    DirectPointers(Vec<(u32, u32)>),
+}
+
+impl Default for Data {
+    fn default() -> Self {
+        Self::Raw(vec![])
+    }
+}
+
+/// Path without its root, prefix, current and parent components
+fn normal_components(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, std::path::Component::Normal(_)))
+        .collect()
+}
+
+/// File name can be created on Windows and unix hosts
+fn portable_name(name: &str) -> bool {
+    const RESERVED: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+
+    if name.is_empty()
+        || name.ends_with(['.', ' '])
+        || name
+            .chars()
+            .any(|c| c.is_control() || "<>:\"/\\|?*".contains(c))
+    {
+        return false;
+    }
+
+    // Device names are reserved with any extension
+    let stem = name.split('.').next().unwrap_or_default().to_uppercase();
+    let device = match stem.as_bytes() {
+        [b'C', b'O', b'M', b'1'..=b'9'] | [b'L', b'P', b'T', b'1'..=b'9'] => {
+            true
+        }
+        _ => RESERVED.contains(&stem.as_str()),
+    };
+    !device
+}
+
+/// Check block size is a power of two the layout supports
+fn check_block_size(block_size: u32) -> anyhow::Result<()> {
+    if !block_size.is_power_of_two()
+        || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+    {
+        return Err(anyhow!(
+            "Unsupported block size {}, expected a power of two between {} and {}",
+            block_size,
+            MIN_BLOCK_SIZE,
+            MAX_BLOCK_SIZE
+        ));
+    }
+    Ok(())
+}
+
+/// Data blocks a group bitmap block can manage
+const fn max_blocks_per_group(block_size: u32) -> u32 {
+    block_size * 8
+}
+
+/// Groups whose per group superblock fields fit into block 0
+/// Every other field has a fixed length in the current layout
+fn max_groups(block_size: u32) -> u32 {
+    let mut sb = Superblock::new(0);
+    let fixed = sb.encode().len();
+    sb.inode_counts.push(0);
+    sb.group_checksums.push(0);
+    let per_group = sb.encode().len() - fixed;
+    ((block_size as usize).saturating_sub(fixed) / per_group) as u32
+}
+
+/// Raw data bytes fitting in an inode block
+/// besides the serialized inode fields
+const fn inode_capacity(block_size: u32) -> usize {
+    block_size as usize - 49
+}
+
+/// Regions fitting in an inode block, 500 for 4KiB blocks
+const fn inode_max_region(block_size: u32) -> usize {
+    (block_size as usize - 96) / 8
+}
+
+/// Check inode index points to a data block of the given groups
+fn check_inode_index(
+    inode_block_index: u32,
+    blocks_per_group: u32,
+    group_count: u32,
+) -> anyhow::Result<()> {
+    let valid =
+        inode_block_index >= Group::first_data_block(0, blocks_per_group) && {
+            let group_index =
+                Group::group_of_block(inode_block_index, blocks_per_group);
+            group_index < group_count
+                && inode_block_index
+                    >= Group::first_data_block(group_index, blocks_per_group)
+        };
+    if !valid {
+        return Err(anyhow!("Invalid inode index {}", inode_block_index));
+    }
+    Ok(())
+}
+
+/// Deserialize directory index from the root inode data
+fn decode_directory_index(
+    mut data: Vec<u8>,
+    compressed: bool,
+) -> anyhow::Result<DirectoryIndex> {
+    // Decompress if index is stored compressed
+    if compressed {
+        data = decompress(&data)?;
+    }
+
+    // Deserialize
+    let mut directory_index: DirectoryIndex = bincode::deserialize(&data)?;
+
+    if !directory_index.verify_checksum() {
+        return Err(anyhow!("Directory index checksum error"));
+    }
+
+    Ok(directory_index)
+}
+
+/// Checksum of the key check bytes encrypted by the cipher
+/// Block 0 is the superblock, it never holds encrypted data
+fn key_check(cipher: &dyn Cipher) -> u32 {
+    let mut bytes = KEY_CHECK_BYTES;
+    cipher.encrypt_block(0, &mut bytes);
+    let mut checksum = Checksum::new();
+    checksum.update(&bytes);
+    checksum.finalize()
+}
+
+/// Append region to direct pointers
+/// Merges holes with holes and blocks with adjacent blocks
+fn push_region(regions: &mut Vec<(u32, u32)>, start: u32, len: u32) {
+    match regions.last_mut() {
+        Some((HOLE, last_len)) if start == HOLE => *last_len += len,
+        Some((last_start, last_len))
+            if *last_start != HOLE
+                && start != HOLE
+                && *last_start + *last_len == start =>
+        {
+            *last_len += len
+        }
+        _ => regions.push((start, len)),
+    }
+}
+
+/// Block holding the n-th block of the data, HOLE for holes
+fn block_at(regions: &[(u32, u32)], n: u64) -> u32 {
+    let mut first = 0;
+    for (start, len) in regions.iter().copied() {
+        if n < first + len as u64 {
+            return match start {
+                HOLE => HOLE,
+                start => start + (n - first) as u32,
+            };
+        }
+        first += len as u64;
+    }
+    HOLE
+}
+
+/// Direct pointers with the n-th block of the data replaced
+fn replace_block(
+    regions: &[(u32, u32)],
+    n: u64,
+    block: u32,
+) -> Vec<(u32, u32)> {
+    let mut replaced = vec![];
+    let mut first = 0;
+    for (start, len) in regions.iter().copied() {
+        let end = first + len as u64;
+        if !(first..end).contains(&n) {
+            push_region(&mut replaced, start, len);
+            first = end;
+            continue;
+        }
+        let before = (n - first) as u32;
+        let after = len - before - 1;
+        if before > 0 {
+            push_region(&mut replaced, start, before);
+        }
+        push_region(&mut replaced, block, 1);
+        if after > 0 {
+            let next = if start == HOLE {
+                HOLE
+            } else {
+                start + before + 1
+            };
+            push_region(&mut replaced, next, after);
+        }
+        first = end;
+    }
+    replaced
+}
+
+/// Direct pointers after moving single data blocks
+fn relocate_regions(
+    regions: &[(u32, u32)],
+    moves: &BTreeMap<u32, u32>,
+) -> Vec<(u32, u32)> {
+    let mut relocated = vec![];
+    for (start, len) in regions.iter().copied() {
+        if start == HOLE || moves.range(start..start + len).next().is_none() {
+            push_region(&mut relocated, start, len);
+            continue;
+        }
+        for block in start..start + len {
+            let target = moves.get(&block).copied().unwrap_or(block);
+            push_region(&mut relocated, target, 1);
+        }
+    }
+    relocated
+}
+
+impl Inode {
+    pub fn new(block_index: u32) -> Self {
+        Self::with_time(block_index, now())
+    }
+
+    /// New inode created at the given time
+    pub fn with_time(block_index: u32, created: u64) -> Self {
+        Self {
+            block_index,
+            created,
+            last_modified: created,
+            size: 0,
+            data_checksum: calculate_checksum(&()),
+            data: Data::Raw(vec![]),
+        }
+    }
+
+    /// Write inode, it has to fit in a block of the given size
+    #[inline]
+    pub fn serialize_into<W>(&self, mut w: W, block_size: u32) -> Result<()>
+    where
+        W: Write + Seek,
+    {
+        // Serialize inode bytes array
+        let serialized = bincode::serialize(&self)?;
+
+        // Check if serialized inode size is correct
+        assert!(serialized.len() as u32 <= block_size);
+
+        // Write serialized inode
+        w.write_all(&serialized)?;
+
+        // Flush buffer
+        w.flush()?;
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn deserialize_from<R>(mut r: R) -> Result<Self>
+    where
+        R: Read + Seek,
+    {
+        let inode: Inode = bincode::deserialize_from(&mut r)?;
+        Ok(inode)
+    }
+
+    #[inline]
+    fn set_last_modified(&mut self, now: u64) {
+        self.last_modified = now;
+    }
+
+    #[inline]
+    fn set_raw_data<R>(
+        &mut self,
+        data: &mut R,
+        data_size: u64,
+        capacity: usize,
+    ) -> anyhow::Result<()>
+    where
+        R: Read,
+    {
+        let mut buffer = vec![];
+        let data_len = data.read_to_end(&mut buffer)?;
+
+        if data_len != data_size as usize {
+            return Err(anyhow!(
+                "Data read and given data size are not the same"
+            ));
+        }
+
+        if data_len > capacity {
+            return Err(anyhow!(
+                "Data is too big to be raw data. Does not fit inside inode"
+            ));
+        }
+
+        self.size = data_size;
+        self.data = Data::Raw(buffer);
+        Ok(())
+    }
+
+    #[inline]
+    fn set_direct_pointers(
+        &mut self,
+        pointers: Vec<(u32, u32)>,
+        data_size: u64,
+    ) {
+        self.data = Data::DirectPointers(pointers);
+        self.size = data_size;
+    }
+
+    /// Data blocks of the inode, holes excluded
+    pub fn data_blocks(&self) -> Vec<u32> {
+        match &self.data {
+            // Raw data is stored in the inode itself
+            Data::Raw(_) => vec![],
+            Data::DirectPointers(pointers) => pointers
+                .iter()
+                .filter(|(start, _)| *start != HOLE)
+                .flat_map(|(start, len)| *start..*start + *len)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct DirectoryIndex {
+    directories: BTreeMap<OsString, u32>,
+    // Since version 5
+    hash: u32, // XOR of directory entry hashes
+    checksum: u32,
+}
+
+impl DirectoryIndex {
+    pub fn init() -> Self {
+        let mut r = Self {
+            directories: BTreeMap::new(),
+            hash: 0,
+            checksum: 0,
+        };
+        r.checksum();
+        r
+    }
+    pub fn find_dir<P>(&self, dir: P) -> Option<&u32>
+    where
+        P: AsRef<Path>,
+    {
+        self.directories.get(dir.as_ref().as_os_str())
+    }
+    pub fn create_dir<P>(&mut self, dir: P, inode_index: u32) -> Option<&u32>
+    where
+        P: AsRef<Path>,
+    {
+        if self.find_dir(&dir).is_some() {
+            return None;
+        }
+        // New directory is empty
+        self.insert_dir(dir.as_ref().as_os_str(), inode_index, 0);
+        self.find_dir(dir)
+    }
+    pub fn remove_dir<P>(&mut self, dir: P, dir_hash: u32) -> Option<u32>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref().as_os_str();
+        let inode_index = self.directories.remove(dir)?;
+        self.hash ^= Self::entry_hash(dir, dir_hash);
+        Some(inode_index)
+    }
+    pub fn move_dir<P>(&mut self, from: P, to: P, dir_hash: u32) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        if self.find_dir(&from).is_none() {
+            return Err(anyhow!("From directory not found").into());
+        }
+        if self.find_dir(&to).is_some() {
+            return Err(anyhow!("Target directory has already exist").into());
+        }
+
+        let dir_inode =
+            self.directories.remove(from.as_ref().as_os_str()).unwrap();
+        self.hash ^= Self::entry_hash(from.as_ref().as_os_str(), dir_hash);
+
+        self.insert_dir(to.as_ref().as_os_str(), dir_inode, dir_hash);
+
+        Ok(())
+    }
+    pub fn directories(&self) -> &BTreeMap<OsString, u32> {
+        &self.directories
+    }
+    /// Point a directory to its moved inode, hashes are not affected
+    fn relink_dir(&mut self, dir: &std::ffi::OsStr, inode_index: u32) {
+        if let Some(index) = self.directories.get_mut(dir) {
+            *index = inode_index;
+        }
+    }
+    /// Inode indices of the indexed directories
+    /// The index itself is stored at the root inode
+    pub fn inode_indices(&self) -> impl Iterator<Item = u32> + '_ {
+        self.directories.values().copied()
+    }
+    /// Paths of directories stored at the same inode
+    /// Every group has at least two paths, in path order
+    pub fn inode_collisions(&self) -> Vec<Vec<PathBuf>> {
+        let mut paths: BTreeMap<u32, Vec<PathBuf>> = BTreeMap::new();
+        for (dir, inode_index) in &self.directories {
+            paths
+                .entry(*inode_index)
+                .or_default()
+                .push(PathBuf::from(dir));
+        }
+        paths
+            .into_values()
+            .filter(|paths| paths.len() > 1)
+            .collect()
+    }
+    /// Combined hash of all directory hashes
+    pub fn hash(&self) -> u32 {
+        self.hash
+    }
+    fn insert_dir(
+        &mut self,
+        dir: &std::ffi::OsStr,
+        inode_index: u32,
+        dir_hash: u32,
+    ) {
+        self.directories.insert(dir.to_os_string(), inode_index);
+        self.hash ^= Self::entry_hash(dir, dir_hash);
+    }
+    /// Replace a directory hash inside the combined hash
+    fn update_dir_hash<P>(&mut self, dir: P, old_hash: u32, new_hash: u32)
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref().as_os_str();
+        self.hash ^= Self::entry_hash(dir, old_hash);
+        self.hash ^= Self::entry_hash(dir, new_hash);
+    }
+    #[inline]
+    fn entry_hash(dir: &std::ffi::OsStr, dir_hash: u32) -> u32 {
+        calculate_checksum(&(dir, dir_hash))
+    }
+    fn checksum(&mut self) {
+        self.checksum = 0;
+        self.checksum = calculate_checksum(&self);
+    }
+
+    fn verify_checksum(&mut self) -> bool {
+        let checksum = self.checksum;
+        self.checksum = 0;
+        let ok = checksum == calculate_checksum(&self);
+        self.checksum = checksum;
+
+        ok
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Directory {
+    pub files: BTreeMap<String, u32>,
+    // Since version 5
+    hash: u32, // XOR of file entry hashes
+    // Since version 16
+    sizes: BTreeMap<String, u64>, // File sizes the entries expect
+    // Since version 17
+    sequence: u64, // Creation sequence of the directory
+    sequences: BTreeMap<String, u64>, // Creation sequence of the files
+    checksum: u32,
+}
+
+impl Directory {
+    fn init() -> Self {
+        let mut dir = Directory {
+            files: BTreeMap::new(),
+            hash: 0,
+            sizes: BTreeMap::new(),
+            sequence: 0,
+            sequences: BTreeMap::new(),
+            checksum: 0,
+        };
+        dir.checksum();
+        dir
+    }
+
+    pub fn get_file(&self, file_name: &str) -> Option<u32> {
+        // self.files.get(file_name).map(|x| *x)
+        self.files.get(file_name).copied()
+    }
+
+    /// Inode indices of the files in the directory
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use walnut::FS;
+    ///
+    /// let path = std::env::temp_dir().join("walnut_doc_inode_indices");
+    /// let mut fs = FS::init(&path, "secret").unwrap();
+    /// fs.create_directory("docs").unwrap();
+    /// fs.add_file("docs", "a", &mut Cursor::new(b"a"), 1).unwrap();
+    /// fs.add_file("docs", "b", &mut Cursor::new(b"b"), 1).unwrap();
+    ///
+    /// // Directory inodes and the file inodes they reference
+    /// let mut referenced = vec![];
+    /// let index = fs.get_directory_index().unwrap();
+    /// for dir in index.directories().keys() {
+    ///     let (directory, _) = fs.find_directory(dir).unwrap();
+    ///     referenced.extend(directory.inode_indices());
+    /// }
+    /// referenced.extend(index.inode_indices());
+    /// assert_eq!(referenced.len(), 3);
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn inode_indices(&self) -> impl Iterator<Item = u32> + '_ {
+        self.files.values().copied()
+    }
+
+    /// Rolling hash of names, inode indices, sizes
+    /// and data checksums of the files
+    pub fn hash(&self) -> u32 {
+        self.hash
+    }
+
+    /// File size recorded when the entry was last written
+    /// None for entries without a recorded size
+    pub fn expected_size(&self, file_name: &str) -> Option<u64> {
+        self.sizes.get(file_name).copied()
+    }
+
+    /// Check file inode against the size its entry expects
+    /// A torn write leaves the inode behind the entry
+    fn check_size(&self, file_name: &str, inode: &Inode) -> Result<()> {
+        match self.expected_size(file_name) {
+            Some(size) if size != inode.size => {
+                Err(WalnutError::IncompleteFile(file_name.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Creation sequence number of a file entry
+    /// 0 for entries created before version 17
+    pub fn sequence(&self, file_name: &str) -> u64 {
+        self.sequences.get(file_name).copied().unwrap_or(0)
+    }
+
+    fn set_sequence(&mut self, file_name: &str, sequence: u64) {
+        if sequence != 0 {
+            self.sequences.insert(file_name.into(), sequence);
+        }
+    }
+
+    pub fn add_file(&mut self, file_name: &str, inode: &Inode) -> Result<()> {
+        match self.get_file(file_name) {
+            Some(_) => Err(WalnutError::FileExists(file_name.to_string())),
+            None => {
+                self.files.insert(file_name.into(), inode.block_index);
+                self.sizes.insert(file_name.into(), inode.size);
+                self.hash ^= Self::entry_hash(file_name, inode);
+                Ok(())
+            }
+        }
+    }
+
+    /// Update hash after file data has been rewritten
+    fn update_file(&mut self, file_name: &str, old: &Inode, new: &Inode) {
+        self.hash ^= Self::entry_hash(file_name, old);
+        self.hash ^= Self::entry_hash(file_name, new);
+        self.sizes.insert(file_name.into(), new.size);
+    }
+
+    fn remove_file(
+        &mut self,
+        file_name: &str,
+        inode: &Inode,
+    ) -> anyhow::Result<()> {
+        match self.files.remove(file_name) {
+            Some(_) => {
+                self.sizes.remove(file_name);
+                self.sequences.remove(file_name);
+                self.hash ^= Self::entry_hash(file_name, inode);
+                Ok(())
+            }
+            None => Err(anyhow!("File not found!")),
+        }
+    }
+
+    #[inline]
+    fn entry_hash(file_name: &str, inode: &Inode) -> u32 {
+        calculate_checksum(&(
+            file_name,
+            inode.block_index,
+            inode.size,
+            inode.data_checksum,
+        ))
+    }
+
+    fn checksum(&mut self) {
+        self.checksum = 0;
+        self.checksum = calculate_checksum(&self);
+    }
+
+    #[allow(dead_code)]
+    fn verify_checksum(&mut self) -> bool {
+        let checksum = self.checksum;
+        self.checksum = 0;
+        let ok = checksum == calculate_checksum(&self);
+        self.checksum = checksum;
+
+        ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    // use std::time::{self, SystemTime};
+
+    pub(crate) const SECRET: &str = "walnut-test-secr";
+
+    /// Image path in the temp dir, removed on drop
+    pub(crate) struct TempImage {
+        path: PathBuf,
+    }
+
+    impl TempImage {
+        pub(crate) fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "walnut-{}-{}.img",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            Self { path }
+        }
+
+        pub(crate) fn path(&self) -> &Path {
+            &self.path
+        }
+    }
+
+    impl Drop for TempImage {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    /// Adds a block dependent byte to every byte
+    #[derive(Debug)]
+    pub(crate) struct AddCipher;
+
+    impl Cipher for AddCipher {
+        fn id(&self) -> u8 {
+            200
+        }
+
+        fn encrypt_block(&self, block_index: u64, buf: &mut [u8]) {
+            buf.iter_mut()
+                .for_each(|b| *b = b.wrapping_add(block_index as u8 | 1));
+        }
+
+        fn decrypt_block(&self, block_index: u64, buf: &mut [u8]) {
+            buf.iter_mut()
+                .for_each(|b| *b = b.wrapping_sub(block_index as u8 | 1));
+        }
+    }
+
+    pub(crate) fn add_bytes(fs: &mut FS, dir: &str, name: &str, data: &[u8]) {
+        fs.add_file(dir, name, &mut Cursor::new(data), data.len() as u64)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_label_persists() {
+        let img = TempImage::new("label_persists");
+        {
+            let fs =
+                FS::init_with_label(img.path(), SECRET, "backup-01").unwrap();
+            assert_eq!(fs.label(), "backup-01");
+        }
+
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.label(), "backup-01");
+
+        fs.set_label("archive").unwrap();
+        assert!(fs.set_label(&"x".repeat(LABEL_SIZE + 1)).is_err());
+        drop(fs);
+
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.label(), "archive");
+    }
+
+    #[test]
+    fn test_open_version_1_superblock() {
+        let img = TempImage::new("open_version_1_superblock");
+        {
+            let mut fs = FS::init(img.path(), SECRET).unwrap();
+            // Rewrite superblock using the original layout
+            fs.superblock.fs_version = 1;
+            fs.save_superblock().unwrap();
+        }
+
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.superblock.fs_version, FS_VERSION);
+        assert_eq!(fs.label(), "");
+        assert!(!fs.uuid().is_nil());
+        drop(fs);
+
+        // Upgraded superblock is readable again
+        FS::new(img.path(), SECRET).unwrap();
+    }
+
+    #[test]
+    fn test_uuid_unique_and_persistent() {
+        let img_a = TempImage::new("uuid_a");
+        let img_b = TempImage::new("uuid_b");
+
+        let uuid_a = FS::init(img_a.path(), SECRET).unwrap().uuid();
+        let uuid_b = FS::init(img_b.path(), SECRET).unwrap().uuid();
+        assert_ne!(uuid_a, uuid_b);
+
+        let mut fs = FS::new(img_a.path(), SECRET).unwrap();
+        assert_eq!(fs.uuid(), uuid_a);
+        assert_eq!(FS::new(img_b.path(), SECRET).unwrap().uuid(), uuid_b);
+
+        let regenerated = fs.regenerate_uuid().unwrap();
+        assert_ne!(regenerated, uuid_a);
+        drop(fs);
+        assert_eq!(FS::new(img_a.path(), SECRET).unwrap().uuid(), regenerated);
+    }
+
+    #[test]
+    fn test_audit_free_space() {
+        let img = TempImage::new("audit_free_space");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "big", &vec![1u8; 3 * BLOCK_SIZE as usize]);
+
+        let (stored, computed) = fs.audit_free_space();
+        assert_eq!(stored, computed);
+
+        // Force drift
+        fs.superblock.free_blocks += 10;
+        let (stored, computed) = fs.audit_free_space();
+        assert_eq!(stored, computed + 10);
+    }
+
+    #[test]
+    fn test_list_by_inode() {
+        let img = TempImage::new("list_by_inode");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        for name in ["zeta", "alpha", "mid"] {
+            add_bytes(&mut fs, "docs", name, name.as_bytes());
+        }
+
+        let files = fs.list_by_inode("docs").unwrap();
+        let names: Vec<&str> = files.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["zeta", "alpha", "mid"]);
+        assert!(files.windows(2).all(|w| w[0].1 < w[1].1));
+    }
+
+    #[test]
+    fn test_reserved_space() {
+        let img = TempImage::new("reserved_space");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        assert_eq!(fs.reserved_percentage(), 5);
+        assert!(fs.reserved_blocks() > 0);
+        assert!(fs
+            .set_reserved_percentage(MAX_RESERVED_PERCENTAGE + 1)
+            .is_err());
+        fs.set_reserved_percentage(10).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Fill the group up to the reserve threshold
+        let to_fill = (fs.free_blocks() - fs.reserved_blocks()) as usize;
+        let start = fs.groups[0].block_bitmap.first_zero().unwrap();
+        fs.groups[0].block_bitmap[start..start + to_fill].fill(true);
+        fs.save_group(fs.groups[0].clone(), 0).unwrap();
+        assert_eq!(fs.free_blocks(), fs.reserved_blocks());
+
+        let data = b"audit log entry";
+        let normal =
+            fs.add_file("docs", "normal", &mut &data[..], data.len() as u64);
+        assert!(normal.is_err());
+        // Data allocation honours the same threshold
+        assert_eq!(fs.allocate_contiguous(1).unwrap(), None);
+
+        fs.add_file_force("docs", "forced", &mut &data[..], data.len() as u64)
+            .unwrap();
+        let mut out = vec![];
+        fs.get_file_data("docs", "forced", &mut out).unwrap();
+        assert_eq!(out, data);
+
+        drop(fs);
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.reserved_percentage(), 10);
+    }
+
+    #[test]
+    fn test_directory_hash() {
+        let img = TempImage::new("directory_hash");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        fs.create_directory("other").unwrap();
+        add_bytes(&mut fs, "docs", "a", b"first");
+
+        let dir_hash = fs.directory_hash("docs").unwrap();
+        let index_hash = fs.index_hash().unwrap();
+        let other_hash = fs.directory_hash("other").unwrap();
+
+        // Stable when nothing changes
+        let mut out = vec![];
+        fs.get_file_data("docs", "a", &mut out).unwrap();
+        drop(fs);
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.directory_hash("docs").unwrap(), dir_hash);
+        assert_eq!(fs.index_hash().unwrap(), index_hash);
+
+        // Adding a file changes the hash
+        add_bytes(&mut fs, "docs", "b", &vec![3u8; 2 * BLOCK_SIZE as usize]);
+        assert_ne!(fs.directory_hash("docs").unwrap(), dir_hash);
+        assert_ne!(fs.index_hash().unwrap(), index_hash);
+        assert_eq!(fs.directory_hash("other").unwrap(), other_hash);
+
+        // Rewriting content changes the hash
+        let with_b = fs.directory_hash("docs").unwrap();
+        add_bytes(&mut fs, "docs", "b", &vec![4u8; 2 * BLOCK_SIZE as usize]);
+        assert_ne!(fs.directory_hash("docs").unwrap(), with_b);
+
+        // Removing the file restores the previous hash
+        fs.remove_file("docs", "b").unwrap();
+        assert_eq!(fs.directory_hash("docs").unwrap(), dir_hash);
+        assert_eq!(fs.index_hash().unwrap(), index_hash);
+    }
+
+    #[test]
+    fn test_index_compression() {
+        let img = TempImage::new("index_compression");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        assert!(!fs.index_compression());
+
+        // Build a large index directly, creating directories
+        // one by one would rewrite the index every time
+        let mut directory_index = fs.get_directory_index().unwrap();
+        for i in 0..5000 {
+            directory_index.insert_dir(
+                std::ffi::OsStr::new(&format!("/projects/2024/dir-{:05}", i)),
+                i,
+                0,
+            );
+        }
+        fs.save_directory_index(directory_index).unwrap();
+
+        let index_blocks =
+            |fs: &FS| match fs.get_inode(ROOT_INODE_INDEX).unwrap().data {
+                Data::Raw(_) => 0,
+                Data::DirectPointers(pointers) => {
+                    pointers.iter().map(|(_, len)| *len).sum::<u32>()
+                }
+            };
+
+        let plain_blocks = index_blocks(&fs);
+        let hash = fs.index_hash().unwrap();
+
+        fs.set_index_compression(true).unwrap();
+        assert!(index_blocks(&fs) < plain_blocks);
+        drop(fs);
+
+        // Round-trips after reopen
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert!(fs.index_compression());
+        let directory_index = fs.get_directory_index().unwrap();
+        assert_eq!(directory_index.directories().len(), 5000);
+        assert_eq!(
+            directory_index.find_dir("/projects/2024/dir-04321"),
+            Some(&4321)
+        );
+        assert_eq!(fs.index_hash().unwrap(), hash);
+
+        // And back
+        fs.set_index_compression(false).unwrap();
+        assert_eq!(index_blocks(&fs), plain_blocks);
+        assert_eq!(fs.get_directory_index().unwrap().directories().len(), 5000);
+    }
+
+    #[test]
+    fn test_temp_file() {
+        let img = TempImage::new("temp_file");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+
+        // First temp file creates the hidden directory
+        fs.create_temp().unwrap().discard().unwrap();
+        let free = fs.audit_free_space().1;
+
+        let name = {
+            let mut temp = fs.create_temp().unwrap();
+            let data = vec![9u8; 3 * BLOCK_SIZE as usize];
+            temp.write(&mut Cursor::new(&data), data.len() as u64)
+                .unwrap();
+
+            let mut out = vec![];
+            temp.read(&mut out).unwrap();
+            assert_eq!(out, data);
+
+            temp.name().to_string()
+        };
+
+        // Dropped handle released inode and blocks
+        assert_eq!(fs.audit_free_space().1, free);
+        let (directory, _) = fs.find_directory(TEMP_DIRECTORY).unwrap();
+        assert!(directory.get_file(&name).is_none());
+    }
+
+    #[test]
+    fn test_min_free_inodes() {
+        let img = TempImage::new("min_free_inodes");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        assert_eq!(fs.inode_count(), 2);
+
+        // Leave one inode above the reserve
+        fs.set_min_free_inodes(fs.free_inodes() - 1).unwrap();
+        add_bytes(&mut fs, "docs", "first", b"1");
+        assert_eq!(fs.free_inodes(), fs.min_free_inodes());
+
+        let data = b"2";
+        let normal =
+            fs.add_file("docs", "second", &mut &data[..], data.len() as u64);
+        assert!(normal.is_err());
+        assert_eq!(fs.free_inodes(), fs.min_free_inodes());
+
+        fs.add_file_force("docs", "second", &mut &data[..], data.len() as u64)
+            .unwrap();
+        assert_eq!(fs.inode_count(), 4);
+
+        // Released inodes are accounted again
+        fs.remove_file("docs", "first").unwrap();
+        assert_eq!(fs.free_inodes(), fs.min_free_inodes());
+        assert_eq!(fs.inode_count(), 3);
+
+        drop(fs);
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.inode_count(), 3);
+        assert_eq!(fs.min_free_inodes(), fs.inodes_per_group() - 3);
+    }
+
+    #[test]
+    fn test_open_metadata_only() {
+        let img = TempImage::new("open_metadata_only");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        add_bytes(
+            &mut fs,
+            "docs",
+            "large",
+            &vec![5u8; 3 * BLOCK_SIZE as usize],
+        );
+        let file_inode = fs.get_file_info("docs", "large").unwrap();
+        let free = fs.audit_free_space();
+        let inode_count = fs.inode_count();
+        drop(fs);
+
+        let mut fs = FS::open_metadata_only(img.path()).unwrap();
+        assert_eq!(fs.mode(), Mode::MetadataOnly);
+        assert_eq!(fs.audit_free_space(), free);
+        assert_eq!(fs.inode_count(), inode_count);
+
+        // Inode structure is readable
+        let inode = fs.inode_info(file_inode.block_index).unwrap();
+        assert_eq!(inode.size, 3 * BLOCK_SIZE as u64);
+        assert_eq!(
+            format!("{:?}", inode.data),
+            format!("{:?}", file_inode.data)
+        );
+
+        // Data is not
+        assert!(fs.get_directory_index().is_err());
+        let mut out = vec![];
+        assert!(fs.get_file_data("docs", "large", &mut out).is_err());
+        assert!(out.is_empty());
+        let data = b"x";
+        assert!(fs
+            .add_file("docs", "new", &mut &data[..], data.len() as u64)
+            .is_err());
+    }
+
+    #[test]
+    fn test_rename_file() {
+        let img = TempImage::new("rename_file");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE).map(|i| i as u8).collect();
+        add_bytes(&mut fs, "docs", "old", &data);
+        add_bytes(&mut fs, "docs", "other", b"other");
+        let inode = fs.get_file_info("docs", "old").unwrap();
+
+        fs.rename_file("docs", "old", "new").unwrap();
+
+        // Same inode, data blocks are not rewritten
+        let renamed = fs.get_file_info("docs", "new").unwrap();
+        assert_eq!(renamed.block_index, inode.block_index);
+        assert_eq!(renamed.data_blocks(), inode.data_blocks());
+        let mut out = vec![];
+        fs.get_file_data("docs", "new", &mut out).unwrap();
+        assert_eq!(out, data);
+        assert!(fs.get_file_info("docs", "old").is_err());
+
+        assert!(fs.rename_file("docs", "old", "again").is_err());
+        assert!(fs.rename_file("docs", "new", "other").is_err());
+    }
+
+    #[test]
+    fn test_rename_matching() {
+        let img = TempImage::new("rename_matching");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "a.tmp", b"first");
+        add_bytes(&mut fs, "docs", "b.tmp", &vec![2u8; 5000]);
+        add_bytes(&mut fs, "docs", "c.txt", b"third");
+
+        let renamed = fs.rename_matching("docs", "*.tmp", "*.bak").unwrap();
+        assert_eq!(renamed, 2);
+
+        let (directory, _) = fs.find_directory("docs").unwrap();
+        let names: Vec<&String> = directory.files.keys().collect();
+        assert_eq!(names, vec!["a.bak", "b.bak", "c.txt"]);
+
+        // Data is untouched
+        let mut out = vec![];
+        fs.get_file_data("docs", "a.bak", &mut out).unwrap();
+        assert_eq!(out, b"first");
+        let mut out = vec![];
+        fs.get_file_data("docs", "b.bak", &mut out).unwrap();
+        assert_eq!(out, vec![2u8; 5000]);
+
+        // Collision rejects every rename
+        add_bytes(&mut fs, "docs", "a.tmp", b"again");
+        add_bytes(&mut fs, "docs", "d.tmp", b"fourth");
+        let hash = fs.directory_hash("docs").unwrap();
+        assert!(fs.rename_matching("docs", "*.tmp", "*.bak").is_err());
+        let (directory, _) = fs.find_directory("docs").unwrap();
+        assert!(directory.get_file("d.tmp").is_some());
+        assert_eq!(fs.directory_hash("docs").unwrap(), hash);
+
+        // Targets colliding with each other are rejected as well
+        assert!(fs.rename_matching("docs", "?.*", "same").is_err());
+    }
+
+    #[test]
+    fn test_open_with_params() {
+        let img = TempImage::new("open_with_params");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "a", b"first");
+        add_bytes(&mut fs, "docs", "b", &vec![2u8; 5000]);
+        let free = fs.audit_free_space().1;
+        let inode_count = fs.inode_count();
+
+        // Destroy superblock
+        fs.file.seek(SeekFrom::Start(0)).unwrap();
+        fs.file.write_all(&[0; BLOCK_SIZE as usize]).unwrap();
+        drop(fs);
+        assert!(FS::new(img.path(), SECRET).is_err());
+
+        assert!(FS::open_with_params(img.path(), SECRET, 2 * BLOCK_SIZE, 1)
+            .is_err());
+        assert!(FS::open_with_params(
+            img.path(),
+            "wrong-secret-16b",
+            BLOCK_SIZE,
+            1
+        )
+        .is_err());
+
+        let fs = FS::open_with_params_and_clock(
+            img.path(),
+            SECRET,
+            BLOCK_SIZE,
+            1,
+            Box::new(FixedClock(1_000_000)),
+        )
+        .unwrap();
+        assert_eq!(fs.audit_free_space(), (free, free));
+        assert_eq!(fs.inode_count(), inode_count);
+        assert_eq!(fs.superblock.file_count, 2);
+        assert_eq!(fs.superblock.created, 1_000_000);
+        assert_eq!(fs.superblock.modified, 1_000_000);
+        drop(fs);
+
+        // Fresh superblock is on disk
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        let mut out = vec![];
+        fs.get_file_data("docs", "b", &mut out).unwrap();
+        assert_eq!(out, vec![2u8; 5000]);
+    }
+
+    #[test]
+    fn test_diff() {
+        let base_img = TempImage::new("diff_base");
+        let img = TempImage::new("diff_current");
+        let mut base = FS::init(base_img.path(), SECRET).unwrap();
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+
+        for fs in [&mut base, &mut fs] {
+            fs.create_directory("docs").unwrap();
+            fs.create_directory("docs/sub").unwrap();
+            add_bytes(fs, "docs", "same", b"unchanged");
+            add_bytes(fs, "docs", "changed", &vec![1u8; 5000]);
+            add_bytes(fs, "docs/sub", "removed", b"old");
+        }
+
+        add_bytes(&mut fs, "docs", "changed", &vec![2u8; 5000]);
+        fs.remove_file("docs/sub", "removed").unwrap();
+        add_bytes(&mut fs, "docs/sub", "added", b"new");
+
+        let diff = fs.diff(&mut base).unwrap();
+        assert_eq!(diff.added, vec![PathBuf::from("docs/sub/added")]);
+        assert_eq!(diff.removed, vec![PathBuf::from("docs/sub/removed")]);
+        assert_eq!(diff.modified, vec![PathBuf::from("docs/changed")]);
+
+        // Same image has no diff
+        let mut again = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.diff(&mut again).unwrap(), Diff::default());
+    }
+
+    #[test]
+    fn test_throttle() {
+        let img = TempImage::new("throttle");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // 256KiB at 1MiB/s takes at least 250ms
+        fs.set_throttle(Some(1024 * 1024));
+        assert_eq!(fs.throttle(), Some(1024 * 1024));
+        let data = vec![1u8; 256 * 1024];
+        let start = std::time::Instant::now();
+        add_bytes(&mut fs, "docs", "large", &data);
+        assert!(start.elapsed() >= std::time::Duration::from_millis(250));
+
+        fs.set_throttle(None);
+        let mut out = vec![];
+        fs.get_file_data("docs", "large", &mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_bytes_counters() {
+        let block = BLOCK_SIZE as u64;
+        let img = TempImage::new("bytes_counters");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        assert_eq!((fs.bytes_read(), fs.bytes_written()), (0, 0));
+
+        // The last block holds only the rest of the data
+        let data = vec![1u8; (2 * block + 100) as usize];
+        add_bytes(&mut fs, "docs", "large", &data);
+        assert_eq!(fs.bytes_written(), data.len() as u64);
+        assert_eq!(fs.bytes_read(), 0);
+
+        // Raw data inside the inode is not counted
+        add_bytes(&mut fs, "docs", "small", b"small");
+        assert_eq!(fs.bytes_written(), data.len() as u64);
+
+        let mut out = vec![];
+        fs.get_file_data("docs", "large", &mut out).unwrap();
+        fs.get_file_data("docs", "large", &mut out).unwrap();
+        assert_eq!(fs.bytes_read(), 2 * data.len() as u64);
+
+        // Counters start over with a new handle
+        drop(fs);
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!((fs.bytes_read(), fs.bytes_written()), (0, 0));
+    }
+
+    #[test]
+    fn test_verify_against() {
+        let img = TempImage::new("verify_against");
+        let host = TempImage::new("verify_against_host");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect();
+        add_bytes(&mut fs, "docs", "large", &data);
+
+        let mut file = File::create(host.path()).unwrap();
+        fs.get_file_data("docs", "large", &mut file).unwrap();
+        drop(file);
+        assert!(fs.verify_against("docs", "large", host.path()).unwrap());
+
+        // Modify host copy
+        let mut changed = data.clone();
+        changed[5000] ^= 1;
+        std::fs::write(host.path(), &changed).unwrap();
+        assert!(!fs.verify_against("docs", "large", host.path()).unwrap());
+        assert_eq!(
+            fs.first_mismatch("docs", "large", host.path()).unwrap(),
+            Some(5000)
+        );
+
+        // Length differences count as mismatch
+        std::fs::write(host.path(), &data[..100]).unwrap();
+        assert_eq!(
+            fs.first_mismatch("docs", "large", host.path()).unwrap(),
+            Some(100)
+        );
+        let mut longer = data.clone();
+        longer.push(0);
+        std::fs::write(host.path(), &longer).unwrap();
+        assert_eq!(
+            fs.first_mismatch("docs", "large", host.path()).unwrap(),
+            Some(data.len() as u64)
+        );
+    }
+
+    /// Reader failing after the given bytes, simulating a crash
+    struct FailingReader {
+        data: Vec<u8>,
+        position: usize,
+        fail_at: usize,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.position >= self.fail_at {
+                return Err(std::io::Error::other("Simulated crash"));
+            }
+            let end = self.fail_at.min(self.position + buf.len());
+            let n = end - self.position;
+            buf[..n].copy_from_slice(&self.data[self.position..end]);
+            self.position = end;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let img = TempImage::new("checkpoint");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let a = vec![1u8; 5 * BLOCK_SIZE as usize];
+        let b = b"small".to_vec();
+        add_bytes(&mut fs, "docs", "a", &a);
+        add_bytes(&mut fs, "docs", "b", &b);
+        fs.checkpoint().unwrap();
+        let free = fs.audit_free_space().1;
+
+        // Writes after the checkpoint are interrupted
+        let failing = |len: usize| {
+            BufReader::new(FailingReader {
+                data: vec![7u8; len],
+                position: 0,
+                fail_at: 2 * BLOCK_SIZE as usize + 10,
+            })
+        };
+        let len = 4 * BLOCK_SIZE as u64;
+        assert!(fs
+            .add_file("docs", "a", &mut failing(len as usize), len)
+            .is_err());
+        assert!(fs
+            .add_file("docs", "c", &mut failing(len as usize), len)
+            .is_err());
+        drop(fs);
+
+        // Checkpointed state is fully readable
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        let mut out = vec![];
+        fs.get_file_data("docs", "a", &mut out).unwrap();
+        assert_eq!(out, a);
+        let mut out = vec![];
+        fs.get_file_data("docs", "b", &mut out).unwrap();
+        assert_eq!(out, b);
+        assert!(fs.get_file_info("docs", "c").is_err());
+        assert_eq!(fs.audit_free_space().1, free);
+    }
+
+    #[test]
+    fn test_list_detailed() {
+        let img = TempImage::new("list_detailed");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        fs.create_directory("docs/sub").unwrap();
+        add_bytes(&mut fs, "docs", "small", b"small file");
+        add_bytes(
+            &mut fs,
+            "docs",
+            "large",
+            &vec![1u8; 3 * BLOCK_SIZE as usize],
+        );
+        add_bytes(&mut fs, "docs/sub", "nested", b"not listed");
+
+        let entries = fs.list_detailed("docs").unwrap();
+        let summary: Vec<(&str, u64, EntryKind)> = entries
+            .iter()
+            .map(|e| (e.name.as_str(), e.size, e.kind))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("large", 3 * BLOCK_SIZE as u64, EntryKind::File),
+                ("small", 10, EntryKind::File),
+                ("sub", entries[2].size, EntryKind::Directory),
+            ]
+        );
+
+        let info = fs.get_file_info("docs", "large").unwrap();
+        assert_eq!(entries[0].inode, info.block_index);
+        assert_eq!(entries[0].modified, info.last_modified);
+    }
+
+    #[test]
+    fn test_invalid_inode_index() {
+        let img = TempImage::new("invalid_inode_index");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+
+        // Corrupt index entry pointing past the image
+        let mut directory_index = fs.get_directory_index().unwrap();
+        directory_index.insert_dir(std::ffi::OsStr::new("bad"), 10_000_000, 0);
+        fs.save_directory_index(directory_index).unwrap();
+
+        let err = fs.find_directory("bad").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid inode index 10000000");
+
+        // Superblock and bitmap blocks are not inodes either
+        assert!(fs.inode_info(0).is_err());
+        assert!(fs.inode_info(1).is_err());
+        assert!(fs
+            .inode_info(Group::bitmap_block(1, BLOCKS_PER_GROUP))
+            .is_err());
+        assert!(fs.inode_info(ROOT_INODE_INDEX).is_ok());
+    }
+
+    #[test]
+    fn test_pin_file() {
+        let img = TempImage::new("pin_file");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let hot = vec![1u8; 4 * BLOCK_SIZE as usize];
+        add_bytes(&mut fs, "docs", "hot", &hot);
+        for i in 0..4 {
+            add_bytes(&mut fs, "docs", &format!("cold{}", i), &vec![2u8; 8000]);
+        }
+
+        fs.set_cache_capacity(4);
+        fs.set_pin_budget(4 * BLOCK_SIZE as u64);
+        assert!(fs.pin_file("docs", "cold0").is_ok());
+        assert!(fs.pin_file("docs", "hot").is_err());
+        fs.unpin_file("docs", "cold0").unwrap();
+        fs.pin_file("docs", "hot").unwrap();
+        assert_eq!(fs.pinned_bytes(), 4 * BLOCK_SIZE as u64);
+
+        // Flood the cache with other reads
+        for i in 0..4 {
+            let mut out = vec![];
+            fs.get_file_data("docs", &format!("cold{}", i), &mut out)
+                .unwrap();
+            assert_eq!(out, vec![2u8; 8000]);
+        }
+
+        let hot_blocks: Vec<u32> =
+            match fs.get_file_info("docs", "hot").unwrap().data {
+                Data::DirectPointers(pointers) => pointers
+                    .iter()
+                    .flat_map(|(start, len)| *start..*start + *len)
+                    .collect(),
+                Data::Raw(_) => panic!("Hot file stored raw"),
+            };
+        assert!(hot_blocks.iter().all(|b| fs.is_block_cached(*b)));
+
+        let mut out = vec![];
+        fs.get_file_data("docs", "hot", &mut out).unwrap();
+        assert_eq!(out, hot);
+
+        // Unpinned blocks can be evicted again
+        fs.unpin_file("docs", "hot").unwrap();
+        fs.set_cache_capacity(0);
+        assert!(hot_blocks.iter().all(|b| !fs.is_block_cached(*b)));
+    }
+
+    #[test]
+    fn test_max_files() {
+        let img = TempImage::new("max_files");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+
+        // Inode slots are the limit, root index holds one
+        assert_eq!(fs.max_files(), fs.inodes_per_group() - 1);
+
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "a", b"first");
+        add_bytes(&mut fs, "docs", "b", b"second");
+        assert_eq!(fs.superblock.file_count, 2);
+        assert_eq!(fs.max_files(), 2 + fs.inodes_per_group() - 4);
+
+        fs.set_min_free_inodes(100).unwrap();
+        assert_eq!(fs.max_files(), 2 + fs.inodes_per_group() - 4 - 100);
+
+        // Blocks are the limit, reserved ones excluded
+        let to_fill = (fs.free_blocks() - fs.reserved_blocks() - 50) as usize;
+        let start = fs.groups[0].block_bitmap.first_zero().unwrap();
+        fs.groups[0].block_bitmap[start..start + to_fill].fill(true);
+        assert_eq!(fs.max_files(), 2 + 50);
+    }
+
+    /// Reader without length, readable once
+    struct OneShotReader {
+        data: Vec<u8>,
+        position: usize,
+    }
+
+    impl Read for OneShotReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            // Return odd sized chunks like a pipe would
+            let n = buf.len().min(1000).min(self.data.len() - self.position);
+            buf[..n].copy_from_slice(&self.data[self.position..][..n]);
+            self.position += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_add_file_streaming() {
+        let img = TempImage::new("add_file_streaming");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Spans several chunks, last one partial
+        let len = (2 * STREAM_CHUNK_BLOCKS + 3) * BLOCK_SIZE as u64 + 77;
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let small = b"small streamed file".to_vec();
+
+        for (name, data) in [("large", &data), ("small", &small)] {
+            let mut r = OneShotReader {
+                data: data.clone(),
+                position: 0,
+            };
+            let (written, crc) =
+                fs.add_file_streaming("docs", name, &mut r).unwrap();
+            assert_eq!(written, data.len() as u64);
+            assert_eq!(crc, crc32fast::hash(data));
+
+            let inode = fs.get_file_info("docs", name).unwrap();
+            assert_eq!(inode.size, data.len() as u64);
+            assert_eq!(inode.data_checksum, crc);
+
+            let mut out = vec![];
+            fs.get_file_data("docs", name, &mut out).unwrap();
+            assert_eq!(&out, data);
+        }
+
+        // Chunks allocated one after another form one region
+        match fs.get_file_info("docs", "large").unwrap().data {
+            Data::DirectPointers(pointers) => assert_eq!(pointers.len(), 1),
+            Data::Raw(_) => panic!("Large file stored raw"),
+        }
+    }
+
+    #[test]
+    fn test_reformat_groups() {
+        let img = TempImage::new("reformat_groups");
+        let mut fs =
+            FS::init_with_label(img.path(), SECRET, "resized").unwrap();
+        fs.create_directory("docs").unwrap();
+        fs.create_directory("empty").unwrap();
+
+        let large: Vec<u8> =
+            (0..1500 * BLOCK_SIZE).map(|i| (i % 253) as u8).collect();
+        add_bytes(&mut fs, "docs", "large", &large);
+        add_bytes(&mut fs, "docs", "small", b"small file");
+        fs.set_original_path("docs", "small", "home/small.txt")
+            .unwrap();
+        fs.set_max_directory_depth(4).unwrap();
+        fs.set_reserved_percentage(10).unwrap();
+        let uuid = fs.uuid();
+
+        assert!(fs.reformat_groups(100).is_err());
+        assert!(fs.reformat_groups(BLOCKS_PER_GROUP * 2).is_err());
+        fs.reformat_groups(1024).unwrap();
+        assert_eq!(fs.blocks_per_group(), 1024);
+        assert_eq!(
+            fs.original_path("docs", "small").unwrap(),
+            Some(PathBuf::from("home/small.txt"))
+        );
+
+        drop(fs);
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.blocks_per_group(), 1024);
+        assert_eq!(fs.label(), "resized");
+        assert_eq!(fs.uuid(), uuid);
+        assert_eq!(fs.superblock.max_dir_depth, 4);
+        assert_eq!(fs.reserved_percentage(), 10);
+        assert_eq!(
+            fs.original_path("docs", "small").unwrap(),
+            Some(PathBuf::from("home/small.txt"))
+        );
+        assert!(fs.fsck().unwrap().is_clean());
+        assert!(fs.groups.len() > 1);
+        assert_eq!(
+            std::fs::metadata(img.path()).unwrap().len(),
+            (BLOCK_SIZE + fs.groups.len() as u32 * 1025 * BLOCK_SIZE) as u64
+        );
+
+        for (dir, name, data) in [
+            ("docs", "large", &large[..]),
+            ("docs", "small", b"small file"),
+        ] {
+            let mut out = vec![];
+            fs.get_file_data(dir, name, &mut out).unwrap();
+            assert_eq!(out, data);
+        }
+        assert!(fs.list_files_recursive("/empty").unwrap().is_empty());
+        assert!(fs.find_directory("empty").is_ok());
+
+        // Clones of an in memory image see the new layout
+        let mut fs = FS::init_in_memory(SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "small", b"small file");
+        let storage = fs.file.try_clone().unwrap();
+        fs.reformat_groups(1024).unwrap();
+        let mut fs = FS::from_storage(storage, SECRET).unwrap();
+        assert_eq!(fs.blocks_per_group(), 1024);
+        let mut out = vec![];
+        fs.get_file_data("docs", "small", &mut out).unwrap();
+        assert_eq!(out, b"small file");
+    }
+
+    #[test]
+    fn test_reformat_groups_failure() {
+        let img = TempImage::new("reformat_groups_failure");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "good", b"readable");
+        let data = vec![7u8; 3 * BLOCK_SIZE as usize];
+        add_bytes(&mut fs, "docs", "bad", &data);
+
+        // Damaged file cannot be copied
+        let inode = fs.get_file_info("docs", "bad").unwrap();
+        let block = match &inode.data {
+            Data::DirectPointers(pointers) => pointers[0].0,
+            Data::Raw(_) => panic!("File data should be in blocks"),
+        };
+        let pos = block_seek_position(block, BLOCK_SIZE);
+        (&fs.file).seek(SeekFrom::Start(pos)).unwrap();
+        (&fs.file).write_all(b"garbage").unwrap();
+        let image = std::fs::read(img.path()).unwrap();
+
+        assert!(fs.reformat_groups(1024).is_err());
+
+        // Old image is untouched and no sibling is left behind
+        assert_eq!(std::fs::read(img.path()).unwrap(), image);
+        let dir = img.path().parent().unwrap();
+        let name = img.path().file_name().unwrap().to_string_lossy();
+        let leftovers = std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                let file_name = e.file_name().to_string_lossy().into_owned();
+                file_name.starts_with(&format!(".{}.", name))
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+
+        let mut out = vec![];
+        fs.get_file_data("docs", "good", &mut out).unwrap();
+        assert_eq!(out, b"readable");
+        drop(fs);
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.blocks_per_group(), BLOCKS_PER_GROUP);
+    }
+
+    /// Device failing with TimedOut a given number of times
+    struct FlakyDevice {
+        inner: Cursor<Vec<u8>>,
+        failures: u32,
+    }
+
+    impl FlakyDevice {
+        fn fail(&mut self) -> std::io::Result<()> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                // Partial progress before failing
+                self.inner.set_position(self.inner.position() + 1);
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            Ok(())
+        }
+    }
+
+    impl Read for FlakyDevice {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.fail()?;
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for FlakyDevice {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.fail()?;
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FlakyDevice {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_retry_transient_errors() {
+        let retry = RetryPolicy::new(3, std::time::Duration::from_millis(1));
+        let block = vec![5u8; BLOCK_SIZE as usize];
+        let pos = BLOCK_SIZE as u64;
+
+        let mut device = FlakyDevice {
+            inner: Cursor::new(vec![0; 2 * BLOCK_SIZE as usize]),
+            failures: 2,
+        };
+        device.seek(SeekFrom::Start(pos)).unwrap();
+        write_all_at(&mut device, pos, &block, &retry).unwrap();
+
+        device.failures = 2;
+        device.seek(SeekFrom::Start(pos)).unwrap();
+        let mut out = vec![0; BLOCK_SIZE as usize];
+        read_exact_at(&mut device, pos, &mut out, &retry).unwrap();
+        assert_eq!(out, block);
+
+        // Gives up after the last attempt
+        device.failures = 3;
+        device.seek(SeekFrom::Start(pos)).unwrap();
+        let err = read_exact_at(&mut device, pos, &mut out, &retry);
+        assert_eq!(err.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+
+        // Image operations keep working with a policy set
+        let img = TempImage::new("retry_transient_errors");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        assert_eq!(fs.retry_policy(), RetryPolicy::default());
+        fs.set_retry_policy(retry);
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "block", &block);
+        let mut out = vec![];
+        fs.get_file_data("docs", "block", &mut out).unwrap();
+        assert_eq!(out, block);
+    }
+
+    #[test]
+    fn test_restore_directory() {
+        let img = TempImage::new("restore_directory");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        let large: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect();
+        add_bytes(&mut fs, "docs", "small", b"small file");
+        add_bytes(&mut fs, "docs", "large", &large);
+        add_bytes(&mut fs, "docs", "kept", b"kept file");
+        let snapshot = fs.snapshot_directory("docs").unwrap();
+        let free = fs.free_blocks();
+        let hash = fs.directory_hash("docs").unwrap();
+
+        // Add, remove and rewrite files
+        // without allocating the released blocks again
+        add_bytes(&mut fs, "docs", "new", b"new file");
+        fs.remove_file("docs", "small").unwrap();
+        add_bytes(&mut fs, "docs", "large", &[1u8; 2 * BLOCK_SIZE as usize]);
+
+        fs.restore_directory(&snapshot).unwrap();
+        assert_eq!(fs.free_blocks(), free);
+        assert_eq!(fs.directory_hash("docs").unwrap(), hash);
+        assert_eq!(fs.superblock.file_count, 3);
+
+        drop(fs);
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        let (directory, _) = fs.find_directory("docs").unwrap();
+        assert_eq!(directory.files.len(), 3);
+        assert_eq!(fs.superblock.file_count, 3);
+        for (name, data) in [
+            ("small", &b"small file"[..]),
+            ("large", &large[..]),
+            ("kept", b"kept file"),
+        ] {
+            let mut out = vec![];
+            fs.get_file_data("docs", name, &mut out).unwrap();
+            assert_eq!(out, data);
+        }
+
+        // Blocks allocated again cannot be restored
+        let snapshot = fs.snapshot_directory("docs").unwrap();
+        fs.remove_file("docs", "large").unwrap();
+        add_bytes(&mut fs, "docs", "other", &large);
+        assert!(fs.restore_directory(&snapshot).is_err());
+        assert!(fs.get_file_info("docs", "large").is_err());
+    }
+
+    #[test]
+    fn test_inode_block_map() {
+        let img = TempImage::new("inode_block_map");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Leave a gap, so the next file gets two regions
+        let two_blocks = vec![3u8; 2 * BLOCK_SIZE as usize];
+        add_bytes(&mut fs, "docs", "a", &two_blocks);
+        add_bytes(&mut fs, "docs", "b", &two_blocks);
+        add_bytes(&mut fs, "docs", "c", &two_blocks);
+        fs.remove_file("docs", "b").unwrap();
+        add_bytes(
+            &mut fs,
+            "docs",
+            "large",
+            &vec![4u8; 5 * BLOCK_SIZE as usize],
+        );
+
+        let inode = fs.get_file_info("docs", "large").unwrap();
+        let regions = match &inode.data {
+            Data::DirectPointers(pointers) => pointers.clone(),
+            Data::Raw(_) => panic!("Large file stored raw"),
+        };
+        assert!(regions.len() > 1);
+
+        let map = fs.inode_block_map(inode.block_index).unwrap();
+        let total: u32 = regions.iter().map(|(_, len)| *len).sum();
+        assert_eq!(map.len(), total as usize);
+        assert!(map.windows(2).all(|w| w[0] < w[1]));
+        for (start, len) in regions {
+            assert!((start..start + len).all(|b| map.contains(&b)));
+        }
+
+        // Raw data occupies no data blocks
+        let a = fs.get_file_info("docs", "a").unwrap();
+        add_bytes(&mut fs, "docs", "small", b"small");
+        let small_raw = fs.get_file_info("docs", "small").unwrap();
+        assert_eq!(fs.inode_block_map(a.block_index).unwrap().len(), 2);
+        assert!(fs
+            .inode_block_map(small_raw.block_index)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_fixed_clock() {
+        let build = |name: &str| {
+            let img = TempImage::new(name);
+            let mut fs = FS::init_with_clock(
+                img.path(),
+                "archive",
+                Box::new(XorCipher::new(SECRET)),
+                Box::new(FixedClock(1_000_000)),
+            )
+            .unwrap();
+            fs.create_directory("docs").unwrap();
+            add_bytes(&mut fs, "docs", "small", b"small file");
+            add_bytes(
+                &mut fs,
+                "docs",
+                "large",
+                &[1u8; 2 * BLOCK_SIZE as usize],
+            );
+
+            drop(fs);
+            let fs = FS::new(img.path(), SECRET).unwrap();
+            let timestamps: Vec<(PathBuf, u64, u64)> = fs
+                .list_files_recursive("")
+                .unwrap()
+                .into_iter()
+                .map(|(path, inode)| (path, inode.created, inode.last_modified))
+                .collect();
+            let index = fs.inode_info(ROOT_INODE_INDEX).unwrap();
+            (
+                fs.superblock.created,
+                fs.superblock.modified,
+                index.created,
+                index.last_modified,
+                timestamps,
+            )
+        };
+
+        let first = build("fixed_clock_1");
+        let second = build("fixed_clock_2");
+        assert_eq!(first.0, 1_000_000);
+        assert_eq!(first.1, 1_000_000);
+        assert_eq!(first.4.len(), 2);
+        assert!(first.4.iter().all(|(_, c, m)| *c == 1_000_000 && *m == *c));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_block_size_mismatch() {
+        let img = TempImage::new("block_size_mismatch");
+        {
+            let mut fs = FS::init(img.path(), SECRET).unwrap();
+            fs.create_directory("docs").unwrap();
+            add_bytes(&mut fs, "docs", "small", b"small file");
+
+            // Stored with a valid checksum
+            fs.superblock.block_size = 3 * BLOCK_SIZE;
+            fs.save_superblock().unwrap();
+        }
+
+        let err = FS::new(img.path(), SECRET).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Unsupported block size {}, expected a power of two between {} and {}",
+                3 * BLOCK_SIZE,
+                MIN_BLOCK_SIZE,
+                MAX_BLOCK_SIZE
+            )
+        );
+        assert!(FS::open_metadata_only(img.path()).is_err());
+    }
+
+    #[test]
+    fn test_list_ordered() {
+        let img = TempImage::new("list_ordered");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        for name in ["file10", "file2", "file1", "file20b", "file20a"] {
+            add_bytes(&mut fs, "docs", name, name.as_bytes());
+        }
+        fs.create_directory("docs/file3").unwrap();
+
+        let names = |fs: &mut FS, order| -> Vec<String> {
+            fs.list_ordered("docs", order)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.name)
+                .collect()
+        };
+        assert_eq!(
+            names(&mut fs, ListOrder::Natural),
+            ["file1", "file2", "file3", "file10", "file20a", "file20b"]
+        );
+        assert_eq!(
+            names(&mut fs, ListOrder::Lexical),
+            ["file1", "file10", "file2", "file20a", "file20b", "file3"]
+        );
+        assert_eq!(
+            names(&mut fs, ListOrder::Creation),
+            ["file10", "file2", "file1", "file20b", "file20a", "file3"]
+        );
+        assert_eq!(
+            fs.list_detailed("docs").unwrap(),
+            fs.list_ordered("docs", ListOrder::Lexical).unwrap()
+        );
+
+        // Released inodes are reused within the same second
+        fs.create_directory("logs").unwrap();
+        add_bytes(&mut fs, "logs", "first", b"first");
+        add_bytes(&mut fs, "logs", "second", b"second");
+        fs.remove_file("logs", "first").unwrap();
+        add_bytes(&mut fs, "logs", "third", b"third");
+        fs.rename_file("logs", "second", "renamed").unwrap();
+        let order: Vec<String> = fs
+            .list_ordered("logs", ListOrder::Creation)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(order, ["renamed", "third"]);
+    }
+
+    #[test]
+    fn test_sparsify() {
+        let img = TempImage::new("sparsify");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let empty_free = fs.free_blocks();
+
+        // data, 5 zero blocks, data, zero partial last block
+        let block = BLOCK_SIZE as usize;
+        let mut data = vec![7u8; 2 * block];
+        data.extend(vec![0; 5 * block]);
+        data.extend(vec![9u8; block]);
+        data.extend(vec![0; 100]);
+        add_bytes(&mut fs, "docs", "sparse", &data);
+        let crc = fs.get_file_info("docs", "sparse").unwrap().data_checksum;
+        let dense_free = fs.free_blocks();
+
+        assert_eq!(fs.superblock.flags & FLAG_SPARSE, 0);
+        assert_eq!(fs.sparsify("docs", "sparse").unwrap(), 6);
+        assert_eq!(fs.free_blocks(), dense_free + 6);
+        assert_ne!(fs.superblock.flags & FLAG_SPARSE, 0);
+        assert_eq!(fs.sparsify("docs", "sparse").unwrap(), 0);
+
+        drop(fs);
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        let mut out = vec![];
+        assert_eq!(fs.get_file_data("docs", "sparse", &mut out).unwrap(), crc);
+        assert_eq!(out, data);
+        assert_eq!(
+            fs.space_breakdown().unwrap().file_data,
+            3 * BLOCK_SIZE as u64
+        );
+
+        // Holes release nothing on removal
+        fs.remove_file("docs", "sparse").unwrap();
+        assert_eq!(fs.free_blocks(), empty_free);
+    }
+
+    #[test]
+    fn test_unknown_flags() {
+        let img = TempImage::new("unknown_flags");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.superblock.flags |= KNOWN_FLAGS + 1;
+        fs.save_superblock().unwrap();
+        drop(fs);
+
+        // Flags of a newer tool change the meaning of the image
+        assert!(FS::new(img.path(), SECRET).is_err());
+    }
+
+    /// Writer asserting every write fits into one block
+    struct ChunkSink {
+        written: u64,
+        max_chunk: usize,
+    }
+
+    impl Write for ChunkSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            assert!(buf.len() <= BLOCK_SIZE as usize);
+            self.max_chunk = self.max_chunk.max(buf.len());
+            self.written += buf.len() as u64;
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_streaming_read_bounded() {
+        let img = TempImage::new("streaming_read_bounded");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // 8MiB file streamed in without buffering it
+        let size = 8 * 1024 * 1024 + 123;
+        let mut data = BufReader::new(std::io::repeat(7).take(size));
+        fs.add_file("docs", "large", &mut data, size).unwrap();
+
+        let mut sink = ChunkSink {
+            written: 0,
+            max_chunk: 0,
+        };
+        fs.get_file_data("docs", "large", &mut sink).unwrap();
+        assert_eq!(sink.written, size);
+        assert_eq!(sink.max_chunk, BLOCK_SIZE as usize);
+    }
+
+    #[test]
+    fn test_space_breakdown() {
+        let img = TempImage::new("space_breakdown");
+        {
+            let mut fs = FS::init(img.path(), SECRET).unwrap();
+            fs.create_directory("docs").unwrap();
+            // 3 data blocks
+            add_bytes(&mut fs, "docs", "big", &vec![7u8; 10_000]);
+            // Raw data inside inode
+            add_bytes(&mut fs, "docs", "small", b"hello");
+        }
+
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        let b = fs.space_breakdown().unwrap();
+
+        assert_eq!(b.superblock, BLOCK_SIZE as u64);
+        assert_eq!(b.bitmaps, BLOCK_SIZE as u64);
+        assert_eq!(b.file_data, 3 * BLOCK_SIZE as u64);
+        // Directory index, directory and two file inodes
+        assert_eq!(b.inodes, 4 * BLOCK_SIZE as u64);
+        assert_eq!(b.total(), std::fs::metadata(img.path()).unwrap().len());
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let block = BLOCK_SIZE as usize;
+        let img = TempImage::new("fragmentation");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        assert_eq!(fs.fragmentation().unwrap(), 0.0);
+
+        // One contiguous region of 8 blocks
+        add_bytes(&mut fs, "docs", "large", &vec![1u8; 8 * block]);
+        let contiguous = fs.fragmentation().unwrap();
+        assert_eq!(contiguous, 0.125);
+
+        // Rewrite it into the gaps of removed one block files
+        let img_frag = TempImage::new("fragmentation_gaps");
+        let mut fs = FS::init(img_frag.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        for i in 0..4 {
+            add_bytes(
+                &mut fs,
+                "docs",
+                &format!("keep{}", i),
+                &vec![2u8; block],
+            );
+            add_bytes(&mut fs, "docs", &format!("gap{}", i), &vec![3u8; block]);
+        }
+        for i in 0..4 {
+            fs.remove_file("docs", &format!("gap{}", i)).unwrap();
+        }
+        add_bytes(&mut fs, "docs", "large", &vec![1u8; 8 * block]);
+        assert!(fs.fragmentation().unwrap() > contiguous);
+    }
+
+    #[test]
+    fn test_allocate_inode_at() {
+        let img = TempImage::new("allocate_inode_at");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        let index = ROOT_INODE_INDEX + 3;
+
+        let inode = fs.allocate_inode_at(index).unwrap();
+        assert_eq!(inode.block_index, index);
+        assert_eq!(fs.inode_count(), 2);
+        assert!(fs.allocate_inode_at(index).is_err());
+        assert!(fs.allocate_inode_at(ROOT_INODE_INDEX).is_err());
+        assert!(fs.allocate_inode_at(1).is_err());
+
+        drop(fs);
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert!(!fs.is_block_free(index));
+        let allocated: Vec<u32> = (0..5)
+            .map(|_| fs.allocate_inode(false).unwrap().unwrap().block_index)
+            .collect();
+        assert!(!allocated.contains(&index));
+        assert!(allocated.contains(&(index - 1)));
+        assert!(allocated.contains(&(index + 1)));
+    }
+
+    #[test]
+    fn test_colocate_allocation() {
+        let img = TempImage::new("colocate_allocation");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        fs.create_directory("docs").unwrap();
+        let bpg = fs.blocks_per_group();
+
+        // Leave a single free block in group 0
+        let filler = 60 * BLOCK_SIZE as usize;
+        add_bytes(&mut fs, "docs", "filler", &vec![1u8; filler]);
+        assert_eq!(fs.groups[0].free_data_blocks(), 1);
+        fs.add_group(Group::init(bpg)).unwrap();
+
+        let groups = |fs: &mut FS, name: &str| {
+            let inode = fs.get_file_info("docs", name).unwrap();
+            let first_region = match &inode.data {
+                Data::DirectPointers(pointers) => pointers[0].0,
+                Data::Raw(_) => panic!("Large file stored raw"),
+            };
+            (
+                Group::group_of_block(inode.block_index, bpg),
+                Group::group_of_block(first_region, bpg),
+            )
+        };
+
+        let large = vec![2u8; 10 * BLOCK_SIZE as usize];
+        fs.set_allocation_mode(AllocationMode::Colocate);
+        add_bytes(&mut fs, "docs", "near", &large);
+        assert_eq!(groups(&mut fs, "near"), (1, 1));
+
+        // First fit takes the last block of group 0 for the inode
+        fs.set_allocation_mode(AllocationMode::FirstFit);
+        add_bytes(&mut fs, "docs", "far", &large);
+        assert_eq!(groups(&mut fs, "far"), (0, 1));
+
+        let mut out = vec![];
+        fs.get_file_data("docs", "near", &mut out).unwrap();
+        assert_eq!(out, large);
+    }
+
+    #[test]
+    fn test_open_file() {
+        let block = BLOCK_SIZE as usize;
+        let img = TempImage::new("open_file");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Blocks, a hole and a partial last block
+        let data: Vec<u8> = (0..3 * block + 17).map(|i| i as u8).collect();
+        add_bytes(&mut fs, "docs", "file", &data);
+        fs.extend_with_zeros("docs", "file", 2 * BLOCK_SIZE as u64 + 5)
+            .unwrap();
+        let mut expected = data.clone();
+        expected.resize(data.len() + 2 * block + 5, 0);
+        add_bytes(&mut fs, "docs", "small", b"small");
+
+        let mut reader = fs.open_file("docs", "file").unwrap();
+        assert_eq!(reader.inode().size, expected.len() as u64);
+        let mut out = vec![];
+        let copied = std::io::copy(&mut reader, &mut out).unwrap();
+        assert_eq!(copied, expected.len() as u64);
+        assert_eq!(out, expected);
+        // Stops at the file size
+        assert_eq!(reader.read(&mut [0u8; 10]).unwrap(), 0);
+
+        let mut out = vec![];
+        fs.open_file("docs", "small")
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, b"small");
+
+        assert!(fs.open_file("docs", "missing").is_err());
+    }
+
+    #[test]
+    fn test_open_concat() {
+        let img = TempImage::new("open_concat");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Raw, multi block and empty files
+        let large: Vec<u8> =
+            (0..2 * BLOCK_SIZE + 10).map(|i| i as u8).collect();
+        add_bytes(&mut fs, "docs", "a", b"first part");
+        add_bytes(&mut fs, "docs", "b", &large);
+        add_bytes(&mut fs, "docs", "c", b"");
+        add_bytes(&mut fs, "docs", "d", b"last part");
+
+        let mut out = vec![];
+        fs.open_concat("docs", &["a", "b", "c", "d"])
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        let mut expected = b"first part".to_vec();
+        expected.extend(&large);
+        expected.extend(b"last part");
+        assert_eq!(out, expected);
+
+        // Same file twice, small reads crossing block borders
+        let mut r = fs.open_concat("docs", &["b", "b"]).unwrap();
+        let mut out: Vec<u8> = vec![];
+        let mut buf = [0u8; 1000];
+        loop {
+            match r.read(&mut buf).unwrap() {
+                0 => break,
+                n => out.extend(&buf[..n]),
+            }
+        }
+        assert_eq!(out, [large.clone(), large].concat());
+
+        assert!(fs.open_concat("docs", &["a", "missing"]).is_err());
+    }
+
+    #[test]
+    fn test_extend_with_zeros() {
+        let block = BLOCK_SIZE as usize;
+        let img = TempImage::new("extend_with_zeros");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Stale bytes behind the partial last block once it is reused
+        add_bytes(&mut fs, "docs", "stale", &vec![9u8; 2 * block]);
+        fs.remove_file("docs", "stale").unwrap();
+        let data = vec![7u8; block + block / 2];
+        add_bytes(&mut fs, "docs", "file", &data);
+        let free = fs.free_blocks();
+
+        fs.extend_with_zeros("docs", "file", 3 * BLOCK_SIZE as u64 + 100)
+            .unwrap();
+        assert_eq!(fs.free_blocks(), free);
+        assert_ne!(fs.superblock.flags & FLAG_SPARSE, 0);
+
+        drop(fs);
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        let mut expected = data.clone();
+        expected.resize(data.len() + 3 * block + 100, 0);
+        let inode = fs.get_file_info("docs", "file").unwrap();
+        assert_eq!(inode.size, expected.len() as u64);
+        let mut out = vec![];
+        let crc = fs.get_file_data("docs", "file", &mut out).unwrap();
+        assert_eq!(out, expected);
+        assert_eq!(crc, inode.data_checksum);
+
+        // Raw data stays raw while it fits into the inode
+        add_bytes(&mut fs, "docs", "small", b"small");
+        fs.extend_with_zeros("docs", "small", 10).unwrap();
+        let mut out = vec![];
+        fs.get_file_data("docs", "small", &mut out).unwrap();
+        assert_eq!(out, b"small\0\0\0\0\0\0\0\0\0\0");
+
+        // then moves into a single block
+        let free = fs.free_blocks();
+        fs.extend_with_zeros("docs", "small", 2 * BLOCK_SIZE as u64)
+            .unwrap();
+        assert_eq!(fs.free_blocks(), free - 1);
+        let mut out = vec![];
+        let crc = fs.get_file_data("docs", "small", &mut out).unwrap();
+        assert_eq!(&out[..5], b"small");
+        assert_eq!(out.len(), 15 + 2 * block);
+        assert!(out[5..].iter().all(|b| *b == 0));
+        assert_eq!(
+            crc,
+            fs.get_file_info("docs", "small").unwrap().data_checksum
+        );
+    }
+
+    #[test]
+    fn test_largest_files() {
+        let img = TempImage::new("largest_files");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        fs.create_directory("docs/old").unwrap();
+        add_bytes(&mut fs, "docs", "small", b"small");
+        add_bytes(
+            &mut fs,
+            "docs",
+            "large",
+            &vec![1u8; 3 * BLOCK_SIZE as usize],
+        );
+        add_bytes(&mut fs, "docs/old", "medium", &vec![2u8; 5000]);
+        add_bytes(&mut fs, "docs/old", "empty", b"");
+
+        let largest = fs.largest_files(3).unwrap();
+        assert_eq!(
+            largest,
+            [
+                ("docs".into(), "large".to_string(), 3 * BLOCK_SIZE as u64),
+                ("docs/old".into(), "medium".to_string(), 5000),
+                ("docs".into(), "small".to_string(), 5),
+            ]
+        );
+        assert_eq!(fs.largest_files(10).unwrap().len(), 4);
+        assert!(fs.largest_files(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_export_mmap() {
+        let block = BLOCK_SIZE as usize;
+        let img = TempImage::new("export_mmap");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Data, a hole and a partial last block
+        let mut data: Vec<u8> = (0..3 * block).map(|i| i as u8).collect();
+        data.extend(vec![0; 2 * block]);
+        data.extend(vec![5u8; 123]);
+        add_bytes(&mut fs, "docs", "large", &data);
+        fs.sparsify("docs", "large").unwrap();
+        add_bytes(&mut fs, "docs", "small", b"small file");
+        add_bytes(&mut fs, "docs", "empty", b"");
+
+        let mapped = TempImage::new("export_mmap_out");
+        let plain = TempImage::new("export_mmap_plain");
+        for name in ["large", "small", "empty"] {
+            let crc = fs.export_mmap("docs", name, mapped.path()).unwrap();
+            let mut w = File::create(plain.path()).unwrap();
+            assert_eq!(fs.get_file_data("docs", name, &mut w).unwrap(), crc);
+            assert_eq!(
+                std::fs::read(mapped.path()).unwrap(),
+                std::fs::read(plain.path()).unwrap()
+            );
+        }
+        assert_eq!(std::fs::read(mapped.path()).unwrap(), b"");
+        fs.export_mmap("docs", "large", mapped.path()).unwrap();
+        assert_eq!(std::fs::read(mapped.path()).unwrap(), data);
     }
 
-    #[inline]
-    pub fn serialize_into<W>(&mut self, w: W) -> anyhow::Result<()>
-    where
-        W: Write,
-    {
-        self.checksum();
-        bincode::serialize_into(w, self).map_err(|e| e.into())
-    }
+    #[test]
+    fn test_recently_accessed() {
+        let img = TempImage::new("recently_accessed");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        fs.create_directory("logs").unwrap();
+        for name in ["a", "b", "c"] {
+            add_bytes(&mut fs, "docs", name, name.as_bytes());
+        }
+        add_bytes(&mut fs, "logs", "today", b"today");
+        assert!(fs.recently_accessed(10).is_empty());
 
-    #[inline]
-    pub fn deserialize_from<R>(r: R) -> anyhow::Result<Self>
-    where
-        R: Read,
-    {
-        let mut sb: Self = bincode::deserialize_from(r)?;
-        if !sb.verify_checksum() {
-            return Err(anyhow!("Superblock checksum verification failed"));
+        for (dir, name) in [("docs", "b"), ("logs", "today"), ("docs", "a")] {
+            fs.get_file_data(dir, name, &mut std::io::sink()).unwrap();
         }
+        fs.open_file("docs", "c").unwrap();
+        // Read again moves it to the front
+        fs.get_file_data("docs", "b", &mut std::io::sink()).unwrap();
+
+        let names = |fs: &FS, n| -> Vec<(String, String)> {
+            fs.recently_accessed(n)
+                .into_iter()
+                .map(|(dir, name, _)| (dir.to_string_lossy().into(), name))
+                .collect()
+        };
+        let pair = |dir: &str, name: &str| (dir.to_string(), name.to_string());
+        assert_eq!(
+            names(&fs, 10),
+            [
+                pair("docs", "b"),
+                pair("docs", "c"),
+                pair("docs", "a"),
+                pair("logs", "today"),
+            ]
+        );
+        assert_eq!(names(&fs, 2), [pair("docs", "b"), pair("docs", "c")]);
+
+        // Renamed files keep their read time, removed ones are dropped
+        fs.rename_file("docs", "c", "d").unwrap();
+        fs.remove_file("docs", "b").unwrap();
+        assert_eq!(names(&fs, 2), [pair("docs", "d"), pair("docs", "a")]);
+        assert!(fs.recently_accessed(1)[0].2 > 0);
+    }
 
-        Ok(sb)
+    #[test]
+    fn test_manifest() {
+        let build = |name: &str, order: &[&str]| {
+            let img = TempImage::new(name);
+            let mut fs = FS::init(img.path(), SECRET).unwrap();
+            for dir in ["docs", "logs", "empty"] {
+                fs.create_directory(dir).unwrap();
+            }
+            for file_name in order {
+                add_bytes(&mut fs, "docs", file_name, &vec![1u8; 5000]);
+            }
+            add_bytes(&mut fs, "logs", "today", b"today");
+            (img, fs)
+        };
+
+        // Same content in a different block layout
+        let (_img, mut fs) = build("manifest_1", &["a", "b"]);
+        let (_img_2, other) = build("manifest_2", &["b", "a"]);
+        let manifest = fs.manifest().unwrap();
+        assert_eq!(manifest.len(), 3);
+        assert_eq!(manifest, other.manifest().unwrap());
+
+        // Only the changed directory differs
+        add_bytes(&mut fs, "logs", "today", b"tomorrow");
+        let changed = fs.manifest().unwrap();
+        let differ: Vec<&OsString> = manifest
+            .iter()
+            .filter(|(dir, hash)| changed[*dir] != **hash)
+            .map(|(dir, _)| dir)
+            .collect();
+        assert_eq!(differ, [&OsString::from("logs")]);
     }
 
-    #[inline]
-    fn checksum(&mut self) {
-        self.checksum = 0;
-        self.checksum = calculate_checksum(&self);
+    #[test]
+    fn test_view() {
+        let img = TempImage::new("view");
+        let mut fs = FS::init_with_label(img.path(), SECRET, "before").unwrap();
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "a", b"first");
+        add_bytes(&mut fs, "docs", "b", &vec![1u8; 5000]);
+
+        let view = fs.view().unwrap();
+        let free = fs.free_blocks();
+        let hash = fs.directory_hash("docs").unwrap();
+
+        fs.set_label("after").unwrap();
+        fs.remove_file("docs", "a").unwrap();
+        add_bytes(&mut fs, "docs", "c", &vec![2u8; 9000]);
+        fs.create_directory("logs").unwrap();
+
+        assert_eq!(view.label(), "before");
+        assert_eq!(view.free_blocks(), free);
+        assert_eq!(view.file_count(), 2);
+        assert_eq!(view.directory_index().directories().len(), 1);
+        assert!(view.find_directory("logs").is_none());
+        let docs = view.find_directory("docs").unwrap();
+        assert_eq!(docs.files.keys().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(docs.hash(), hash);
+
+        let current = fs.view().unwrap();
+        assert_eq!(current.label(), "after");
+        assert_eq!(current.free_blocks(), fs.free_blocks());
+        assert_eq!(current.directory_index().directories().len(), 2);
     }
 
-    #[inline]
-    fn verify_checksum(&mut self) -> bool {
-        let checksum = self.checksum;
-        self.checksum = 0;
-        let ok = checksum == calculate_checksum(&self);
-        self.checksum = checksum;
+    #[test]
+    fn test_allocate_contiguous() {
+        let img = TempImage::new("allocate_contiguous");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Gaps between kept files
+        let two_blocks = vec![1u8; 2 * BLOCK_SIZE as usize];
+        for i in 0..20 {
+            add_bytes(&mut fs, "docs", &i.to_string(), &two_blocks);
+        }
+        for i in (0..20).step_by(2) {
+            fs.remove_file("docs", &i.to_string()).unwrap();
+        }
+        let free = fs.free_blocks();
+
+        assert_eq!(
+            fs.allocate_contiguous(MIN_BLOCKS_PER_GROUP + 1).unwrap(),
+            None
+        );
+        assert_eq!(fs.allocate_contiguous(0).unwrap(), None);
+        assert_eq!(fs.free_blocks(), free);
+
+        let (start, len) = fs.allocate_contiguous(5).unwrap().unwrap();
+        assert_eq!(len, 5);
+        assert!((start..start + 5).all(|b| !fs.is_block_free(b)));
+        assert_eq!(fs.free_blocks(), free - 5);
+
+        // Removed files left 3 block gaps, inode and data
+        let (gap_start, _) = fs.allocate_contiguous(3).unwrap().unwrap();
+        assert!(gap_start < start);
+
+        drop(fs);
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.free_blocks(), free - 8);
+        drop(fs);
+
+        // Failed writes give the run back
+        let file = File::open(img.path()).unwrap();
+        let mut fs = FS::from_storage(Storage::File(file), SECRET).unwrap();
+        assert!(fs.allocate_contiguous(5).is_err());
+        assert_eq!(fs.free_blocks(), free - 8);
+    }
 
-        ok
+    #[test]
+    fn test_for_each_block() {
+        let block = BLOCK_SIZE as usize;
+        let img = TempImage::new("for_each_block");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Data, a hole and a partial last block
+        let mut data: Vec<u8> = (0..2 * block).map(|i| i as u8).collect();
+        data.extend(vec![0; block]);
+        data.extend(vec![3u8; block / 2]);
+        add_bytes(&mut fs, "docs", "large", &data);
+        fs.sparsify("docs", "large").unwrap();
+        add_bytes(&mut fs, "docs", "small", b"small");
+
+        let mut out = vec![];
+        let mut lengths = vec![];
+        fs.for_each_block("docs", "large", |offset, buf| {
+            assert_eq!(offset, out.len() as u64);
+            out.extend_from_slice(buf);
+            lengths.push(buf.len());
+        })
+        .unwrap();
+        assert_eq!(lengths.iter().sum::<usize>(), data.len());
+        assert_eq!(lengths, [block, block, block, block / 2]);
+        assert_eq!(out, data);
+
+        let mut calls = vec![];
+        fs.for_each_block("docs", "small", |offset, buf| {
+            calls.push((offset, buf.to_vec()))
+        })
+        .unwrap();
+        assert_eq!(calls, [(0, b"small".to_vec())]);
     }
-}
 
-#[derive(Debug, Default, Clone)]
-pub struct Group {
-    pub block_bitmap: BitVec<u8, Lsb0>,
-}
+    #[test]
+    fn test_free_runs() {
+        let mut group = Group::init(16);
+        assert_eq!(group.free_runs(), [(0, 16)]);
 
-impl Group {
-    fn new(block_bitmap: BitVec<u8, Lsb0>) -> Self {
-        Self { block_bitmap }
+        // Taken: 0, 3, 4, 9, 15
+        for bitmap_index in [0, 3, 4, 9, 15] {
+            group.force_allocate_at(bitmap_index);
+        }
+        assert_eq!(group.free_runs(), [(1, 2), (5, 4), (10, 5)]);
+
+        // Released blocks join their neighbour runs
+        group.release_one(4);
+        group.release_data_region(9, 1);
+        assert_eq!(group.free_runs(), [(1, 2), (4, 11)]);
+
+        let start = group.allocate_run(0, 6).unwrap();
+        assert_eq!(start, Group::create_public_address(0, 4, 16));
+        assert_eq!(group.free_runs(), [(1, 2), (10, 5)]);
+        assert_eq!(group.allocate_run(0, 6), None);
+
+        let img = TempImage::new("free_runs");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        let free: u32 = fs.free_regions().iter().map(|(_, len)| len).sum();
+        assert_eq!(free, fs.free_blocks());
+        let (start, len) = fs.free_regions()[0];
+        assert!((start..start + len).all(|b| fs.is_block_free(b)));
     }
 
-    pub fn init() -> Self {
-        let mut block_bitmap =
-            BitVec::<u8, Lsb0>::with_capacity(BLOCK_SIZE as usize * 8);
-        block_bitmap.resize(BLOCK_SIZE as usize * 8, false);
-        Self { block_bitmap }
+    #[test]
+    fn test_fs_reader_retry() {
+        let img = TempImage::new("fs_reader_retry");
+        let mut writer = FS::init(img.path(), SECRET).unwrap();
+        writer.create_directory("docs").unwrap();
+        add_bytes(&mut writer, "docs", "file", b"old content");
+
+        let mut reader = FsReader::open(img.path(), SECRET).unwrap();
+        let generation = reader.generation().unwrap();
+        assert_eq!(generation, writer.generation());
+
+        // Writer changes the file while the first attempt reads it
+        let mut attempts = vec![];
+        let data = reader
+            .read(|fs, attempt| {
+                let mut data = vec![];
+                fs.open_file("docs", "file")?.read_to_end(&mut data)?;
+                if attempt == 1 {
+                    add_bytes(&mut writer, "docs", "file", b"new content");
+                }
+                attempts.push(attempt);
+                Ok(data)
+            })
+            .unwrap();
+        assert_eq!(attempts, [1, 2]);
+        assert_eq!(data, b"new content");
+        assert!(reader.generation().unwrap() > generation);
+
+        // Every change counts, renames included
+        let generation = writer.generation();
+        writer.rename_file("docs", "file", "renamed").unwrap();
+        assert!(writer.generation() > generation);
+        assert_eq!(
+            reader.get_file_data("docs", "renamed").unwrap(),
+            b"new content"
+        );
+
+        reader.set_max_attempts(2);
+        let res = reader.read(|_, _| {
+            add_bytes(&mut writer, "docs", "other", b"other");
+            Ok(())
+        });
+        assert!(res.is_err());
+
+        // Odd generation marks a write in progress, nothing is read
+        writer.begin_write().unwrap();
+        assert_eq!(reader.generation().unwrap() % 2, 1);
+        let mut called = false;
+        let res = reader.read(|_, _| {
+            called = true;
+            Ok(())
+        });
+        assert!(res.is_err());
+        assert!(!called);
+
+        // Saves end the write with an even generation
+        writer.create_directory("more").unwrap();
+        assert_eq!(writer.generation() % 2, 0);
+        assert_eq!(reader.generation().unwrap(), writer.generation());
+
+        // Readers never write the image
+        drop(writer);
+        let image = std::fs::read(img.path()).unwrap();
+        let mut reader = FsReader::open(img.path(), SECRET).unwrap();
+        assert_eq!(
+            reader.get_file_data("docs", "renamed").unwrap(),
+            b"new content"
+        );
+        assert_eq!(std::fs::read(img.path()).unwrap(), image);
     }
 
-    #[inline]
-    fn seek_position(group_index: u32) -> u32 {
-        // Superblock BLOCK_SIZE (4kib)
-        // + Group ID * (BLOCK_SIZE + BLOCKS_PER_GROUP * BLOCK_SIZE)
-        BLOCK_SIZE
-            + (group_index * (BLOCK_SIZE + BLOCKS_PER_GROUP * BLOCK_SIZE))
-    }
+    #[test]
+    fn test_bitmap_image() {
+        let img = TempImage::new("bitmap_image");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // File does not fit into the first group
+        let data = vec![1u8; 70 * BLOCK_SIZE as usize];
+        add_bytes(&mut fs, "docs", "big", &data);
+        // Fill up the first group
+        let free = fs.groups[0].block_bitmap.count_zeros();
+        for _ in 0..free {
+            fs.allocate_contiguous(1).unwrap().unwrap();
+        }
 
-    #[inline]
-    pub fn create_public_address(group_index: u32, bitmap_index: u32) -> u32 {
-        // Maybe +1?
-        Self::seek_position(group_index) / BLOCK_SIZE + bitmap_index + 1
+        let image = fs.bitmap_image();
+        assert_eq!(image.width, MIN_BLOCKS_PER_GROUP);
+        assert_eq!(image.height, fs.groups.len() as u32);
+        assert_eq!(image.width * image.height, fs.superblock.block_count);
+        assert_eq!(image.pixels.len() as u32, fs.superblock.block_count);
+        assert!(
+            (0..image.width).all(|x| image.pixel(x, 0) == render::ALLOCATED)
+        );
+        let last = image.height - 1;
+        assert!((0..image.width).any(|x| image.pixel(x, last) == render::FREE));
+
+        #[cfg(feature = "png")]
+        {
+            let png = TempImage::new("bitmap_image_png");
+            fs.bitmap_png(png.path()).unwrap();
+            let bytes = std::fs::read(png.path()).unwrap();
+            assert_eq!(&bytes[..8], b"\x89PNG\r\n\x1a\n");
+            assert_eq!(&bytes[12..16], b"IHDR");
+            assert_eq!(&bytes[16..20], &image.width.to_be_bytes());
+            assert_eq!(&bytes[20..24], &image.height.to_be_bytes());
+        }
     }
 
-    /// Returns (group_index, bitmap_index)
-    #[inline]
-    pub fn translate_public_address(mut block_index: u32) -> (u32, u32) {
-        block_index -= 1;
-        let n = BLOCKS_PER_GROUP + 1;
-        let group_index = (block_index as u32) / n;
-        let bitmap_index = if group_index == 0 {
-            block_index - 1
-        } else {
-            block_index % (group_index * n) - 1
+    #[test]
+    fn test_data_checksum_verification() {
+        let img = TempImage::new("data_checksum_verification");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let data = vec![7u8; 3 * BLOCK_SIZE as usize];
+        add_bytes(&mut fs, "docs", "file", &data);
+
+        let inode = fs.get_file_info("docs", "file").unwrap();
+        let mut checksum = Checksum::new();
+        checksum.update(&data);
+        assert_eq!(inode.data_checksum, checksum.finalize());
+        let block = match &inode.data {
+            Data::DirectPointers(pointers) => pointers[0].0 + 1,
+            Data::Raw(_) => panic!("File data should be in blocks"),
         };
-        (group_index, bitmap_index)
-    }
 
-    #[inline]
-    pub fn serialize_into<W>(&self, mut w: W) -> anyhow::Result<()>
-    where
-        W: Write + Seek,
-    {
-        w.write_all(self.block_bitmap.as_raw_slice())?;
+        // Flip a byte of the second block on disk
+        let pos = block_seek_position(block, BLOCK_SIZE) + 100;
+        let mut byte = [0u8];
+        (&fs.file).seek(SeekFrom::Start(pos)).unwrap();
+        (&fs.file).read_exact(&mut byte).unwrap();
+        byte[0] ^= 0xff;
+        (&fs.file).seek(SeekFrom::Start(pos)).unwrap();
+        (&fs.file).write_all(&byte).unwrap();
+
+        let mut out = vec![];
+        let err = fs.get_file_data("docs", "file", &mut out).unwrap_err();
+        assert_eq!(err.to_string(), "Data checksum verification failed");
+    }
 
-        Ok(())
+    #[test]
+    fn test_head() {
+        let img = TempImage::new("head");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let data: Vec<u8> = (0..10 * BLOCK_SIZE).map(|i| i as u8).collect();
+        add_bytes(&mut fs, "docs", "file", &data);
+        add_bytes(&mut fs, "docs", "small", b"small file");
+
+        // Every block read from disk gets cached
+        fs.set_cache_capacity(100);
+        let n = BLOCK_SIZE as u64 + 10;
+        assert_eq!(fs.head("docs", "file", n).unwrap(), &data[..n as usize]);
+        let blocks = fs.get_file_info("docs", "file").unwrap().data_blocks();
+        let read: Vec<bool> = blocks
+            .iter()
+            .map(|b| fs.cache.borrow().contains(*b))
+            .collect();
+        assert_eq!(read.iter().filter(|r| **r).count(), 2);
+        assert!(read[0] && read[1]);
+
+        assert_eq!(fs.head("docs", "small", 5).unwrap(), b"small");
+        assert_eq!(fs.head("docs", "small", 100).unwrap(), b"small file");
+        assert!(fs.head("docs", "file", 0).unwrap().is_empty());
     }
 
-    #[inline]
-    pub fn deserialize_from<R>(
-        mut r: R,
-        group_index: u32,
-    ) -> anyhow::Result<Group>
-    where
-        R: Read + Seek,
-    {
-        let mut buf = Vec::with_capacity(BLOCK_SIZE as usize);
-        unsafe {
-            buf.set_len(BLOCK_SIZE as usize);
+    #[test]
+    fn test_tail() {
+        let img = TempImage::new("tail");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Gaps between kept files split the file into regions
+        let two_blocks = vec![1u8; 2 * BLOCK_SIZE as usize];
+        for i in 0..6 {
+            add_bytes(&mut fs, "docs", &i.to_string(), &two_blocks);
+        }
+        for i in (0..6).step_by(2) {
+            fs.remove_file("docs", &i.to_string()).unwrap();
+        }
+        let data: Vec<u8> =
+            (0..10 * BLOCK_SIZE + 100).map(|i| (i / 7) as u8).collect();
+        add_bytes(&mut fs, "docs", "file", &data);
+        add_bytes(&mut fs, "docs", "small", b"small file");
+        let inode = fs.get_file_info("docs", "file").unwrap();
+        match &inode.data {
+            Data::DirectPointers(pointers) => assert!(pointers.len() > 1),
+            Data::Raw(_) => panic!("File data should be in blocks"),
         }
 
-        let offset = Self::seek_position(group_index);
-        r.seek(SeekFrom::Start(offset as u64))?;
-        r.read_exact(&mut buf)?;
-        let data_bitmap = BitVec::<u8, Lsb0>::from_slice(&buf);
-
-        Ok(Group::new(data_bitmap))
+        // Every block read from disk gets cached
+        fs.set_cache_capacity(100);
+        let n = BLOCK_SIZE as u64 + 200;
+        let tail = fs.tail("docs", "file", n).unwrap();
+        assert_eq!(tail, &data[data.len() - n as usize..]);
+        let read: Vec<bool> = inode
+            .data_blocks()
+            .iter()
+            .map(|b| fs.cache.borrow().contains(*b))
+            .collect();
+        assert_eq!(read, [vec![false; 8], vec![true; 3]].concat());
+
+        // Seeking inside the file
+        let mut reader = fs.open_file("docs", "file").unwrap();
+        let offset = 5 * BLOCK_SIZE as u64 - 3;
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        let mut buf = [0u8; 6];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[offset as usize..offset as usize + 6]);
+        assert_eq!(reader.stream_position().unwrap(), offset + 6);
+
+        // Seeking past the end keeps the position
+        let end = data.len() as u64;
+        assert_eq!(reader.seek(SeekFrom::End(10)).unwrap(), end + 10);
+        assert_eq!(reader.seek(SeekFrom::Current(5)).unwrap(), end + 15);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(reader.stream_position().unwrap(), end + 15);
+        assert_eq!(reader.seek(SeekFrom::Current(-16)).unwrap(), end - 1);
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], data[data.len() - 1]);
+
+        assert_eq!(fs.tail("docs", "small", 4).unwrap(), b"file");
+        assert_eq!(fs.tail("docs", "small", 100).unwrap(), b"small file");
+        assert_eq!(fs.tail("docs", "file", data.len() as u64).unwrap(), data);
+        assert!(fs.tail("docs", "file", 0).unwrap().is_empty());
     }
 
-    // #[inline]
-    // pub fn has_data_block(&self, i: usize) -> bool {
-    //     self.block_bitmap.get(i - 1).as_deref().unwrap_or(&false) == &true
-    // }
+    #[test]
+    fn test_check_directory_entries() {
+        let img = TempImage::new("check_directory_entries");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        fs.create_directory("other").unwrap();
+        add_bytes(&mut fs, "docs", "file", b"file");
+        assert!(fs.check_directory_entries("docs").unwrap().is_empty());
+
+        // Corrupt entries pointing to a directory and the root
+        let (mut directory, dir_inode_index) =
+            fs.find_directory("docs").unwrap();
+        let old_hash = directory.hash();
+        let (_, other_inode_index) = fs.find_directory("other").unwrap();
+        let other_inode = fs.get_inode(other_inode_index).unwrap();
+        let root_inode = fs.get_inode(ROOT_INODE_INDEX).unwrap();
+        directory.add_file("dir", &other_inode).unwrap();
+        directory.add_file("root", &root_inode).unwrap();
+        fs.commit_directory("docs", directory, dir_inode_index, old_hash)
+            .unwrap();
+
+        assert_eq!(
+            fs.check_directory_entries("docs").unwrap(),
+            ["dir", "root"]
+        );
+        assert!(fs.check_directory_entries("other").unwrap().is_empty());
+        assert!(fs.check_directory_entries("missing").is_err());
+
+        // Metadata inodes are no files either
+        let file_index = fs.get_file_info("docs", "file").unwrap().block_index;
+        fs.save_shared_blocks(BTreeMap::from([(file_index, 2)]))
+            .unwrap();
+        fs.save_original_paths(BTreeMap::from([(
+            file_index,
+            PathBuf::from("docs/file"),
+        )]))
+        .unwrap();
+        let (mut directory, dir_inode_index) =
+            fs.find_directory("other").unwrap();
+        let old_hash = directory.hash();
+        let shared_inode = fs.get_inode(fs.superblock.shared_blocks).unwrap();
+        let paths_inode = fs.get_inode(fs.superblock.original_paths).unwrap();
+        directory.add_file("shared", &shared_inode).unwrap();
+        directory.add_file("paths", &paths_inode).unwrap();
+        fs.commit_directory("other", directory, dir_inode_index, old_hash)
+            .unwrap();
+        assert_eq!(
+            fs.check_directory_entries("other").unwrap(),
+            ["paths", "shared"]
+        );
+    }
 
-    #[inline]
-    pub fn free_data_blocks(&self) -> usize {
-        self.block_bitmap.count_zeros()
+    #[test]
+    fn test_nested_directories() {
+        let img = TempImage::new("nested_directories");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("a/b/c").unwrap();
+        fs.create_directory("other").unwrap();
+        assert_eq!(fs.inode_count(), 5);
+        assert!(fs.find_directory("a").is_ok());
+        assert!(fs.find_directory("a/b").is_ok());
+
+        // Only the missing directory is created
+        fs.create_directory("a/d").unwrap();
+        assert_eq!(fs.inode_count(), 6);
+
+        for root in ["", "/"] {
+            assert_eq!(fs.list_subdirectories(root).unwrap(), ["a", "other"]);
+        }
+        assert_eq!(fs.list_subdirectories("a").unwrap(), ["a/b", "a/d"]);
+        assert_eq!(fs.list_subdirectories("a/b").unwrap(), ["a/b/c"]);
+        assert!(fs.list_subdirectories("a/b/c").unwrap().is_empty());
+        assert!(fs.list_subdirectories("missing").unwrap().is_empty());
+
+        add_bytes(&mut fs, "a/b/c", "file", b"deep");
+        let mut out = vec![];
+        fs.get_file_data("a/b/c", "file", &mut out).unwrap();
+        assert_eq!(out, b"deep");
     }
 
-    #[inline]
-    pub fn total_data_blocks(&self) -> usize {
-        self.block_bitmap.len()
+    #[test]
+    fn test_add_file_from_chunk_channel() {
+        let img = TempImage::new("add_file_from_chunk_channel");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Producer stands in for an async stream
+        let (tx, mut reader) = chunk_channel(2);
+        let producer = std::thread::spawn(move || {
+            let mut sent = vec![];
+            for i in 0..50u32 {
+                let chunk = vec![i as u8; (i * 331) as usize];
+                sent.extend(&chunk);
+                tx.send(chunk).unwrap();
+            }
+            tx.finish().unwrap();
+            sent
+        });
+
+        let (len, _) =
+            fs.add_file_streaming("docs", "file", &mut reader).unwrap();
+        let sent = producer.join().unwrap();
+        assert_eq!(len, sent.len() as u64);
+
+        let mut out = vec![];
+        fs.get_file_data("docs", "file", &mut out).unwrap();
+        assert_eq!(out, sent);
+
+        // Producer failing before finish leaves no file
+        let free_blocks = fs.free_blocks();
+        let (tx, mut reader) = chunk_channel(2);
+        let producer = std::thread::spawn(move || {
+            for _ in 0..10 {
+                tx.send(vec![7; 5000]).unwrap();
+            }
+        });
+        let err = fs
+            .add_file_streaming("docs", "torn", &mut reader)
+            .unwrap_err();
+        producer.join().unwrap();
+        assert!(
+            matches!(err, WalnutError::IncompleteFile(name) if name == "torn")
+        );
+        assert!(fs.get_file_info("docs", "torn").is_err());
+        assert_eq!(fs.free_blocks(), free_blocks);
     }
 
-    #[inline]
-    fn release_one(&mut self, bitmap_index: u32) {
-        self.block_bitmap.set(bitmap_index as usize, false);
+    #[test]
+    fn test_check_secret() {
+        let img = TempImage::new("check_secret");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        drop(fs);
+
+        assert!(FS::check_secret(img.path(), SECRET));
+        assert!(!FS::check_secret(img.path(), "wrong secret"));
+        assert!(!FS::check_secret("/nonexistent/walnut.img", SECRET));
+
+        let err = FS::new(img.path(), "wrong secret").unwrap_err();
+        assert_eq!(err.to_string(), "wrong secret");
+        let cipher = Box::new(XorCipher::new("wrong secret"));
+        assert!(FS::new_with_cipher(img.path(), cipher).is_err());
+        assert_eq!(
+            FS::new(img.path(), SECRET).unwrap().cipher_id(),
+            XOR_CIPHER_ID
+        );
+
+        // Groups are not read, a copy of the superblock is enough
+        let copy = TempImage::new("check_secret_copy");
+        let bytes = std::fs::read(img.path()).unwrap();
+        std::fs::write(copy.path(), &bytes[..BLOCK_SIZE as usize]).unwrap();
+        assert!(FS::new(copy.path(), SECRET).is_err());
+        assert!(FS::check_secret(copy.path(), SECRET));
+        assert!(!FS::check_secret(copy.path(), "wrong secret"));
     }
 
-    #[inline]
-    pub fn release_data_region(&mut self, bitmap_index: u32, length: u32) {
-        for i in bitmap_index..(bitmap_index + length) {
-            self.block_bitmap.set(i as usize, false);
+    #[test]
+    fn test_file_writer() {
+        let img = TempImage::new("file_writer");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "file", &vec![1u8; 3 * BLOCK_SIZE as usize]);
+        let free = fs.free_blocks();
+
+        // Odd write sizes crossing chunk and block borders
+        let data: Vec<u8> = (0..STREAM_CHUNK_BLOCKS * BLOCK_SIZE as u64 + 5000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut w = fs.create_writer("docs", "file").unwrap();
+        for chunk in data.chunks(7777) {
+            w.write_all(chunk).unwrap();
         }
-    }
+        let (len, crc) = w.finish().unwrap();
+        assert_eq!(len, data.len() as u64);
 
-    /// Set bitmap index by force
-    #[inline]
-    fn force_allocate_at(&mut self, bitmap_index: u32) {
-        // Set it to be taken
-        self.block_bitmap.set(bitmap_index as usize, true);
+        // Replaced data is released
+        let blocks = (len as u32).div_ceil(BLOCK_SIZE);
+        assert_eq!(fs.free_blocks(), free + 3 - blocks);
+        let mut out = vec![];
+        assert_eq!(fs.get_file_data("docs", "file", &mut out).unwrap(), crc);
+        assert_eq!(out, data);
+
+        // Dropping the writer adds the file too
+        {
+            let mut w = fs.create_writer("docs", "small").unwrap();
+            w.write_all(b"small ").unwrap();
+            w.write_all(b"file").unwrap();
+        }
+        let mut out = vec![];
+        fs.get_file_data("docs", "small", &mut out).unwrap();
+        assert_eq!(out, b"small file");
+        assert_eq!(fs.superblock.file_count, 2);
+
+        assert!(fs.create_writer("missing", "file").is_err());
     }
 
-    /// Allocate one block
-    #[inline]
-    fn allocate_one(&mut self, group_index: u32) -> Option<u32> {
-        // If we have at least one free block index
-        if let Some(bitmap_index) = self.block_bitmap.iter_zeros().next() {
-            // Set it to be taken
-            self.block_bitmap.set(bitmap_index, true);
-            // Return index as public address
-            return Some(Self::create_public_address(
-                group_index,
-                bitmap_index as u32,
-            ));
+    #[test]
+    fn test_remove_directory() {
+        let img = TempImage::new("remove_directory");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let free = fs.free_blocks();
+        let inodes = fs.inode_count();
+        let index_size = fs.get_inode(ROOT_INODE_INDEX).unwrap().size;
+        let index_hash = fs.index_hash().unwrap();
+
+        // Index grows beyond raw inode data
+        for i in 0..300 {
+            fs.create_directory(format!("dir-{}", i)).unwrap();
         }
-        None
+        assert!(fs.get_inode(ROOT_INODE_INDEX).unwrap().size > index_size);
+        for i in 0..300 {
+            fs.remove_directory(format!("dir-{}", i)).unwrap();
+        }
+
+        assert_eq!(fs.free_blocks(), free);
+        assert_eq!(fs.inode_count(), inodes);
+        assert_eq!(fs.get_inode(ROOT_INODE_INDEX).unwrap().size, index_size);
+        assert_eq!(fs.index_hash().unwrap(), index_hash);
+        assert!(fs.find_directory("dir-0").is_err());
+
+        // Only empty directories are removed
+        add_bytes(&mut fs, "docs", "file", b"file");
+        assert!(fs.remove_directory("docs").is_err());
+        fs.create_directory("a/b").unwrap();
+        assert!(fs.remove_directory("a").is_err());
+        fs.remove_directory("a/b").unwrap();
+        fs.remove_directory("a").unwrap();
+        assert!(fs.remove_directory("a").is_err());
     }
 
-    /// Allocate data region
-    #[inline]
-    fn allocate_region(
-        &mut self,
-        // to translate internal ID into public address
-        group_index: u32,
-        // Blocks to allocate
-        mut blocks_to_allocate: usize,
-        // Maximum number of region to allocate
-        max_regions: usize,
-    ) -> (Vec<(u32, u32)>, usize) {
-        let mut regions = Vec::new();
-        let mut region: Option<(u32, u32)> = None;
+    #[test]
+    fn test_stat() {
+        use std::cell::Cell;
+        use std::rc::Rc;
 
-        let mut iter = self.block_bitmap.iter_mut().enumerate().peekable();
+        /// No-op cipher counting its calls
+        #[derive(Debug)]
+        struct CountingCipher(Rc<Cell<usize>>);
 
-        while let Some((bitmap_index, mut i)) = iter.next() {
-            // Break loop if we dont need more blocks
-            // to allocate
-            if blocks_to_allocate == 0 {
-                // Add opened region to regions if we have one opened
-                if let Some(r) = region.take() {
-                    regions.push(r);
-                }
-                break;
+        impl Cipher for CountingCipher {
+            fn id(&self) -> u8 {
+                201
             }
+            fn encrypt_block(&self, _block_index: u64, _buf: &mut [u8]) {
+                self.0.set(self.0.get() + 1);
+            }
+            fn decrypt_block(&self, _block_index: u64, _buf: &mut [u8]) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
 
-            // If current block index is free
-            if !*i {
-                // If we have opened region
-                if let Some((_block_index, region_length)) = region.as_mut() {
-                    // Then increment region_length
-                    *region_length += 1;
-                } else {
-                    // Else we need to create a new opened region
-                    region = Some((
-                        Self::create_public_address(
-                            group_index,
-                            bitmap_index as u32,
-                        ),
-                        1,
-                    ));
-                }
-
-                // Decrease blocks number to allocate by one
-                // As we allocate on in this if block
-                blocks_to_allocate -= 1;
+        let img = TempImage::new("stat");
+        let calls = Rc::new(Cell::new(0));
+        let cipher = Box::new(CountingCipher(calls.clone()));
+        let mut fs = FS::init_with_cipher(img.path(), "", cipher).unwrap();
+        fs.create_directory("docs").unwrap();
+        let data = vec![3u8; 2 * BLOCK_SIZE as usize + 1];
+        add_bytes(&mut fs, "docs", "file", &data);
+        let inode = fs.get_file_info("docs", "file").unwrap();
+
+        calls.set(0);
+        let stat = fs.stat(inode.block_index).unwrap();
+        assert_eq!(calls.get(), 0);
+        assert_eq!(stat.size, data.len() as u64);
+        assert_eq!(stat.data_checksum, inode.data_checksum);
+        assert_eq!(stat.blocks, 3);
+        assert!(fs.stat(inode.block_index + 100).is_err());
+        drop(fs);
+
+        // Without secret or cipher
+        let fs = FS::open_metadata_only(img.path()).unwrap();
+        assert_eq!(fs.stat(inode.block_index).unwrap(), stat);
+    }
 
-                // Set block index as taken
-                i.set(true);
+    #[test]
+    fn test_exists_and_size() {
+        let img = TempImage::new("exists_and_size");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "small", b"small");
+        add_bytes(
+            &mut fs,
+            "docs",
+            "large",
+            &vec![1u8; 3 * BLOCK_SIZE as usize],
+        );
+
+        assert!(fs.dir_exists("docs"));
+        assert!(!fs.dir_exists("missing"));
+        assert!(fs.file_exists("docs", "small").unwrap());
+        assert!(!fs.file_exists("docs", "missing").unwrap());
+        assert!(fs.file_exists("missing", "small").is_err());
+
+        assert_eq!(fs.file_size("docs", "small").unwrap(), 5);
+        assert_eq!(
+            fs.file_size("docs", "large").unwrap(),
+            3 * BLOCK_SIZE as u64
+        );
+        assert!(fs.file_size("docs", "missing").is_err());
+    }
 
-                // If i is taken
-            } else {
-                // Check if we have opened region
-                // and close it
-                if let Some(r) = region.take() {
-                    regions.push(r);
+    #[test]
+    fn test_scrub() {
+        let img = TempImage::new("scrub");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let data = vec![5u8; 2 * BLOCK_SIZE as usize];
+        add_bytes(&mut fs, "docs", "bad", &data);
+        add_bytes(&mut fs, "docs", "good", &data);
+        add_bytes(&mut fs, "docs", "small", b"small");
+        let good = fs.get_file_info("docs", "good").unwrap();
+
+        // Flip a byte of the first data block on disk
+        let block = fs.get_file_info("docs", "bad").unwrap().data_blocks()[0];
+        let pos = block_seek_position(block, BLOCK_SIZE) + 10;
+        let mut byte = [0u8];
+        (&fs.file).seek(SeekFrom::Start(pos)).unwrap();
+        (&fs.file).read_exact(&mut byte).unwrap();
+        byte[0] ^= 0xff;
+        (&fs.file).seek(SeekFrom::Start(pos)).unwrap();
+        (&fs.file).write_all(&byte).unwrap();
+
+        let report = fs.scrub().unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(
+            report.quarantined,
+            [(
+                PathBuf::from("docs/bad"),
+                PathBuf::from(".corrupt/docs/bad")
+            )]
+        );
+        assert!(!fs.file_exists("docs", "bad").unwrap());
+        assert_eq!(fs.superblock.file_count, 3);
+
+        // Clean files are untouched
+        let inode = fs.get_file_info("docs", "good").unwrap();
+        assert_eq!(inode.data_blocks(), good.data_blocks());
+        let mut out = vec![];
+        fs.get_file_data("docs", "good", &mut out).unwrap();
+        assert_eq!(out, data);
+
+        // Readable data is kept
+        let mut out = vec![];
+        let dir = ".corrupt/docs";
+        assert!(fs.get_file_data(dir, "bad", &mut vec![]).is_err());
+        fs.get_file_data_lenient(dir, "bad", &mut out).unwrap();
+        assert_eq!(out.len(), data.len());
+        assert_eq!(out.iter().filter(|b| **b != 5).count(), 1);
+
+        // Quarantined files are not checked again
+        let report = fs.scrub().unwrap();
+        assert_eq!(report.checked, 2);
+        assert!(report.quarantined.is_empty());
+
+        // Quarantine is found by its reference, not by its name
+        fs.remove_directory_recursive(".corrupt").unwrap();
+        assert_eq!(fs.superblock.quarantine, 0);
+        fs.create_directory(".corrupt").unwrap();
+        add_bytes(&mut fs, ".corrupt", "mine", b"mine");
+
+        // Unreadable files are quarantined too, the scrub goes on
+        let mut inode = fs.get_file_info("docs", "good").unwrap();
+        let outside = fs.superblock.block_count + 10;
+        inode.data = Data::DirectPointers(vec![(outside, 2)]);
+        fs.write_inode_as_is(&inode).unwrap();
+        let report = fs.scrub().unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(
+            report.quarantined,
+            [(
+                PathBuf::from("docs/good"),
+                PathBuf::from(".corrupt.1/docs/good")
+            )]
+        );
+        assert!(fs.file_exists(".corrupt", "mine").unwrap());
+        assert_eq!(fs.scrub().unwrap().checked, 2);
+    }
 
-                    // Break loop if we reached the maximum region number
-                    // we dont have room to allocate more regions
-                    if regions.len() == max_regions {
-                        break;
-                    }
-                }
-            }
+    #[test]
+    fn test_append_only() {
+        let block = BLOCK_SIZE as usize;
+        let img = TempImage::new("append_only");
+        {
+            let mut fs = FS::init(img.path(), SECRET).unwrap();
+            fs.create_directory("log").unwrap();
+            add_bytes(&mut fs, "log", "raw", b"first line\n");
+            add_bytes(&mut fs, "log", "blocks", &vec![1u8; block + 10]);
+            add_bytes(&mut fs, "log", "sparse", &vec![2u8; block]);
+            fs.extend_with_zeros("log", "sparse", 10).unwrap();
+        }
 
-            // If last item, then clean up
-            if iter.peek().is_none() {
-                // If we have opened region
-                // then close it
-                if let Some(r) = region.take() {
-                    regions.push(r);
-                }
-            }
+        let mut fs = FS::open_append_only(img.path(), SECRET).unwrap();
+        assert_eq!(fs.mode(), Mode::AppendOnly);
+
+        // Existing data cannot be changed
+        let err = fs.remove_file("log", "raw").unwrap_err();
+        assert_eq!(err.to_string(), "Image is opened append only");
+        assert!(fs.rename_file("log", "raw", "other").is_err());
+        let mut data = Cursor::new(b"overwrite");
+        assert!(fs.add_file("log", "raw", &mut data, 9).is_err());
+        assert!(fs.create_writer("log", "raw").is_err());
+
+        // New files and appends are allowed
+        add_bytes(&mut fs, "log", "new", b"new file");
+        let appends: [(&str, Vec<u8>); 3] = [
+            ("raw", vec![3u8; 2 * block]),
+            ("blocks", vec![4u8; 3 * block]),
+            ("sparse", vec![5u8; block]),
+        ];
+        let mut expected = vec![
+            b"first line\n".to_vec(),
+            vec![1u8; block + 10],
+            [vec![2u8; block], vec![0; 10]].concat(),
+        ];
+        for ((name, data), expected) in appends.iter().zip(&mut expected) {
+            let mut r = Cursor::new(data);
+            fs.append_file("log", name, &mut r, data.len() as u64)
+                .unwrap();
+            expected.extend(data);
         }
+        fs.append_file("log", "raw", &mut Cursor::new(b"x"), 1)
+            .unwrap();
+        expected[0].push(b'x');
+        drop(fs);
+
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        for ((name, _), expected) in appends.iter().zip(&expected) {
+            let mut out = vec![];
+            let crc = fs.get_file_data("log", name, &mut out).unwrap();
+            assert_eq!(&out, expected);
+            assert_eq!(
+                crc,
+                fs.get_file_info("log", name).unwrap().data_checksum
+            );
+        }
+        assert!(fs.file_exists("log", "new").unwrap());
+    }
 
-        // allocated regions
-        //  |                  remaining blocks to allocate
-        //  |                     |
-        (regions, blocks_to_allocate)
+    #[test]
+    fn test_remove_directory_recursive() {
+        let img = TempImage::new("remove_directory_recursive");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("keep").unwrap();
+        let free = fs.superblock.free_blocks;
+
+        fs.create_directory("docs/sub/deep").unwrap();
+        add_bytes(&mut fs, "docs", "a", b"small");
+        add_bytes(&mut fs, "docs", "b", &vec![1u8; 3 * BLOCK_SIZE as usize]);
+        add_bytes(&mut fs, "docs/sub/deep", "c", &vec![2u8; 5000]);
+        assert!(fs.superblock.free_blocks < free);
+
+        let err = fs.remove_directory("docs").unwrap_err();
+        assert_eq!(err.to_string(), "Directory is not empty, it has a, b");
+
+        fs.remove_directory_recursive("docs").unwrap();
+        assert_eq!(fs.superblock.free_blocks, free);
+        assert_eq!(fs.superblock.file_count, 0);
+        assert_eq!(fs.list_subdirectories("").unwrap(), ["keep"]);
+
+        assert!(fs.remove_directory_recursive("docs").is_err());
+        assert!(fs.remove_directory_recursive("").is_err());
     }
 
-    // #[inline]
-    // fn next_free_data_region(&self, size: u32) -> Option<(usize, usize)> {
-    //     self.block_bitmap
-    //         .windows(size as usize)
-    //         .position(|p| p.not_any())
-    //         .map(|p| (p + 1, p + size as usize + 1))
-    // }
-}
+    #[test]
+    fn test_fsck() {
+        let img = TempImage::new("fsck");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let data = vec![3u8; 2 * BLOCK_SIZE as usize];
+        add_bytes(&mut fs, "docs", "a", &data);
+        add_bytes(&mut fs, "docs", "b", &data);
+        add_bytes(&mut fs, "docs", "small", b"small");
+
+        let report = fs.fsck().unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.files, 3);
+        let free_blocks = fs.free_blocks();
+
+        // Leak a block
+        let mut group = fs.groups[0].clone();
+        let leaked = group.allocate_one(0).unwrap();
+        fs.save_group(group, 0).unwrap();
+
+        // Point b to the blocks of a
+        let a = fs.get_file_info("docs", "a").unwrap();
+        let mut b = fs.get_file_info("docs", "b").unwrap();
+        let b_blocks = b.data_blocks();
+        b.data = a.data.clone();
+        fs.save_inode(&mut b).unwrap();
+
+        let report = fs.fsck().unwrap();
+        assert!(!report.is_clean());
+        assert!(report.bad_checksums.is_empty());
+        assert_eq!(report.double_allocated, a.data_blocks());
+        let mut expected = b_blocks.clone();
+        expected.push(leaked);
+        expected.sort_unstable();
+        assert_eq!(report.leaked, expected);
+        assert!(report.unallocated.is_empty());
+
+        // Repair frees leaked blocks only
+        let report = fs.fsck_repair().unwrap();
+        assert_eq!(report.leaked.len(), 3);
+        assert_eq!(fs.free_blocks(), free_blocks + 2);
+        assert_eq!(fs.superblock.inode_counts[0], 5);
+        let report = fs.fsck().unwrap();
+        assert!(report.leaked.is_empty());
+        assert_eq!(report.double_allocated, a.data_blocks());
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Inode {
-    pub block_index: u32,
-    pub created: u64,
-    pub last_modified: u64,
-    pub size: u64,
-    pub data_checksum: u32,
-    pub data: Data,
-}
+    #[test]
+    fn test_segregate_inodes() {
+        let block = BLOCK_SIZE as usize;
+        let img = TempImage::new("segregate_inodes");
+        // Moved blocks are encrypted by their new block index
+        let mut fs =
+            FS::init_with_cipher(img.path(), "", Box::new(AddCipher)).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Inode and data blocks alternate in the first group
+        // the last file keeps its data in the inode
+        let mut files = vec![];
+        for i in 0..14u8 {
+            let data = match i {
+                13 => b"raw".to_vec(),
+                _ if i % 2 == 0 => vec![i + 1; block],
+                _ => vec![0; block],
+            };
+            add_bytes(&mut fs, "docs", &i.to_string(), &data);
+            files.push((i.to_string(), data));
+        }
+        // Fill the rest of the group, its inode goes to a new group
+        let free = fs.groups[0].free_data_blocks();
+        files.push(("filler".into(), vec![9; free * block]));
+        add_bytes(&mut fs, "docs", "filler", &files[14].1);
+        assert_eq!(fs.groups[0].free_data_blocks(), 0);
+
+        // Free the data of every second file
+        for i in (1..13).step_by(2) {
+            fs.sparsify("docs", &i.to_string()).unwrap();
+        }
+        assert!(fs.groups[0].free_runs().iter().all(|(_, len)| *len == 1));
+        assert!(fs.inode_data_interleave().unwrap() > 0.5);
+
+        assert!(fs.segregate_inodes().unwrap() > 0);
+        assert_eq!(fs.inode_data_interleave().unwrap(), 0.0);
+        assert_eq!(fs.groups[0].free_runs(), [(16, 6)]);
+        assert!(fs.fsck().unwrap().is_clean());
+
+        // Large run fits into the first group now
+        let first = Group::first_data_block(0, MIN_BLOCKS_PER_GROUP);
+        assert_eq!(fs.allocate_contiguous(6).unwrap(), Some((first + 16, 6)));
+
+        let mut fs =
+            FS::new_with_cipher(img.path(), Box::new(AddCipher)).unwrap();
+        for (name, data) in files {
+            let mut out = vec![];
+            fs.get_file_data("docs", &name, &mut out).unwrap();
+            assert_eq!(out, data);
+        }
+    }
+
+    #[test]
+    fn test_clone_file() {
+        let block = BLOCK_SIZE as usize;
+        let img = TempImage::new("clone_file");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let data: Vec<u8> =
+            (0..3 * block).map(|i| (i / block) as u8 + 1).collect();
+        add_bytes(&mut fs, "docs", "a", &data);
+        add_bytes(&mut fs, "docs", "small", b"small");
+        let a = fs.get_file_info("docs", "a").unwrap();
+
+        // Clones take their inodes only, plus the reference counts
+        let free = fs.free_blocks();
+        fs.clone_file("docs", "a", "docs", "b").unwrap();
+        fs.clone_file("docs", "small", "docs", "small2").unwrap();
+        let b = fs.get_file_info("docs", "b").unwrap();
+        assert_eq!(b.data_blocks(), a.data_blocks());
+        assert_eq!(fs.free_blocks(), free - 3);
+        assert!(fs.fsck().unwrap().is_clean());
+
+        // Only the written block is copied
+        fs.write_file_range("docs", "b", block as u64 + 10, b"changed")
+            .unwrap();
+        let b_blocks = fs.get_file_info("docs", "b").unwrap().data_blocks();
+        let a_blocks = a.data_blocks();
+        assert_eq!(b_blocks[0], a_blocks[0]);
+        assert_ne!(b_blocks[1], a_blocks[1]);
+        assert_eq!(b_blocks[2], a_blocks[2]);
+        assert_eq!(fs.free_blocks(), free - 4);
+
+        let mut expected = data.clone();
+        expected[block + 10..block + 17].copy_from_slice(b"changed");
+        let mut out = vec![];
+        fs.get_file_data("docs", "b", &mut out).unwrap();
+        assert_eq!(out, expected);
+        let mut out = vec![];
+        fs.get_file_data("docs", "a", &mut out).unwrap();
+        assert_eq!(out, data);
+        let mut out = vec![];
+        fs.get_file_data("docs", "small2", &mut out).unwrap();
+        assert_eq!(out, b"small");
+
+        // Shared blocks stay with the original
+        fs.remove_file("docs", "b").unwrap();
+        fs.remove_file("docs", "small2").unwrap();
+        assert_eq!(fs.superblock.shared_blocks, 0);
+        assert_eq!(fs.free_blocks(), free);
+        assert!(fs.fsck().unwrap().is_clean());
+        let mut out = vec![];
+        fs.get_file_data("docs", "a", &mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_allocate_fragmented() {
+        let img = TempImage::new("allocate_fragmented");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        fs.set_reserved_percentage(0).unwrap();
+
+        // Every second block of the first group is taken
+        let mut group = fs.groups[0].clone();
+        for bitmap_index in (0..MIN_BLOCKS_PER_GROUP).step_by(2) {
+            group.force_allocate_at(bitmap_index);
+        }
+        fs.save_group(group, 0).unwrap();
+        let bitmap = fs.groups[0].block_bitmap.clone();
+
+        // Free blocks are enough, but not in 3 regions
+        let ranges = fs.allocate_blocks(20, 3, 0, false).unwrap();
+        let first = Group::first_data_block(1, MIN_BLOCKS_PER_GROUP);
+        assert_eq!(ranges, [(first, 20)]);
+        assert_eq!(fs.groups.len(), 2);
+        assert_eq!(fs.groups[0].block_bitmap, bitmap);
+
+        // More new groups than regions, the added groups are dropped
+        let free = fs.free_blocks();
+        let len = std::fs::metadata(img.path()).unwrap().len();
+        let count = 3 * MIN_BLOCKS_PER_GROUP as u64;
+        let err = fs.allocate_blocks(count, 2, 0, false).unwrap_err();
+        assert_eq!(err.to_string(), "Free space is too fragmented");
+        assert_eq!(fs.groups.len(), 2);
+        assert_eq!(fs.free_blocks(), free);
+        assert_eq!(std::fs::metadata(img.path()).unwrap().len(), len);
+
+        // Groups beyond the superblock capacity are not even started
+        let groups = (fs.max_groups() as u64 - 1) * MIN_BLOCKS_PER_GROUP as u64;
+        let err = fs.allocate_in_new_groups(groups, usize::MAX).unwrap_err();
+        assert!(matches!(err.downcast(), Ok(WalnutError::OutOfSpace)));
+        assert_eq!(fs.groups.len(), 2);
+
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.groups.len(), 2);
+        assert_eq!(fs.free_blocks(), free);
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Data {
-    Raw(Vec<u8>),
-    DirectPointers(Vec<(u32, u32)>),
-}
+    #[test]
+    fn test_data_offsets() {
+        let block = BLOCK_SIZE as u64;
+        let img = TempImage::new("data_offsets");
+        let mut fs =
+            FS::init_with_cipher(img.path(), "", Box::new(AddCipher)).unwrap();
+        fs.create_directory("docs").unwrap();
+        let large: Vec<u8> = (0..3 * block + 100).map(|i| i as u8).collect();
+        add_bytes(&mut fs, "docs", "large", &large);
+        add_bytes(&mut fs, "docs", "small", b"small");
+
+        // Read the image file directly, decrypt block by block
+        let image = std::fs::read(img.path()).unwrap();
+        let read = |fs: &FS, name: &str| {
+            let mut out = vec![];
+            for (offset, len) in fs.data_offsets("docs", name).unwrap() {
+                let mut data =
+                    image[offset as usize..(offset + len) as usize].to_vec();
+                for (i, chunk) in data.chunks_mut(block as usize).enumerate() {
+                    let block_index = offset / block + i as u64;
+                    fs.cipher.decrypt_block(block_index, chunk);
+                }
+                out.extend(data);
+            }
+            out
+        };
+        assert_eq!(read(&fs, "large"), large);
+        assert_eq!(read(&fs, "small"), b"small");
+        assert_eq!(fs.data_offsets("docs", "small").unwrap().len(), 1);
+    }
 
-impl Default for Data {
-    fn default() -> Self {
-        Self::Raw(vec![])
+    #[test]
+    fn test_block_boundary_roundtrip() {
+        let block = BLOCK_SIZE as usize;
+        let img = TempImage::new("block_boundary_roundtrip");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Raw data, full blocks and partial last blocks
+        let lens = [
+            0,
+            1,
+            inode_capacity(BLOCK_SIZE) + 1,
+            block,
+            block + 1,
+            3 * block + 7,
+        ];
+        for len in lens {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let name = len.to_string();
+            add_bytes(&mut fs, "docs", &name, &data);
+            let mut out = vec![];
+            fs.get_file_data("docs", &name, &mut out).unwrap();
+            assert_eq!(out, data);
+        }
+
+        // Group bitmaps are read back as written
+        let bitmaps: Vec<_> =
+            fs.groups.iter().map(|g| g.block_bitmap.clone()).collect();
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        for (group, bitmap) in fs.groups.iter().zip(bitmaps) {
+            assert_eq!(group.block_bitmap, bitmap);
+        }
     }
-}
 
-impl Inode {
-    pub fn new(block_index: u32) -> Self {
-        Self {
-            block_index,
-            created: now(),
-            last_modified: now(),
-            size: 0,
-            data_checksum: calculate_checksum(&()),
-            data: Data::Raw(vec![]),
+    #[test]
+    fn test_max_regions_per_file() {
+        let img = TempImage::new("max_regions_per_file");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        fs.set_reserved_percentage(0).unwrap();
+        fs.create_directory("docs").unwrap();
+        assert!(fs.set_max_regions_per_file(0).is_err());
+        assert!(fs
+            .set_max_regions_per_file(inode_max_region(BLOCK_SIZE) + 1)
+            .is_err());
+        fs.set_max_regions_per_file(2).unwrap();
+
+        // Every second data block of the existing groups is taken
+        for group_index in 0..fs.groups.len() {
+            let mut group = fs.groups[group_index].clone();
+            for bitmap_index in (0..MIN_BLOCKS_PER_GROUP).step_by(2) {
+                if !group.block_bitmap[bitmap_index as usize] {
+                    group.force_allocate_at(bitmap_index);
+                }
+            }
+            fs.save_group(group, group_index as u32).unwrap();
+        }
+
+        // Fragmented write is consolidated into a new group
+        let data = vec![7u8; 10 * BLOCK_SIZE as usize];
+        add_bytes(&mut fs, "docs", "file", &data);
+        let inode = fs.get_file_info("docs", "file").unwrap();
+        match inode.data {
+            Data::DirectPointers(pointers) => assert_eq!(pointers.len(), 1),
+            Data::Raw(_) => panic!("expected pointers"),
         }
+        let mut out = vec![];
+        fs.get_file_data("docs", "file", &mut out).unwrap();
+        assert_eq!(out, data);
+
+        // Data needing more groups than regions fails early
+        let big = vec![1u8; 3 * (MIN_BLOCKS_PER_GROUP * BLOCK_SIZE) as usize];
+        let err = fs
+            .add_file("docs", "big", &mut big.as_slice(), big.len() as u64)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Free space is too fragmented");
     }
 
-    #[inline]
-    pub fn serialize_into<W>(&self, mut w: W) -> anyhow::Result<()>
-    where
-        W: Write + Seek,
-    {
-        // Serialize inode bytes array
-        let serialized = bincode::serialize(&self)?;
+    #[test]
+    fn test_open_readonly_threads() {
+        let img = TempImage::new("open_readonly_threads");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let files: Vec<(String, Vec<u8>)> = (0..4)
+            .map(|i| {
+                let len = i * BLOCK_SIZE as usize + 1000 * i + 10;
+                (
+                    format!("file{}", i),
+                    (0..len).map(|b| (b + i) as u8).collect(),
+                )
+            })
+            .collect();
+        for (name, data) in &files {
+            add_bytes(&mut fs, "docs", name, data);
+        }
+        assert!(FS::open_readonly(img.path(), "wrong").is_err());
+
+        let ro = FS::open_readonly(img.path(), SECRET).unwrap();
+        std::thread::scope(|scope| {
+            for (name, data) in &files {
+                let ro = &ro;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        let mut out = vec![];
+                        ro.get_file_data("docs", name, &mut out).unwrap();
+                        assert_eq!(&out, data);
+                    }
+                });
+            }
+        });
+        assert!(ro.get_file_data("docs", "missing", &mut vec![]).is_err());
+    }
 
-        // Check if serialized inode size is correct
-        assert!(serialized.len() as u32 <= BLOCK_SIZE);
+    #[test]
+    fn test_original_paths() {
+        let img = TempImage::new("original_paths");
+        let temp = |name: &str| {
+            let path = std::env::temp_dir().join(format!(
+                "walnut-original-paths-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            path
+        };
+        let host = temp("host");
+        std::fs::create_dir_all(host.join("sub")).unwrap();
+        std::fs::write(host.join("a.txt"), b"alpha").unwrap();
+        std::fs::write(host.join("sub").join("b.txt"), b"beta").unwrap();
+
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        assert_eq!(fs.import_dir(&host, "imported").unwrap(), 2);
+        assert_eq!(
+            fs.original_path("imported/sub", "b.txt").unwrap(),
+            Some(host.join("sub").join("b.txt"))
+        );
+        add_bytes(&mut fs, "imported", "c.txt", b"gamma");
+        assert_eq!(fs.original_path("imported", "c.txt").unwrap(), None);
+        fs.set_original_path("imported", "c.txt", "docs/c.txt")
+            .unwrap();
+
+        // Files go back to their recorded paths below the output
+        let out = temp("out");
+        assert_eq!(fs.export_all(&out, true).unwrap(), 3);
+        let under_out = |path: PathBuf| {
+            out.join(
+                path.components()
+                    .filter(|c| matches!(c, std::path::Component::Normal(_)))
+                    .collect::<PathBuf>(),
+            )
+        };
+        let read = std::fs::read;
+        assert_eq!(read(under_out(host.join("a.txt"))).unwrap(), b"alpha");
+        let b = under_out(host.join("sub").join("b.txt"));
+        assert_eq!(read(b).unwrap(), b"beta");
+        assert_eq!(read(out.join("docs").join("c.txt")).unwrap(), b"gamma");
+
+        // Without original paths files go to their image paths
+        let plain = temp("plain");
+        assert_eq!(fs.export_all(&plain, false).unwrap(), 3);
+        let b = plain.join("imported").join("sub").join("b.txt");
+        assert_eq!(read(b).unwrap(), b"beta");
+
+        // Recorded paths are dropped with their files
+        fs.remove_file("imported", "a.txt").unwrap();
+        fs.remove_file("imported", "c.txt").unwrap();
+        assert_ne!(fs.superblock.original_paths, 0);
+        fs.remove_file("imported/sub", "b.txt").unwrap();
+        assert_eq!(fs.superblock.original_paths, 0);
+        assert!(fs.fsck().unwrap().is_clean());
+
+        for dir in [host, out, plain] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
 
-        // Write serialized inode
-        w.write_all(&serialized)?;
+    #[test]
+    fn test_alloc_strategy() {
+        let block = BLOCK_SIZE as usize;
+        let regions = |strategy: AllocStrategy| {
+            let img = TempImage::new(&format!("alloc_strategy_{:?}", strategy));
+            let mut fs = FS::init(img.path(), SECRET).unwrap();
+            fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+            fs.set_reserved_percentage(0).unwrap();
+            fs.set_alloc_strategy(strategy);
+            fs.create_directory("docs").unwrap();
+
+            // Small holes first, a bigger one after them
+            for (name, blocks) in [("a", 2), ("b", 2), ("c", 2), ("d", 8)] {
+                add_bytes(&mut fs, "docs", name, &vec![1u8; blocks * block]);
+            }
+            add_bytes(&mut fs, "docs", "rest", &vec![2u8; 30 * block]);
+            for name in ["a", "c", "d"] {
+                fs.remove_file("docs", name).unwrap();
+            }
 
-        // Flush buffer
-        w.flush()?;
+            let data: Vec<u8> = (0..6 * block).map(|i| i as u8).collect();
+            add_bytes(&mut fs, "docs", "new", &data);
+            let mut out = vec![];
+            fs.get_file_data("docs", "new", &mut out).unwrap();
+            assert_eq!(out, data);
+            match fs.get_file_info("docs", "new").unwrap().data {
+                Data::DirectPointers(pointers) => pointers.len(),
+                Data::Raw(_) => panic!("expected pointers"),
+            }
+        };
 
-        Ok(())
+        let greedy = regions(AllocStrategy::Greedy);
+        let best_fit = regions(AllocStrategy::BestFit);
+        assert_eq!(best_fit, 1);
+        assert!(best_fit < greedy);
     }
 
-    #[inline]
-    pub fn deserialize_from<R>(mut r: R) -> anyhow::Result<Self>
-    where
-        R: Read + Seek,
-    {
-        let inode: Inode = bincode::deserialize_from(&mut r)?;
-        Ok(inode)
+    #[test]
+    fn test_fsck_directory_collisions() {
+        let img = TempImage::new("fsck_directory_collisions");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("a").unwrap();
+        fs.create_directory("b").unwrap();
+        fs.create_directory("c").unwrap();
+        assert!(fs.fsck().unwrap().directory_collisions.is_empty());
+
+        // Corrupt index pointing b to the directory of a
+        let mut directory_index = fs.get_directory_index().unwrap();
+        let a = *directory_index.find_dir("a").unwrap();
+        directory_index.relink_dir(std::ffi::OsStr::new("b"), a);
+        fs.save_directory_index(directory_index).unwrap();
+
+        let report = fs.fsck().unwrap();
+        assert_eq!(
+            report.directory_collisions,
+            [vec![PathBuf::from("a"), PathBuf::from("b")]]
+        );
+        assert!(!report.is_clean());
     }
 
-    #[inline]
-    fn set_last_modified(&mut self) {
-        self.last_modified = now();
+    #[test]
+    fn test_import_tree() {
+        let img = TempImage::new("import_tree");
+        let host = std::env::temp_dir()
+            .join(format!("walnut-import-tree-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&host);
+        std::fs::create_dir_all(host.join("sub").join("empty")).unwrap();
+        let large = vec![3u8; BLOCK_SIZE as usize + 3];
+        std::fs::write(host.join("a"), b"alpha").unwrap();
+        std::fs::write(host.join("sub").join("b"), &large).unwrap();
+
+        // Names that are not UTF-8 cannot be stored
+        #[cfg(unix)]
+        let bad = {
+            use std::os::unix::ffi::OsStrExt;
+            let bad = host.join(std::ffi::OsStr::from_bytes(b"bad\xff"));
+            std::fs::write(&bad, b"bad").unwrap();
+            bad
+        };
+
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        let stats = fs.import_tree(&host, "seed").unwrap();
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.bytes, 5 + large.len() as u64);
+        assert!(fs.dir_exists("seed/sub/empty"));
+        let mut out = vec![];
+        fs.get_file_data("seed/sub", "b", &mut out).unwrap();
+        assert_eq!(out, large);
+
+        #[cfg(unix)]
+        {
+            let skipped: Vec<_> = stats.skipped.iter().map(|s| &s.0).collect();
+            assert_eq!(skipped, [&bad]);
+            assert!(fs.import_dir(&host, "strict").is_err());
+        }
+
+        std::fs::remove_dir_all(host).unwrap();
     }
 
-    #[inline]
-    fn set_raw_data<R>(
-        &mut self,
-        data: &mut R,
-        data_size: u64,
-    ) -> anyhow::Result<()>
-    where
-        R: Read,
-    {
-        let mut buffer = vec![];
-        let data_len = data.read_to_end(&mut buffer)?;
+    #[test]
+    fn test_ls_stream() {
+        /// Writer counting its write calls
+        #[derive(Default)]
+        struct CountingWriter {
+            writes: usize,
+            data: Vec<u8>,
+        }
 
-        if data_len != data_size as usize {
-            return Err(anyhow!(
-                "Data read and given data size are not the same"
-            ));
+        impl Write for CountingWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.writes += 1;
+                self.data.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
         }
 
-        if data_len > INODE_CAPACITY {
-            return Err(anyhow!(
-                "Data is too big to be raw data. Does not fit inside inode"
+        let img = TempImage::new("ls_stream");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("big").unwrap();
+        let names: Vec<String> = (0..400)
+            .map(|i| format!("file_with_a_long_name_{:04}", i))
+            .collect();
+        let mut directory = fs.find_directory("big").unwrap().0;
+        for name in &names {
+            directory.files.insert(name.clone(), ROOT_INODE_INDEX);
+        }
+        let (_, dir_inode_index) = fs.find_directory("big").unwrap();
+        fs.save_directory(directory, dir_inode_index).unwrap();
+
+        // Directory data is stored in blocks
+        let inode = fs.get_inode(dir_inode_index).unwrap();
+        assert!(matches!(inode.data, Data::DirectPointers(_)));
+
+        let mut w = CountingWriter::default();
+        assert_eq!(fs.ls_stream("big", &mut w).unwrap(), 400);
+        assert!(w.writes >= names.len());
+        let lines: Vec<String> = String::from_utf8(w.data)
+            .unwrap()
+            .lines()
+            .map(|line| line.split(" |").next().unwrap().to_string())
+            .collect();
+        assert_eq!(lines, names);
+        assert!(fs.ls_stream("missing", &mut std::io::sink()).is_err());
+    }
+
+    #[test]
+    fn test_export_tree() {
+        let img = TempImage::new("export_tree");
+        let temp = |name: &str| {
+            let path = std::env::temp_dir().join(format!(
+                "walnut-export-tree-{}-{}",
+                name,
+                std::process::id()
             ));
+            let _ = std::fs::remove_dir_all(&path);
+            path
+        };
+        let host = temp("host");
+        let files = [
+            ("a", vec![1u8; 10]),
+            ("sub/b", (0..3 * BLOCK_SIZE + 7).map(|i| i as u8).collect()),
+            ("sub/deep/c", vec![]),
+        ];
+        std::fs::create_dir_all(host.join("sub/deep")).unwrap();
+        std::fs::create_dir_all(host.join("empty")).unwrap();
+        for (path, data) in &files {
+            std::fs::write(host.join(path), data).unwrap();
         }
 
-        self.size = data_size;
-        self.data = Data::Raw(buffer);
-        Ok(())
-    }
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.import_tree(&host, "seed").unwrap();
+        fs.create_directory("other").unwrap();
+        add_bytes(&mut fs, "other", "x", b"not exported");
 
-    #[inline]
-    fn set_direct_pointers(
-        &mut self,
-        pointers: Vec<(u32, u32)>,
-        data_size: u64,
-    ) {
-        self.data = Data::DirectPointers(pointers);
-        self.size = data_size;
-    }
-}
+        let dest = temp("dest");
+        fs.export_tree("seed", &dest).unwrap();
+        for (path, data) in &files {
+            assert_eq!(&std::fs::read(dest.join(path)).unwrap(), data);
+        }
+        assert!(dest.join("empty").is_dir());
+        assert!(!dest.join("x").exists());
+        assert!(fs.export_tree("missing", &dest).is_err());
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub struct DirectoryIndex {
-    directories: BTreeMap<OsString, u32>,
-    checksum: u32,
-}
+        std::fs::remove_dir_all(host).unwrap();
+        std::fs::remove_dir_all(dest).unwrap();
+    }
 
-impl DirectoryIndex {
-    pub fn init() -> Self {
-        let mut r = Self {
-            directories: BTreeMap::new(),
-            checksum: 0,
+    #[test]
+    fn test_walk() {
+        let img = TempImage::new("walk");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        for dir in ["a", "a/b", "c", "empty"] {
+            fs.create_directory(dir).unwrap();
+        }
+        add_bytes(&mut fs, "a", "1", b"1");
+        add_bytes(&mut fs, "a/b", "2", b"2");
+        add_bytes(&mut fs, "c", "3", b"3");
+        add_bytes(&mut fs, "c", "4", b"4");
+
+        let names = |fs: &FS| -> Vec<_> {
+            fs.walk()
+                .unwrap()
+                .map(|entry| {
+                    entry.map(|(dir, name, _)| {
+                        format!("{}/{}", dir.to_string_lossy(), name)
+                    })
+                })
+                .collect()
         };
-        r.checksum();
-        r
-    }
-    pub fn find_dir<P>(&self, dir: P) -> Option<&u32>
-    where
-        P: AsRef<Path>,
-    {
-        self.directories.get(dir.as_ref().as_os_str())
+        let walked: Vec<_> =
+            names(&fs).into_iter().map(Result::unwrap).collect();
+        assert_eq!(walked, ["a/1", "a/b/2", "c/3", "c/4"]);
+        let inode = fs.walk().unwrap().next().unwrap().unwrap().2;
+        assert_eq!(inode, fs.get_file_info("a", "1").unwrap().block_index);
+
+        // Directory data no longer matching its checksum
+        let (_, dir_inode_index) = fs.find_directory("a/b").unwrap();
+        let mut inode = fs.get_inode(dir_inode_index).unwrap();
+        inode.data_checksum ^= 1;
+        let mut w = BufWriter::new(&fs.file);
+        w.seek(SeekFrom::Start(block_seek_position(
+            dir_inode_index,
+            BLOCK_SIZE,
+        )))
+        .unwrap();
+        inode.serialize_into(w, BLOCK_SIZE).unwrap();
+
+        let walked = names(&fs);
+        assert_eq!(walked.len(), 4);
+        assert!(walked[1].is_err());
+        let ok: Vec<_> = walked.into_iter().filter_map(Result::ok).collect();
+        assert_eq!(ok, ["a/1", "c/3", "c/4"]);
     }
-    pub fn create_dir<P>(&mut self, dir: P, inode_index: u32) -> Option<&u32>
-    where
-        P: AsRef<Path>,
-    {
-        if self.find_dir(&dir).is_some() {
-            return None;
+
+    #[test]
+    fn test_max_directory_depth() {
+        let img = TempImage::new("max_directory_depth");
+        {
+            let mut fs = FS::init(img.path(), SECRET).unwrap();
+            assert_eq!(fs.max_directory_depth(), 0);
+            fs.set_max_directory_depth(3).unwrap();
         }
-        self.directories
-            .insert(dir.as_ref().as_os_str().to_os_string(), inode_index);
-        self.find_dir(dir)
+
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.max_directory_depth(), 3);
+        fs.create_directory("a/b/c").unwrap();
+        fs.create_directory("/x/y/z").unwrap();
+
+        // Parents are not created either
+        let err = fs.create_directory("d/e/f/g").unwrap_err();
+        assert_eq!(err.to_string(), "Directory depth 4 exceeds the limit of 3");
+        assert!(!fs.dir_exists("d"));
+        assert!(fs.create_directory("a/b/c/d").is_err());
+
+        fs.set_max_directory_depth(0).unwrap();
+        fs.create_directory("a/b/c/d").unwrap();
     }
-    pub fn move_dir<P>(&mut self, from: P, to: P) -> anyhow::Result<()>
-    where
-        P: AsRef<Path>,
-    {
-        if self.find_dir(&from).is_none() {
-            return Err(anyhow!("From directory not found"));
-        }
-        if self.find_dir(&to).is_some() {
-            return Err(anyhow!("Target directory has already exist"));
+
+    #[test]
+    fn test_structured_errors() {
+        let img = TempImage::new("structured_errors");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "a", &vec![1u8; 2 * BLOCK_SIZE as usize]);
+        add_bytes(&mut fs, "docs", "b", b"b");
+
+        assert!(matches!(
+            fs.get_file_info("docs", "missing"),
+            Err(WalnutError::FileNotFound)
+        ));
+        assert!(matches!(
+            fs.get_file_info("nowhere", "a"),
+            Err(WalnutError::DirNotFound)
+        ));
+        match fs.rename_file("docs", "a", "b") {
+            Err(WalnutError::FileExists(name)) => assert_eq!(name, "b"),
+            res => panic!("unexpected {:?}", res),
         }
+        assert!(matches!(
+            FS::new(img.path(), "other secret"),
+            Err(WalnutError::WrongSecret)
+        ));
+        assert!(matches!(
+            FS::new(img.path().with_extension("missing"), SECRET),
+            Err(WalnutError::Io(_))
+        ));
+
+        // Flip a byte of the file data on disk
+        let block = fs.get_file_info("docs", "a").unwrap().data_blocks()[0];
+        let pos = block_seek_position(block, BLOCK_SIZE);
+        (&fs.file).seek(SeekFrom::Start(pos)).unwrap();
+        (&fs.file).write_all(&[0xff]).unwrap();
+        assert!(matches!(
+            fs.get_file_data("docs", "a", &mut std::io::sink()),
+            Err(WalnutError::ChecksumMismatch)
+        ));
+
+        // Errors keep converting into anyhow
+        let err: anyhow::Error =
+            fs.get_file_info("docs", "x").unwrap_err().into();
+        assert_eq!(err.to_string(), "File not found");
+    }
 
-        let dir_inode =
-            self.directories.remove(from.as_ref().as_os_str()).unwrap();
+    #[test]
+    fn test_copy_file() {
+        let block = BLOCK_SIZE as usize;
+        let img = TempImage::new("copy_file");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("a").unwrap();
+        fs.create_directory("b").unwrap();
+        let data: Vec<u8> = (0..3 * block + 17).map(|i| i as u8).collect();
+        add_bytes(&mut fs, "a", "big", &data);
+        add_bytes(&mut fs, "a", "small", b"small");
+
+        fs.copy_file("a", "big", "b", "big").unwrap();
+        fs.copy_file("a", "small", "b", "small").unwrap();
+
+        // Copy has the same data in its own blocks
+        let source = fs.get_file_info("a", "big").unwrap();
+        let copy = fs.get_file_info("b", "big").unwrap();
+        assert_eq!(copy.data_checksum, source.data_checksum);
+        assert_eq!(copy.size, source.size);
+        let source_blocks = source.data_blocks();
+        assert!(copy
+            .data_blocks()
+            .iter()
+            .all(|b| !source_blocks.contains(b)));
+        let small = fs.get_file_info("a", "small").unwrap();
+        let small_copy = fs.get_file_info("b", "small").unwrap();
+        assert_eq!(small_copy.data_checksum, small.data_checksum);
+
+        // Removing the source keeps the copy
+        fs.remove_file("a", "big").unwrap();
+        let mut out = vec![];
+        fs.get_file_data("b", "big", &mut out).unwrap();
+        assert_eq!(out, data);
+        let mut out = vec![];
+        fs.get_file_data("b", "small", &mut out).unwrap();
+        assert_eq!(out, b"small");
+        assert!(fs.fsck().unwrap().is_clean());
+
+        assert!(matches!(
+            fs.copy_file("a", "small", "b", "small"),
+            Err(WalnutError::FileExists(_))
+        ));
+        assert!(matches!(
+            fs.copy_file("a", "big", "b", "big2"),
+            Err(WalnutError::FileNotFound)
+        ));
+    }
 
-        let _ = self
-            .directories
-            .insert(to.as_ref().as_os_str().to_os_string(), dir_inode);
+    #[test]
+    fn test_name_policy() {
+        let img = TempImage::new("name_policy");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        assert_eq!(fs.name_policy(), NamePolicy::Permissive);
+        add_bytes(&mut fs, "docs", "a:b", b"data");
+
+        fs.set_name_policy(NamePolicy::Portable).unwrap();
+        for name in ["c:d", "e?", "f\\g", "h.", "nul.txt", "COM1"] {
+            let res = fs.add_file("docs", name, &mut Cursor::new(b"data"), 4);
+            assert!(res.is_err(), "{}", name);
+        }
+        assert!(fs.rename_file("docs", "a:b", "x|y").is_err());
+        add_bytes(&mut fs, "docs", "report.txt", b"data");
+        add_bytes(&mut fs, "docs", "COM10", b"data");
+
+        // Existing names can still be written, policy persists
+        add_bytes(&mut fs, "docs", "a:b", b"new");
+        drop(fs);
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.name_policy(), NamePolicy::Portable);
+        fs.rename_file("docs", "a:b", "a_b").unwrap();
+
+        fs.set_name_policy(NamePolicy::Permissive).unwrap();
+        add_bytes(&mut fs, "docs", "c:d", b"data");
+        assert!(fs.file_exists("docs", "c:d").unwrap());
+    }
 
-        Ok(())
+    #[test]
+    fn test_directory_stats() {
+        let img = TempImage::new("directory_stats");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        fs.create_directory("docs/sub").unwrap();
+        let data = vec![1u8; BLOCK_SIZE as usize + 10];
+        add_bytes(&mut fs, "docs", "a", b"a");
+        add_bytes(&mut fs, "docs", "b", &data);
+        add_bytes(&mut fs, "docs", "c", b"ccc");
+        add_bytes(&mut fs, "docs/sub", "d", b"not counted");
+
+        fs.remove_file("docs", "a").unwrap();
+        fs.remove_file("docs/sub", "d").unwrap();
+        assert_eq!(fs.superblock.file_count, 2);
+
+        let stats = fs.directory_stats("docs").unwrap();
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.bytes, data.len() as u64 + 3);
+        assert_eq!(
+            fs.directory_stats("docs/sub").unwrap(),
+            DirStats::default()
+        );
+        assert!(fs.directory_stats("missing").is_err());
     }
-    pub fn directories(&self) -> &BTreeMap<OsString, u32> {
-        &self.directories
+
+    #[test]
+    fn test_compact() {
+        let block = BLOCK_SIZE as usize;
+        let img = TempImage::new("compact");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Tail spills over to the second group, in two regions
+        add_bytes(&mut fs, "docs", "big", &vec![1; 40 * block]);
+        let tail: Vec<u8> = (0..30 * block - 7).map(|i| i as u8).collect();
+        add_bytes(&mut fs, "docs", "tail", &tail);
+        let before = fs.get_file_info("docs", "tail").unwrap();
+        assert!(
+            matches!(&before.data, Data::DirectPointers(p) if p.len() == 2)
+        );
+        assert_eq!(fs.groups.len(), 2);
+        fs.remove_file("docs", "big").unwrap();
+        let len = std::fs::metadata(img.path()).unwrap().len();
+
+        let report = fs.compact().unwrap();
+        assert_eq!(report.bytes_moved, tail.len() as u64);
+        assert_eq!(report.regions_eliminated, 1);
+        assert_eq!(report.groups_removed, 1);
+        assert_eq!(fs.groups.len(), 1);
+        assert!(std::fs::metadata(img.path()).unwrap().len() < len);
+
+        // Data moved to the front, the inode stayed
+        let after = fs.get_file_info("docs", "tail").unwrap();
+        assert_eq!(after.block_index, before.block_index);
+        assert_eq!(after.last_modified, before.last_modified);
+        assert!(after.data_blocks()[0] < before.data_blocks()[0]);
+        assert!(fs.fsck().unwrap().is_clean());
+        let mut out = vec![];
+        fs.get_file_data("docs", "tail", &mut out).unwrap();
+        assert_eq!(out, tail);
+
+        // Nothing left to move, the image opens again
+        assert_eq!(fs.compact().unwrap(), CompactReport::default());
+        drop(fs);
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        let mut out = vec![];
+        fs.get_file_data("docs", "tail", &mut out).unwrap();
+        assert_eq!(out, tail);
     }
-    fn checksum(&mut self) {
-        self.checksum = 0;
-        self.checksum = calculate_checksum(&self);
+
+    #[test]
+    fn test_maintenance_defragment_rekey() {
+        let block = BLOCK_SIZE as usize;
+        let img = TempImage::new("maintenance");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        // Tail in two regions, a raw file and a clone sharing blocks
+        add_bytes(&mut fs, "docs", "big", &vec![1; 40 * block]);
+        let tail: Vec<u8> = (0..30 * block - 7).map(|i| i as u8).collect();
+        add_bytes(&mut fs, "docs", "tail", &tail);
+        fs.remove_file("docs", "big").unwrap();
+        add_bytes(&mut fs, "docs", "raw", b"raw");
+        let shared: Vec<u8> = (0..2 * block).map(|i| (i / 7) as u8).collect();
+        add_bytes(&mut fs, "docs", "shared", &shared);
+        fs.clone_file("docs", "shared", "docs", "clone").unwrap();
+        let regions = fs.fragmentation().unwrap();
+
+        let report = fs
+            .maintenance(MaintenanceOpts {
+                defragment: true,
+                rekey: Some("new secret".into()),
+                compact: false,
+            })
+            .unwrap();
+        assert_eq!(report.compact.bytes_moved, tail.len() as u64);
+        assert_eq!(report.compact.regions_eliminated, 1);
+        assert!(report.bytes_rekeyed > (tail.len() + shared.len()) as u64);
+        assert!(fs.fragmentation().unwrap() < regions);
+        drop(fs);
+
+        assert!(!FS::check_secret(img.path(), SECRET));
+        let mut fs = FS::new(img.path(), "new secret").unwrap();
+        for (name, data) in [
+            ("tail", &tail),
+            ("raw", &b"raw".to_vec()),
+            ("shared", &shared),
+            ("clone", &shared),
+        ] {
+            let mut out = vec![];
+            fs.get_file_data("docs", name, &mut out).unwrap();
+            assert_eq!(&out, data, "{}", name);
+        }
+        assert!(fs.fsck().unwrap().is_clean());
     }
 
-    fn verify_checksum(&mut self) -> bool {
-        let checksum = self.checksum;
-        self.checksum = 0;
-        let ok = checksum == calculate_checksum(&self);
-        self.checksum = checksum;
+    #[test]
+    fn test_shrink() {
+        let block = BLOCK_SIZE as usize;
+        let group_bytes = (MIN_BLOCKS_PER_GROUP as u64 + 1) * block as u64;
+        let img = TempImage::new("shrink");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        fs.create_directory("docs").unwrap();
+        assert_eq!(fs.shrink().unwrap(), 0);
+
+        add_bytes(&mut fs, "docs", "a", &vec![1; 20 * block]);
+        add_bytes(&mut fs, "docs", "b", &vec![2; 120 * block]);
+        assert_eq!(fs.groups.len(), 3);
+        let len = std::fs::metadata(img.path()).unwrap().len();
+
+        // A free bitmap with referenced blocks is not dropped
+        let groups = fs.groups.clone();
+        fs.groups[2].block_bitmap.fill(false);
+        assert!(fs.shrink().is_err());
+        assert_eq!(fs.groups.len(), 3);
+        fs.groups = groups;
+
+        fs.remove_file("docs", "b").unwrap();
+        assert_eq!(fs.shrink().unwrap(), 2 * group_bytes);
+        assert_eq!(fs.groups.len(), 1);
+        assert_eq!(
+            std::fs::metadata(img.path()).unwrap().len(),
+            len - 2 * group_bytes
+        );
+        assert!(fs.fsck().unwrap().is_clean());
+        drop(fs);
+
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.superblock.group_count, 1);
+        let mut out = vec![];
+        fs.get_file_data("docs", "a", &mut out).unwrap();
+        assert_eq!(out, vec![1; 20 * block]);
+    }
 
-        ok
+    #[test]
+    fn test_file_reader_verifies_checksum() {
+        let img = TempImage::new("file_reader_verifies");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE + 5).map(|i| i as u8).collect();
+        add_bytes(&mut fs, "docs", "good", &data);
+        add_bytes(&mut fs, "docs", "bad", &data);
+
+        let mut out = vec![];
+        fs.open_file("docs", "good")
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+
+        // Flip a byte of the last data block on disk
+        let block = fs.get_file_info("docs", "bad").unwrap().data_blocks()[2];
+        let pos = block_seek_position(block, BLOCK_SIZE) + 1;
+        let mut byte = [0u8];
+        (&fs.file).seek(SeekFrom::Start(pos)).unwrap();
+        (&fs.file).read_exact(&mut byte).unwrap();
+        byte[0] ^= 0xff;
+        (&fs.file).seek(SeekFrom::Start(pos)).unwrap();
+        (&fs.file).write_all(&byte).unwrap();
+
+        // Every byte is read before the error
+        let mut reader = fs.open_file("docs", "bad").unwrap();
+        let mut out = vec![];
+        let err = reader.read_to_end(&mut out).unwrap_err();
+        assert_eq!(out.len(), data.len());
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(matches!(
+            WalnutError::from(err),
+            WalnutError::ChecksumMismatch
+        ));
+        assert_eq!(reader.read(&mut [0; 8]).unwrap(), 0);
+
+        // Data read after a seek is not verified
+        let mut reader = fs.open_file("docs", "bad").unwrap();
+        reader.seek(SeekFrom::Start(1)).unwrap();
+        assert!(reader.read_to_end(&mut vec![]).is_ok());
     }
-}
 
-#[derive(Serialize, Deserialize, Debug, Default)]
-pub struct Directory {
-    pub files: BTreeMap<String, u32>,
-    checksum: u32,
-}
+    #[test]
+    fn test_in_memory() {
+        let block = BLOCK_SIZE as usize;
+        let mut fs = FS::init_in_memory(SECRET).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        fs.create_directory("docs").unwrap();
+        let data: Vec<u8> = (0..100 * block + 3).map(|i| i as u8).collect();
+        add_bytes(&mut fs, "docs", "small", b"small");
+        add_bytes(&mut fs, "docs", "large", &data);
+        assert!(fs.groups.len() > 1);
+        assert!(fs.fsck().unwrap().is_clean());
+
+        // Image shrinks in memory too
+        fs.remove_file("docs", "large").unwrap();
+        assert!(fs.shrink().unwrap() > 0);
+        add_bytes(&mut fs, "docs", "large", &data);
+        let size = fs.file.size().unwrap();
+
+        // Clones share the bytes, the image outlives the first handle
+        let storage = fs.file.try_clone().unwrap();
+        drop(fs);
+        assert!(
+            FS::from_storage(storage.try_clone().unwrap(), "other").is_err()
+        );
+        let mut fs = FS::from_storage(storage, SECRET).unwrap();
+        assert_eq!(fs.file.size().unwrap(), size);
+        let mut out = vec![];
+        fs.get_file_data("docs", "large", &mut out).unwrap();
+        assert_eq!(out, data);
+        let mut out = vec![];
+        fs.get_file_data("docs", "small", &mut out).unwrap();
+        assert_eq!(out, b"small");
+    }
 
-impl Directory {
-    fn init() -> Self {
-        let mut dir = Directory {
-            files: BTreeMap::new(),
-            checksum: 0,
-        };
-        dir.checksum();
-        dir
+    #[test]
+    fn test_config() {
+        let img = TempImage::new("config");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        assert!(fs.config().unwrap().is_empty());
+        fs.set_config("alloc_strategy", "best_fit").unwrap();
+        fs.set_config("max_regions", "4").unwrap();
+        fs.set_config("future_option", "on").unwrap();
+        assert!(fs.set_config("alloc_strategy", "worst_fit").is_err());
+        assert!(fs.set_config("max_regions", "many").is_err());
+        drop(fs);
+
+        // Stored options replace the defaults at open
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.alloc_strategy(), AllocStrategy::BestFit);
+        assert_eq!(fs.max_regions_per_file(), 4);
+        assert_eq!(fs.config().unwrap().len(), 3);
+        assert!(fs.fsck().unwrap().is_clean());
+
+        // Options are metadata, not files
+        assert!(fs.list_subdirectories("").unwrap().is_empty());
+        assert_eq!(fs.superblock.file_count, 0);
+
+        fs.remove_config("alloc_strategy").unwrap();
+        drop(fs);
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.alloc_strategy(), AllocStrategy::Greedy);
+        assert_eq!(fs.max_regions_per_file(), 4);
+
+        // Reformat keeps the options
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        assert_eq!(fs.config().unwrap().len(), 2);
+        assert!(fs.fsck().unwrap().is_clean());
+
+        // Options that cannot be decoded are dropped
+        let mut inode = fs.get_inode(fs.superblock.config).unwrap();
+        let data = b"broken";
+        fs.write_inode_data(
+            &mut inode,
+            &mut Cursor::new(data),
+            data.len() as u64,
+            true,
+        )
+        .unwrap();
+        drop(fs);
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.max_regions_per_file(), inode_max_region(BLOCK_SIZE));
+        assert!(fs.config().unwrap().is_empty());
+        fs.set_config("max_regions", "4").unwrap();
+        assert_eq!(fs.config().unwrap().len(), 1);
+
+        fs.remove_config("max_regions").unwrap();
+        assert_eq!(fs.superblock.config, 0);
     }
 
-    pub fn get_file(&self, file_name: &str) -> Option<u32> {
-        // self.files.get(file_name).map(|x| *x)
-        self.files.get(file_name).copied()
+    #[test]
+    fn test_group_checksum() {
+        let img = TempImage::new("group_checksum");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "a", b"first");
+        drop(fs);
+        FS::new(img.path(), SECRET).unwrap();
+
+        // Flip a bit of the group bitmap
+        let position =
+            Group::seek_position(0, BLOCKS_PER_GROUP, BLOCK_SIZE) + 100;
+        let file = OpenOptions::new().write(true).open(img.path()).unwrap();
+        (&file).seek(SeekFrom::Start(position)).unwrap();
+        (&file).write_all(&[0x10]).unwrap();
+        drop(file);
+
+        assert!(FS::new(img.path(), SECRET).is_err());
+
+        // Recovery does not rely on the superblock checksums
+        FS::open_with_params(img.path(), SECRET, BLOCK_SIZE, 1).unwrap();
     }
 
-    pub fn add_file(
-        &mut self,
-        file_name: &str,
-        inode_block_index: u32,
-    ) -> anyhow::Result<()> {
-        match self.get_file(file_name) {
-            Some(_) => Err(anyhow!("File already exist")),
-            None => {
-                self.files.insert(file_name.into(), inode_block_index);
-                Ok(())
-            }
+    #[test]
+    fn test_incomplete_file() {
+        let img = TempImage::new("incomplete_file");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        add_bytes(&mut fs, "docs", "torn", &vec![3u8; 5000]);
+        let (directory, _) = fs.find_directory("docs").unwrap();
+        assert_eq!(directory.expected_size("torn"), Some(5000));
+
+        // Torn write, the inode lost its size
+        let mut inode = fs.get_file_info("docs", "torn").unwrap();
+        inode.size = 0;
+        fs.write_inode_as_is(&inode).unwrap();
+
+        let err = fs.get_file_data("docs", "torn", &mut vec![]).unwrap_err();
+        assert!(
+            matches!(err, WalnutError::IncompleteFile(name) if name == "torn")
+        );
+        assert!(matches!(
+            fs.open_file("docs", "torn"),
+            Err(WalnutError::IncompleteFile(_))
+        ));
+        assert_eq!(fs.get_file_info("docs", "torn").unwrap().size, 0);
+    }
+
+    #[test]
+    fn test_block_size_options() {
+        let img = TempImage::new("block_size_options");
+        assert!(FS::init_with_options(img.path(), SECRET, 3000).is_err());
+        assert!(FS::init_with_options(img.path(), SECRET, 512).is_err());
+        assert!(!img.path().exists());
+
+        // A group of the largest blocks spans 32GiB
+        for block_size in [MIN_BLOCK_SIZE, 2 * BLOCK_SIZE] {
+            let img = TempImage::new(&format!("block_size_{}", block_size));
+            let mut fs =
+                FS::init_with_options(img.path(), SECRET, block_size).unwrap();
+            assert_eq!(fs.block_size(), block_size);
+            assert_eq!(fs.blocks_per_group(), block_size * 8);
+            fs.create_directory("docs").unwrap();
+            add_bytes(&mut fs, "docs", "tiny", b"tiny");
+            let large: Vec<u8> = (0..3 * MAX_BLOCK_SIZE + 7)
+                .map(|i| (i % 251) as u8)
+                .collect();
+            add_bytes(&mut fs, "docs", "large", &large);
+            drop(fs);
+
+            let mut fs = FS::new(img.path(), SECRET).unwrap();
+            assert_eq!(fs.block_size(), block_size);
+            let mut out = vec![];
+            fs.get_file_data("docs", "large", &mut out).unwrap();
+            assert_eq!(out, large);
+            assert_eq!(
+                fs.tail("docs", "large", 10).unwrap(),
+                &large[large.len() - 10..]
+            );
+            assert_eq!(fs.head("docs", "tiny", 10).unwrap(), b"tiny");
+            assert!(fs.fsck().unwrap().is_clean());
+
+            // Image ends with the last group
+            let size = std::fs::metadata(img.path()).unwrap().len();
+            assert_eq!(size, (2 + 8 * block_size as u64) * block_size as u64);
         }
     }
 
-    fn remove_file(&mut self, file_name: &str) -> anyhow::Result<()> {
-        match self.files.remove(file_name) {
-            Some(_) => Ok(()),
-            None => Err(anyhow!("File not found!")),
+    #[test]
+    fn test_max_groups() {
+        let img = TempImage::new("max_groups");
+        let mut fs =
+            FS::init_with_options(img.path(), SECRET, MIN_BLOCK_SIZE).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        let max_groups = fs.max_groups();
+        assert!(max_groups > 1);
+
+        while fs.groups.len() < max_groups as usize {
+            fs.add_group(Group::init(MIN_BLOCKS_PER_GROUP)).unwrap();
+        }
+        let size = std::fs::metadata(img.path()).unwrap().len();
+
+        // No room for one more group, the image is left untouched
+        fs.create_directory("docs").unwrap();
+        let free = fs.free_blocks() as usize * MIN_BLOCK_SIZE as usize;
+        let res = fs.add_file(
+            "docs",
+            "large",
+            &mut &vec![1u8; free][..],
+            free as u64,
+        );
+        assert!(matches!(res, Err(WalnutError::OutOfSpace)));
+        assert_eq!(fs.groups.len(), max_groups as usize);
+        assert_eq!(std::fs::metadata(img.path()).unwrap().len(), size);
+        drop(fs);
+
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.groups.len(), max_groups as usize);
+        assert!(fs.fsck().unwrap().is_clean());
+        drop(fs);
+
+        // Superblock of the most groups fits for every block size
+        for block_size in [MIN_BLOCK_SIZE, BLOCK_SIZE, MAX_BLOCK_SIZE] {
+            let mut sb = Superblock::new(0);
+            sb.block_size = block_size;
+            sb.label = [b'x'; LABEL_SIZE];
+            sb.inode_counts =
+                vec![u32::MAX; super::max_groups(block_size) as usize];
+            sb.group_checksums = sb.inode_counts.clone();
+            assert!(sb.encode().len() <= block_size as usize);
+            sb.inode_counts.push(0);
+            sb.group_checksums.push(0);
+            assert!(sb.encode().len() > block_size as usize);
         }
     }
 
-    fn checksum(&mut self) {
-        self.checksum = 0;
-        self.checksum = calculate_checksum(&self);
+    #[test]
+    fn test_add_group_failure() {
+        let img = TempImage::new("add_group_failure");
+        let fs = FS::init(img.path(), SECRET).unwrap();
+        let superblock = fs.superblock.clone();
+        drop(fs);
+        let len = std::fs::metadata(img.path()).unwrap().len();
+
+        // Group cannot be written, nothing of it is kept
+        let file = File::open(img.path()).unwrap();
+        let mut fs = FS::from_storage(Storage::File(file), SECRET).unwrap();
+        assert!(fs.add_group(Group::init(BLOCKS_PER_GROUP)).is_err());
+        assert_eq!(fs.groups.len(), 1);
+        assert_eq!(fs.superblock.inode_counts, superblock.inode_counts);
+        assert_eq!(fs.superblock.group_count, 1);
+        assert_eq!(fs.free_blocks(), superblock.free_blocks);
+        assert_eq!(std::fs::metadata(img.path()).unwrap().len(), len);
     }
 
-    fn verify_checksum(&mut self) -> bool {
-        let checksum = self.checksum;
-        self.checksum = 0;
-        let ok = checksum == calculate_checksum(&self);
-        self.checksum = checksum;
+    #[test]
+    fn test_allocate_inode_failure() {
+        let img = TempImage::new("allocate_inode_failure");
+        let fs = FS::init(img.path(), SECRET).unwrap();
+        let superblock = fs.superblock.clone();
+        let free_inodes = fs.free_inodes();
+        drop(fs);
+
+        // Inode cannot be written, the allocation is undone
+        let file = File::open(img.path()).unwrap();
+        let mut fs = FS::from_storage(Storage::File(file), SECRET).unwrap();
+        assert!(fs.allocate_inode(false).is_err());
+        assert_eq!(fs.free_inodes(), free_inodes);
+        assert_eq!(fs.superblock.inode_counts, superblock.inode_counts);
+    }
 
-        ok
+    #[test]
+    fn test_block_bitmap_seek_position() {
+        assert_eq!(Group::seek_position(0, BLOCKS_PER_GROUP, BLOCK_SIZE), 4096);
+        assert_eq!(
+            Group::seek_position(1, BLOCKS_PER_GROUP, BLOCK_SIZE),
+            134_225_920
+        );
+        assert_eq!(Group::seek_position(1, 1024, BLOCK_SIZE), 1026 * 4096);
+        // Positions past 4GiB
+        assert_eq!(
+            Group::seek_position(
+                1,
+                max_blocks_per_group(MAX_BLOCK_SIZE),
+                MAX_BLOCK_SIZE
+            ),
+            (1 + 1 + 8 * MAX_BLOCK_SIZE as u64) * MAX_BLOCK_SIZE as u64
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    // use super::*;
-    // use std::io::Cursor;
-    // use std::time::{self, SystemTime};
+    #[test]
+    fn test_first_data_block() {
+        // First data block is the first byte after
+        // the superblock and the group 0 bitmap
+        assert_eq!(Group::first_data_block(0, BLOCKS_PER_GROUP), 2);
+        let first = Group::create_public_address(0, 0, BLOCKS_PER_GROUP);
+        assert_eq!(block_seek_position(first, BLOCK_SIZE), 2 * 4096);
+        assert_eq!(
+            block_seek_position(
+                Group::create_public_address(0, 1, BLOCKS_PER_GROUP),
+                BLOCK_SIZE
+            ),
+            3 * 4096
+        );
+    }
 
     #[test]
-    fn test_block_bitmap_seek_position() {
-        // let group = Group::new(0);
-        // assert_eq!(group.bitmap_seek_position(), BLOCK_SIZE);
+    fn test_file_across_groups() {
+        let img = TempImage::new("file_across_groups");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.reformat_groups(MIN_BLOCKS_PER_GROUP).unwrap();
+        fs.create_directory("docs").unwrap();
+
+        let data: Vec<u8> = (0..3 * MIN_BLOCKS_PER_GROUP * BLOCK_SIZE)
+            .map(|i| (i / BLOCK_SIZE) as u8 ^ i as u8)
+            .collect();
+        add_bytes(&mut fs, "docs", "big", &data);
+
+        // Blocks are spread over real group positions
+        let inode = fs.get_file_info("docs", "big").unwrap();
+        let groups: std::collections::BTreeSet<u32> = inode
+            .data_blocks()
+            .iter()
+            .map(|b| Group::group_of_block(*b, MIN_BLOCKS_PER_GROUP))
+            .collect();
+        assert!(groups.len() >= 3);
+        for block in inode.data_blocks() {
+            assert!(!fs.is_block_free(block));
+        }
+
+        drop(fs);
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        let mut out = vec![];
+        fs.get_file_data("docs", "big", &mut out).unwrap();
+        assert_eq!(out, data);
+    }
 
-        // let group = Group::new(1);
-        // assert_eq!(group.bitmap_seek_position(), 134_221_824);
+    #[test]
+    fn test_public_address_exhaustive() {
+        let block_size = BLOCK_SIZE as u64;
+        for bpg in [BLOCKS_PER_GROUP, 1024, MIN_BLOCKS_PER_GROUP] {
+            for group_index in 0..20 {
+                let bitmap_start =
+                    Group::seek_position(group_index, bpg, BLOCK_SIZE);
+                let data_start = bitmap_start + block_size;
+                let data_end =
+                    Group::seek_position(group_index + 1, bpg, BLOCK_SIZE);
+
+                for bitmap_index in 0..bpg {
+                    let block_index = Group::create_public_address(
+                        group_index,
+                        bitmap_index,
+                        bpg,
+                    );
+
+                    // Never collides with superblock, bitmaps
+                    // or other groups
+                    let offset = block_seek_position(block_index, BLOCK_SIZE);
+                    assert!(offset >= data_start);
+                    assert!(offset + block_size <= data_end);
+                    assert_eq!(
+                        offset,
+                        data_start + bitmap_index as u64 * block_size
+                    );
+
+                    assert_eq!(
+                        Group::translate_public_address(block_index, bpg),
+                        (group_index, bitmap_index)
+                    );
+                }
+            }
+        }
     }
 }
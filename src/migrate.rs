@@ -0,0 +1,753 @@
+//! Upgrade images created by older FS versions
+//! Every layout change keeps its previous layout here,
+//! so old images can be read and rewritten in the current one.
+
+use crate::*;
+
+/// Directory index layout before version 5
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DirectoryIndexV1 {
+    pub(crate) directories: BTreeMap<OsString, u32>,
+    pub(crate) checksum: u32,
+}
+
+/// Directory layout before version 5
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DirectoryV1 {
+    pub(crate) files: BTreeMap<String, u32>,
+    pub(crate) checksum: u32,
+}
+
+/// Directory layout before version 16
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DirectoryV5 {
+    pub(crate) files: BTreeMap<String, u32>,
+    pub(crate) hash: u32,
+    pub(crate) checksum: u32,
+}
+
+/// Directory layout before version 17
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DirectoryV16 {
+    pub(crate) files: BTreeMap<String, u32>,
+    pub(crate) hash: u32,
+    pub(crate) sizes: BTreeMap<String, u64>,
+    pub(crate) checksum: u32,
+}
+
+impl FS {
+    /// Upgrade on disk structures to the current FS version
+    pub(crate) fn upgrade(&mut self) -> anyhow::Result<()> {
+        let version = self.superblock.fs_version;
+
+        // Images before version 3 have no UUID yet
+        if version < 3 {
+            self.superblock.uuid = Uuid::new_v4().into_bytes();
+        }
+
+        // Directory hashes were added in version 5
+        if version < 5 {
+            self.upgrade_directory_hashes()?;
+        }
+
+        // Expected file sizes were added in version 16, directories
+        // upgraded from version 1 have them already
+        if (5..16).contains(&version) {
+            self.upgrade_directory_sizes()?;
+        }
+
+        // Creation sequences were added in version 17, entries
+        // created before keep sequence 0
+        if version == 16 {
+            self.upgrade_directory_sequences()?;
+        }
+
+        // Inode accounting was added in version 7
+        if version < 7 {
+            self.recount_inodes()?;
+        }
+
+        // Key check was added in version 11, only set for a secret
+        // that can read the directory index
+        if version < 11 {
+            self.get_directory_index()?;
+            self.superblock.key_check = key_check(self.cipher.as_ref());
+        }
+
+        // Scrub found its quarantine by name before version 18
+        if version < 18 {
+            if let Some(quarantine) =
+                self.get_directory_index()?.find_dir(CORRUPT_DIRECTORY)
+            {
+                self.superblock.quarantine = *quarantine;
+            }
+        }
+
+        // Options were kept in a file before version 18
+        if version < 18 {
+            self.upgrade_config()?;
+        }
+
+        // Older images keep holes without flagging them
+        if version >= 9 && self.has_holes()? {
+            self.superblock.flags |= FLAG_SPARSE;
+        }
+
+        // New superblock fields are already set to their defaults
+        // it is enough to save it using the current layout
+        self.superblock.fs_version = FS_VERSION;
+        self.save_superblock()
+    }
+
+    /// Rewrite the image in the layout of an older FS version
+    /// e.g. to share it with an older tool. Fails without changes
+    /// if the older layout cannot store every setting of the image.
+    /// The handle is consumed, open the image again to use it
+    pub fn downgrade(mut self, target_version: u32) -> Result<()> {
+        self.check_data_access()?;
+
+        let sb = &self.superblock;
+        if target_version >= sb.fs_version {
+            return Err(anyhow!(
+                "Image version {} is not newer than {}",
+                sb.fs_version,
+                target_version
+            )
+            .into());
+        }
+
+        // Step 1: check nothing gets lost
+        // Dropped fields have to hold the value an upgrade restores
+        // except the generation, a change counter can restart from 0,
+        // the key check and group checksums an upgrade computes again
+        // and creation sequences, listings fall back to creation times
+        let lost = if target_version < 3 {
+            Some("image UUID")
+        } else if target_version < 4
+            && sb.reserved_percentage != DEFAULT_RESERVED_PERCENTAGE
+        {
+            Some("reserved percentage")
+        } else if target_version < 6 && sb.flags & !FLAG_COMPRESS_INDEX != 0 {
+            Some("feature flags")
+        } else if target_version < 7 && sb.min_free_inodes != 0 {
+            Some("minimum free inodes")
+        } else if target_version < 8 && sb.cipher_id != XOR_CIPHER_ID {
+            Some("cipher")
+        } else if target_version < 9 && sb.blocks_per_group != BLOCKS_PER_GROUP
+        {
+            Some("blocks per group")
+        } else if target_version < 9
+            && (sb.flags & FLAG_SPARSE != 0 || self.has_holes()?)
+        {
+            // Older tools read holes from the superblock
+            Some("sparse files")
+        } else if target_version < 12 && sb.shared_blocks != 0 {
+            Some("shared blocks")
+        } else if target_version < 13 && sb.original_paths != 0 {
+            Some("original paths")
+        } else if target_version < 14 && sb.max_dir_depth != 0 {
+            Some("maximum directory depth")
+        } else if target_version < 18
+            && self
+                .quarantine_dir()?
+                .is_some_and(|q| q != Path::new(CORRUPT_DIRECTORY))
+        {
+            // Older tools find the quarantine by its name
+            Some("quarantine directory")
+        } else if target_version < 18 && sb.block_size != BLOCK_SIZE {
+            // Older tools seek by the default block size
+            Some("block size")
+        } else {
+            None
+        };
+        if let Some(lost) = lost {
+            return Err(anyhow!(
+                "Version {} cannot store the {}",
+                target_version,
+                lost
+            )
+            .into());
+        }
+
+        // Step 2: rewrite structures in their older layout
+        if target_version < 18 {
+            self.downgrade_config()?;
+        }
+        if target_version < 6 && self.index_compression() {
+            self.set_index_compression(false)?;
+        }
+        if target_version < 5 {
+            self.downgrade_directory_hashes()?;
+        } else if target_version < 16 {
+            self.downgrade_directory_sizes()?;
+        } else if target_version < 17 {
+            self.downgrade_directory_sequences()?;
+        }
+
+        // Step 3: superblock is encoded in the layout of its version
+        self.superblock.fs_version = target_version;
+        self.save_superblock()?;
+        self.file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Some file keeps a hole
+    fn has_holes(&self) -> anyhow::Result<bool> {
+        Ok(self
+            .inode_owners()?
+            .iter()
+            .any(|(_, inode)| match &inode.data {
+                Data::DirectPointers(pointers) => {
+                    pointers.iter().any(|(start, _)| *start == HOLE)
+                }
+                Data::Raw(_) => false,
+            }))
+    }
+
+    /// Move the options into the config file of the layout
+    /// before version 18
+    fn downgrade_config(&mut self) -> anyhow::Result<()> {
+        let config = self.read_config()?;
+        if config.is_empty() {
+            return Ok(());
+        }
+
+        if !self.dir_exists(CONFIG_DIRECTORY) {
+            self.create_directory(CONFIG_DIRECTORY)?;
+        }
+        let data = bincode::serialize(&config)?;
+        self.add_file(
+            CONFIG_DIRECTORY,
+            CONFIG_FILE,
+            &mut Cursor::new(&data),
+            data.len() as u64,
+        )?;
+        self.save_config(BTreeMap::new())
+    }
+
+    /// Move the options of the config file into their metadata inode
+    /// The file is removed, so is its directory once empty
+    fn upgrade_config(&mut self) -> anyhow::Result<()> {
+        let mut directory_index = self.get_directory_index()?;
+        let dir_inode_index = match directory_index.find_dir(CONFIG_DIRECTORY) {
+            Some(dir_inode_index) => *dir_inode_index,
+            None => return Ok(()),
+        };
+        let mut directory = self.read_directory(dir_inode_index)?;
+        let inode = match directory.get_file(CONFIG_FILE) {
+            Some(inode_index) => self.get_inode(inode_index)?,
+            None => return Ok(()),
+        };
+
+        // Broken options are dropped, they do not stop the upgrade
+        let config: BTreeMap<String, String> =
+            bincode::deserialize(&self.read_inode_bytes(inode.block_index)?)
+                .unwrap_or_default();
+
+        let old_hash = directory.hash();
+        directory.remove_file(CONFIG_FILE, &inode)?;
+        self.release_inode(inode.block_index)?;
+        let count = &mut self.superblock.file_count;
+        *count = count.saturating_sub(1);
+
+        if directory.files.is_empty()
+            && self.list_subdirectories(CONFIG_DIRECTORY)?.is_empty()
+        {
+            directory_index.remove_dir(CONFIG_DIRECTORY, old_hash);
+            self.save_directory_index(directory_index)?;
+            self.release_inode(dir_inode_index)?;
+        } else {
+            self.commit_directory(
+                CONFIG_DIRECTORY,
+                directory,
+                dir_inode_index,
+                old_hash,
+            )?;
+        }
+
+        self.save_config(config)
+    }
+
+    /// Rewrite directories and directory index without their hashes
+    /// in the layout before version 5
+    fn downgrade_directory_hashes(&mut self) -> anyhow::Result<()> {
+        let directory_index = self.get_directory_index()?;
+        let mut legacy_index = DirectoryIndexV1 {
+            directories: directory_index.directories().clone(),
+            checksum: 0,
+        };
+        legacy_index.checksum = calculate_checksum(&legacy_index);
+
+        for dir_inode_index in legacy_index.directories.values() {
+            let directory = self.read_directory(*dir_inode_index)?;
+            let legacy_dir = DirectoryV1 {
+                files: directory.files,
+                checksum: 0,
+            };
+            let data = bincode::serialize(&legacy_dir)?;
+            let mut inode = self.get_inode(*dir_inode_index)?;
+            self.write_inode_data(
+                &mut inode,
+                &mut Cursor::new(&data),
+                data.len() as u64,
+                true,
+            )?;
+        }
+
+        let data = bincode::serialize(&legacy_index)?;
+        let mut inode = self.get_inode(ROOT_INODE_INDEX)?;
+        self.write_inode_data(
+            &mut inode,
+            &mut Cursor::new(&data),
+            data.len() as u64,
+            true,
+        )
+    }
+
+    /// Rewrite directories without their expected file sizes
+    /// in the layout before version 16
+    fn downgrade_directory_sizes(&mut self) -> anyhow::Result<()> {
+        let directory_index = self.get_directory_index()?;
+        for dir_inode_index in directory_index.inode_indices() {
+            let directory = self.read_directory(dir_inode_index)?;
+            let mut legacy_dir = DirectoryV5 {
+                files: directory.files,
+                hash: directory.hash,
+                checksum: 0,
+            };
+            legacy_dir.checksum = calculate_checksum(&legacy_dir);
+
+            let data = bincode::serialize(&legacy_dir)?;
+            let mut inode = self.get_inode(dir_inode_index)?;
+            self.write_inode_data(
+                &mut inode,
+                &mut Cursor::new(&data),
+                data.len() as u64,
+                true,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite directories without their creation sequences
+    /// in the layout before version 17
+    fn downgrade_directory_sequences(&mut self) -> anyhow::Result<()> {
+        let directory_index = self.get_directory_index()?;
+        for dir_inode_index in directory_index.inode_indices() {
+            let directory = self.read_directory(dir_inode_index)?;
+            let mut legacy_dir = DirectoryV16 {
+                files: directory.files,
+                hash: directory.hash,
+                sizes: directory.sizes,
+                checksum: 0,
+            };
+            legacy_dir.checksum = calculate_checksum(&legacy_dir);
+
+            let data = bincode::serialize(&legacy_dir)?;
+            let mut inode = self.get_inode(dir_inode_index)?;
+            self.write_inode_data(
+                &mut inode,
+                &mut Cursor::new(&data),
+                data.len() as u64,
+                true,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite directories in the current layout without sequences
+    /// Entry hashes do not change, the directory index is kept
+    fn upgrade_directory_sequences(&mut self) -> anyhow::Result<()> {
+        let directory_index = self.get_directory_index()?;
+        for dir_inode_index in directory_index.inode_indices() {
+            let data = self.read_inode_bytes(dir_inode_index)?;
+            let legacy_dir: DirectoryV16 = bincode::deserialize(&data)?;
+
+            let mut directory = Directory::init();
+            directory.files = legacy_dir.files;
+            directory.hash = legacy_dir.hash;
+            directory.sizes = legacy_dir.sizes;
+            directory.checksum();
+            self.save_directory(directory, dir_inode_index)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite directories with the current sizes of their files
+    /// Entry hashes do not change, the directory index is kept
+    fn upgrade_directory_sizes(&mut self) -> anyhow::Result<()> {
+        let directory_index = self.get_directory_index()?;
+        for dir_inode_index in directory_index.inode_indices() {
+            let data = self.read_inode_bytes(dir_inode_index)?;
+            let legacy_dir: DirectoryV5 = bincode::deserialize(&data)?;
+
+            let mut directory = Directory::init();
+            for (file_name, file_inode_index) in &legacy_dir.files {
+                let inode = self.get_inode(*file_inode_index)?;
+                directory.sizes.insert(file_name.clone(), inode.size);
+            }
+            directory.files = legacy_dir.files;
+            directory.hash = legacy_dir.hash;
+            directory.checksum();
+            self.save_directory(directory, dir_inode_index)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite directories and directory index with their hashes
+    /// Data checksums were not stored before version 5 either,
+    /// file inodes get them here, directories when they are saved
+    fn upgrade_directory_hashes(&mut self) -> anyhow::Result<()> {
+        let data = self.read_legacy_bytes(ROOT_INODE_INDEX)?;
+        let legacy_index: DirectoryIndexV1 = bincode::deserialize(&data)?;
+
+        let mut directory_index = DirectoryIndex::init();
+
+        for (dir, dir_inode_index) in legacy_index.directories {
+            let data = self.read_legacy_bytes(dir_inode_index)?;
+            let legacy_dir: DirectoryV1 = bincode::deserialize(&data)?;
+
+            let mut directory = Directory::init();
+            for (file_name, file_inode_index) in legacy_dir.files {
+                let mut inode = self.get_inode(file_inode_index)?;
+                inode.data_checksum = self.read_inode_data_unverified(
+                    &mut inode.clone(),
+                    &mut std::io::sink(),
+                )?;
+                self.save_inode(&mut inode)?;
+                directory.add_file(&file_name, &inode)?;
+            }
+
+            directory_index.insert_dir(&dir, dir_inode_index, directory.hash());
+            self.save_directory(directory, dir_inode_index)?;
+        }
+
+        self.save_directory_index(directory_index)
+    }
+
+    /// Whether the cipher reads the directory index
+    /// in the layout of the image version
+    pub(crate) fn index_readable(&self) -> bool {
+        if self.superblock.fs_version >= 5 {
+            return self.get_directory_index().is_ok();
+        }
+
+        let Ok(data) = self.read_legacy_bytes(ROOT_INODE_INDEX) else {
+            return false;
+        };
+        match bincode::deserialize::<DirectoryIndexV1>(&data) {
+            Ok(mut legacy_index) => {
+                let checksum = legacy_index.checksum;
+                legacy_index.checksum = 0;
+                checksum == calculate_checksum(&legacy_index)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Read inode data without a stored checksum
+    fn read_legacy_bytes(&self, inode_index: u32) -> anyhow::Result<Vec<u8>> {
+        let mut inode = self.get_inode(inode_index)?;
+        let mut data = Vec::new();
+        self.read_inode_data_unverified(&mut inode, &mut data)?;
+        Ok(data)
+    }
+
+    /// Count inodes of the directory index, directories and files
+    /// Also used by superblock recovery
+    pub(crate) fn recount_inodes(&mut self) -> anyhow::Result<()> {
+        let mut inodes = vec![ROOT_INODE_INDEX];
+
+        let directory_index = self.get_directory_index()?;
+        for dir_inode_index in directory_index.inode_indices() {
+            inodes.push(dir_inode_index);
+            let directory = self.read_directory(dir_inode_index)?;
+            inodes.extend(directory.inode_indices());
+        }
+
+        self.superblock.inode_counts = vec![0; self.groups.len()];
+        let blocks_per_group = self.blocks_per_group();
+        for inode_index in inodes {
+            let (group_index, _) =
+                Group::translate_public_address(inode_index, blocks_per_group);
+            self.superblock.inode_counts[group_index as usize] += 1;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    /// Rewrite directories in the layout before version 5
+    /// with the data checksums left at their initial value
+    fn write_v1_directories(fs: &mut FS) {
+        let mut inodes = vec![ROOT_INODE_INDEX];
+        let directory_index = fs.get_directory_index().unwrap();
+        for dir_inode_index in directory_index.inode_indices() {
+            inodes.push(dir_inode_index);
+            let directory = fs.read_directory(dir_inode_index).unwrap();
+            inodes.extend(directory.inode_indices());
+        }
+
+        fs.downgrade_directory_hashes().unwrap();
+        for inode_index in inodes {
+            let mut inode = fs.get_inode(inode_index).unwrap();
+            inode.data_checksum = calculate_checksum(&());
+            fs.save_inode(&mut inode).unwrap();
+        }
+        fs.superblock.fs_version = 4;
+        fs.save_superblock().unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_directory_hashes() {
+        let img = TempImage::new("upgrade_directory_hashes");
+        let (dir_hash, index_hash) = {
+            let mut fs = FS::init(img.path(), SECRET).unwrap();
+            fs.create_directory("docs").unwrap();
+            add_bytes(&mut fs, "docs", "a", b"first");
+            add_bytes(&mut fs, "docs", "b", &vec![1u8; 5000]);
+            let hashes =
+                (fs.directory_hash("docs").unwrap(), fs.index_hash().unwrap());
+            write_v1_directories(&mut fs);
+            hashes
+        };
+
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.superblock.fs_version, FS_VERSION);
+        assert_eq!(fs.directory_hash("docs").unwrap(), dir_hash);
+        assert_eq!(fs.index_hash().unwrap(), index_hash);
+        assert_eq!(fs.inode_count(), 4);
+
+        let mut out = vec![];
+        fs.get_file_data("docs", "b", &mut out).unwrap();
+        assert_eq!(out, vec![1u8; 5000]);
+    }
+
+    #[test]
+    fn test_upgrade_directory_sizes() {
+        let img = TempImage::new("upgrade_directory_sizes");
+        let dir_hash = {
+            let mut fs = FS::init(img.path(), SECRET).unwrap();
+            fs.create_directory("docs").unwrap();
+            add_bytes(&mut fs, "docs", "a", &vec![1u8; 5000]);
+            let dir_hash = fs.directory_hash("docs").unwrap();
+            fs.downgrade(15).unwrap();
+            dir_hash
+        };
+
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.superblock.fs_version, FS_VERSION);
+        assert_eq!(fs.directory_hash("docs").unwrap(), dir_hash);
+        let (directory, _) = fs.find_directory("docs").unwrap();
+        assert_eq!(directory.expected_size("a"), Some(5000));
+    }
+
+    #[test]
+    fn test_upgrade_directory_sequences() {
+        let img = TempImage::new("upgrade_directory_sequences");
+        let dir_hash = {
+            let mut fs = FS::init(img.path(), SECRET).unwrap();
+            fs.create_directory("docs").unwrap();
+            add_bytes(&mut fs, "docs", "b", b"first");
+            let dir_hash = fs.directory_hash("docs").unwrap();
+            fs.downgrade(16).unwrap();
+            dir_hash
+        };
+
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.superblock.fs_version, FS_VERSION);
+        assert_eq!(fs.directory_hash("docs").unwrap(), dir_hash);
+        let (directory, _) = fs.find_directory("docs").unwrap();
+        assert_eq!(directory.sequence("b"), 0);
+        assert_eq!(directory.expected_size("b"), Some(5));
+
+        // Entries created after the upgrade come last
+        add_bytes(&mut fs, "docs", "a", b"second");
+        let order: Vec<String> = fs
+            .list_ordered("docs", ListOrder::Creation)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(order, ["b", "a"]);
+    }
+
+    #[test]
+    fn test_downgrade_block_size() {
+        let img = TempImage::new("downgrade_block_size");
+        let fs =
+            FS::init_with_options(img.path(), SECRET, MIN_BLOCK_SIZE).unwrap();
+        let mut sb = fs.superblock.clone();
+        let err = fs.downgrade(17).unwrap_err();
+        assert_eq!(err.to_string(), "Version 17 cannot store the block size");
+
+        // Older versions always had the default block size
+        sb.fs_version = 17;
+        let err = Superblock::decode(Cursor::new(sb.encode())).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Block size 1024 is not supported by version 17"
+        );
+
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.superblock.fs_version, FS_VERSION);
+        assert_eq!(fs.block_size(), MIN_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_downgrade_quarantine() {
+        let img = TempImage::new("downgrade_quarantine");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_quarantine().unwrap();
+        fs.downgrade(17).unwrap();
+
+        // Upgrade finds the quarantine by its old name
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        let (_, inode_index) = fs.find_directory(CORRUPT_DIRECTORY).unwrap();
+        assert_eq!(fs.superblock.quarantine, inode_index);
+
+        // Other names are lost to older tools
+        fs.remove_directory(CORRUPT_DIRECTORY).unwrap();
+        fs.create_directory(CORRUPT_DIRECTORY).unwrap();
+        assert_eq!(fs.create_quarantine().unwrap(), Path::new(".corrupt.1"));
+        let err = fs.downgrade(17).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Version 17 cannot store the quarantine directory"
+        );
+    }
+
+    #[test]
+    fn test_downgrade_config() {
+        let img = TempImage::new("downgrade_config");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.set_config("max_regions", "4").unwrap();
+        fs.downgrade(17).unwrap();
+
+        // Older layouts keep the options in a file
+        let mut fs = FS::open_read_write(img.path()).unwrap();
+        fs.cipher = cipher_by_id(XOR_CIPHER_ID, SECRET).unwrap();
+        assert_eq!(fs.superblock.fs_version, 17);
+        assert_eq!(fs.superblock.config, 0);
+        assert!(fs.dir_exists(CONFIG_DIRECTORY));
+        drop(fs);
+
+        // Upgrade moves them back and removes the file
+        let fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.superblock.fs_version, FS_VERSION);
+        assert_eq!(fs.max_regions_per_file(), 4);
+        assert_ne!(fs.superblock.config, 0);
+        assert!(!fs.dir_exists(CONFIG_DIRECTORY));
+        assert_eq!(fs.superblock.file_count, 0);
+        assert!(fs.fsck().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_downgrade_sparse() {
+        let img = TempImage::new("downgrade_sparse");
+        let mut fs = FS::init(img.path(), SECRET).unwrap();
+        fs.create_directory("docs").unwrap();
+        let mut data = vec![7u8; BLOCK_SIZE as usize];
+        data.extend(vec![0; 2 * BLOCK_SIZE as usize]);
+        add_bytes(&mut fs, "docs", "sparse", &data);
+        fs.sparsify("docs", "sparse").unwrap();
+
+        // Holes are found without the flag as well
+        fs.superblock.flags &= !FLAG_SPARSE;
+        fs.save_superblock().unwrap();
+        let err = fs.downgrade(8).unwrap_err();
+        assert_eq!(err.to_string(), "Version 8 cannot store the sparse files");
+
+        // Holes are readable since version 9, upgrade flags them
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        fs.superblock.flags &= !FLAG_SPARSE;
+        fs.save_superblock().unwrap();
+        fs.downgrade(9).unwrap();
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_ne!(fs.superblock.flags & FLAG_SPARSE, 0);
+        let mut out = vec![];
+        fs.get_file_data("docs", "sparse", &mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let img = TempImage::new("downgrade");
+        {
+            let mut fs =
+                FS::init_with_label(img.path(), SECRET, "old").unwrap();
+            fs.create_directory("docs").unwrap();
+            add_bytes(&mut fs, "docs", "a", b"first");
+            add_bytes(&mut fs, "docs", "b", &vec![1u8; 5000]);
+            fs.set_index_compression(true).unwrap();
+            fs.set_min_free_inodes(3).unwrap();
+
+            // Older layouts cannot store the setting
+            let err = fs.downgrade(6).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Version 6 cannot store the minimum free inodes"
+            );
+        }
+
+        let (dir_hash, uuid) = {
+            let mut fs = FS::new(img.path(), SECRET).unwrap();
+            assert_eq!(fs.min_free_inodes(), 3);
+            fs.set_min_free_inodes(0).unwrap();
+            assert!(fs.index_compression());
+            let state = (fs.directory_hash("docs").unwrap(), fs.uuid());
+            assert!(fs.downgrade(FS_VERSION).is_err());
+            let fs = FS::new(img.path(), SECRET).unwrap();
+            assert!(fs.downgrade(2).is_err());
+            FS::new(img.path(), SECRET).unwrap().downgrade(4).unwrap();
+            state
+        };
+
+        // No key check, the directory index tells
+        assert!(FS::check_secret(img.path(), SECRET));
+        assert!(!FS::check_secret(img.path(), "wrong secret"));
+
+        // Parsed the way a version 4 tool does
+        {
+            let mut fs = FS::open_read_write(img.path()).unwrap();
+            fs.cipher = cipher_by_id(XOR_CIPHER_ID, SECRET).unwrap();
+            assert_eq!(fs.superblock.fs_version, 4);
+            assert_eq!(fs.label(), "old");
+            assert_eq!(fs.uuid(), uuid);
+            let encoded = fs.superblock.encode();
+            let mut r = Cursor::new(&encoded);
+            Superblock::decode(&mut r).unwrap();
+            assert_eq!(r.position() as usize, encoded.len());
+
+            let data = fs.read_inode_bytes(ROOT_INODE_INDEX).unwrap();
+            let index: DirectoryIndexV1 = bincode::deserialize(&data).unwrap();
+            assert_eq!(
+                index.checksum,
+                calculate_checksum(&DirectoryIndexV1 {
+                    directories: index.directories.clone(),
+                    checksum: 0,
+                })
+            );
+            let data = fs
+                .read_inode_bytes(index.directories[&OsString::from("docs")])
+                .unwrap();
+            let docs: DirectoryV1 = bincode::deserialize(&data).unwrap();
+            assert_eq!(docs.files.len(), 2);
+        }
+
+        // Current tool upgrades it again
+        let mut fs = FS::new(img.path(), SECRET).unwrap();
+        assert_eq!(fs.superblock.fs_version, FS_VERSION);
+        assert!(!fs.index_compression());
+        assert_eq!(fs.directory_hash("docs").unwrap(), dir_hash);
+        let mut out = vec![];
+        fs.get_file_data("docs", "b", &mut out).unwrap();
+        assert_eq!(out, vec![1u8; 5000]);
+    }
+}
@@ -0,0 +1,86 @@
+//! Block allocation maps for visualization
+//! Every data block is one grayscale pixel, every group is one row
+
+use crate::Group;
+
+/// Pixel of an allocated block
+pub const ALLOCATED: u8 = 0;
+/// Pixel of a free block
+pub const FREE: u8 = 255;
+
+/// 8bit grayscale image, rows are stored top to bottom
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitmapImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl BitmapImage {
+    /// Render group bitmaps, groups have the same size
+    pub fn from_groups(groups: &[Group]) -> Self {
+        let width = groups.first().map(|g| g.blocks_per_group()).unwrap_or(0);
+        let pixels = groups
+            .iter()
+            .flat_map(|g| g.block_bitmap.iter())
+            .map(|used| if *used { ALLOCATED } else { FREE })
+            .collect();
+        Self {
+            width,
+            height: groups.len() as u32,
+            pixels,
+        }
+    }
+
+    /// Pixel at column x of row y
+    pub fn pixel(&self, x: u32, y: u32) -> u8 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// Encode as PNG
+    #[cfg(feature = "png")]
+    pub fn write_png<W: std::io::Write>(&self, mut w: W) -> anyhow::Result<()> {
+        use flate2::{write::ZlibEncoder, Compression};
+        use std::io::Write;
+
+        // 8bit grayscale, default compression, filter and no interlace
+        let mut header = vec![];
+        header.extend(self.width.to_be_bytes());
+        header.extend(self.height.to_be_bytes());
+        header.extend([8, 0, 0, 0, 0]);
+
+        // Every row starts with its filter type, 0 is none
+        let mut e = ZlibEncoder::new(vec![], Compression::default());
+        if self.width > 0 {
+            for row in self.pixels.chunks(self.width as usize) {
+                e.write_all(&[0])?;
+                e.write_all(row)?;
+            }
+        }
+        let data = e.finish()?;
+
+        w.write_all(b"\x89PNG\r\n\x1a\n")?;
+        write_chunk(&mut w, b"IHDR", &header)?;
+        write_chunk(&mut w, b"IDAT", &data)?;
+        write_chunk(&mut w, b"IEND", &[])?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "png")]
+fn write_chunk<W: std::io::Write>(
+    w: &mut W,
+    kind: &[u8; 4],
+    data: &[u8],
+) -> anyhow::Result<()> {
+    // Chunk checksum covers type and data
+    let mut checksum = crate::util::Checksum::new();
+    checksum.update(kind);
+    checksum.update(data);
+
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    w.write_all(&checksum.finalize().to_be_bytes())?;
+    Ok(())
+}
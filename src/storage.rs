@@ -0,0 +1,145 @@
+//! Backing storage of an image
+//! Images live in a host file, e.g. tests can keep them in memory.
+//! Reads and writes go through shared references like &File does
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Bytes of an image
+pub enum Storage {
+    /// Image file on the host
+    File(File),
+    /// Image bytes in memory, clones share the same bytes
+    Memory(Arc<Mutex<Cursor<Vec<u8>>>>),
+}
+
+impl Storage {
+    /// Empty in memory storage
+    pub fn memory() -> Self {
+        Self::Memory(Arc::new(Mutex::new(Cursor::new(vec![]))))
+    }
+
+    /// Image size in bytes
+    pub fn size(&self) -> io::Result<u64> {
+        match self {
+            Self::File(file) => Ok(file.metadata()?.len()),
+            Self::Memory(_) => Ok(self.memory_lock().get_ref().len() as u64),
+        }
+    }
+
+    /// Grow or cut the image, new bytes are zeros
+    pub fn set_len(&self, len: u64) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.set_len(len),
+            Self::Memory(_) => {
+                self.memory_lock().get_mut().resize(len as usize, 0);
+                Ok(())
+            }
+        }
+    }
+
+    /// Flush data to the disk, nothing to do in memory
+    pub fn sync_data(&self) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.sync_data(),
+            Self::Memory(_) => Ok(()),
+        }
+    }
+
+    /// Flush data and metadata to the disk, nothing to do in memory
+    pub fn sync_all(&self) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.sync_all(),
+            Self::Memory(_) => Ok(()),
+        }
+    }
+
+    /// Another handle of the same image
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::File(file) => Ok(Self::File(file.try_clone()?)),
+            Self::Memory(bytes) => Ok(Self::Memory(bytes.clone())),
+        }
+    }
+
+    /// Lock the in memory bytes, a panicked holder left them usable
+    fn memory_lock(&self) -> MutexGuard<'_, Cursor<Vec<u8>>> {
+        match self {
+            Self::File(_) => unreachable!("not an in memory storage"),
+            Self::Memory(bytes) => {
+                bytes.lock().unwrap_or_else(|e| e.into_inner())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(file) => f.debug_tuple("File").field(file).finish(),
+            // Image bytes are not printed
+            Self::Memory(_) => f
+                .debug_tuple("Memory")
+                .field(&self.memory_lock().get_ref().len())
+                .finish(),
+        }
+    }
+}
+
+impl Read for &Storage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Storage::File(file) => (&*file).read(buf),
+            Storage::Memory(_) => self.memory_lock().read(buf),
+        }
+    }
+}
+
+impl Write for &Storage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Storage::File(file) => (&*file).write(buf),
+            Storage::Memory(_) => self.memory_lock().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Storage::File(file) => (&*file).flush(),
+            Storage::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl Seek for &Storage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Storage::File(file) => (&*file).seek(pos),
+            Storage::Memory(_) => self.memory_lock().seek(pos),
+        }
+    }
+}
+
+impl Read for Storage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Storage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Seek for Storage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        (&*self).seek(pos)
+    }
+}
@@ -1,8 +1,10 @@
-use std::time::{self, SystemTime};
+use std::cell::Cell;
+use std::time::{self, Duration, Instant, SystemTime};
 
 use crc32fast::Hasher;
-
-use crate::BLOCK_SIZE;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::mpsc::{self, Receiver, SendError, SyncSender, TrySendError};
 
 /// Create 32bit checksums
 /// Wrapper struct around crc32fast hasher
@@ -10,6 +12,12 @@ pub struct Checksum {
     hasher: Hasher,
 }
 
+impl Default for Checksum {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Checksum {
     #[inline]
     pub fn new() -> Self {
@@ -18,6 +26,14 @@ impl Checksum {
         }
     }
 
+    /// Continue the checksum of already hashed data
+    #[inline]
+    pub fn with_initial(checksum: u32) -> Self {
+        Self {
+            hasher: crc32fast::Hasher::new_with_initial(checksum),
+        }
+    }
+
     #[inline]
     pub fn update(&mut self, bytes: &[u8]) {
         self.hasher.update(bytes);
@@ -49,9 +65,36 @@ pub fn now() -> u64 {
         .as_secs()
 }
 
+/// Time source of image timestamps in seconds
+/// A fixed clock makes images reproducible
+pub trait Clock: std::fmt::Debug {
+    fn now(&self) -> u64;
+}
+
+/// System time
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        now()
+    }
+}
+
+/// Always the same time
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub u64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Byte position of a block in an image of the given block size
 #[inline]
-pub fn block_seek_position(block_index: u32) -> u32 {
-    block_index * BLOCK_SIZE
+pub fn block_seek_position(block_index: u32, block_size: u32) -> u64 {
+    block_index as u64 * block_size as u64
 }
 
 #[inline]
@@ -76,7 +119,442 @@ pub fn create_lookup_table(secret: &[u8], block_size: u32) -> Vec<u8> {
     // unsafe { res.set_len(block_size as usize) };
 
     (0..block_size)
-        .into_iter()
         .map(|i| secret[i as usize & (secret.len() - 1)])
         .collect()
 }
+
+/// Compress bytes with deflate
+#[inline]
+pub fn compress(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompress deflate compressed bytes
+#[inline]
+pub fn decompress(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut res = Vec::new();
+    DeflateDecoder::new(bytes).read_to_end(&mut res)?;
+    Ok(res)
+}
+
+/// Append (start, length) block ranges
+/// merging a range into the previous one when they are adjacent
+pub fn merge_ranges(ranges: &mut Vec<(u32, u32)>, new_ranges: &[(u32, u32)]) {
+    for (start, len) in new_ranges.iter().copied() {
+        match ranges.last_mut() {
+            Some((last_start, last_len))
+                if *last_start + *last_len == start =>
+            {
+                *last_len += len
+            }
+            _ => ranges.push((start, len)),
+        }
+    }
+}
+
+/// Rate limiter for block reads and writes
+/// Sleeps when an operation gets ahead of the allowed rate
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: u64,
+    started: Cell<Instant>,
+    bytes: Cell<u64>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0);
+        Self {
+            bytes_per_sec,
+            started: Cell::new(Instant::now()),
+            bytes: Cell::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Start measuring a new operation
+    #[inline]
+    pub fn begin(&self) {
+        self.started.set(Instant::now());
+        self.bytes.set(0);
+    }
+
+    /// Account transferred bytes, sleep if over the rate
+    #[inline]
+    pub fn consume(&self, bytes: u64) {
+        self.bytes.set(self.bytes.get() + bytes);
+        let expected = Duration::from_secs_f64(
+            self.bytes.get() as f64 / self.bytes_per_sec as f64,
+        );
+        let elapsed = self.started.get().elapsed();
+        if expected > elapsed {
+            std::thread::sleep(expected - elapsed);
+        }
+    }
+}
+
+/// Bounded channel of data chunks read as one stream
+/// e.g. items of an async stream forwarded to a blocking
+/// add_file_streaming. The producer waits while bound chunks are queued,
+/// an async task should use try_send and yield when the channel is full.
+/// Data ends when a sender calls finish, senders dropped before it
+/// fail the read with UnexpectedEof, so a failed producer never
+/// leaves a truncated file
+pub fn chunk_channel(bound: usize) -> (ChunkSender, ChunkReader) {
+    let (tx, rx) = mpsc::sync_channel(bound);
+    let reader = ChunkReader {
+        rx,
+        chunk: vec![],
+        position: 0,
+        finished: false,
+    };
+    (ChunkSender { tx }, reader)
+}
+
+/// Message of chunk_channel
+#[derive(Debug)]
+enum ChunkMessage {
+    Data(Vec<u8>),
+    End,
+}
+
+impl ChunkMessage {
+    fn into_data(self) -> Vec<u8> {
+        match self {
+            Self::Data(chunk) => chunk,
+            Self::End => vec![],
+        }
+    }
+}
+
+/// Sending side of chunk_channel
+#[derive(Debug, Clone)]
+pub struct ChunkSender {
+    tx: SyncSender<ChunkMessage>,
+}
+
+impl ChunkSender {
+    /// Queue a chunk, waits while the channel is full
+    pub fn send(&self, chunk: Vec<u8>) -> Result<(), SendError<Vec<u8>>> {
+        self.tx
+            .send(ChunkMessage::Data(chunk))
+            .map_err(|SendError(message)| SendError(message.into_data()))
+    }
+
+    /// Queue a chunk without waiting
+    pub fn try_send(
+        &self,
+        chunk: Vec<u8>,
+    ) -> Result<(), TrySendError<Vec<u8>>> {
+        self.tx
+            .try_send(ChunkMessage::Data(chunk))
+            .map_err(|e| match e {
+                TrySendError::Full(message) => {
+                    TrySendError::Full(message.into_data())
+                }
+                TrySendError::Disconnected(message) => {
+                    TrySendError::Disconnected(message.into_data())
+                }
+            })
+    }
+
+    /// Mark the data complete, waits while the channel is full
+    pub fn finish(self) -> Result<(), SendError<()>> {
+        self.tx.send(ChunkMessage::End).map_err(|_| SendError(()))
+    }
+}
+
+/// Receiving side of chunk_channel
+#[derive(Debug)]
+pub struct ChunkReader {
+    rx: Receiver<ChunkMessage>,
+    chunk: Vec<u8>,
+    position: usize, // Read position inside the chunk
+    finished: bool,  // End marker received
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Empty chunks are skipped
+        while self.position == self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            match self.rx.recv() {
+                Ok(ChunkMessage::Data(chunk)) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                Ok(ChunkMessage::End) => self.finished = true,
+                Err(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "Chunk senders dropped before finish",
+                    ))
+                }
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+
+        Ok(n)
+    }
+}
+
+/// Retry policy for transient I/O errors
+/// WouldBlock and TimedOut errors are retried with a doubling delay,
+/// interrupts are already retried by the std read and write helpers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// Single attempt, no retry
+    fn default() -> Self {
+        Self::new(1, Duration::from_millis(10))
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, backoff: Duration) -> Self {
+        assert!(max_attempts > 0);
+        Self {
+            max_attempts,
+            backoff,
+        }
+    }
+
+    #[inline]
+    fn is_transient(e: &std::io::Error) -> bool {
+        matches!(
+            e.kind(),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        )
+    }
+
+    /// Run operation until it succeeds, fails permanently
+    /// or runs out of attempts. Op gets the attempt number from 1
+    pub fn run<T, F>(&self, mut op: F) -> std::io::Result<T>
+    where
+        F: FnMut(u32) -> std::io::Result<T>,
+    {
+        let mut delay = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match op(attempt) {
+                Err(e)
+                    if Self::is_transient(&e)
+                        && attempt < self.max_attempts =>
+                {
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Read buf from pos, r has to be positioned at pos already
+/// A failed attempt leaves the position unknown, so retries seek first
+pub fn read_exact_at<R>(
+    r: &mut R,
+    pos: u64,
+    buf: &mut [u8],
+    policy: &RetryPolicy,
+) -> std::io::Result<()>
+where
+    R: Read + Seek,
+{
+    policy.run(|attempt| {
+        if attempt > 1 {
+            r.seek(SeekFrom::Start(pos))?;
+        }
+        r.read_exact(buf)
+    })
+}
+
+/// Read buf from pos without using the file position
+/// Unix reads with pread, Windows with an offset ReadFile,
+/// so threads sharing the file do not race on a seek
+#[cfg(unix)]
+pub fn read_exact_pos(
+    file: &std::fs::File,
+    pos: u64,
+    buf: &mut [u8],
+) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, pos)
+}
+
+/// Read buf from pos without using the file position
+/// Unix reads with pread, Windows with an offset ReadFile,
+/// so threads sharing the file do not race on a seek
+#[cfg(windows)]
+pub fn read_exact_pos(
+    file: &std::fs::File,
+    mut pos: u64,
+    mut buf: &mut [u8],
+) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, pos) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                pos += n as u64;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Write buf to pos, w has to be positioned at pos already
+pub fn write_all_at<W>(
+    w: &mut W,
+    pos: u64,
+    buf: &[u8],
+    policy: &RetryPolicy,
+) -> std::io::Result<()>
+where
+    W: Write + Seek,
+{
+    policy.run(|attempt| {
+        if attempt > 1 {
+            w.seek(SeekFrom::Start(pos))?;
+        }
+        w.write_all(buf)
+    })
+}
+
+/// Compare names with digit runs compared by their value
+/// e.g. file2 < file10, equal values fall back to byte order
+pub fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let (mut a_rest, mut b_rest) = (a, b);
+    loop {
+        let (a_chunk, a_next) = next_chunk(a_rest);
+        let (b_chunk, b_next) = next_chunk(b_rest);
+        let ord = match (a_chunk, b_chunk) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return std::cmp::Ordering::Less,
+            (Some(_), None) => return std::cmp::Ordering::Greater,
+            (Some(x), Some(y)) if is_digits(x) && is_digits(y) => {
+                let x = x.trim_start_matches('0');
+                let y = y.trim_start_matches('0');
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            (Some(x), Some(y)) => x.cmp(y),
+        };
+        if ord.is_ne() {
+            return ord;
+        }
+        a_rest = a_next;
+        b_rest = b_next;
+    }
+}
+
+#[inline]
+fn is_digits(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Split the leading run of digits or non digits
+#[inline]
+fn next_chunk(s: &str) -> (Option<&str>, &str) {
+    let first = match s.bytes().next() {
+        Some(b) => b.is_ascii_digit(),
+        None => return (None, s),
+    };
+    let end = s
+        .bytes()
+        .position(|b| b.is_ascii_digit() != first)
+        .unwrap_or(s.len());
+    (Some(&s[..end]), &s[end..])
+}
+
+/// Match name against a glob pattern
+/// `*` matches any sequence, `?` exactly one character
+/// Returns the text matched by each wildcard in order
+pub fn glob_match(pattern: &str, name: &str) -> Option<Vec<String>> {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let mut captures = vec![];
+    if glob_match_at(&pattern, &name, &mut captures) {
+        Some(captures)
+    } else {
+        None
+    }
+}
+
+fn glob_match_at(
+    pattern: &[char],
+    name: &[char],
+    captures: &mut Vec<String>,
+) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => {
+            // Try the shortest match first
+            for i in 0..=name.len() {
+                captures.push(name[..i].iter().collect());
+                if glob_match_at(&pattern[1..], &name[i..], captures) {
+                    return true;
+                }
+                captures.pop();
+            }
+            false
+        }
+        Some('?') => {
+            if name.is_empty() {
+                return false;
+            }
+            captures.push(name[0].to_string());
+            if glob_match_at(&pattern[1..], &name[1..], captures) {
+                return true;
+            }
+            captures.pop();
+            false
+        }
+        Some(c) => {
+            name.first() == Some(c)
+                && glob_match_at(&pattern[1..], &name[1..], captures)
+        }
+    }
+}
+
+/// Fill template wildcards with glob captures
+/// Each `*` or `?` takes the next capture in order
+pub fn fill_template(
+    template: &str,
+    captures: &[String],
+) -> anyhow::Result<String> {
+    let mut captures = captures.iter();
+    let mut res = String::new();
+    for c in template.chars() {
+        match c {
+            '*' | '?' => match captures.next() {
+                Some(capture) => res.push_str(capture),
+                None => {
+                    return Err(anyhow::anyhow!(
+                        "Template has more wildcards than the pattern"
+                    ))
+                }
+            },
+            c => res.push(c),
+        }
+    }
+    Ok(res)
+}